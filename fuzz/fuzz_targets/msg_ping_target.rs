@@ -3,13 +3,10 @@
 
 extern crate lightning;
 
-use lightning::util::reset_rng_state;
-
 use lightning::ln::msgs::{MsgEncodable, MsgDecodable, Ping};
 
 #[inline]
 pub fn do_test(data: &[u8]) {
-	reset_rng_state();
 	if let Ok(msg) = Ping::decode(data) {
 		let _ = msg.encode();
 	}
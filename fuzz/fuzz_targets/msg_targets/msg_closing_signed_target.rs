@@ -4,7 +4,6 @@
 extern crate lightning;
 
 use lightning::ln::msgs;
-use lightning::util::reset_rng_state;
 
 use lightning::ln::msgs::{MsgEncodable, MsgDecodable};
 
@@ -12,7 +11,6 @@ mod utils;
 
 #[inline]
 pub fn do_test(data: &[u8]) {
-	reset_rng_state();
 	test_msg!(msgs::ClosingSigned, data);
 }
 
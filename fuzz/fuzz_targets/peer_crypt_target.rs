@@ -2,7 +2,7 @@ extern crate lightning;
 extern crate secp256k1;
 
 use lightning::ln::peer_channel_encryptor::PeerChannelEncryptor;
-use lightning::util::reset_rng_state;
+use lightning::util::rng::SeededEntropySource;
 
 use secp256k1::key::{PublicKey,SecretKey};
 use secp256k1::Secp256k1;
@@ -15,7 +15,7 @@ fn slice_to_be16(v: &[u8]) -> u16 {
 
 #[inline]
 pub fn do_test(data: &[u8]) {
-	reset_rng_state();
+	let entropy_source = SeededEntropySource::new([0; 32]);
 
 	let mut read_pos = 0;
 	macro_rules! get_slice {
@@ -42,7 +42,7 @@ pub fn do_test(data: &[u8]) {
 			Ok(key) => key,
 			Err(_) => return,
 		};
-		let mut crypter = PeerChannelEncryptor::new_outbound(their_pubkey);
+		let mut crypter = PeerChannelEncryptor::new_outbound(their_pubkey, &entropy_source);
 		crypter.get_act_one();
 		match crypter.process_act_two(get_slice!(50), &our_network_key) {
 			Ok(_) => {},
@@ -52,7 +52,7 @@ pub fn do_test(data: &[u8]) {
 		crypter
 	} else {
 		let mut crypter = PeerChannelEncryptor::new_inbound(&our_network_key);
-		match crypter.process_act_one_with_key(get_slice!(50), &our_network_key) {
+		match crypter.process_act_one_with_key(get_slice!(50), &our_network_key, &entropy_source) {
 			Ok(_) => {},
 			Err(_) => return,
 		}
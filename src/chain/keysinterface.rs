@@ -0,0 +1,246 @@
+use bitcoin::blockdata::script::{Script, Builder};
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::blockdata::opcodes;
+use bitcoin::network::constants::Network;
+use bitcoin::util::bip32::{ExtendedPrivKey, ChildNumber};
+use bitcoin::util::hash::Hash160;
+
+use secp256k1::key::{SecretKey, PublicKey};
+use secp256k1::{Secp256k1, Signature};
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use ln::channel::ChannelKeys;
+use ln::msgs;
+use util::byte_utils;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The public keys which identify our side of a channel, derived from the ChannelSigner's
+/// private keys and sent to our counterparty in open_channel/accept_channel.
+#[derive(Clone, PartialEq)]
+pub struct ChannelPublicKeys {
+	pub funding_pubkey: PublicKey,
+	pub revocation_basepoint: PublicKey,
+	pub payment_basepoint: PublicKey,
+	pub delayed_payment_basepoint: PublicKey,
+	pub htlc_basepoint: PublicKey,
+}
+
+/// A trait which holds the private keys for a single channel and produces all of the signatures
+/// Channel and ChannelMonitor need. Neither ever sees the private keys themselves, so an
+/// implementation may keep them in a separate process or hardware device.
+/// Each signing function is given the full transaction so that implementations can check what
+/// they are signing, and may refuse (by returning Err) if it violates their policy. Note that
+/// a refusal will generally cause the relevant message handling to fail (or, in ChannelMonitor,
+/// the claim transaction to not be broadcast), so it should not be done lightly.
+/// As with KeysInterface, all functions must be reentrant-safe.
+pub trait ChannelSigner: Send + Sync {
+	/// Gets the public keys (funding pubkey and basepoints) for our side of the channel
+	fn pubkeys(&self, secp_ctx: &Secp256k1) -> ChannelPublicKeys;
+	/// Gets the per-commitment point for our commitment transaction with the given commitment
+	/// number (note that commitment numbers count down from 2^48 - 1).
+	fn get_per_commitment_point(&self, secp_ctx: &Secp256k1, commitment_number: u64) -> PublicKey;
+	/// Releases the per-commitment secret for the given commitment number, revoking our
+	/// commitment transaction with that number. Once this has been called, implementations must
+	/// never again sign that (or any older) local commitment transaction.
+	fn release_commitment_secret(&self, commitment_number: u64) -> [u8; 32];
+	/// Signs a commitment transaction which our counterparty will hold, spending the funding
+	/// output (which pays to funding_redeemscript.to_v0_p2wsh()).
+	fn sign_remote_commitment(&self, secp_ctx: &Secp256k1, commitment_tx: &Transaction, commitment_number: u64, funding_redeemscript: &Script, channel_value_satoshis: u64) -> Result<Signature, ()>;
+	/// Signs our own commitment transaction (which our counterparty has already signed), making
+	/// it broadcastable.
+	fn sign_local_commitment(&self, secp_ctx: &Secp256k1, commitment_tx: &Transaction, commitment_number: u64, funding_redeemscript: &Script, channel_value_satoshis: u64) -> Result<Signature, ()>;
+	/// Signs the given input of a transaction spending an HTLC output of a commitment transaction
+	/// (ie an HTLC-Success/HTLC-Timeout transaction, or a claim of a remote HTLC output), using our
+	/// HTLC key for the commitment transaction with the given per_commitment_point.
+	fn sign_htlc_transaction(&self, secp_ctx: &Secp256k1, htlc_tx: &Transaction, input: usize, htlc_redeemscript: &Script, amount_satoshis: u64, per_commitment_point: &PublicKey) -> Result<Signature, ()>;
+	/// Signs the given input of a transaction claiming an output of a revoked remote commitment
+	/// (or HTLC) transaction, using the revocation key derived from per_commitment_key, the
+	/// per-commitment secret our counterparty revealed when they revoked it.
+	fn sign_justice_transaction(&self, secp_ctx: &Secp256k1, justice_tx: &Transaction, input: usize, redeemscript: &Script, amount_satoshis: u64, per_commitment_key: &SecretKey) -> Result<Signature, ()>;
+	/// Signs a cooperative closing transaction spending the funding output.
+	fn sign_closing_transaction(&self, secp_ctx: &Secp256k1, closing_tx: &Transaction, funding_redeemscript: &Script, channel_value_satoshis: u64) -> Result<Signature, ()>;
+	/// Signs a channel_announcement with our funding key (ie the bitcoin_key signature).
+	fn sign_channel_announcement(&self, secp_ctx: &Secp256k1, msg: &msgs::UnsignedChannelAnnouncement) -> Result<Signature, ()>;
+}
+
+/// A trait to describe an object which can get user secrets and key material.
+/// Note that all of the functions implemented here *must* be reentrant-safe as they may be
+/// called from multiple threads at once.
+pub trait KeysInterface: Send + Sync {
+	/// Gets the secret key for our node, which identifies us in the network (aka our node_id)
+	fn get_node_secret(&self) -> SecretKey;
+	/// Gets the scriptPubKey which funds we claim from the chain (eg after our counterparty
+	/// broadcasts a revoked commitment transaction) will be sent to.
+	fn get_destination_script(&self) -> Script;
+	/// Gets the public key which, as a P2WPKH output, funds will be sent to on cooperative
+	/// channel close, unless a shutdown scriptpubkey is provided for the channel.
+	fn get_shutdown_pubkey(&self) -> PublicKey;
+	/// Gets a new ChannelSigner, holding a fresh set of keys, for a channel. These *must* be
+	/// unique for each call, even across restarts!
+	fn get_channel_signer(&self) -> Arc<ChannelSigner>;
+}
+
+/// BIP32 derivation only fails if it produces an invalid private key, which happens with
+/// negligible probability for any given seed and path.
+const BIP32_DERIVATION_FAILED: &str = "BIP32 derivation from our seed produced an invalid key, which should never happen";
+
+/// The first BIP 32 child index which is hardened, and thus one past the last channel index we
+/// can derive keys for.
+const MAX_CHANNEL_INDEX: usize = 1 << 31;
+
+/// Simple KeysInterface implementor that derives all keys from a single 32-byte seed via BIP 32.
+/// The node secret is derived at m/0', the destination script's key at m/1' and the shutdown
+/// pubkey at m/2'. The seed for the Nth channel's keys is the SHA256 of the key at m/3'/N' and
+/// the KeysManager's starting time, so that channel keys are never re-used even if the channel
+/// index isn't persisted (or is restored from a stale backup).
+pub struct KeysManager {
+	secp_ctx: Secp256k1,
+	node_secret: SecretKey,
+	destination_script: Script,
+	shutdown_pubkey: PublicKey,
+	channel_master_key: ExtendedPrivKey,
+	channel_child_index: AtomicUsize,
+	starting_time_secs: u64,
+	starting_time_nanos: u32,
+}
+
+impl KeysManager {
+	/// Constructs a KeysManager from a 32-byte seed. The seed should be generated from a secure
+	/// source of randomness and persisted, as our node secret, destination script and shutdown
+	/// pubkey can be re-derived from it.
+	/// next_channel_index is the index of the next channel's keys, ie the number of channels
+	/// previously opened using this seed (see get_next_channel_index), and must be below 2^31.
+	/// starting_time_secs and starting_time_nanos must be unique for each KeysManager constructed
+	/// with the same seed, eg the current time since the UNIX epoch. They are mixed into each
+	/// channel's keys, as channel keys are handed out both for channels we open and channels
+	/// opened to us, and thus the index may well be stale on restart. Channel keys are held by
+	/// each channel's ChannelMonitor, so need not be re-derived from the seed.
+	pub fn new(seed: &[u8; 32], network: Network, next_channel_index: u32, starting_time_secs: u64, starting_time_nanos: u32) -> KeysManager {
+		assert!((next_channel_index as usize) < MAX_CHANNEL_INDEX, "next_channel_index must be below 2^31");
+		let secp_ctx = Secp256k1::new();
+		match ExtendedPrivKey::new_master(&secp_ctx, network, seed) {
+			Ok(master_key) => {
+				let node_secret = master_key.ckd_priv(&secp_ctx, ChildNumber::Hardened(0)).expect(BIP32_DERIVATION_FAILED).secret_key;
+				let destination_script = match master_key.ckd_priv(&secp_ctx, ChildNumber::Hardened(1)) {
+					Ok(destination_key) => {
+						let pubkey_hash = Hash160::from_data(&PublicKey::from_secret_key(&secp_ctx, &destination_key.secret_key).unwrap().serialize());
+						Builder::new().push_opcode(opcodes::All::OP_PUSHBYTES_0)
+						              .push_slice(&pubkey_hash[..])
+						              .into_script()
+					},
+					Err(_) => panic!("{}", BIP32_DERIVATION_FAILED),
+				};
+				let shutdown_pubkey = match master_key.ckd_priv(&secp_ctx, ChildNumber::Hardened(2)) {
+					Ok(shutdown_key) => PublicKey::from_secret_key(&secp_ctx, &shutdown_key.secret_key).unwrap(),
+					Err(_) => panic!("{}", BIP32_DERIVATION_FAILED),
+				};
+				let channel_master_key = master_key.ckd_priv(&secp_ctx, ChildNumber::Hardened(3)).expect(BIP32_DERIVATION_FAILED);
+				KeysManager {
+					secp_ctx: secp_ctx,
+					node_secret: node_secret,
+					destination_script: destination_script,
+					shutdown_pubkey: shutdown_pubkey,
+					channel_master_key: channel_master_key,
+					channel_child_index: AtomicUsize::new(next_channel_index as usize),
+					starting_time_secs,
+					starting_time_nanos,
+				}
+			},
+			Err(_) => panic!("{}", BIP32_DERIVATION_FAILED),
+		}
+	}
+
+	/// Gets the index of the next channel's keys, ie the next_channel_index to pass to new() on
+	/// restart. While the starting time passed to new() keeps channel keys unique on its own,
+	/// persisting this (each time a channel is opened via create_channel or upon receiving an
+	/// open_channel) avoids relying on the clock alone.
+	pub fn get_next_channel_index(&self) -> u32 {
+		self.channel_child_index.load(Ordering::Acquire) as u32
+	}
+}
+
+impl KeysInterface for KeysManager {
+	fn get_node_secret(&self) -> SecretKey {
+		self.node_secret.clone()
+	}
+
+	fn get_destination_script(&self) -> Script {
+		self.destination_script.clone()
+	}
+
+	fn get_shutdown_pubkey(&self) -> PublicKey {
+		self.shutdown_pubkey.clone()
+	}
+
+	fn get_channel_signer(&self) -> Arc<ChannelSigner> {
+		let child_ix = self.channel_child_index.fetch_add(1, Ordering::AcqRel);
+		if child_ix >= MAX_CHANNEL_INDEX {
+			panic!("Exhausted the 2^31 channel key indexes available for this KeysManager");
+		}
+		let child_privkey = self.channel_master_key.ckd_priv(&self.secp_ctx, ChildNumber::Hardened(child_ix as u32)).expect(BIP32_DERIVATION_FAILED);
+		let mut sha = Sha256::new();
+		sha.input(&child_privkey.secret_key[..]);
+		sha.input(&byte_utils::be64_to_array(self.starting_time_secs));
+		sha.input(&byte_utils::be32_to_array(self.starting_time_nanos));
+		let mut seed = [0; 32];
+		sha.result(&mut seed);
+		Arc::new(ChannelKeys::new_from_seed(&seed).expect("Channel keys derived from a BIP32 child key were invalid, which should never happen"))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bitcoin::network::constants::Network;
+
+	use chain::keysinterface::{KeysInterface, KeysManager};
+
+	use secp256k1::Secp256k1;
+
+	#[test]
+	fn keys_manager_restart_test() {
+		// Tests that a KeysManager reloaded from the same seed re-derives the same node keys and
+		// continues where the first one left off, and that channel keys are unique even if the
+		// channel index is stale, as long as the starting time differs.
+		let secp_ctx = Secp256k1::new();
+		let seed = [42; 32];
+		let keys_manager = KeysManager::new(&seed, Network::Testnet, 0, 1000, 0);
+		assert_eq!(keys_manager.get_next_channel_index(), 0);
+		let channel_keys: Vec<_> = (0..3).map(|_| keys_manager.get_channel_signer().pubkeys(&secp_ctx)).collect();
+		assert_eq!(keys_manager.get_next_channel_index(), 3);
+		assert!(channel_keys[0] != channel_keys[1] && channel_keys[1] != channel_keys[2] && channel_keys[0] != channel_keys[2]);
+
+		let restarted = KeysManager::new(&seed, Network::Testnet, keys_manager.get_next_channel_index(), 1001, 0);
+		assert_eq!(restarted.get_node_secret(), keys_manager.get_node_secret());
+		assert!(restarted.get_destination_script() == keys_manager.get_destination_script());
+		assert_eq!(restarted.get_shutdown_pubkey(), keys_manager.get_shutdown_pubkey());
+		let next_keys = restarted.get_channel_signer().pubkeys(&secp_ctx);
+		assert!(!channel_keys.contains(&next_keys));
+		assert_eq!(restarted.get_next_channel_index(), 4);
+
+		// Channel keys depend only on the seed, index and starting time...
+		let same_start = KeysManager::new(&seed, Network::Testnet, 1, 1000, 0);
+		assert!(same_start.get_channel_signer().pubkeys(&secp_ctx) == channel_keys[1]);
+
+		// ...so restarting from a stale index still gives fresh keys
+		let stale_index = KeysManager::new(&seed, Network::Testnet, 0, 1000, 1);
+		assert!(!channel_keys.contains(&stale_index.get_channel_signer().pubkeys(&secp_ctx)));
+
+		// ...and a different seed gives entirely different keys
+		let other = KeysManager::new(&[43; 32], Network::Testnet, 0, 1000, 0);
+		assert!(other.get_node_secret() != keys_manager.get_node_secret());
+		assert!(other.get_channel_signer().pubkeys(&secp_ctx) != channel_keys[0]);
+	}
+
+	#[test]
+	#[should_panic(expected = "Exhausted the 2^31 channel key indexes available for this KeysManager")]
+	fn keys_manager_index_exhaustion_test() {
+		// The last hardened index is usable, but we must not wrap around to re-use index 0's keys
+		let keys_manager = KeysManager::new(&[42; 32], Network::Testnet, (1 << 31) - 1, 0, 0);
+		keys_manager.get_channel_signer();
+		keys_manager.get_channel_signer();
+	}
+}
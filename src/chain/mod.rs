@@ -1,2 +1,3 @@
 pub mod chaininterface;
+pub mod keysinterface;
 pub mod transaction;
@@ -1,5 +1,8 @@
 #![crate_name = "lightning"]
 
+#![cfg_attr(all(test, feature = "unstable"), feature(test))]
+#[cfg(all(test, feature = "unstable"))] extern crate test;
+
 extern crate bitcoin;
 extern crate secp256k1;
 extern crate rand;
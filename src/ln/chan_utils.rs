@@ -114,6 +114,7 @@ pub fn derive_public_revocation_key(secp_ctx: &Secp256k1, per_commitment_point:
 	part_a.combine(&secp_ctx, &part_b)
 }
 
+#[derive(Clone, PartialEq)]
 pub struct TxCreationKeys {
 	pub per_commitment_point: PublicKey,
 	pub revocation_key: PublicKey,
@@ -151,7 +152,7 @@ pub fn get_revokeable_redeemscript(revocation_key: &PublicKey, to_self_delay: u1
 	              .into_script()
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct HTLCOutputInCommitment {
 	pub offered: bool,
 	pub amount_msat: u64,
@@ -16,27 +16,29 @@ use crypto::hkdf::{hkdf_extract,hkdf_expand};
 
 use ln::msgs;
 use ln::msgs::{HandleError, MsgEncodable};
-use ln::channelmonitor::ChannelMonitor;
-use ln::channelmanager::{PendingForwardHTLCInfo, HTLCFailReason};
+use ln::channelmonitor::{ChannelMonitor, ChannelMonitorUpdate, ChannelMonitorUpdateStep};
+use ln::channelmanager::{PendingForwardHTLCInfo, HTLCFailReason, ChannelForwardingPolicy, ClosingFeeParameters};
 use ln::chan_utils::{TxCreationKeys,HTLCOutputInCommitment,HTLC_SUCCESS_TX_WEIGHT,HTLC_TIMEOUT_TX_WEIGHT};
 use ln::chan_utils;
 use chain::chaininterface::{FeeEstimator,ConfirmationTarget};
+use chain::keysinterface::{KeysInterface, ChannelSigner, ChannelPublicKeys};
 use chain::transaction::OutPoint;
-use util::{transaction_utils,rng};
+use util::transaction_utils;
+use util::rng::EntropySource;
 use util::sha2::Sha256;
 
 use std::default::Default;
 use std::{cmp,mem};
-use std::time::Instant;
+use std::sync::Arc;
+use std::time::{Instant,SystemTime,UNIX_EPOCH};
 
+#[derive(Clone)]
 pub struct ChannelKeys {
 	pub funding_key: SecretKey,
 	pub revocation_base_key: SecretKey,
 	pub payment_base_key: SecretKey,
 	pub delayed_payment_base_key: SecretKey,
 	pub htlc_base_key: SecretKey,
-	pub channel_close_key: SecretKey,
-	pub channel_monitor_claim_key: SecretKey,
 	pub commitment_seed: [u8; 32],
 }
 
@@ -62,12 +64,6 @@ impl ChannelKeys {
 		hkdf_expand(Sha256::new(), &prk, b"rust-lightning htlc base key info", &mut okm);
 		let htlc_base_key = SecretKey::from_slice(&secp_ctx, &okm)?;
 
-		hkdf_expand(Sha256::new(), &prk, b"rust-lightning channel close key info", &mut okm);
-		let channel_close_key = SecretKey::from_slice(&secp_ctx, &okm)?;
-
-		hkdf_expand(Sha256::new(), &prk, b"rust-lightning channel monitor claim key info", &mut okm);
-		let channel_monitor_claim_key = SecretKey::from_slice(&secp_ctx, &okm)?;
-
 		hkdf_expand(Sha256::new(), &prk, b"rust-lightning local commitment seed info", &mut okm);
 
 		Ok(ChannelKeys {
@@ -76,11 +72,74 @@ impl ChannelKeys {
 			payment_base_key: payment_base_key,
 			delayed_payment_base_key: delayed_payment_base_key,
 			htlc_base_key: htlc_base_key,
-			channel_close_key: channel_close_key,
-			channel_monitor_claim_key: channel_monitor_claim_key,
 			commitment_seed: okm
 		})
 	}
+
+	fn sign_funding_input(&self, secp_ctx: &Secp256k1, tx: &Transaction, funding_redeemscript: &Script, channel_value_satoshis: u64) -> Result<Signature, ()> {
+		if tx.input.len() != 1 {
+			return Err(());
+		}
+		let sighash = Message::from_slice(&bip143::SighashComponents::new(tx).sighash_all(&tx.input[0], funding_redeemscript, channel_value_satoshis)[..]).map_err(|_| ())?;
+		secp_ctx.sign(&sighash, &self.funding_key).map_err(|_| ())
+	}
+}
+
+/// The default, in-process ChannelSigner, which simply holds the private keys in memory.
+impl ChannelSigner for ChannelKeys {
+	fn pubkeys(&self, secp_ctx: &Secp256k1) -> ChannelPublicKeys {
+		ChannelPublicKeys {
+			funding_pubkey: PublicKey::from_secret_key(secp_ctx, &self.funding_key).unwrap(),
+			revocation_basepoint: PublicKey::from_secret_key(secp_ctx, &self.revocation_base_key).unwrap(),
+			payment_basepoint: PublicKey::from_secret_key(secp_ctx, &self.payment_base_key).unwrap(),
+			delayed_payment_basepoint: PublicKey::from_secret_key(secp_ctx, &self.delayed_payment_base_key).unwrap(),
+			htlc_basepoint: PublicKey::from_secret_key(secp_ctx, &self.htlc_base_key).unwrap(),
+		}
+	}
+
+	fn get_per_commitment_point(&self, secp_ctx: &Secp256k1, commitment_number: u64) -> PublicKey {
+		let secret = chan_utils::build_commitment_secret(self.commitment_seed, commitment_number);
+		PublicKey::from_secret_key(secp_ctx, &SecretKey::from_slice(secp_ctx, &secret).unwrap()).unwrap()
+	}
+
+	fn release_commitment_secret(&self, commitment_number: u64) -> [u8; 32] {
+		chan_utils::build_commitment_secret(self.commitment_seed, commitment_number)
+	}
+
+	fn sign_remote_commitment(&self, secp_ctx: &Secp256k1, commitment_tx: &Transaction, _commitment_number: u64, funding_redeemscript: &Script, channel_value_satoshis: u64) -> Result<Signature, ()> {
+		self.sign_funding_input(secp_ctx, commitment_tx, funding_redeemscript, channel_value_satoshis)
+	}
+
+	fn sign_local_commitment(&self, secp_ctx: &Secp256k1, commitment_tx: &Transaction, _commitment_number: u64, funding_redeemscript: &Script, channel_value_satoshis: u64) -> Result<Signature, ()> {
+		self.sign_funding_input(secp_ctx, commitment_tx, funding_redeemscript, channel_value_satoshis)
+	}
+
+	fn sign_htlc_transaction(&self, secp_ctx: &Secp256k1, htlc_tx: &Transaction, input: usize, htlc_redeemscript: &Script, amount_satoshis: u64, per_commitment_point: &PublicKey) -> Result<Signature, ()> {
+		if input >= htlc_tx.input.len() {
+			return Err(());
+		}
+		let htlc_key = chan_utils::derive_private_key(secp_ctx, per_commitment_point, &self.htlc_base_key).map_err(|_| ())?;
+		let sighash = Message::from_slice(&bip143::SighashComponents::new(htlc_tx).sighash_all(&htlc_tx.input[input], htlc_redeemscript, amount_satoshis)[..]).map_err(|_| ())?;
+		secp_ctx.sign(&sighash, &htlc_key).map_err(|_| ())
+	}
+
+	fn sign_justice_transaction(&self, secp_ctx: &Secp256k1, justice_tx: &Transaction, input: usize, redeemscript: &Script, amount_satoshis: u64, per_commitment_key: &SecretKey) -> Result<Signature, ()> {
+		if input >= justice_tx.input.len() {
+			return Err(());
+		}
+		let revocation_key = chan_utils::derive_private_revocation_key(secp_ctx, per_commitment_key, &self.revocation_base_key).map_err(|_| ())?;
+		let sighash = Message::from_slice(&bip143::SighashComponents::new(justice_tx).sighash_all(&justice_tx.input[input], redeemscript, amount_satoshis)[..]).map_err(|_| ())?;
+		secp_ctx.sign(&sighash, &revocation_key).map_err(|_| ())
+	}
+
+	fn sign_closing_transaction(&self, secp_ctx: &Secp256k1, closing_tx: &Transaction, funding_redeemscript: &Script, channel_value_satoshis: u64) -> Result<Signature, ()> {
+		self.sign_funding_input(secp_ctx, closing_tx, funding_redeemscript, channel_value_satoshis)
+	}
+
+	fn sign_channel_announcement(&self, secp_ctx: &Secp256k1, msg: &msgs::UnsignedChannelAnnouncement) -> Result<Signature, ()> {
+		let msghash = Message::from_slice(&Sha256dHash::from_data(&msg.encode()[..])[..]).map_err(|_| ())?;
+		secp_ctx.sign(&msghash, &self.funding_key).map_err(|_| ())
+	}
 }
 
 #[derive(PartialEq)]
@@ -229,6 +288,14 @@ enum ChannelState {
 }
 const BOTH_SIDES_SHUTDOWN_MASK: u32 = (ChannelState::LocalShutdownSent as u32 | ChannelState::RemoteShutdownSent as u32);
 
+/// The order in which a held revoke_and_ack and commitment update must be sent to our
+/// counterparty once the ChannelMonitorUpdate they were waiting on completes.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RAACommitmentOrder {
+	CommitmentFirst,
+	RevokeAndACKFirst,
+}
+
 // TODO: We should refactor this to be an Inbound/OutboundChannel until initial setup handshaking
 // has been completed, and then turn into a Channel to get compiler-time enforcement of things like
 // calling get_channel_id() before we're set up or things like get_outbound_funding_signed on an
@@ -243,7 +310,7 @@ pub struct Channel {
 	announce_publicly: bool,
 	channel_value_satoshis: u64,
 
-	local_keys: ChannelKeys,
+	signer: Arc<ChannelSigner>,
 
 	cur_local_commitment_transaction_number: u64,
 	cur_remote_commitment_transaction_number: u64,
@@ -252,7 +319,9 @@ pub struct Channel {
 	holding_cell_htlc_updates: Vec<HTLCUpdateAwaitingACK>,
 	next_local_htlc_id: u64,
 	next_remote_htlc_id: u64,
-	channel_update_count: u32,
+	/// The timestamp of our next channel_update, moved forward (to the current time, if we can)
+	/// each time the channel's state changes.
+	channel_update_timestamp: u32,
 	feerate_per_kw: u64,
 
 	#[cfg(test)]
@@ -262,6 +331,10 @@ pub struct Channel {
 	last_local_commitment_txn: Vec<Transaction>,
 
 	last_sent_closing_fee: Option<(u64, u64)>, // (feerate, fee)
+	/// The bounds set via ChannelManager::close_channel, if any, on closing_signed negotiation
+	closing_fee_params: ClosingFeeParameters,
+	/// Set if closing_signed negotiation failed to converge within closing_fee_params
+	closing_fee_negotiation_failed: bool,
 
 	/// The hash of the block in which the funding transaction reached our CONF_TARGET. We use this
 	/// to detect unconfirmation after a serialize-unserialize roudtrip where we may not see a full
@@ -297,8 +370,44 @@ pub struct Channel {
 	their_node_id: PublicKey,
 
 	their_shutdown_scriptpubkey: Option<Script>,
+	/// The scriptpubkey the user asked us to close to, either via create_channel (or
+	/// ChannelManager::set_inbound_shutdown_scriptpubkey for inbound channels, in which case we
+	/// committed to it in open_channel/accept_channel if upfront_shutdown_script is set) or
+	/// close_channel. If None, we close to shutdown_pubkey.
+	our_shutdown_scriptpubkey: Option<Script>,
+	/// Whether both we and our peer advertised option_upfront_shutdown_script, in which case the
+	/// shutdown scriptpubkeys exchanged in open_channel/accept_channel are binding.
+	upfront_shutdown_script: bool,
+	/// Our KeysInterface's shutdown pubkey at the time the channel was created
+	shutdown_pubkey: PublicKey,
+
+	/// The policy set via ChannelManager::update_forwarding_policy, if any. Otherwise we use the
+	/// ChannelManager's defaults.
+	forwarding_policy: Option<ChannelForwardingPolicy>,
+	/// The policy forwarding_policy replaced, and when we stop accepting HTLCs which only pay
+	/// according to it.
+	prev_forwarding_policy: Option<(ChannelForwardingPolicy, Instant)>,
 
 	channel_monitor: ChannelMonitor,
+	/// The update_id of the last ChannelMonitorUpdate we generated
+	latest_monitor_update_id: u64,
+	/// Changes we've made to channel_monitor since the last ChannelMonitorUpdate we generated
+	pending_monitor_update_steps: Vec<ChannelMonitorUpdateStep>,
+
+	/// Set while a ChannelMonitorUpdate (or the initial ChannelMonitor) is still being persisted by
+	/// the ManyChannelMonitor. During this time we must not send any message which relies on it,
+	/// so they are held in the monitor_pending_* fields below, and new local HTLC updates are put
+	/// in the holding cell, just as with AwaitingRemoteRevoke.
+	monitor_update_in_progress: bool,
+	/// The update_id of the latest ChannelMonitorUpdate the ManyChannelMonitor told us is still
+	/// in progress.
+	monitor_in_progress_update_id: u64,
+	monitor_pending_revoke_and_ack: Option<msgs::RevokeAndACK>,
+	monitor_pending_commitment_update: Option<msgs::CommitmentUpdate>,
+	monitor_pending_order: RAACommitmentOrder,
+	monitor_pending_forwards: Vec<PendingForwardHTLCInfo>,
+	monitor_pending_failures: Vec<([u8; 32], HTLCFailReason)>,
+	monitor_pending_funding_broadcast_safe: bool,
 }
 
 const OUR_MAX_HTLCS: u16 = 5; //TODO
@@ -320,6 +429,13 @@ const B_OUTPUT_PLUS_SPENDING_INPUT_WEIGHT: u64 = 104; // prevout: 40, nSequence:
 /// it's 2^24.
 pub const MAX_FUNDING_SATOSHIS: u64 = (1 << 24);
 
+fn unix_time_secs() -> u32 {
+	match SystemTime::now().duration_since(UNIX_EPOCH) {
+		Ok(time) => time.as_secs() as u32,
+		Err(_) => 0,
+	}
+}
+
 macro_rules! secp_call {
 	( $res: expr, $err: expr ) => {
 		match $res {
@@ -356,34 +472,38 @@ impl Channel {
 
 	// Constructors:
 
-	/// panics if channel_value_satoshis is >= `MAX_FUNDING_SATOSHIS`
-	pub fn new_outbound(fee_estimator: &FeeEstimator, chan_keys: ChannelKeys, their_node_id: PublicKey, channel_value_satoshis: u64, announce_publicly: bool, user_id: u64) -> Channel {
+	/// upfront_shutdown_script should be set iff both we and the remote node advertised
+	/// option_upfront_shutdown_script in our init messages.
+	/// panics if channel_value_satoshis is >= `MAX_FUNDING_SATOSHIS` or if shutdown_scriptpubkey
+	/// is not of a standard form (see is_standard_shutdown_script)
+	pub fn new_outbound(fee_estimator: &FeeEstimator, keys_provider: &Arc<KeysInterface>, entropy_source: &EntropySource, their_node_id: PublicKey, channel_value_satoshis: u64, announce_publicly: bool, user_id: u64, shutdown_scriptpubkey: Option<Script>, upfront_shutdown_script: bool) -> Channel {
 		if channel_value_satoshis >= MAX_FUNDING_SATOSHIS {
 			panic!("funding value > 2^24");
 		}
+		if let Some(ref script) = shutdown_scriptpubkey {
+			if !Channel::is_standard_shutdown_script(script) {
+				panic!("shutdown_scriptpubkey of nonstandard form");
+			}
+		}
 
 		let feerate = fee_estimator.get_est_sat_per_vbyte(ConfirmationTarget::Normal);
 		let background_feerate = fee_estimator.get_est_sat_per_vbyte(ConfirmationTarget::Background);
 
+		let signer = keys_provider.get_channel_signer();
 		let secp_ctx = Secp256k1::new();
-		let our_channel_monitor_claim_key_hash = Hash160::from_data(&PublicKey::from_secret_key(&secp_ctx, &chan_keys.channel_monitor_claim_key).unwrap().serialize());
-		let our_channel_monitor_claim_script = Builder::new().push_opcode(opcodes::All::OP_PUSHBYTES_0).push_slice(&our_channel_monitor_claim_key_hash[..]).into_script();
-		let channel_monitor = ChannelMonitor::new(&chan_keys.revocation_base_key,
-		                                          &PublicKey::from_secret_key(&secp_ctx, &chan_keys.delayed_payment_base_key).unwrap(),
-		                                          &chan_keys.htlc_base_key,
-		                                          BREAKDOWN_TIMEOUT, our_channel_monitor_claim_script);
+		let channel_monitor = ChannelMonitor::new(&signer, BREAKDOWN_TIMEOUT, keys_provider.get_destination_script());
 
 		Channel {
 			user_id: user_id,
 
-			channel_id: rng::rand_uint256(),
+			channel_id: entropy_source.rand_uint256(),
 			channel_state: ChannelState::OurInitSent as u32,
 			channel_outbound: true,
 			secp_ctx: secp_ctx,
 			announce_publicly: announce_publicly,
 			channel_value_satoshis: channel_value_satoshis,
 
-			local_keys: chan_keys,
+			signer: signer,
 			cur_local_commitment_transaction_number: (1 << 48) - 1,
 			cur_remote_commitment_transaction_number: (1 << 48) - 1,
 			value_to_self_msat: channel_value_satoshis * 1000, //TODO: give them something on open? Parameterize it?
@@ -391,11 +511,13 @@ impl Channel {
 			holding_cell_htlc_updates: Vec::new(),
 			next_local_htlc_id: 0,
 			next_remote_htlc_id: 0,
-			channel_update_count: 1,
+			channel_update_timestamp: unix_time_secs(),
 
 			last_local_commitment_txn: Vec::new(),
 
 			last_sent_closing_fee: None,
+			closing_fee_params: ClosingFeeParameters::new(),
+			closing_fee_negotiation_failed: false,
 
 			funding_tx_confirmed_in: Default::default(),
 			short_channel_id: None,
@@ -423,8 +545,49 @@ impl Channel {
 			their_node_id: their_node_id,
 
 			their_shutdown_scriptpubkey: None,
+			our_shutdown_scriptpubkey: shutdown_scriptpubkey,
+			upfront_shutdown_script,
+			shutdown_pubkey: keys_provider.get_shutdown_pubkey(),
+
+			forwarding_policy: None,
+			prev_forwarding_policy: None,
 
 			channel_monitor: channel_monitor,
+			latest_monitor_update_id: 0,
+			pending_monitor_update_steps: Vec::new(),
+
+			monitor_update_in_progress: false,
+			monitor_in_progress_update_id: 0,
+			monitor_pending_revoke_and_ack: None,
+			monitor_pending_commitment_update: None,
+			monitor_pending_order: RAACommitmentOrder::RevokeAndACKFirst,
+			monitor_pending_forwards: Vec::new(),
+			monitor_pending_failures: Vec::new(),
+			monitor_pending_funding_broadcast_safe: false,
+		}
+	}
+
+	/// Checks that a shutdown scriptpubkey is one of the forms BOLT 2 allows: P2PKH, P2SH, P2WPKH
+	/// or P2WSH. Anything else may be non-standard and thus leave the closing transaction stuck.
+	pub fn is_standard_shutdown_script(script: &Script) -> bool {
+		script.is_p2pkh() || script.is_p2sh() || script.is_v0_p2wpkh() || script.is_v0_p2wsh()
+	}
+
+	/// Checks the upfront_shutdown_script a peer included in open_channel/accept_channel, if any.
+	/// An empty script indicates the peer did not wish to commit to a script, and if
+	/// option_upfront_shutdown_script was not negotiated the field is ignored entirely.
+	fn check_their_upfront_shutdown_script(script: &Option<Script>, upfront_shutdown_script: bool) -> Result<Option<Script>, HandleError> {
+		if !upfront_shutdown_script {
+			return Ok(None);
+		}
+		match script {
+			&Some(ref script) if script.len() != 0 => {
+				if !Channel::is_standard_shutdown_script(script) {
+					return Err(HandleError{err: "Peer's upfront_shutdown_script was of a nonstandard form", msg: Some(msgs::ErrorAction::DisconnectPeer{})});
+				}
+				Ok(Some(script.clone()))
+			},
+			_ => Ok(None),
 		}
 	}
 
@@ -442,7 +605,10 @@ impl Channel {
 	/// Assumes chain_hash has already been checked and corresponds with what we expect!
 	/// Generally prefers to take the DisconnectPeer action on failure, as a notice to the sender
 	/// that we're rejecting the new channel.
-	pub fn new_from_req(fee_estimator: &FeeEstimator, chan_keys: ChannelKeys, their_node_id: PublicKey, msg: &msgs::OpenChannel, user_id: u64, announce_publicly: bool) -> Result<Channel, HandleError> {
+	/// upfront_shutdown_script should be set iff both we and the remote node advertised
+	/// option_upfront_shutdown_script, in which case we commit to shutdown_scriptpubkey (which
+	/// must be of a standard form) in accept_channel.
+	pub fn new_from_req(fee_estimator: &FeeEstimator, keys_provider: &Arc<KeysInterface>, their_node_id: PublicKey, msg: &msgs::OpenChannel, user_id: u64, announce_publicly: bool, shutdown_scriptpubkey: Option<Script>, upfront_shutdown_script: bool) -> Result<Channel, HandleError> {
 		// Check sanity of message fields:
 		if msg.funding_satoshis >= MAX_FUNDING_SATOSHIS {
 			return Err(HandleError{err: "funding value > 2^24", msg: Some(msgs::ErrorAction::DisconnectPeer{})});
@@ -472,6 +638,7 @@ impl Channel {
 		if (msg.channel_flags & 254) != 0 {
 			return Err(HandleError{err: "unknown channel_flags", msg: Some(msgs::ErrorAction::DisconnectPeer{})});
 		}
+		let their_shutdown_scriptpubkey = Channel::check_their_upfront_shutdown_script(&msg.shutdown_scriptpubkey, upfront_shutdown_script)?;
 
 		// Convert things into internal flags and prep our state:
 
@@ -479,13 +646,9 @@ impl Channel {
 
 		let background_feerate = fee_estimator.get_est_sat_per_vbyte(ConfirmationTarget::Background);
 
+		let signer = keys_provider.get_channel_signer();
 		let secp_ctx = Secp256k1::new();
-		let our_channel_monitor_claim_key_hash = Hash160::from_data(&PublicKey::from_secret_key(&secp_ctx, &chan_keys.channel_monitor_claim_key).unwrap().serialize());
-		let our_channel_monitor_claim_script = Builder::new().push_opcode(opcodes::All::OP_PUSHBYTES_0).push_slice(&our_channel_monitor_claim_key_hash[..]).into_script();
-		let mut channel_monitor = ChannelMonitor::new(&chan_keys.revocation_base_key,
-		                                              &PublicKey::from_secret_key(&secp_ctx, &chan_keys.delayed_payment_base_key).unwrap(),
-		                                              &chan_keys.htlc_base_key,
-		                                              BREAKDOWN_TIMEOUT, our_channel_monitor_claim_script);
+		let mut channel_monitor = ChannelMonitor::new(&signer, BREAKDOWN_TIMEOUT, keys_provider.get_destination_script());
 		channel_monitor.set_their_htlc_base_key(&msg.htlc_basepoint);
 		channel_monitor.set_their_to_self_delay(msg.to_self_delay);
 
@@ -498,7 +661,7 @@ impl Channel {
 			secp_ctx: secp_ctx,
 			announce_publicly: their_announce && announce_publicly,
 
-			local_keys: chan_keys,
+			signer: signer,
 			cur_local_commitment_transaction_number: (1 << 48) - 1,
 			cur_remote_commitment_transaction_number: (1 << 48) - 1,
 			value_to_self_msat: msg.push_msat,
@@ -506,11 +669,13 @@ impl Channel {
 			holding_cell_htlc_updates: Vec::new(),
 			next_local_htlc_id: 0,
 			next_remote_htlc_id: 0,
-			channel_update_count: 1,
+			channel_update_timestamp: unix_time_secs(),
 
 			last_local_commitment_txn: Vec::new(),
 
 			last_sent_closing_fee: None,
+			closing_fee_params: ClosingFeeParameters::new(),
+			closing_fee_negotiation_failed: false,
 
 			funding_tx_confirmed_in: Default::default(),
 			short_channel_id: None,
@@ -538,9 +703,26 @@ impl Channel {
 			their_prev_commitment_point: None,
 			their_node_id: their_node_id,
 
-			their_shutdown_scriptpubkey: None,
+			their_shutdown_scriptpubkey: their_shutdown_scriptpubkey,
+			our_shutdown_scriptpubkey: shutdown_scriptpubkey,
+			upfront_shutdown_script,
+			shutdown_pubkey: keys_provider.get_shutdown_pubkey(),
+
+			forwarding_policy: None,
+			prev_forwarding_policy: None,
 
 			channel_monitor: channel_monitor,
+			latest_monitor_update_id: 0,
+			pending_monitor_update_steps: Vec::new(),
+
+			monitor_update_in_progress: false,
+			monitor_in_progress_update_id: 0,
+			monitor_pending_revoke_and_ack: None,
+			monitor_pending_commitment_update: None,
+			monitor_pending_order: RAACommitmentOrder::RevokeAndACKFirst,
+			monitor_pending_forwards: Vec::new(),
+			monitor_pending_failures: Vec::new(),
+			monitor_pending_funding_broadcast_safe: false,
 		};
 
 		let obscure_factor = chan.get_commitment_transaction_number_obscure_factor();
@@ -549,18 +731,11 @@ impl Channel {
 		Ok(chan)
 	}
 
-	// Utilities to derive keys:
-
-	fn build_local_commitment_secret(&self, idx: u64) -> SecretKey {
-		let res = chan_utils::build_commitment_secret(self.local_keys.commitment_seed, idx);
-		SecretKey::from_slice(&self.secp_ctx, &res).unwrap()
-	}
-
 	// Utilities to build transactions:
 
 	fn get_commitment_transaction_number_obscure_factor(&self) -> u64 {
 		let mut sha = Sha256::new();
-		let our_payment_basepoint = PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.payment_base_key).unwrap();
+		let our_payment_basepoint = self.signer.pubkeys(&self.secp_ctx).payment_basepoint;
 
 		if self.channel_outbound {
 			sha.input(&our_payment_basepoint.serialize());
@@ -720,7 +895,10 @@ impl Channel {
 
 	#[inline]
 	fn get_closing_scriptpubkey(&self) -> Script {
-		let our_channel_close_key_hash = Hash160::from_data(&PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.channel_close_key).unwrap().serialize());
+		if let Some(ref script) = self.our_shutdown_scriptpubkey {
+			return script.clone();
+		}
+		let our_channel_close_key_hash = Hash160::from_data(&self.shutdown_pubkey.serialize());
 		Builder::new().push_opcode(opcodes::All::OP_PUSHBYTES_0).push_slice(&our_channel_close_key_hash[..]).into_script()
 	}
 
@@ -794,11 +972,10 @@ impl Channel {
 	/// The result is a transaction which we can revoke ownership of (ie a "local" transaction)
 	/// TODO Some magic rust shit to compile-time check this?
 	fn build_local_transaction_keys(&self, commitment_number: u64) -> Result<TxCreationKeys, HandleError> {
-		let per_commitment_point = PublicKey::from_secret_key(&self.secp_ctx, &self.build_local_commitment_secret(commitment_number)).unwrap();
-		let delayed_payment_base = PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.delayed_payment_base_key).unwrap();
-		let htlc_basepoint = PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.htlc_base_key).unwrap();
+		let per_commitment_point = self.signer.get_per_commitment_point(&self.secp_ctx, commitment_number);
+		let our_pubkeys = self.signer.pubkeys(&self.secp_ctx);
 
-		Ok(secp_derived_key!(TxCreationKeys::new(&self.secp_ctx, &per_commitment_point, &delayed_payment_base, &htlc_basepoint, &self.their_revocation_basepoint, &self.their_payment_basepoint, &self.their_htlc_basepoint)))
+		Ok(secp_derived_key!(TxCreationKeys::new(&self.secp_ctx, &per_commitment_point, &our_pubkeys.delayed_payment_basepoint, &our_pubkeys.htlc_basepoint, &self.their_revocation_basepoint, &self.their_payment_basepoint, &self.their_htlc_basepoint)))
 	}
 
 	#[inline]
@@ -807,18 +984,16 @@ impl Channel {
 	fn build_remote_transaction_keys(&self) -> Result<TxCreationKeys, HandleError> {
 		//TODO: Ensure that the payment_key derived here ends up in the library users' wallet as we
 		//may see payments to it!
-		let payment_basepoint = PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.payment_base_key).unwrap();
-		let revocation_basepoint = PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.revocation_base_key).unwrap();
-		let htlc_basepoint = PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.htlc_base_key).unwrap();
+		let our_pubkeys = self.signer.pubkeys(&self.secp_ctx);
 
-		Ok(secp_derived_key!(TxCreationKeys::new(&self.secp_ctx, &self.their_cur_commitment_point, &self.their_delayed_payment_basepoint, &self.their_htlc_basepoint, &revocation_basepoint, &payment_basepoint, &htlc_basepoint)))
+		Ok(secp_derived_key!(TxCreationKeys::new(&self.secp_ctx, &self.their_cur_commitment_point, &self.their_delayed_payment_basepoint, &self.their_htlc_basepoint, &our_pubkeys.revocation_basepoint, &our_pubkeys.payment_basepoint, &our_pubkeys.htlc_basepoint)))
 	}
 
 	/// Gets the redeemscript for the funding transaction output (ie the funding transaction output
 	/// pays to get_funding_redeemscript().to_v0_p2wsh()).
 	pub fn get_funding_redeemscript(&self) -> Script {
 		let builder = Builder::new().push_opcode(opcodes::All::OP_PUSHNUM_2);
-		let our_funding_key = PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.funding_key).unwrap().serialize();
+		let our_funding_key = self.signer.pubkeys(&self.secp_ctx).funding_pubkey.serialize();
 		let their_funding_key = self.their_funding_pubkey.serialize();
 		if our_funding_key[..] < their_funding_key[..] {
 			builder.push_slice(&our_funding_key)
@@ -829,7 +1004,18 @@ impl Channel {
 		}.push_opcode(opcodes::All::OP_PUSHNUM_2).push_opcode(opcodes::All::OP_CHECKMULTISIG).into_script()
 	}
 
-	fn sign_commitment_transaction(&self, tx: &mut Transaction, their_sig: &Signature) -> Signature {
+	/// Signs the remote commitment transaction with number cur_remote_commitment_transaction_number
+	/// so that we can send the signature to our counterparty.
+	fn sign_remote_commitment(&self, tx: &Transaction, funding_redeemscript: &Script) -> Result<Signature, HandleError> {
+		match self.signer.sign_remote_commitment(&self.secp_ctx, tx, self.cur_remote_commitment_transaction_number, funding_redeemscript, self.channel_value_satoshis) {
+			Ok(sig) => Ok(sig),
+			Err(_) => Err(HandleError{err: "Signer refused to sign remote commitment transaction", msg: None}),
+		}
+	}
+
+	/// Signs our commitment transaction with the given commitment number, which our counterparty
+	/// has already signed with their_sig.
+	fn sign_commitment_transaction(&self, tx: &mut Transaction, their_sig: &Signature, commitment_number: u64) -> Result<Signature, HandleError> {
 		if tx.input.len() != 1 {
 			panic!("Tried to sign commitment transaction that had input count != 1!");
 		}
@@ -838,13 +1024,36 @@ impl Channel {
 		}
 
 		let funding_redeemscript = self.get_funding_redeemscript();
+		let our_sig = match self.signer.sign_local_commitment(&self.secp_ctx, tx, commitment_number, &funding_redeemscript, self.channel_value_satoshis) {
+			Ok(sig) => sig,
+			Err(_) => return Err(HandleError{err: "Signer refused to sign local commitment transaction", msg: None}),
+		};
+		self.add_funding_witness(tx, &our_sig, their_sig, funding_redeemscript);
+		Ok(our_sig)
+	}
+
+	/// Signs a closing transaction, which our counterparty has already signed with their_sig.
+	fn sign_closing_transaction(&self, tx: &mut Transaction, their_sig: &Signature) -> Result<Signature, HandleError> {
+		if tx.input.len() != 1 {
+			panic!("Tried to sign closing transaction that had input count != 1!");
+		}
+		if tx.input[0].witness.len() != 0 {
+			panic!("Tried to re-sign closing transaction");
+		}
 
-		let sighash = Message::from_slice(&bip143::SighashComponents::new(&tx).sighash_all(&tx.input[0], &funding_redeemscript, self.channel_value_satoshis)[..]).unwrap();
-		let our_sig = self.secp_ctx.sign(&sighash, &self.local_keys.funding_key).unwrap();
+		let funding_redeemscript = self.get_funding_redeemscript();
+		let our_sig = match self.signer.sign_closing_transaction(&self.secp_ctx, tx, &funding_redeemscript, self.channel_value_satoshis) {
+			Ok(sig) => sig,
+			Err(_) => return Err(HandleError{err: "Signer refused to sign closing transaction", msg: None}),
+		};
+		self.add_funding_witness(tx, &our_sig, their_sig, funding_redeemscript);
+		Ok(our_sig)
+	}
 
+	fn add_funding_witness(&self, tx: &mut Transaction, our_sig: &Signature, their_sig: &Signature, funding_redeemscript: Script) {
 		tx.input[0].witness.push(Vec::new()); // First is the multisig dummy
 
-		let our_funding_key = PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.funding_key).unwrap().serialize();
+		let our_funding_key = self.signer.pubkeys(&self.secp_ctx).funding_pubkey.serialize();
 		let their_funding_key = self.their_funding_pubkey.serialize();
 		if our_funding_key[..] < their_funding_key[..] {
 			tx.input[0].witness.push(our_sig.serialize_der(&self.secp_ctx).to_vec());
@@ -857,8 +1066,6 @@ impl Channel {
 		tx.input[0].witness[2].push(SigHashType::All as u8);
 
 		tx.input[0].witness.push(funding_redeemscript.into_vec());
-
-		our_sig
 	}
 
 	/// Builds the htlc-success or htlc-timeout transaction which spends a given HTLC output
@@ -875,10 +1082,13 @@ impl Channel {
 
 		let htlc_redeemscript = chan_utils::get_htlc_redeemscript(&htlc, &keys);
 
-		let our_htlc_key = secp_derived_key!(chan_utils::derive_private_key(&self.secp_ctx, &keys.per_commitment_point, &self.local_keys.htlc_base_key));
-		let sighash = Message::from_slice(&bip143::SighashComponents::new(&tx).sighash_all(&tx.input[0], &htlc_redeemscript, htlc.amount_msat / 1000)[..]).unwrap();
-		let is_local_tx = PublicKey::from_secret_key(&self.secp_ctx, &our_htlc_key).unwrap() == keys.a_htlc_key;
-		Ok((htlc_redeemscript, self.secp_ctx.sign(&sighash, &our_htlc_key).unwrap(), is_local_tx))
+		let our_htlc_key = secp_derived_key!(chan_utils::derive_public_key(&self.secp_ctx, &keys.per_commitment_point, &self.signer.pubkeys(&self.secp_ctx).htlc_basepoint));
+		let is_local_tx = our_htlc_key == keys.a_htlc_key;
+		let our_sig = match self.signer.sign_htlc_transaction(&self.secp_ctx, tx, 0, &htlc_redeemscript, htlc.amount_msat / 1000, &keys.per_commitment_point) {
+			Ok(sig) => sig,
+			Err(_) => return Err(HandleError{err: "Signer refused to sign HTLC transaction", msg: None}),
+		};
+		Ok((htlc_redeemscript, our_sig, is_local_tx))
 	}
 
 	/// Signs a transaction created by build_htlc_transaction. If the transaction is an
@@ -916,7 +1126,28 @@ impl Channel {
 		Ok(our_sig)
 	}
 
-	pub fn get_update_fulfill_htlc(&mut self, payment_preimage_arg: [u8; 32]) -> Result<Option<(msgs::UpdateFulfillHTLC, ChannelMonitor)>, HandleError> {
+	/// Applies the given step to our ChannelMonitor, queueing it to be included in the next
+	/// ChannelMonitorUpdate we generate (see get_monitor_update).
+	fn update_monitor(&mut self, step: ChannelMonitorUpdateStep) -> Result<(), HandleError> {
+		self.channel_monitor.apply_update_step(&step)?;
+		self.pending_monitor_update_steps.push(step);
+		Ok(())
+	}
+
+	/// Bundles all the changes made to our ChannelMonitor since the last call into a new
+	/// ChannelMonitorUpdate, to be handed to the ManyChannelMonitor.
+	fn get_monitor_update(&mut self) -> ChannelMonitorUpdate {
+		self.latest_monitor_update_id += 1;
+		self.channel_monitor.set_latest_update_id(self.latest_monitor_update_id);
+		let mut updates = Vec::new();
+		mem::swap(&mut updates, &mut self.pending_monitor_update_steps);
+		ChannelMonitorUpdate {
+			updates: updates,
+			update_id: self.latest_monitor_update_id,
+		}
+	}
+
+	fn get_update_fulfill_htlc(&mut self, payment_preimage_arg: [u8; 32]) -> Result<Option<msgs::UpdateFulfillHTLC>, HandleError> {
 		// Either ChannelFunded got set (which means it wont bet unset) or there is no way any
 		// caller thought we could have something claimed (cause we wouldn't have accepted in an
 		// incoming HTLC anyway). If we got to ShutdownComplete, callers aren't allowed to call us,
@@ -932,7 +1163,7 @@ impl Channel {
 		sha.result(&mut payment_hash_calc);
 
 		// Now update local state:
-		if (self.channel_state & (ChannelState::AwaitingRemoteRevoke as u32)) == (ChannelState::AwaitingRemoteRevoke as u32) {
+		if (self.channel_state & (ChannelState::AwaitingRemoteRevoke as u32)) == (ChannelState::AwaitingRemoteRevoke as u32) || self.monitor_update_in_progress {
 			for pending_update in self.holding_cell_htlc_updates.iter() {
 				match pending_update {
 					&HTLCUpdateAwaitingACK::ClaimHTLC { ref payment_preimage, .. } => {
@@ -978,20 +1209,23 @@ impl Channel {
 		if htlc_amount_msat == 0 {
 			return Err(HandleError{err: "Unable to find a pending HTLC which matched the given payment preimage", msg: None});
 		}
-		self.channel_monitor.provide_payment_preimage(&payment_hash_calc, &payment_preimage_arg);
+		self.update_monitor(ChannelMonitorUpdateStep::PaymentPreimage {
+			payment_hash: payment_hash_calc,
+			payment_preimage: payment_preimage_arg,
+		})?;
 
-		Ok(Some((msgs::UpdateFulfillHTLC {
+		Ok(Some(msgs::UpdateFulfillHTLC {
 			channel_id: self.channel_id(),
 			htlc_id: htlc_id,
 			payment_preimage: payment_preimage_arg,
-		}, self.channel_monitor.clone())))
+		}))
 	}
 
-	pub fn get_update_fulfill_htlc_and_commit(&mut self, payment_preimage: [u8; 32]) -> Result<Option<(msgs::UpdateFulfillHTLC, msgs::CommitmentSigned, ChannelMonitor)>, HandleError> {
+	pub fn get_update_fulfill_htlc_and_commit(&mut self, payment_preimage: [u8; 32]) -> Result<Option<(msgs::UpdateFulfillHTLC, msgs::CommitmentSigned, ChannelMonitorUpdate)>, HandleError> {
 		match self.get_update_fulfill_htlc(payment_preimage)? {
 			Some(update_fulfill_htlc) => {
-				let (commitment, monitor_update) = self.send_commitment_no_status_check()?;
-				Ok(Some((update_fulfill_htlc.0, commitment, monitor_update)))
+				let commitment = self.send_commitment_no_status_check()?;
+				Ok(Some((update_fulfill_htlc, commitment, self.get_monitor_update())))
 			},
 			None => Ok(None)
 		}
@@ -1004,7 +1238,7 @@ impl Channel {
 		assert_eq!(self.channel_state & ChannelState::ShutdownComplete as u32, 0);
 
 		// Now update local state:
-		if (self.channel_state & (ChannelState::AwaitingRemoteRevoke as u32)) == (ChannelState::AwaitingRemoteRevoke as u32) {
+		if (self.channel_state & (ChannelState::AwaitingRemoteRevoke as u32)) == (ChannelState::AwaitingRemoteRevoke as u32) || self.monitor_update_in_progress {
 			for pending_update in self.holding_cell_htlc_updates.iter() {
 				match pending_update {
 					&HTLCUpdateAwaitingACK::ClaimHTLC { ref payment_hash, .. } => {
@@ -1058,11 +1292,11 @@ impl Channel {
 		}))
 	}
 
-	pub fn get_update_fail_htlc_and_commit(&mut self, payment_hash: &[u8; 32], err_packet: msgs::OnionErrorPacket) -> Result<Option<(msgs::UpdateFailHTLC, msgs::CommitmentSigned, ChannelMonitor)>, HandleError> {
+	pub fn get_update_fail_htlc_and_commit(&mut self, payment_hash: &[u8; 32], err_packet: msgs::OnionErrorPacket) -> Result<Option<(msgs::UpdateFailHTLC, msgs::CommitmentSigned, ChannelMonitorUpdate)>, HandleError> {
 		match self.get_update_fail_htlc(payment_hash, err_packet)? {
 			Some(update_fail_htlc) => {
-				let (commitment, monitor_update) = self.send_commitment_no_status_check()?;
-				Ok(Some((update_fail_htlc, commitment, monitor_update)))
+				let commitment = self.send_commitment_no_status_check()?;
+				Ok(Some((update_fail_htlc, commitment, self.get_monitor_update())))
 			},
 			None => Ok(None)
 		}
@@ -1097,6 +1331,7 @@ impl Channel {
 		if msg.max_accepted_htlcs < 1 {
 			return Err(HandleError{err: "0 max_accpted_htlcs makes for a useless channel", msg: None});
 		}
+		let their_shutdown_scriptpubkey = Channel::check_their_upfront_shutdown_script(&msg.shutdown_scriptpubkey, self.upfront_shutdown_script)?;
 
 		self.channel_monitor.set_their_htlc_base_key(&msg.htlc_basepoint);
 
@@ -1112,6 +1347,7 @@ impl Channel {
 		self.their_delayed_payment_basepoint = msg.delayed_payment_basepoint;
 		self.their_htlc_basepoint = msg.htlc_basepoint;
 		self.their_cur_commitment_point = msg.first_per_commitment_point;
+		self.their_shutdown_scriptpubkey = their_shutdown_scriptpubkey;
 
 		let obscure_factor = self.get_commitment_transaction_number_obscure_factor();
 		self.channel_monitor.set_commitment_obscure_factor(obscure_factor);
@@ -1127,7 +1363,6 @@ impl Channel {
 
 		let remote_keys = self.build_remote_transaction_keys()?;
 		let remote_initial_commitment_tx = self.build_commitment_transaction(self.cur_remote_commitment_transaction_number, &remote_keys, false, false).0;
-		let remote_sighash = Message::from_slice(&bip143::SighashComponents::new(&remote_initial_commitment_tx).sighash_all(&remote_initial_commitment_tx.input[0], &funding_script, self.channel_value_satoshis)[..]).unwrap();
 
 		let local_keys = self.build_local_transaction_keys(self.cur_local_commitment_transaction_number)?;
 		let local_initial_commitment_tx = self.build_commitment_transaction(self.cur_local_commitment_transaction_number, &local_keys, true, false).0;
@@ -1137,7 +1372,8 @@ impl Channel {
 		secp_call!(self.secp_ctx.verify(&local_sighash, &sig, &self.their_funding_pubkey), "Invalid funding_created signature from peer");
 
 		// We sign the "remote" commitment transaction, allowing them to broadcast the tx if they wish.
-		Ok((remote_initial_commitment_tx, self.secp_ctx.sign(&remote_sighash, &self.local_keys.funding_key).unwrap()))
+		let our_sig = self.sign_remote_commitment(&remote_initial_commitment_tx, &funding_script)?;
+		Ok((remote_initial_commitment_tx, our_sig))
 	}
 
 	pub fn funding_created(&mut self, msg: &msgs::FundingCreated) -> Result<(msgs::FundingSigned, ChannelMonitor), HandleError> {
@@ -1178,7 +1414,7 @@ impl Channel {
 
 	/// Handles a funding_signed message from the remote end.
 	/// If this call is successful, broadcast the funding transaction (and not before!)
-	pub fn funding_signed(&mut self, msg: &msgs::FundingSigned) -> Result<ChannelMonitor, HandleError> {
+	pub fn funding_signed(&mut self, msg: &msgs::FundingSigned) -> Result<ChannelMonitorUpdate, HandleError> {
 		if !self.channel_outbound {
 			return Err(HandleError{err: "Received funding_signed for an inbound channel?", msg: None});
 		}
@@ -1198,13 +1434,19 @@ impl Channel {
 		// They sign the "local" commitment transaction, allowing us to broadcast the tx if we wish.
 		secp_call!(self.secp_ctx.verify(&local_sighash, &msg.signature, &self.their_funding_pubkey), "Invalid funding_signed signature from peer");
 
-		self.sign_commitment_transaction(&mut local_initial_commitment_tx, &msg.signature);
-		self.channel_monitor.provide_latest_local_commitment_tx_info(local_initial_commitment_tx.clone(), local_keys, self.feerate_per_kw, Vec::new());
+		self.sign_commitment_transaction(&mut local_initial_commitment_tx, &msg.signature, self.cur_local_commitment_transaction_number)?;
+		let feerate_per_kw = self.feerate_per_kw;
+		self.update_monitor(ChannelMonitorUpdateStep::LatestLocalCommitmentTXInfo {
+			signed_commitment_tx: local_initial_commitment_tx.clone(),
+			local_keys: local_keys,
+			feerate_per_kw: feerate_per_kw,
+			htlc_outputs: Vec::new(),
+		})?;
 		self.last_local_commitment_txn = vec![local_initial_commitment_tx];
 		self.channel_state = ChannelState::FundingSent as u32;
 		self.cur_local_commitment_transaction_number -= 1;
 
-		Ok(self.channel_monitor.clone())
+		Ok(self.get_monitor_update())
 	}
 
 	pub fn funding_locked(&mut self, msg: &msgs::FundingLocked) -> Result<(), HandleError> {
@@ -1213,7 +1455,7 @@ impl Channel {
 			self.channel_state |= ChannelState::TheirFundingLocked as u32;
 		} else if non_shutdown_state == (ChannelState::FundingSent as u32 | ChannelState::OurFundingLocked as u32) {
 			self.channel_state = ChannelState::ChannelFunded as u32 | (self.channel_state & BOTH_SIDES_SHUTDOWN_MASK);
-			self.channel_update_count += 1;
+			self.bump_channel_update_timestamp();
 		} else {
 			return Err(HandleError{err: "Peer sent a funding_locked at a strange time", msg: None});
 		}
@@ -1258,6 +1500,12 @@ impl Channel {
 		(inbound_htlc_count, outbound_htlc_count, htlc_outbound_value_msat, htlc_inbound_value_msat)
 	}
 
+	/// Records the onion of an HTLC we just accepted in our ChannelMonitor, so that it is persisted
+	/// with the next ChannelMonitorUpdate (see ChannelMonitor::provide_onion_replay_log_entry).
+	pub fn provide_onion_replay_log_entry(&mut self, onion_secret_hash: [u8; 32], cltv_expiry: u32) -> Result<(), HandleError> {
+		self.update_monitor(ChannelMonitorUpdateStep::OnionReplayLogEntry { onion_secret_hash, cltv_expiry })
+	}
+
 	pub fn update_add_htlc(&mut self, msg: &msgs::UpdateAddHTLC, pending_forward_state: PendingForwardHTLCInfo) -> Result<(), HandleError> {
 		if (self.channel_state & (ChannelState::ChannelFunded as u32 | ChannelState::RemoteShutdownSent as u32)) != (ChannelState::ChannelFunded as u32) {
 			return Err(HandleError{err: "Got add HTLC message when channel was not in an operational state", msg: None});
@@ -1291,7 +1539,8 @@ impl Channel {
 			return Err(HandleError{err: "Remote provided CLTV expiry in seconds instead of block height", msg: None});
 		}
 
-		//TODO: Check msg.cltv_expiry further? Do this in channel manager?
+		// Note that cltv_expiry is checked against our CLTV_EXPIRY_DELTA and the current block height
+		// in ChannelManager, which has the onion data and chain state required to do so.
 
 		// Now update local state:
 		self.next_remote_htlc_id += 1;
@@ -1338,7 +1587,7 @@ impl Channel {
 		Err(HandleError{err: "Remote tried to fulfill/fail an HTLC we couldn't find", msg: None})
 	}
 
-	pub fn update_fulfill_htlc(&mut self, msg: &msgs::UpdateFulfillHTLC) -> Result<ChannelMonitor, HandleError> {
+	pub fn update_fulfill_htlc(&mut self, msg: &msgs::UpdateFulfillHTLC) -> Result<ChannelMonitorUpdate, HandleError> {
 		if (self.channel_state & (ChannelState::ChannelFunded as u32)) != (ChannelState::ChannelFunded as u32) {
 			return Err(HandleError{err: "Got add HTLC message when channel was not in an operational state", msg: None});
 		}
@@ -1348,9 +1597,12 @@ impl Channel {
 		let mut payment_hash = [0; 32];
 		sha.result(&mut payment_hash);
 
-		self.channel_monitor.provide_payment_preimage(&payment_hash, &msg.payment_preimage);
+		self.update_monitor(ChannelMonitorUpdateStep::PaymentPreimage {
+			payment_hash: payment_hash,
+			payment_preimage: msg.payment_preimage,
+		})?;
 		self.mark_outbound_htlc_removed(msg.htlc_id, Some(payment_hash), None)?;
-		Ok(self.channel_monitor.clone())
+		Ok(self.get_monitor_update())
 	}
 
 	pub fn update_fail_htlc(&mut self, msg: &msgs::UpdateFailHTLC, fail_reason: HTLCFailReason) -> Result<[u8; 32], HandleError> {
@@ -1370,10 +1622,13 @@ impl Channel {
 		Ok(())
 	}
 
-	pub fn commitment_signed(&mut self, msg: &msgs::CommitmentSigned) -> Result<(msgs::RevokeAndACK, Option<msgs::CommitmentSigned>, ChannelMonitor), HandleError> {
+	pub fn commitment_signed(&mut self, msg: &msgs::CommitmentSigned) -> Result<(msgs::RevokeAndACK, Option<msgs::CommitmentSigned>, ChannelMonitorUpdate), HandleError> {
 		if (self.channel_state & (ChannelState::ChannelFunded as u32)) != (ChannelState::ChannelFunded as u32) {
 			return Err(HandleError{err: "Got commitment signed message when channel was not in an operational state", msg: None});
 		}
+		if self.monitor_pending_revoke_and_ack.is_some() {
+			return Err(HandleError{err: "Got commitment signed message before we sent our revoke_and_ack for the previous one", msg: None});
+		}
 
 		let funding_script = self.get_funding_redeemscript();
 
@@ -1388,7 +1643,7 @@ impl Channel {
 		}
 
 		let mut new_local_commitment_txn = Vec::with_capacity(local_commitment_tx.1.len() + 1);
-		self.sign_commitment_transaction(&mut local_commitment_tx.0, &msg.signature);
+		self.sign_commitment_transaction(&mut local_commitment_tx.0, &msg.signature, self.cur_local_commitment_transaction_number)?;
 		new_local_commitment_txn.push(local_commitment_tx.0.clone());
 
 		let mut htlcs_and_sigs = Vec::with_capacity(local_commitment_tx.1.len());
@@ -1407,11 +1662,17 @@ impl Channel {
 			htlcs_and_sigs.push(((*htlc).clone(), msg.htlc_signatures[idx], htlc_sig));
 		}
 
-		let next_per_commitment_point = PublicKey::from_secret_key(&self.secp_ctx, &self.build_local_commitment_secret(self.cur_local_commitment_transaction_number - 1)).unwrap();
-		let per_commitment_secret = chan_utils::build_commitment_secret(self.local_keys.commitment_seed, self.cur_local_commitment_transaction_number + 1);
+		let next_per_commitment_point = self.signer.get_per_commitment_point(&self.secp_ctx, self.cur_local_commitment_transaction_number - 1);
+		let per_commitment_secret = self.signer.release_commitment_secret(self.cur_local_commitment_transaction_number + 1);
 
 		// Update state now that we've passed all the can-fail calls...
-		self.channel_monitor.provide_latest_local_commitment_tx_info(local_commitment_tx.0, local_keys, self.feerate_per_kw, htlcs_and_sigs);
+		let feerate_per_kw = self.feerate_per_kw;
+		self.update_monitor(ChannelMonitorUpdateStep::LatestLocalCommitmentTXInfo {
+			signed_commitment_tx: local_commitment_tx.0,
+			local_keys: local_keys,
+			feerate_per_kw: feerate_per_kw,
+			htlc_outputs: htlcs_and_sigs,
+		})?;
 
 		let mut need_our_commitment = false;
 		for htlc in self.pending_htlcs.iter_mut() {
@@ -1437,24 +1698,23 @@ impl Channel {
 		self.cur_local_commitment_transaction_number -= 1;
 		self.last_local_commitment_txn = new_local_commitment_txn;
 
-		let (our_commitment_signed, monitor_update) = if need_our_commitment && (self.channel_state & (ChannelState::AwaitingRemoteRevoke as u32)) == 0 {
+		let our_commitment_signed = if need_our_commitment && (self.channel_state & (ChannelState::AwaitingRemoteRevoke as u32)) == 0 {
 			// If we're AwaitingRemoteRevoke we can't send a new commitment here, but that's ok -
 			// we'll send one right away when we get the revoke_and_ack when we
 			// free_holding_cell_htlcs().
-			let (msg, monitor) = self.send_commitment_no_status_check()?;
-			(Some(msg), monitor)
-		} else { (None, self.channel_monitor.clone()) };
+			Some(self.send_commitment_no_status_check()?)
+		} else { None };
 
 		Ok((msgs::RevokeAndACK {
 			channel_id: self.channel_id,
 			per_commitment_secret: per_commitment_secret,
 			next_per_commitment_point: next_per_commitment_point,
-		}, our_commitment_signed, monitor_update))
+		}, our_commitment_signed, self.get_monitor_update()))
 	}
 
 	/// Used to fulfill holding_cell_htlcs when we get a remote ack (or implicitly get it by them
 	/// fulfilling or failing the last pending HTLC)
-	fn free_holding_cell_htlcs(&mut self) -> Result<Option<(msgs::CommitmentUpdate, ChannelMonitor)>, HandleError> {
+	fn free_holding_cell_htlcs(&mut self) -> Result<Option<msgs::CommitmentUpdate>, HandleError> {
 		if self.holding_cell_htlc_updates.len() != 0 {
 			let mut htlc_updates = Vec::new();
 			mem::swap(&mut htlc_updates, &mut self.holding_cell_htlc_updates);
@@ -1482,7 +1742,7 @@ impl Channel {
 						},
 						&HTLCUpdateAwaitingACK::ClaimHTLC { payment_preimage, .. } => {
 							match self.get_update_fulfill_htlc(payment_preimage) {
-								Ok(update_fulfill_msg_option) => update_fulfill_htlcs.push(update_fulfill_msg_option.unwrap().0),
+								Ok(update_fulfill_msg_option) => update_fulfill_htlcs.push(update_fulfill_msg_option.unwrap()),
 								Err(e) => {
 									err = Some(e);
 								}
@@ -1506,13 +1766,13 @@ impl Channel {
 			//fail it back the route, if its a temporary issue we can ignore it...
 			match err {
 				None => {
-					let (commitment_signed, monitor_update) = self.send_commitment_no_status_check()?;
-					Ok(Some((msgs::CommitmentUpdate {
+					let commitment_signed = self.send_commitment_no_status_check()?;
+					Ok(Some(msgs::CommitmentUpdate {
 						update_add_htlcs,
 						update_fulfill_htlcs,
 						update_fail_htlcs,
 						commitment_signed,
-					}, monitor_update)))
+					}))
 				},
 				Some(e) => Err(e)
 			}
@@ -1526,7 +1786,7 @@ impl Channel {
 	/// waiting on this revoke_and_ack. The generation of this new commitment_signed may also fail,
 	/// generating an appropriate error *after* the channel state has been updated based on the
 	/// revoke_and_ack message.
-	pub fn revoke_and_ack(&mut self, msg: &msgs::RevokeAndACK) -> Result<(Option<msgs::CommitmentUpdate>, Vec<PendingForwardHTLCInfo>, Vec<([u8; 32], HTLCFailReason)>, ChannelMonitor), HandleError> {
+	pub fn revoke_and_ack(&mut self, msg: &msgs::RevokeAndACK) -> Result<(Option<msgs::CommitmentUpdate>, Vec<PendingForwardHTLCInfo>, Vec<([u8; 32], HTLCFailReason)>, ChannelMonitorUpdate), HandleError> {
 		if (self.channel_state & (ChannelState::ChannelFunded as u32)) != (ChannelState::ChannelFunded as u32) {
 			return Err(HandleError{err: "Got revoke/ACK message when channel was not in an operational state", msg: None});
 		}
@@ -1535,7 +1795,13 @@ impl Channel {
 				return Err(HandleError{err: "Got a revoke commitment secret which didn't correspond to their current pubkey", msg: None});
 			}
 		}
-		self.channel_monitor.provide_secret(self.cur_remote_commitment_transaction_number + 1, msg.per_commitment_secret, Some((self.cur_remote_commitment_transaction_number - 1, msg.next_per_commitment_point)))?;
+		let revoked_commitment_number = self.cur_remote_commitment_transaction_number + 1;
+		let next_commitment_number = self.cur_remote_commitment_transaction_number - 1;
+		self.update_monitor(ChannelMonitorUpdateStep::CommitmentSecret {
+			idx: revoked_commitment_number,
+			secret: msg.per_commitment_secret,
+			their_next_revocation_point: Some((next_commitment_number, msg.next_per_commitment_point)),
+		})?;
 
 		// Update state now that we've passed all the can-fail calls...
 		// (note that we may still fail to generate the new commitment_signed message, but that's
@@ -1583,21 +1849,26 @@ impl Channel {
 		}
 		self.value_to_self_msat = (self.value_to_self_msat as i64 + value_to_self_msat_diff) as u64;
 
-		match self.free_holding_cell_htlcs()? {
+		// If a ChannelMonitorUpdate is still in progress, anything in the holding cell stays there
+		// until it completes (see free_holding_cell_after_monitor_update).
+		let holding_cell_update = if !self.monitor_update_in_progress {
+			self.free_holding_cell_htlcs()?
+		} else { None };
+		match holding_cell_update {
 			Some(commitment_update) => {
-				Ok((Some(commitment_update.0), to_forward_infos, revoked_htlcs, commitment_update.1))
+				Ok((Some(commitment_update), to_forward_infos, revoked_htlcs, self.get_monitor_update()))
 			},
 			None => {
 				if require_commitment {
-					let (commitment_signed, monitor_update) = self.send_commitment_no_status_check()?;
+					let commitment_signed = self.send_commitment_no_status_check()?;
 					Ok((Some(msgs::CommitmentUpdate {
 						update_add_htlcs: Vec::new(),
 						update_fulfill_htlcs: Vec::new(),
 						update_fail_htlcs: Vec::new(),
 						commitment_signed
-					}), to_forward_infos, revoked_htlcs, monitor_update))
+					}), to_forward_infos, revoked_htlcs, self.get_monitor_update()))
 				} else {
-					Ok((None, to_forward_infos, revoked_htlcs, self.channel_monitor.clone()))
+					Ok((None, to_forward_infos, revoked_htlcs, self.get_monitor_update()))
 				}
 			}
 		}
@@ -1608,7 +1879,7 @@ impl Channel {
 			return Err(HandleError{err: "Non-funding remote tried to update channel fee", msg: None});
 		}
 		Channel::check_remote_fee(fee_estimator, msg.feerate_per_kw)?;
-		self.channel_update_count += 1;
+		self.bump_channel_update_timestamp();
 		self.feerate_per_kw = msg.feerate_per_kw as u64;
 		Ok(())
 	}
@@ -1616,7 +1887,7 @@ impl Channel {
 	pub fn shutdown(&mut self, fee_estimator: &FeeEstimator, msg: &msgs::Shutdown) -> Result<(Option<msgs::Shutdown>, Option<msgs::ClosingSigned>, Vec<[u8; 32]>), HandleError> {
 		if self.channel_state < ChannelState::FundingSent as u32 {
 			self.channel_state = ChannelState::ShutdownComplete as u32;
-			self.channel_update_count += 1;
+			self.bump_channel_update_timestamp();
 			return Ok((None, None, Vec::new()));
 		}
 		for htlc in self.pending_htlcs.iter() {
@@ -1630,11 +1901,11 @@ impl Channel {
 		assert_eq!(self.channel_state & ChannelState::ShutdownComplete as u32, 0);
 
 		// BOLT 2 says we must only send a scriptpubkey of certain standard forms, which are up to
-		// 34 bytes in length, so dont let the remote peer feed us some super fee-heavy script.
-		if self.channel_outbound && msg.scriptpubkey.len() > 34 {
-			return Err(HandleError{err: "Got shutdown_scriptpubkey of absurd length from remote peer", msg: None});
+		// 34 bytes in length, so dont let the remote peer feed us some super fee-heavy (or
+		// non-standard, and thus unrelayable) script.
+		if !Channel::is_standard_shutdown_script(&msg.scriptpubkey) {
+			return Err(HandleError{err: "Got shutdown_scriptpubkey of nonstandard form from remote peer", msg: None});
 		}
-		//TODO: Check shutdown_scriptpubkey form as BOLT says we must? WHYYY
 
 		if self.their_shutdown_scriptpubkey.is_some() {
 			if Some(&msg.scriptpubkey) != self.their_shutdown_scriptpubkey.as_ref() {
@@ -1647,24 +1918,36 @@ impl Channel {
 		let our_closing_script = self.get_closing_scriptpubkey();
 
 		let (proposed_feerate, proposed_fee, our_sig) = if self.channel_outbound && self.pending_htlcs.is_empty() {
-			let mut proposed_feerate = fee_estimator.get_est_sat_per_vbyte(ConfirmationTarget::Background);
-			if self.feerate_per_kw > proposed_feerate * 250 {
-				proposed_feerate = self.feerate_per_kw / 250;
-			}
+			let proposed_feerate = match self.closing_fee_params.target_feerate {
+				Some(target_feerate) => target_feerate,
+				None => {
+					let mut proposed_feerate = fee_estimator.get_est_sat_per_vbyte(ConfirmationTarget::Background);
+					if self.feerate_per_kw > proposed_feerate * 250 {
+						proposed_feerate = self.feerate_per_kw / 250;
+					}
+					proposed_feerate
+				}
+			};
 			let tx_weight = Self::get_closing_transaction_weight(&our_closing_script, &msg.scriptpubkey);
-			let proposed_total_fee_satoshis = proposed_feerate * tx_weight / 4;
+			let mut proposed_total_fee_satoshis = proposed_feerate * tx_weight / 4;
+			if let Some(max_fee_satoshis) = self.closing_fee_params.max_fee_satoshis {
+				proposed_total_fee_satoshis = cmp::min(proposed_total_fee_satoshis, max_fee_satoshis);
+			}
 
 			let (closing_tx, total_fee_satoshis) = self.build_closing_transaction(proposed_total_fee_satoshis, false);
 			let funding_redeemscript = self.get_funding_redeemscript();
-			let sighash = Message::from_slice(&bip143::SighashComponents::new(&closing_tx).sighash_all(&closing_tx.input[0], &funding_redeemscript, self.channel_value_satoshis)[..]).unwrap();
+			let our_sig = match self.signer.sign_closing_transaction(&self.secp_ctx, &closing_tx, &funding_redeemscript, self.channel_value_satoshis) {
+				Ok(sig) => sig,
+				Err(_) => return Err(HandleError{err: "Signer refused to sign closing transaction", msg: None}),
+			};
 
-			(Some(proposed_feerate), Some(total_fee_satoshis), Some(self.secp_ctx.sign(&sighash, &self.local_keys.funding_key).unwrap()))
+			(Some(proposed_feerate), Some(total_fee_satoshis), Some(our_sig))
 		} else { (None, None, None) };
 
 		// From here on out, we may not fail!
 
 		self.channel_state |= ChannelState::RemoteShutdownSent as u32;
-		self.channel_update_count += 1;
+		self.bump_channel_update_timestamp();
 
 		// We can't send our shutdown until we've committed all of our pending HTLCs, but the
 		// remote side is unlikely to accept any new HTLCs, so we go ahead and "free" any holding
@@ -1695,7 +1978,7 @@ impl Channel {
 		};
 
 		self.channel_state |= ChannelState::LocalShutdownSent as u32;
-		self.channel_update_count += 1;
+		self.bump_channel_update_timestamp();
 		if self.pending_htlcs.is_empty() && self.channel_outbound {
 			// There are no more HTLCs and we're the funder, this means we start the closing_signed
 			// dance with an initial fee proposal!
@@ -1741,19 +2024,24 @@ impl Channel {
 
 		if let Some((_, last_fee)) = self.last_sent_closing_fee {
 			if last_fee == msg.fee_satoshis {
-				self.sign_commitment_transaction(&mut closing_tx, &msg.signature);
+				self.sign_closing_transaction(&mut closing_tx, &msg.signature)?;
 				self.channel_state = ChannelState::ShutdownComplete as u32;
-				self.channel_update_count += 1;
+				self.bump_channel_update_timestamp();
 				return Ok((None, Some(closing_tx)));
 			}
 		}
 
-		macro_rules! propose_new_feerate {
-			($new_feerate: expr) => {
-				let closing_tx_max_weight = Self::get_closing_transaction_weight(&self.get_closing_scriptpubkey(), self.their_shutdown_scriptpubkey.as_ref().unwrap());
-				let (closing_tx, used_total_fee) = self.build_closing_transaction($new_feerate * closing_tx_max_weight / 4, false);
-				sighash = Message::from_slice(&bip143::SighashComponents::new(&closing_tx).sighash_all(&closing_tx.input[0], &funding_redeemscript, self.channel_value_satoshis)[..]).unwrap();
-				let our_sig = self.secp_ctx.sign(&sighash, &self.local_keys.funding_key).unwrap();
+		// Only the funder pays the closing fee, so only it has any use for a maximum
+		let max_fee_satoshis = if self.channel_outbound { self.closing_fee_params.max_fee_satoshis } else { None };
+		let closing_tx_max_weight = Self::get_closing_transaction_weight(&self.get_closing_scriptpubkey(), self.their_shutdown_scriptpubkey.as_ref().unwrap());
+
+		macro_rules! propose_new_fee {
+			($new_feerate: expr, $new_total_fee: expr) => {
+				let (closing_tx, used_total_fee) = self.build_closing_transaction($new_total_fee, false);
+				let our_sig = match self.signer.sign_closing_transaction(&self.secp_ctx, &closing_tx, &funding_redeemscript, self.channel_value_satoshis) {
+					Ok(sig) => sig,
+					Err(_) => return Err(HandleError{err: "Signer refused to sign closing transaction", msg: None}),
+				};
 				self.last_sent_closing_fee = Some(($new_feerate, used_total_fee));
 				return Ok((Some(msgs::ClosingSigned {
 					channel_id: self.channel_id,
@@ -1762,33 +2050,64 @@ impl Channel {
 				}), None))
 			}
 		}
+		macro_rules! propose_new_feerate {
+			($new_feerate: expr) => {
+				let mut new_total_fee = $new_feerate * closing_tx_max_weight / 4;
+				if let Some(max_fee_satoshis) = max_fee_satoshis {
+					new_total_fee = cmp::min(new_total_fee, max_fee_satoshis);
+				}
+				propose_new_fee!($new_feerate, new_total_fee);
+			}
+		}
+		macro_rules! negotiation_failed {
+			($err: expr) => {
+				self.closing_fee_negotiation_failed = true;
+				return Err(HandleError{err: $err, msg: None});
+			}
+		}
 
 		let proposed_sat_per_vbyte = msg.fee_satoshis * 4 / closing_tx.get_weight();
 		if self.channel_outbound {
-			let our_max_feerate = fee_estimator.get_est_sat_per_vbyte(ConfirmationTarget::Normal);
+			let mut our_max_feerate = fee_estimator.get_est_sat_per_vbyte(ConfirmationTarget::Normal);
+			if let Some(target_feerate) = self.closing_fee_params.target_feerate {
+				our_max_feerate = cmp::max(our_max_feerate, target_feerate);
+			}
 			if proposed_sat_per_vbyte > our_max_feerate {
 				if let Some((last_feerate, _)) = self.last_sent_closing_fee {
 					if our_max_feerate <= last_feerate {
-						return Err(HandleError{err: "Unable to come to consensus about closing feerate, remote wanted something higher than our Normal feerate", msg: None});
+						negotiation_failed!("Unable to come to consensus about closing feerate, remote wanted something higher than our Normal feerate");
 					}
 				}
 				propose_new_feerate!(our_max_feerate);
 			}
+			if let Some(max_fee_satoshis) = max_fee_satoshis {
+				if msg.fee_satoshis > max_fee_satoshis {
+					if let Some((_, last_fee)) = self.last_sent_closing_fee {
+						if max_fee_satoshis <= last_fee {
+							negotiation_failed!("Unable to come to consensus about closing fee, remote wanted more than our maximum fee");
+						}
+					}
+					propose_new_fee!(max_fee_satoshis * 4 / closing_tx_max_weight, max_fee_satoshis);
+				}
+			}
 		} else {
-			let our_min_feerate = fee_estimator.get_est_sat_per_vbyte(ConfirmationTarget::Background);
+			let our_min_feerate = match self.closing_fee_params.target_feerate {
+				Some(target_feerate) => target_feerate,
+				None => fee_estimator.get_est_sat_per_vbyte(ConfirmationTarget::Background),
+			};
 			if proposed_sat_per_vbyte < our_min_feerate {
 				if let Some((last_feerate, _)) = self.last_sent_closing_fee {
 					if our_min_feerate >= last_feerate {
-						return Err(HandleError{err: "Unable to come to consensus about closing feerate, remote wanted something lower than our Background feerate", msg: None});
+						negotiation_failed!("Unable to come to consensus about closing feerate, remote wanted something lower than our Background feerate");
 					}
 				}
 				propose_new_feerate!(our_min_feerate);
 			}
 		}
 
-		let our_sig = self.sign_commitment_transaction(&mut closing_tx, &msg.signature);
+		let our_sig = self.sign_closing_transaction(&mut closing_tx, &msg.signature)?;
 		self.channel_state = ChannelState::ShutdownComplete as u32;
-		self.channel_update_count += 1;
+		self.bump_channel_update_timestamp();
 
 		Ok((Some(msgs::ClosingSigned {
 			channel_id: self.channel_id,
@@ -1809,11 +2128,13 @@ impl Channel {
 		self.user_id
 	}
 
-	pub fn channel_monitor(&self) -> ChannelMonitor {
+	/// Gets our copy of the channel's ChannelMonitor, which reflects all the ChannelMonitorUpdates
+	/// we've generated.
+	pub fn channel_monitor(&self) -> &ChannelMonitor {
 		if self.channel_state < ChannelState::FundingCreated as u32 {
 			panic!("Can't get a channel monitor until funding has been created");
 		}
-		self.channel_monitor.clone()
+		&self.channel_monitor
 	}
 
 	/// Guaranteed to be Some after both FundingLocked messages have been exchanged (and, thus,
@@ -1840,8 +2161,36 @@ impl Channel {
 		self.channel_value_satoshis
 	}
 
-	pub fn get_channel_update_count(&self) -> u32 {
-		self.channel_update_count
+	/// Gets the UNIX time (in seconds) to use as the timestamp of our next channel_update
+	pub fn get_channel_update_timestamp(&self) -> u32 {
+		self.channel_update_timestamp
+	}
+
+	/// Moves the timestamp of our next channel_update forward, so that it supersedes any we've
+	/// sent before.
+	pub fn bump_channel_update_timestamp(&mut self) {
+		self.channel_update_timestamp = cmp::max(unix_time_secs(), self.channel_update_timestamp + 1);
+	}
+
+	pub fn get_forwarding_policy(&self) -> Option<&ChannelForwardingPolicy> {
+		self.forwarding_policy.as_ref()
+	}
+
+	/// Gets the policy we replaced last, if we should still accept HTLCs which pay according to it
+	pub fn get_prev_forwarding_policy(&self) -> Option<&ChannelForwardingPolicy> {
+		match self.prev_forwarding_policy {
+			Some((ref policy, grace_period_end)) if Instant::now() < grace_period_end => Some(policy),
+			_ => None,
+		}
+	}
+
+	/// Sets a new forwarding policy, continuing to accept HTLCs according to prev_policy (which
+	/// should be the policy we've been advertising until now) until grace_period_end.
+	/// Bumps the channel_update timestamp so that the new policy will supersede the old one.
+	pub fn set_forwarding_policy(&mut self, policy: ChannelForwardingPolicy, prev_policy: ChannelForwardingPolicy, grace_period_end: Instant) {
+		self.forwarding_policy = Some(policy);
+		self.prev_forwarding_policy = Some((prev_policy, grace_period_end));
+		self.bump_channel_update_timestamp();
 	}
 
 	/// Gets the fee we'd want to charge for adding an HTLC output to this Channel
@@ -1863,6 +2212,11 @@ impl Channel {
 		res as u32
 	}
 
+	/// Returns true if this channel is (to be) announced to the network
+	pub fn should_announce(&self) -> bool {
+		self.announce_publicly
+	}
+
 	/// Returns true if this channel is fully established and not known to be closing.
 	pub fn is_usable(&self) -> bool {
 		let mask = ChannelState::ChannelFunded as u32 | BOTH_SIDES_SHUTDOWN_MASK;
@@ -1885,10 +2239,99 @@ impl Channel {
 		} else { false }
 	}
 
+	/// Gets the bounds we negotiate the closing fee within
+	pub fn get_closing_fee_params(&self) -> &ClosingFeeParameters {
+		&self.closing_fee_params
+	}
+
+	/// Returns true if we gave up on closing_signed negotiation as our counterparty wouldn't agree
+	/// to a fee within get_closing_fee_params()
+	pub fn closing_fee_negotiation_failed(&self) -> bool {
+		self.closing_fee_negotiation_failed
+	}
+
+	/// Returns true if we're waiting on a ChannelMonitorUpdate to be persisted before we can send
+	/// messages which rely on it. See monitor_update_in_progress.
+	pub fn is_awaiting_monitor_update(&self) -> bool {
+		self.monitor_update_in_progress
+	}
+
+	/// Indicates that the ManyChannelMonitor is still persisting the ChannelMonitorUpdate with the
+	/// given update_id (or, for 0, our initial ChannelMonitor). This freezes the channel until
+	/// monitor_update_completed is called with that (or a later) update_id.
+	pub fn monitor_update_in_progress(&mut self, update_id: u64) {
+		self.monitor_update_in_progress = true;
+		self.monitor_in_progress_update_id = cmp::max(self.monitor_in_progress_update_id, update_id);
+	}
+
+	/// Holds messages and HTLC updates which rely on a ChannelMonitorUpdate which is still in
+	/// progress, to be handed back by monitor_update_completed. Must only be called while
+	/// is_awaiting_monitor_update(). Messages are handed back in the order they were held.
+	pub fn hold_monitor_pending(&mut self, revoke_and_ack: Option<msgs::RevokeAndACK>, commitment_update: Option<msgs::CommitmentUpdate>, mut forwards: Vec<PendingForwardHTLCInfo>, mut failures: Vec<([u8; 32], HTLCFailReason)>, funding_broadcast_safe: bool) {
+		assert!(self.monitor_update_in_progress);
+		// We can only have one of each message outstanding: once we hold a revoke_and_ack our
+		// counterparty cannot send another commitment_signed, and once we hold a commitment update
+		// we are AwaitingRemoteRevoke and cannot generate another.
+		if let Some(msg) = revoke_and_ack {
+			assert!(self.monitor_pending_revoke_and_ack.is_none());
+			if self.monitor_pending_commitment_update.is_some() {
+				self.monitor_pending_order = RAACommitmentOrder::CommitmentFirst;
+			}
+			self.monitor_pending_revoke_and_ack = Some(msg);
+		}
+		if let Some(update) = commitment_update {
+			assert!(self.monitor_pending_commitment_update.is_none());
+			if self.monitor_pending_revoke_and_ack.is_some() {
+				self.monitor_pending_order = RAACommitmentOrder::RevokeAndACKFirst;
+			}
+			self.monitor_pending_commitment_update = Some(update);
+		}
+		self.monitor_pending_forwards.append(&mut forwards);
+		self.monitor_pending_failures.append(&mut failures);
+		self.monitor_pending_funding_broadcast_safe |= funding_broadcast_safe;
+	}
+
+	/// Indicates that all ChannelMonitorUpdates up to and including update_id have been persisted.
+	/// If that includes every update which was in progress, unfreezes the channel and returns
+	/// everything which was held by hold_monitor_pending: the revoke_and_ack and commitment
+	/// update to send (in the returned order), the HTLCs to forward and fail backwards, and
+	/// whether it is now safe to broadcast the funding transaction.
+	/// Once the held messages have been sent, free_holding_cell_after_monitor_update should be
+	/// called to send any HTLC updates which were put in the holding cell in the mean time.
+	pub fn monitor_update_completed(&mut self, update_id: u64) -> Option<(Option<msgs::RevokeAndACK>, Option<msgs::CommitmentUpdate>, RAACommitmentOrder, Vec<PendingForwardHTLCInfo>, Vec<([u8; 32], HTLCFailReason)>, bool)> {
+		if !self.monitor_update_in_progress || update_id < self.monitor_in_progress_update_id {
+			return None;
+		}
+		self.monitor_update_in_progress = false;
+
+		let mut forwards = Vec::new();
+		mem::swap(&mut forwards, &mut self.monitor_pending_forwards);
+		let mut failures = Vec::new();
+		mem::swap(&mut failures, &mut self.monitor_pending_failures);
+		let funding_broadcast_safe = self.monitor_pending_funding_broadcast_safe;
+		self.monitor_pending_funding_broadcast_safe = false;
+		Some((self.monitor_pending_revoke_and_ack.take(), self.monitor_pending_commitment_update.take(), self.monitor_pending_order, forwards, failures, funding_broadcast_safe))
+	}
+
+	/// Sends any HTLC updates which were put in the holding cell while a ChannelMonitorUpdate was
+	/// in progress, if we aren't otherwise waiting on our counterparty (in which case they'll be
+	/// sent once they revoke_and_ack).
+	pub fn free_holding_cell_after_monitor_update(&mut self) -> Result<Option<(msgs::CommitmentUpdate, ChannelMonitorUpdate)>, HandleError> {
+		if self.monitor_update_in_progress || (self.channel_state & (ChannelState::ChannelFunded as u32)) != (ChannelState::ChannelFunded as u32) ||
+				(self.channel_state & (ChannelState::AwaitingRemoteRevoke as u32)) == (ChannelState::AwaitingRemoteRevoke as u32) {
+			return Ok(None);
+		}
+		match self.free_holding_cell_htlcs()? {
+			Some(commitment_update) => Ok(Some((commitment_update, self.get_monitor_update()))),
+			None => Ok(None),
+		}
+	}
+
 	/// Called by channelmanager based on chain blocks being connected.
 	/// Note that we only need to use this to detect funding_signed, anything else is handled by
 	/// the channel_monitor.
 	pub fn block_connected(&mut self, header: &BlockHeader, height: u32, txn_matched: &[&Transaction], indexes_of_txn_matched: &[u32]) -> Option<msgs::FundingLocked> {
+		self.channel_monitor.prune_onion_replay_log(height);
 		let non_shutdown_state = self.channel_state & (!BOTH_SIDES_SHUTDOWN_MASK);
 		if self.funding_tx_confirmations > 0 {
 			if header.bitcoin_hash() != self.last_block_connected {
@@ -1899,7 +2342,7 @@ impl Channel {
 						self.channel_state |= ChannelState::OurFundingLocked as u32;
 					} else if non_shutdown_state == (ChannelState::FundingSent as u32 | ChannelState::TheirFundingLocked as u32) {
 						self.channel_state = ChannelState::ChannelFunded as u32 | (self.channel_state & BOTH_SIDES_SHUTDOWN_MASK);
-						self.channel_update_count += 1;
+						self.bump_channel_update_timestamp();
 						//TODO: Something about a state where we "lost confirmation"
 					} else if self.channel_state < ChannelState::ChannelFunded as u32 {
 						panic!("Started confirming a channel in a state pre-FundingSent?");
@@ -1910,8 +2353,7 @@ impl Channel {
 					//as otherwise we will have a commitment transaction that they can't revoke (well, kinda,
 					//they can by sending two revoke_and_acks back-to-back, but not really). This appears to be
 					//a protocol oversight, but I assume I'm just missing something.
-					let next_per_commitment_secret = self.build_local_commitment_secret(self.cur_local_commitment_transaction_number);
-					let next_per_commitment_point = PublicKey::from_secret_key(&self.secp_ctx, &next_per_commitment_secret).unwrap();
+					let next_per_commitment_point = self.signer.get_per_commitment_point(&self.secp_ctx, self.cur_local_commitment_transaction_number);
 					return Some(msgs::FundingLocked {
 						channel_id: self.channel_id,
 						next_per_commitment_point: next_per_commitment_point,
@@ -1926,7 +2368,7 @@ impl Channel {
 					if txo_idx >= tx.output.len() || tx.output[txo_idx].script_pubkey != self.get_funding_redeemscript().to_v0_p2wsh() ||
 						tx.output[txo_idx].value != self.channel_value_satoshis {
 						self.channel_state = ChannelState::ShutdownComplete as u32;
-						self.channel_update_count += 1;
+						self.bump_channel_update_timestamp();
 					} else {
 						self.funding_tx_confirmations = 1;
 						self.short_channel_id = Some(((height as u64)          << (5*8)) |
@@ -1970,7 +2412,7 @@ impl Channel {
 			panic!("Tried to send an open_channel for a channel that has already advanced");
 		}
 
-		let local_commitment_secret = self.build_local_commitment_secret(self.cur_local_commitment_transaction_number);
+		let our_pubkeys = self.signer.pubkeys(&self.secp_ctx);
 
 		Ok(msgs::OpenChannel {
 			chain_hash: chain_hash,
@@ -1984,14 +2426,14 @@ impl Channel {
 			feerate_per_kw: fee_estimator.get_est_sat_per_vbyte(ConfirmationTarget::Background) as u32 * 250,
 			to_self_delay: BREAKDOWN_TIMEOUT,
 			max_accepted_htlcs: OUR_MAX_HTLCS,
-			funding_pubkey: PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.funding_key).unwrap(),
-			revocation_basepoint: PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.revocation_base_key).unwrap(),
-			payment_basepoint: PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.payment_base_key).unwrap(),
-			delayed_payment_basepoint: PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.delayed_payment_base_key).unwrap(),
-			htlc_basepoint: PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.htlc_base_key).unwrap(),
-			first_per_commitment_point: PublicKey::from_secret_key(&self.secp_ctx, &local_commitment_secret).unwrap(),
+			funding_pubkey: our_pubkeys.funding_pubkey,
+			revocation_basepoint: our_pubkeys.revocation_basepoint,
+			payment_basepoint: our_pubkeys.payment_basepoint,
+			delayed_payment_basepoint: our_pubkeys.delayed_payment_basepoint,
+			htlc_basepoint: our_pubkeys.htlc_basepoint,
+			first_per_commitment_point: self.signer.get_per_commitment_point(&self.secp_ctx, self.cur_local_commitment_transaction_number),
 			channel_flags: if self.announce_publicly {1} else {0},
-			shutdown_scriptpubkey: None,
+			shutdown_scriptpubkey: self.get_upfront_shutdown_script(),
 		})
 	}
 
@@ -2006,7 +2448,7 @@ impl Channel {
 			panic!("Tried to send an accept_channel for a channel that has already advanced");
 		}
 
-		let local_commitment_secret = self.build_local_commitment_secret(self.cur_local_commitment_transaction_number);
+		let our_pubkeys = self.signer.pubkeys(&self.secp_ctx);
 
 		Ok(msgs::AcceptChannel {
 			temporary_channel_id: self.channel_id,
@@ -2017,25 +2459,36 @@ impl Channel {
 			minimum_depth: CONF_TARGET,
 			to_self_delay: BREAKDOWN_TIMEOUT,
 			max_accepted_htlcs: OUR_MAX_HTLCS,
-			funding_pubkey: PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.funding_key).unwrap(),
-			revocation_basepoint: PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.revocation_base_key).unwrap(),
-			payment_basepoint: PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.payment_base_key).unwrap(),
-			delayed_payment_basepoint: PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.delayed_payment_base_key).unwrap(),
-			htlc_basepoint: PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.htlc_base_key).unwrap(),
-			first_per_commitment_point: PublicKey::from_secret_key(&self.secp_ctx, &local_commitment_secret).unwrap(),
-			shutdown_scriptpubkey: None,
+			funding_pubkey: our_pubkeys.funding_pubkey,
+			revocation_basepoint: our_pubkeys.revocation_basepoint,
+			payment_basepoint: our_pubkeys.payment_basepoint,
+			delayed_payment_basepoint: our_pubkeys.delayed_payment_basepoint,
+			htlc_basepoint: our_pubkeys.htlc_basepoint,
+			first_per_commitment_point: self.signer.get_per_commitment_point(&self.secp_ctx, self.cur_local_commitment_transaction_number),
+			shutdown_scriptpubkey: self.get_upfront_shutdown_script(),
 		})
 	}
 
+	/// Gets the shutdown_scriptpubkey to include in open_channel/accept_channel. This is only
+	/// included if option_upfront_shutdown_script was negotiated, with an empty script opting out
+	/// of committing to one.
+	fn get_upfront_shutdown_script(&self) -> Option<Script> {
+		if self.upfront_shutdown_script {
+			Some(self.our_shutdown_scriptpubkey.clone().unwrap_or(Script::new()))
+		} else {
+			None
+		}
+	}
+
 	fn get_outbound_funding_created_signature(&mut self) -> Result<(Signature, Transaction), HandleError> {
 		let funding_script = self.get_funding_redeemscript();
 
 		let remote_keys = self.build_remote_transaction_keys()?;
 		let remote_initial_commitment_tx = self.build_commitment_transaction(self.cur_remote_commitment_transaction_number, &remote_keys, false, false).0;
-		let remote_sighash = Message::from_slice(&bip143::SighashComponents::new(&remote_initial_commitment_tx).sighash_all(&remote_initial_commitment_tx.input[0], &funding_script, self.channel_value_satoshis)[..]).unwrap();
 
 		// We sign the "remote" commitment transaction, allowing them to broadcast the tx if they wish.
-		Ok((self.secp_ctx.sign(&remote_sighash, &self.local_keys.funding_key).unwrap(), remote_initial_commitment_tx))
+		let our_sig = self.sign_remote_commitment(&remote_initial_commitment_tx, &funding_script)?;
+		Ok((our_sig, remote_initial_commitment_tx))
 	}
 
 	/// Updates channel state with knowledge of the funding transaction's txid/index, and generates
@@ -2097,7 +2550,7 @@ impl Channel {
 		}
 
 		let were_node_one = our_node_id.serialize()[..] < self.their_node_id.serialize()[..];
-		let our_bitcoin_key = PublicKey::from_secret_key(&self.secp_ctx, &self.local_keys.funding_key).unwrap();
+		let our_bitcoin_key = self.signer.pubkeys(&self.secp_ctx).funding_pubkey;
 
 		let msg = msgs::UnsignedChannelAnnouncement {
 			features: msgs::GlobalFeatures::new(),
@@ -2109,8 +2562,10 @@ impl Channel {
 			bitcoin_key_2: if were_node_one { self.their_funding_pubkey } else { our_bitcoin_key },
 		};
 
-		let msghash = Message::from_slice(&Sha256dHash::from_data(&msg.encode()[..])[..]).unwrap();
-		let sig = self.secp_ctx.sign(&msghash, &self.local_keys.funding_key).unwrap();
+		let sig = match self.signer.sign_channel_announcement(&self.secp_ctx, &msg) {
+			Ok(sig) => sig,
+			Err(_) => return Err(HandleError{err: "Signer refused to sign channel announcement", msg: None}),
+		};
 
 		Ok((msg, sig))
 	}
@@ -2152,7 +2607,7 @@ impl Channel {
 		//TODO: Check cltv_expiry? Do this in channel manager?
 
 		// Now update local state:
-		if (self.channel_state & (ChannelState::AwaitingRemoteRevoke as u32)) == (ChannelState::AwaitingRemoteRevoke as u32) {
+		if (self.channel_state & (ChannelState::AwaitingRemoteRevoke as u32)) == (ChannelState::AwaitingRemoteRevoke as u32) || self.monitor_update_in_progress {
 			//TODO: Check the limits *including* other pending holding cell HTLCs!
 			self.holding_cell_htlc_updates.push(HTLCUpdateAwaitingACK::AddHTLC {
 				amount_msat: amount_msat,
@@ -2190,13 +2645,16 @@ impl Channel {
 	}
 
 	/// Creates a signed commitment transaction to send to the remote peer.
-	pub fn send_commitment(&mut self) -> Result<(msgs::CommitmentSigned, ChannelMonitor), HandleError> {
+	pub fn send_commitment(&mut self) -> Result<(msgs::CommitmentSigned, ChannelMonitorUpdate), HandleError> {
 		if (self.channel_state & (ChannelState::ChannelFunded as u32)) != (ChannelState::ChannelFunded as u32) {
 			return Err(HandleError{err: "Cannot create commitment tx until channel is fully established", msg: None});
 		}
 		if (self.channel_state & (ChannelState::AwaitingRemoteRevoke as u32)) == (ChannelState::AwaitingRemoteRevoke as u32) {
 			return Err(HandleError{err: "Cannot create commitment tx until remote revokes their previous commitment", msg: None});
 		}
+		if self.monitor_update_in_progress {
+			return Err(HandleError{err: "Cannot create commitment tx while a channel monitor update is in progress", msg: None});
+		}
 		let mut have_updates = false; // TODO initialize with "have we sent a fee update?"
 		for htlc in self.pending_htlcs.iter() {
 			if htlc.state == HTLCState::LocalAnnounced {
@@ -2207,10 +2665,12 @@ impl Channel {
 		if !have_updates {
 			return Err(HandleError{err: "Cannot create commitment tx until we have some updates to send", msg: None});
 		}
-		self.send_commitment_no_status_check()
+		let commitment_signed = self.send_commitment_no_status_check()?;
+		Ok((commitment_signed, self.get_monitor_update()))
 	}
-	/// Only fails in case of bad keys
-	fn send_commitment_no_status_check(&mut self) -> Result<(msgs::CommitmentSigned, ChannelMonitor), HandleError> {
+	/// Only fails in case of bad keys. Note that the new remote commitment transaction info is
+	/// only queued for the next ChannelMonitorUpdate, callers must call get_monitor_update.
+	fn send_commitment_no_status_check(&mut self) -> Result<msgs::CommitmentSigned, HandleError> {
 		let funding_script = self.get_funding_redeemscript();
 
 		// We can upgrade the status of some HTLCs that are waiting on a commitment, even if we
@@ -2227,39 +2687,40 @@ impl Channel {
 		let remote_keys = self.build_remote_transaction_keys()?;
 		let remote_commitment_tx = self.build_commitment_transaction(self.cur_remote_commitment_transaction_number, &remote_keys, false, true);
 		let remote_commitment_txid = remote_commitment_tx.0.txid();
-		let remote_sighash = Message::from_slice(&bip143::SighashComponents::new(&remote_commitment_tx.0).sighash_all(&remote_commitment_tx.0.input[0], &funding_script, self.channel_value_satoshis)[..]).unwrap();
-		let our_sig = self.secp_ctx.sign(&remote_sighash, &self.local_keys.funding_key).unwrap();
+		let our_sig = self.sign_remote_commitment(&remote_commitment_tx.0, &funding_script)?;
 
 		let mut htlc_sigs = Vec::new();
 
 		for ref htlc in remote_commitment_tx.1.iter() {
 			let htlc_tx = self.build_htlc_transaction(&remote_commitment_txid, htlc, false, &remote_keys);
-			let htlc_redeemscript = chan_utils::get_htlc_redeemscript(&htlc, &remote_keys);
-			let htlc_sighash = Message::from_slice(&bip143::SighashComponents::new(&htlc_tx).sighash_all(&htlc_tx.input[0], &htlc_redeemscript, htlc.amount_msat / 1000)[..]).unwrap();
-			let our_htlc_key = secp_derived_key!(chan_utils::derive_private_key(&self.secp_ctx, &remote_keys.per_commitment_point, &self.local_keys.htlc_base_key));
-			htlc_sigs.push(self.secp_ctx.sign(&htlc_sighash, &our_htlc_key).unwrap());
+			htlc_sigs.push(self.create_htlc_tx_signature(&htlc_tx, htlc, &remote_keys)?.1);
 		}
 
 		// Update state now that we've passed all the can-fail calls...
-		self.channel_monitor.provide_latest_remote_commitment_tx_info(&remote_commitment_tx.0, remote_commitment_tx.1, self.cur_remote_commitment_transaction_number);
+		let commitment_number = self.cur_remote_commitment_transaction_number;
+		self.update_monitor(ChannelMonitorUpdateStep::LatestRemoteCommitmentTXInfo {
+			unsigned_commitment_tx: remote_commitment_tx.0,
+			htlc_outputs: remote_commitment_tx.1,
+			commitment_number: commitment_number,
+		})?;
 		self.channel_state |= ChannelState::AwaitingRemoteRevoke as u32;
 
-		Ok((msgs::CommitmentSigned {
+		Ok(msgs::CommitmentSigned {
 			channel_id: self.channel_id,
 			signature: our_sig,
 			htlc_signatures: htlc_sigs,
-		}, self.channel_monitor.clone()))
+		})
 	}
 
 	/// Adds a pending outbound HTLC to this channel, and creates a signed commitment transaction
 	/// to send to the remote peer in one go.
 	/// Shorthand for calling send_htlc() followed by send_commitment(), see docs on those for
 	/// more info.
-	pub fn send_htlc_and_commit(&mut self, amount_msat: u64, payment_hash: [u8; 32], cltv_expiry: u32, onion_routing_packet: msgs::OnionPacket) -> Result<Option<(msgs::UpdateAddHTLC, msgs::CommitmentSigned, ChannelMonitor)>, HandleError> {
+	pub fn send_htlc_and_commit(&mut self, amount_msat: u64, payment_hash: [u8; 32], cltv_expiry: u32, onion_routing_packet: msgs::OnionPacket) -> Result<Option<(msgs::UpdateAddHTLC, msgs::CommitmentSigned, ChannelMonitorUpdate)>, HandleError> {
 		match self.send_htlc(amount_msat, payment_hash, cltv_expiry, onion_routing_packet)? {
 			Some(update_add_htlc) => {
-				let (commitment_signed, monitor_update) = self.send_commitment_no_status_check()?;
-				Ok(Some((update_add_htlc, commitment_signed, monitor_update)))
+				let commitment_signed = self.send_commitment_no_status_check()?;
+				Ok(Some((update_add_htlc, commitment_signed, self.get_monitor_update())))
 			},
 			None => Ok(None)
 		}
@@ -2267,7 +2728,10 @@ impl Channel {
 
 	/// Begins the shutdown process, getting a message for the remote peer and returning all
 	/// holding cell HTLCs for payment failure.
-	pub fn get_shutdown(&mut self) -> Result<(msgs::Shutdown, Vec<[u8; 32]>), HandleError> {
+	/// If shutdown_scriptpubkey is set, we close to it instead of shutdown_pubkey, though it
+	/// must match the one we committed to in open_channel/accept_channel, if any.
+	/// fee_params, if set, bound the fee we'll agree to in the closing_signed negotiation.
+	pub fn get_shutdown(&mut self, shutdown_scriptpubkey: Option<Script>, fee_params: Option<ClosingFeeParameters>) -> Result<(msgs::Shutdown, Vec<[u8; 32]>), HandleError> {
 		for htlc in self.pending_htlcs.iter() {
 			if htlc.state == HTLCState::LocalAnnounced {
 				return Err(HandleError{err: "Cannot begin shutdown with pending HTLCs, call send_commitment first", msg: None});
@@ -2277,16 +2741,30 @@ impl Channel {
 			return Err(HandleError{err: "Shutdown already in progress", msg: None});
 		}
 		assert_eq!(self.channel_state & ChannelState::ShutdownComplete as u32, 0);
+		if let Some(ref script) = shutdown_scriptpubkey {
+			if !Channel::is_standard_shutdown_script(script) {
+				return Err(HandleError{err: "Provided shutdown_scriptpubkey is of a nonstandard form", msg: None});
+			}
+			if self.upfront_shutdown_script && self.our_shutdown_scriptpubkey.is_some() && self.our_shutdown_scriptpubkey.as_ref() != Some(script) {
+				return Err(HandleError{err: "Provided shutdown_scriptpubkey does not match the one committed to at channel open", msg: None});
+			}
+		}
 
+		// From here on out, we may not fail!
+		if shutdown_scriptpubkey.is_some() {
+			self.our_shutdown_scriptpubkey = shutdown_scriptpubkey;
+		}
+		if let Some(fee_params) = fee_params {
+			self.closing_fee_params = fee_params;
+		}
 		let our_closing_script = self.get_closing_scriptpubkey();
 
-		// From here on out, we may not fail!
 		if self.channel_state < ChannelState::FundingSent as u32 {
 			self.channel_state = ChannelState::ShutdownComplete as u32;
 		} else {
 			self.channel_state |= ChannelState::LocalShutdownSent as u32;
 		}
-		self.channel_update_count += 1;
+		self.bump_channel_update_timestamp();
 
 		// We can't send our shutdown until we've committed all of our pending HTLCs, but the
 		// remote side is unlikely to accept any new HTLCs, so we go ahead and "free" any holding
@@ -2313,7 +2791,7 @@ impl Channel {
 	pub fn force_shutdown(&mut self) -> Vec<Transaction> {
 		assert!(self.channel_state != ChannelState::ShutdownComplete as u32);
 		self.channel_state = ChannelState::ShutdownComplete as u32;
-		self.channel_update_count += 1;
+		self.bump_channel_update_timestamp();
 		let mut res = Vec::new();
 		mem::swap(&mut res, &mut self.last_local_commitment_txn);
 		res
@@ -2330,12 +2808,18 @@ mod tests {
 	use ln::channel::{Channel,ChannelKeys,HTLCOutput,HTLCState,HTLCOutputInCommitment,TxCreationKeys};
 	use ln::channel::MAX_FUNDING_SATOSHIS;
 	use ln::chan_utils;
+	use bitcoin::blockdata::script::{Script, Builder};
+	use bitcoin::blockdata::opcodes;
+	use bitcoin::util::hash::Hash160;
 	use chain::chaininterface::{FeeEstimator,ConfirmationTarget};
+	use chain::keysinterface::{KeysInterface, ChannelSigner};
 	use chain::transaction::OutPoint;
+	use util::rng::SeededEntropySource;
 	use secp256k1::{Secp256k1,Message,Signature};
 	use secp256k1::key::{SecretKey,PublicKey};
 	use crypto::sha2::Sha256;
 	use crypto::digest::Digest;
+	use std::sync::Arc;
 
 	struct TestFeeEstimator {
 		fee_est: u64
@@ -2346,6 +2830,25 @@ mod tests {
 		}
 	}
 
+	struct Keys {
+		chan_keys: ChannelKeys,
+	}
+	impl KeysInterface for Keys {
+		fn get_node_secret(&self) -> SecretKey { panic!(); }
+		fn get_destination_script(&self) -> Script {
+			let secp_ctx = Secp256k1::new();
+			let channel_monitor_claim_key = SecretKey::from_slice(&secp_ctx, &hex_bytes("0fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").unwrap()[..]).unwrap();
+			let our_channel_monitor_claim_key_hash = Hash160::from_data(&PublicKey::from_secret_key(&secp_ctx, &channel_monitor_claim_key).unwrap().serialize());
+			Builder::new().push_opcode(opcodes::All::OP_PUSHBYTES_0).push_slice(&our_channel_monitor_claim_key_hash[..]).into_script()
+		}
+		fn get_shutdown_pubkey(&self) -> PublicKey {
+			let secp_ctx = Secp256k1::new();
+			let channel_close_key = SecretKey::from_slice(&secp_ctx, &hex_bytes("0fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").unwrap()[..]).unwrap();
+			PublicKey::from_secret_key(&secp_ctx, &channel_close_key).unwrap()
+		}
+		fn get_channel_signer(&self) -> Arc<ChannelSigner> { Arc::new(self.chan_keys.clone()) }
+	}
+
 	#[test]
 	fn test_max_funding_satoshis() {
 		assert!(MAX_FUNDING_SATOSHIS <= 21_000_000 * 100_000_000,
@@ -2366,14 +2869,13 @@ mod tests {
 
 			// These aren't set in the test vectors:
 			revocation_base_key: SecretKey::from_slice(&secp_ctx, &hex_bytes("0fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").unwrap()[..]).unwrap(),
-			channel_close_key: SecretKey::from_slice(&secp_ctx, &hex_bytes("0fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").unwrap()[..]).unwrap(),
-			channel_monitor_claim_key: SecretKey::from_slice(&secp_ctx, &hex_bytes("0fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").unwrap()[..]).unwrap(),
 			commitment_seed: [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
 		};
 		assert_eq!(PublicKey::from_secret_key(&secp_ctx, &chan_keys.funding_key).unwrap().serialize()[..],
 				hex_bytes("023da092f6980e58d2c037173180e9a465476026ee50f96695963e8efe436f54eb").unwrap()[..]);
 
-		let mut chan = Channel::new_outbound(&feeest, chan_keys, PublicKey::new(), 10000000, false, 42); // Nothing uses their network key in this test
+		let keys_provider: Arc<KeysInterface> = Arc::new(Keys { chan_keys: chan_keys.clone() });
+		let mut chan = Channel::new_outbound(&feeest, &keys_provider, &SeededEntropySource::new([42; 32]), PublicKey::new(), 10000000, false, 42, None, false); // Nothing uses their network key in this test
 		chan.their_to_self_delay = 144;
 		chan.our_dust_limit_satoshis = 546;
 
@@ -2397,10 +2899,10 @@ mod tests {
 		// We can't just use build_local_transaction_keys here as the per_commitment_secret is not
 		// derived from a commitment_seed, so instead we copy it here and call
 		// build_commitment_transaction.
-		let delayed_payment_base = PublicKey::from_secret_key(&secp_ctx, &chan.local_keys.delayed_payment_base_key).unwrap();
+		let delayed_payment_base = PublicKey::from_secret_key(&secp_ctx, &chan_keys.delayed_payment_base_key).unwrap();
 		let per_commitment_secret = SecretKey::from_slice(&secp_ctx, &hex_bytes("1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100").unwrap()[..]).unwrap();
 		let per_commitment_point = PublicKey::from_secret_key(&secp_ctx, &per_commitment_secret).unwrap();
-		let htlc_basepoint = PublicKey::from_secret_key(&secp_ctx, &chan.local_keys.htlc_base_key).unwrap();
+		let htlc_basepoint = PublicKey::from_secret_key(&secp_ctx, &chan_keys.htlc_base_key).unwrap();
 		let keys = TxCreationKeys::new(&secp_ctx, &per_commitment_point, &delayed_payment_base, &htlc_basepoint, &chan.their_revocation_basepoint, &chan.their_payment_basepoint, &chan.their_htlc_basepoint).unwrap();
 
 		let mut unsigned_tx: (Transaction, Vec<HTLCOutputInCommitment>);
@@ -2412,7 +2914,7 @@ mod tests {
 				let sighash = Message::from_slice(&bip143::SighashComponents::new(&unsigned_tx.0).sighash_all(&unsigned_tx.0.input[0], &chan.get_funding_redeemscript(), chan.channel_value_satoshis)[..]).unwrap();
 				secp_ctx.verify(&sighash, &their_signature, &chan.their_funding_pubkey).unwrap();
 
-				chan.sign_commitment_transaction(&mut unsigned_tx.0, &their_signature);
+				chan.sign_commitment_transaction(&mut unsigned_tx.0, &their_signature, 42).unwrap();
 
 				assert_eq!(serialize(&unsigned_tx.0).unwrap()[..],
 						hex_bytes($tx_hex).unwrap()[..]);
@@ -1,4 +1,5 @@
 use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::script::Script;
 use bitcoin::blockdata::transaction::Transaction;
 use bitcoin::blockdata::constants::genesis_block;
 use bitcoin::network::constants::Network;
@@ -12,13 +13,15 @@ use secp256k1::ecdh::SharedSecret;
 use secp256k1;
 
 use chain::chaininterface::{BroadcasterInterface,ChainListener,ChainWatchInterface,FeeEstimator};
+use chain::keysinterface::KeysInterface;
 use chain::transaction::OutPoint;
-use ln::channel::{Channel, ChannelKeys};
-use ln::channelmonitor::ManyChannelMonitor;
+use ln::channel::{Channel, RAACommitmentOrder};
+use ln::channelmonitor::{ChannelMonitor, ChannelMonitorUpdate, ChannelMonitorUpdateErr, ManyChannelMonitor, CLTV_CLAIM_BUFFER};
 use ln::router::{Route,RouteHop};
 use ln::msgs;
 use ln::msgs::{HandleError,ChannelMessageHandler,MsgEncodable,MsgDecodable};
-use util::{byte_utils, events, internal_traits, rng};
+use util::{byte_utils, events, internal_traits};
+use util::rng::EntropySource;
 use util::sha2::Sha256;
 
 use crypto;
@@ -29,10 +32,11 @@ use crypto::symmetriccipher::SynchronousStreamCipher;
 use crypto::chacha20::ChaCha20;
 
 use std::sync::{Mutex,MutexGuard,Arc};
-use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::{HashMap, HashSet};
 use std::collections::hash_map;
-use std::{ptr, mem};
-use std::time::{Instant,Duration};
+use std::{cmp, ptr, mem, thread};
+use std::time::{Instant,Duration,SystemTime,UNIX_EPOCH};
 
 mod channel_held_info {
 	use ln::msgs;
@@ -94,6 +98,8 @@ enum PendingOutboundHTLC {
 	OutboundRoute {
 		route: Route,
 		session_priv: SecretKey,
+		/// Set for HTLCs sent by send_probe, which we expect to fail
+		is_probe: bool,
 	},
 	/// Used for channel rebalancing
 	CycledRoute {
@@ -104,12 +110,43 @@ enum PendingOutboundHTLC {
 	}
 }
 
+/// An authenticated failure for one of our own payments, see process_onion_failure
+struct DecodedOnionFailure {
+	erring_node: PublicKey,
+	short_channel_id: u64,
+	failure_code: msgs::HTLCFailureCode,
+	rejected_by_dest: bool,
+	channel_update: Option<msgs::HTLCFailChannelUpdate>,
+}
+
 /// We hold back HTLCs we intend to relay for a random interval in the range (this, 5*this). This
 /// provides some limited amount of privacy. Ideally this would range from somewhere like 1 second
 /// to 30 seconds, but people expect lightning to be, you know, kinda fast, sadly. We could
 /// probably increase this significantly.
 const MIN_HTLC_RELAY_HOLDING_CELL_MILLIS: u32 = 50;
 
+/// How long we continue to accept HTLCs which pay according to a channel's previous forwarding
+/// policy after it has been changed via update_forwarding_policy. Our new channel_update takes a
+/// while to propagate, and senders which haven't seen it yet would otherwise fail for no reason.
+const FORWARDING_POLICY_GRACE_PERIOD_SECS: u64 = 10 * 60;
+
+/// How often (in blocks) we re-broadcast our node_announcement, so that nodes which missed or
+/// pruned it learn of us again.
+const NODE_ANNOUNCEMENT_INTERVAL_BLOCKS: u32 = 144;
+
+/// How often (in blocks) we re-sign and re-broadcast the channel_updates for our public channels,
+/// about once a week so that they're never pruned as stale (after two weeks) by other nodes.
+const CHANNEL_UPDATE_REFRESH_INTERVAL_BLOCKS: u32 = 144 * 7;
+
+/// The fields of our node_announcement, as last set via broadcast_node_announcement
+struct NodeAnnouncementInfo {
+	rgb: [u8; 3],
+	alias: [u8; 32],
+	addresses: Vec<msgs::NetAddress>,
+	/// The timestamp of the last node_announcement we generated, which the next one must exceed
+	last_timestamp: u32,
+}
+
 struct ChannelHolder {
 	by_id: HashMap<Uint256, Channel>,
 	short_to_id: HashMap<u64, Uint256>,
@@ -117,6 +154,9 @@ struct ChannelHolder {
 	/// short channel id -> forward infos. Key of 0 means payments received
 	forward_htlcs: HashMap<u64, Vec<PendingForwardHTLCInfo>>,
 	claimable_htlcs: HashMap<[u8; 32], PendingOutboundHTLC>,
+	/// SHA256(onion shared secret) -> cltv_expiry for each onion we've accepted an HTLC for, so
+	/// that we can reject replays. Pruned once the HTLC has expired.
+	onion_replay_log: HashMap<[u8; 32], u32>,
 }
 struct MutChannelHolder<'a> {
 	by_id: &'a mut HashMap<Uint256, Channel>,
@@ -125,6 +165,7 @@ struct MutChannelHolder<'a> {
 	/// short channel id -> forward infos. Key of 0 means payments received
 	forward_htlcs: &'a mut HashMap<u64, Vec<PendingForwardHTLCInfo>>,
 	claimable_htlcs: &'a mut HashMap<[u8; 32], PendingOutboundHTLC>,
+	onion_replay_log: &'a mut HashMap<[u8; 32], u32>,
 }
 impl ChannelHolder {
 	fn borrow_parts(&mut self) -> MutChannelHolder {
@@ -135,10 +176,40 @@ impl ChannelHolder {
 			/// short channel id -> forward infos. Key of 0 means payments received
 			forward_htlcs: &mut self.forward_htlcs,
 			claimable_htlcs: &mut self.claimable_htlcs,
+			onion_replay_log: &mut self.onion_replay_log,
+		}
+	}
+}
+
+/// Removes the channel with the given id, and its short_to_id entry, from a ChannelHolder (or
+/// MutChannelHolder), eg after it was force-closed.
+macro_rules! remove_channel {
+	($channel_state: expr, $channel_id: expr) => {
+		if let Some(chan) = $channel_state.by_id.remove($channel_id) {
+			if let Some(short_id) = chan.get_short_channel_id() {
+				$channel_state.short_to_id.remove(&short_id);
+			}
+		}
+	}
+}
+
+/// Gets the Ok value of an update_channel_monitor/add_channel_monitor call in a
+/// ChannelMessageHandler function, otherwise removing the (now force-closed) channel and
+/// returning the error message for our counterparty.
+macro_rules! try_monitor_update {
+	($channel_state: expr, $res: expr) => {
+		match $res {
+			Ok(res) => res,
+			Err(msg) => {
+				remove_channel!($channel_state, &msg.channel_id);
+				return Err(HandleError{err: MONITOR_PERMANENT_FAILURE_ERR, msg: Some(msgs::ErrorAction::SendErrorMessage{msg})});
+			}
 		}
 	}
 }
 
+const MONITOR_PERMANENT_FAILURE_ERR: &'static str = "ChannelMonitor update failed permanently, force-closed channel";
+
 /// Manager which keeps track of a number of channels and sends messages to the appropriate
 /// channel, also tracking HTLC preimages and forwarding onion packets appropriately.
 /// Implements ChannelMessageHandler, handling the multi-channel parts and passing things through
@@ -154,13 +225,27 @@ pub struct ChannelManager {
 	fee_proportional_millionths: u32,
 	secp_ctx: Secp256k1,
 
+	latest_block_height: AtomicUsize,
 	channel_state: Mutex<ChannelHolder>,
 	our_network_key: SecretKey,
+	keys_manager: Arc<KeysInterface>,
+	entropy_source: Arc<EntropySource>,
+	node_announcement_info: Mutex<Option<NodeAnnouncementInfo>>,
+	/// The scriptpubkey we commit to closing inbound channels to, if any.
+	inbound_shutdown_scriptpubkey: Mutex<Option<Script>>,
+	/// The local features of each connected peer, as sent in their init message.
+	peer_features: Mutex<HashMap<PublicKey, msgs::LocalFeatures>>,
+	/// The threads which are currently calling into our ManyChannelMonitor, used to catch
+	/// update_completed being called from within add_monitor/update_monitor.
+	monitor_callers: Mutex<HashSet<thread::ThreadId>>,
 
 	pending_events: Mutex<Vec<events::Event>>,
 }
 
 const CLTV_EXPIRY_DELTA: u16 = 6 * 24 * 2; //TODO?
+/// The number of blocks we allow for an HTLC to reach us (or for our failure of it to make it back
+/// upstream) on top of the CLTV_CLAIM_BUFFER we need to claim it on-chain.
+const LATENCY_GRACE_PERIOD_BLOCKS: u32 = 3;
 
 macro_rules! secp_call {
 	( $res : expr ) => {
@@ -182,6 +267,58 @@ struct OnionKeys {
 	mu: [u8; 32],
 }
 
+/// The terms on which we forward HTLCs over a channel, as advertised in our channel_update for it.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ChannelForwardingPolicy {
+	/// The fixed fee we charge, in millisatoshi, to forward an HTLC over the channel
+	pub fee_base_msat: u32,
+	/// The fee we charge, in millionths of the amount forwarded, on top of fee_base_msat
+	pub fee_proportional_millionths: u32,
+	/// The number of blocks between the expiry of the HTLC we receive and the one we forward
+	/// over the channel
+	pub cltv_expiry_delta: u16,
+	/// The smallest HTLC, in millisatoshi, we will forward over the channel
+	pub htlc_minimum_msat: u64,
+}
+
+/// Bounds on the fee we'll agree to in closing_signed negotiation, see
+/// ChannelManager::close_channel.
+#[derive(Clone)]
+pub struct ClosingFeeParameters {
+	/// The feerate, in satoshis per vbyte, we'll propose if we're the funder (otherwise the lowest
+	/// feerate we'll accept). Defaults to our FeeEstimator's Background estimate (or, if we're
+	/// the funder, the commitment transaction's feerate, if higher).
+	pub target_feerate: Option<u64>,
+	/// The most we'll pay, in satoshis, in closing transaction fees. Only used if we're the
+	/// funder, as otherwise our counterparty pays the fee.
+	pub max_fee_satoshis: Option<u64>,
+	/// What to do if our counterparty won't agree to a fee within these bounds
+	pub on_failure: ClosingFeeFailureAction,
+}
+
+impl ClosingFeeParameters {
+	/// Creates a new ClosingFeeParameters which uses our FeeEstimator and leaves the channel be
+	/// if negotiation fails.
+	pub fn new() -> ClosingFeeParameters {
+		ClosingFeeParameters {
+			target_feerate: None,
+			max_fee_satoshis: None,
+			on_failure: ClosingFeeFailureAction::KeepOpen,
+		}
+	}
+}
+
+/// What to do if closing_signed negotiation fails to converge within our ClosingFeeParameters
+#[derive(Clone, Copy, PartialEq)]
+pub enum ClosingFeeFailureAction {
+	/// Broadcast our latest commitment transaction, closing the channel unilaterally
+	ForceClose,
+	/// Leave the channel in its (no longer usable) shutdown state, in case our counterparty
+	/// comes around, or so that it may be force-closed later with
+	/// ChannelManager::force_close_channel
+	KeepOpen,
+}
+
 pub struct ChannelDetails {
 	/// The channel's ID (prior to funding transaction generation, this is a random 32 bytes,
 	/// thereafter this is the txid of the funding transaction xor the funding transaction output).
@@ -195,6 +332,8 @@ pub struct ChannelDetails {
 	pub channel_value_satoshis: u64,
 	/// The user_id passed in to create_channel, or 0 if the channel was inbound.
 	pub user_id: u64,
+	/// The policy we currently advertise for forwarding HTLCs over this channel.
+	pub forwarding_policy: ChannelForwardingPolicy,
 }
 
 impl ChannelManager {
@@ -202,8 +341,13 @@ impl ChannelManager {
 	/// the main "logic hub" for all channel-related actions, and implements ChannelMessageHandler.
 	/// fee_proportional_millionths is an optional fee to charge any payments routed through us.
 	/// Non-proportional fees are fixed according to our risk using the provided fee estimator.
+	/// These defaults may be overridden per-channel with update_forwarding_policy.
+	/// Our node secret, as well as all per-channel keys and the scripts we claim funds to, are
+	/// fetched from the provided keys_manager.
+	/// entropy_source is used for temporary channel ids, onion session keys, probe payment
+	/// hashes and HTLC forwarding delays.
 	/// panics if channel_value_satoshis is >= `MAX_FUNDING_SATOSHIS`!
-	pub fn new(our_network_key: SecretKey, fee_proportional_millionths: u32, announce_channels_publicly: bool, network: Network, feeest: Arc<FeeEstimator>, monitor: Arc<ManyChannelMonitor>, chain_monitor: Arc<ChainWatchInterface>, tx_broadcaster: Arc<BroadcasterInterface>) -> Result<Arc<ChannelManager>, secp256k1::Error> {
+	pub fn new(keys_manager: Arc<KeysInterface>, entropy_source: Arc<EntropySource>, fee_proportional_millionths: u32, announce_channels_publicly: bool, network: Network, feeest: Arc<FeeEstimator>, monitor: Arc<ManyChannelMonitor>, chain_monitor: Arc<ChainWatchInterface>, tx_broadcaster: Arc<BroadcasterInterface>) -> Result<Arc<ChannelManager>, secp256k1::Error> {
 		let secp_ctx = Secp256k1::new();
 
 		let res = Arc::new(ChannelManager {
@@ -217,14 +361,22 @@ impl ChannelManager {
 			fee_proportional_millionths,
 			secp_ctx,
 
+			latest_block_height: AtomicUsize::new(0), //TODO: Get an init value (generally need to replay recent chain on chain_monitor registration)
 			channel_state: Mutex::new(ChannelHolder{
 				by_id: HashMap::new(),
 				short_to_id: HashMap::new(),
 				next_forward: Instant::now(),
 				forward_htlcs: HashMap::new(),
 				claimable_htlcs: HashMap::new(),
+				onion_replay_log: HashMap::new(),
 			}),
-			our_network_key,
+			our_network_key: keys_manager.get_node_secret(),
+			keys_manager,
+			entropy_source,
+			node_announcement_info: Mutex::new(None),
+			inbound_shutdown_scriptpubkey: Mutex::new(None),
+			peer_features: Mutex::new(HashMap::new()),
+			monitor_callers: Mutex::new(HashSet::new()),
 
 			pending_events: Mutex::new(Vec::new()),
 		});
@@ -238,30 +390,20 @@ impl ChannelManager {
 	/// FundingBroadcastSafe events to allow tracking of which events correspond with which
 	/// create_channel call. Note that user_channel_id defaults to 0 for inbound channels, so you
 	/// may wish to avoid using 0 for user_id here.
+	/// If shutdown_scriptpubkey is set, we close the channel to it, committing to it via
+	/// upfront_shutdown_script if the peer is connected and supports it, so it must be a P2PKH,
+	/// P2SH, P2WPKH or P2WSH script.
 	/// If successful, will generate a SendOpenChannel event, so you should probably poll
 	/// PeerManager::process_events afterwards.
-	pub fn create_channel(&self, their_network_key: PublicKey, channel_value_satoshis: u64, user_id: u64) -> Result<(), HandleError> {
-		let chan_keys = if cfg!(feature = "fuzztarget") {
-			ChannelKeys {
-				funding_key:               SecretKey::from_slice(&self.secp_ctx, &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap(),
-				revocation_base_key:       SecretKey::from_slice(&self.secp_ctx, &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap(),
-				payment_base_key:          SecretKey::from_slice(&self.secp_ctx, &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap(),
-				delayed_payment_base_key:  SecretKey::from_slice(&self.secp_ctx, &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap(),
-				htlc_base_key:             SecretKey::from_slice(&self.secp_ctx, &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap(),
-				channel_close_key:         SecretKey::from_slice(&self.secp_ctx, &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap(),
-				channel_monitor_claim_key: SecretKey::from_slice(&self.secp_ctx, &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap(),
-				commitment_seed: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-			}
-		} else {
-			let mut key_seed = [0u8; 32];
-			rng::fill_bytes(&mut key_seed);
-			match ChannelKeys::new_from_seed(&key_seed) {
-				Ok(key) => key,
-				Err(_) => panic!("RNG is busted!")
+	pub fn create_channel(&self, their_network_key: PublicKey, channel_value_satoshis: u64, user_id: u64, shutdown_scriptpubkey: Option<Script>) -> Result<(), HandleError> {
+		if let Some(ref script) = shutdown_scriptpubkey {
+			if !Channel::is_standard_shutdown_script(script) {
+				return Err(HandleError{err: "Provided shutdown_scriptpubkey is of a nonstandard form", msg: None});
 			}
-		};
+		}
 
-		let channel = Channel::new_outbound(&*self.fee_estimator, chan_keys, their_network_key, channel_value_satoshis, self.announce_channels_publicly, user_id);
+		let upfront_shutdown_script = self.peer_supports_upfront_shutdown_script(&their_network_key);
+		let channel = Channel::new_outbound(&*self.fee_estimator, &self.keys_manager, &*self.entropy_source, their_network_key, channel_value_satoshis, self.announce_channels_publicly, user_id, shutdown_scriptpubkey, upfront_shutdown_script);
 		let res = channel.get_open_channel(self.genesis_hash.clone(), &*self.fee_estimator)?;
 		let mut channel_state = self.channel_state.lock().unwrap();
 		match channel_state.by_id.insert(channel.channel_id(), channel) {
@@ -289,21 +431,99 @@ impl ChannelManager {
 				remote_network_id: channel.get_their_node_id(),
 				channel_value_satoshis: channel.get_value_satoshis(),
 				user_id: channel.get_user_id(),
+				forwarding_policy: self.get_forwarding_policy(channel),
 			});
 		}
 		res
 	}
 
+	/// Sets the scriptpubkey we close inbound channels opened after this call to, committing to it
+	/// via upfront_shutdown_script if the opening peer supports it. If None, inbound channels close
+	/// to a key from our KeysInterface. The script must be a P2PKH, P2SH, P2WPKH or P2WSH script.
+	pub fn set_inbound_shutdown_scriptpubkey(&self, shutdown_scriptpubkey: Option<Script>) -> Result<(), HandleError> {
+		if let Some(ref script) = shutdown_scriptpubkey {
+			if !Channel::is_standard_shutdown_script(script) {
+				return Err(HandleError{err: "Provided shutdown_scriptpubkey is of a nonstandard form", msg: None});
+			}
+		}
+		*self.inbound_shutdown_scriptpubkey.lock().unwrap() = shutdown_scriptpubkey;
+		Ok(())
+	}
+
+	/// Checks whether the given peer advertised option_upfront_shutdown_script in its init message
+	/// (which we always do). Peers which aren't connected are assumed not to.
+	fn peer_supports_upfront_shutdown_script(&self, their_node_id: &PublicKey) -> bool {
+		match self.peer_features.lock().unwrap().get(their_node_id) {
+			Some(features) => features.supports_upfront_shutdown_script(),
+			None => false,
+		}
+	}
+
+	/// Changes the forwarding policy of the given channel, or of all our channels if channel_id is
+	/// None, broadcasting a new channel_update for each channel which has one. HTLCs which pay
+	/// according to the previous policy continue to be accepted for a short grace period.
+	/// Note that channels opened later still use the defaults passed to ChannelManager::new.
+	pub fn update_forwarding_policy(&self, channel_id: Option<&Uint256>, policy: ChannelForwardingPolicy) -> Result<(), HandleError> {
+		let mut updates = Vec::new();
+		{
+			let mut channel_state = self.channel_state.lock().unwrap();
+			let grace_period_end = Instant::now() + Duration::from_secs(FORWARDING_POLICY_GRACE_PERIOD_SECS);
+			let mut update_channel = |chan: &mut Channel| {
+				let prev_policy = self.get_forwarding_policy(chan);
+				chan.set_forwarding_policy(policy.clone(), prev_policy, grace_period_end);
+				if let Ok(update) = self.get_channel_update(chan) {
+					updates.push(update);
+				}
+			};
+			match channel_id {
+				Some(channel_id) => match channel_state.by_id.get_mut(channel_id) {
+					Some(chan) => update_channel(chan),
+					None => return Err(HandleError{err: "No such channel", msg: None}),
+				},
+				None => {
+					for chan in channel_state.by_id.values_mut() {
+						update_channel(chan);
+					}
+				},
+			}
+		}
+		let mut events = self.pending_events.lock().unwrap();
+		for update in updates.drain(..) {
+			events.push(events::Event::BroadcastChannelUpdate {
+				msg: update
+			});
+		}
+		Ok(())
+	}
+
+	/// Gets the forwarding policy we currently advertise for the given channel
+	fn get_forwarding_policy(&self, chan: &Channel) -> ChannelForwardingPolicy {
+		match chan.get_forwarding_policy() {
+			Some(policy) => policy.clone(),
+			None => ChannelForwardingPolicy {
+				fee_base_msat: chan.get_our_fee_base_msat(&*self.fee_estimator),
+				fee_proportional_millionths: self.fee_proportional_millionths,
+				cltv_expiry_delta: CLTV_EXPIRY_DELTA,
+				htlc_minimum_msat: chan.get_our_htlc_minimum_msat(),
+			},
+		}
+	}
+
 	/// Begins the process of closing a channel. After this call (plus some timeout), no new HTLCs
 	/// will be accepted on the given channel, and after additional timeout/the closing of all
 	/// pending HTLCs, the channel will be closed on chain.
-	pub fn close_channel(&self, channel_id: &Uint256) -> Result<msgs::Shutdown, HandleError> {
+	/// If shutdown_scriptpubkey is set, our funds will be paid to it, though it must match the
+	/// one passed to create_channel, if any.
+	/// If fee_params is set, the closing fee will be negotiated within its bounds, instead of
+	/// purely based on our FeeEstimator. Once the channel has closed, a ChannelClosed event will
+	/// be generated.
+	pub fn close_channel(&self, channel_id: &Uint256, shutdown_scriptpubkey: Option<Script>, fee_params: Option<ClosingFeeParameters>) -> Result<msgs::Shutdown, HandleError> {
 		let (res, chan_option) = {
 			let mut channel_state_lock = self.channel_state.lock().unwrap();
 			let channel_state = channel_state_lock.borrow_parts();
 			match channel_state.by_id.entry(channel_id.clone()) {
 				hash_map::Entry::Occupied(mut chan_entry) => {
-					let res = chan_entry.get_mut().get_shutdown()?;
+					let res = chan_entry.get_mut().get_shutdown(shutdown_scriptpubkey, fee_params)?;
 					if chan_entry.get().is_shutdown() {
 						if let Some(short_id) = chan_entry.get().get_short_channel_id() {
 							channel_state.short_to_id.remove(&short_id);
@@ -319,16 +539,69 @@ impl ChannelManager {
 			self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), &payment_hash, HTLCFailReason::Reason { failure_code: 0x4000 | 10, data: Vec::new() });
 		}
 		if let Some(chan) = chan_option {
-			if let Ok(update) = self.get_channel_update(&chan) {
-				let mut events = self.pending_events.lock().unwrap();
-				events.push(events::Event::BroadcastChannelUpdate {
-					msg: update
-				});
-			}
+			self.finish_unfunded_close_channel(&chan);
 		}
 		Ok(res.0)
 	}
 
+	/// Generates the events for the closure of a channel which has been removed from our
+	/// channel_state upon shutdown before it was funded, and thus has no closing transaction.
+	fn finish_unfunded_close_channel(&self, chan: &Channel) {
+		let mut events = self.pending_events.lock().unwrap();
+		if let Ok(update) = self.get_channel_update(&chan) {
+			events.push(events::Event::BroadcastChannelUpdate {
+				msg: update
+			});
+		}
+		events.push(events::Event::ChannelClosed {
+			channel_id: chan.channel_id(),
+			user_channel_id: chan.get_user_id(),
+			closing_fee_satoshis: None,
+		});
+	}
+
+	/// Force-closes a channel, broadcasting our latest commitment transaction. Useful for channels
+	/// which can't be closed cooperatively, eg because closing fee negotiation failed and
+	/// ClosingFeeFailureAction::KeepOpen was set. A ChannelClosed event will be generated.
+	/// Note that our counterparty isn't told, it will find out once the commitment transaction
+	/// confirms.
+	pub fn force_close_channel(&self, channel_id: &Uint256) -> Result<(), HandleError> {
+		let mut chan = {
+			let mut channel_state_lock = self.channel_state.lock().unwrap();
+			let channel_state = channel_state_lock.borrow_parts();
+			match channel_state.by_id.remove(channel_id) {
+				Some(chan) => {
+					if let Some(short_id) = chan.get_short_channel_id() {
+						channel_state.short_to_id.remove(&short_id);
+					}
+					chan
+				},
+				None => return Err(HandleError{err: "No such channel", msg: None}),
+			}
+		};
+		self.finish_force_close_channel(&mut chan);
+		Ok(())
+	}
+
+	/// Broadcasts the latest commitment transaction of a channel which has been (or is about to
+	/// be) removed from our channel_state, and generates the events for its closure.
+	fn finish_force_close_channel(&self, chan: &mut Channel) {
+		for tx in chan.force_shutdown() {
+			self.tx_broadcaster.broadcast_transaction(&tx);
+		}
+		let mut events = self.pending_events.lock().unwrap();
+		if let Ok(update) = self.get_channel_update(&chan) {
+			events.push(events::Event::BroadcastChannelUpdate {
+				msg: update
+			});
+		}
+		events.push(events::Event::ChannelClosed {
+			channel_id: chan.channel_id(),
+			user_channel_id: chan.get_user_id(),
+			closing_fee_satoshis: None,
+		});
+	}
+
 	#[inline]
 	fn gen_rho_mu_from_shared_secret(shared_secret: &SharedSecret) -> ([u8; 32], [u8; 32]) {
 		({
@@ -418,9 +691,11 @@ impl ChannelManager {
 	}
 
 	/// returns the hop data, as well as the first-hop value_msat and CLTV value we should send.
-	fn build_onion_payloads(route: &Route) -> Result<(Vec<msgs::OnionHopData>, u64, u32), HandleError> {
+	/// starting_htlc_offset is the block height the final hop's CLTV expiry delta is relative to
+	/// (generally the height the HTLC will be included in), as CLTV values are absolute.
+	fn build_onion_payloads(route: &Route, starting_htlc_offset: u32) -> Result<(Vec<msgs::OnionHopData>, u64, u32), HandleError> {
 		let mut cur_value_msat = 0u64;
-		let mut cur_cltv = 0u32;
+		let mut cur_cltv = starting_htlc_offset;
 		let mut last_short_channel_id = 0;
 		let mut res: Vec<msgs::OnionHopData> = Vec::with_capacity(route.hops.len());
 		internal_traits::test_no_dealloc::<msgs::OnionHopData>(None);
@@ -431,7 +706,7 @@ impl ChannelManager {
 			// exactly as it should be (and the next hop isn't trying to probe to find out if we're
 			// the intended recipient).
 			let value_msat = if cur_value_msat == 0 { hop.fee_msat } else { cur_value_msat };
-			let cltv = if cur_cltv == 0 { hop.cltv_expiry_delta } else { cur_cltv };
+			let cltv = if cur_cltv == starting_htlc_offset { hop.cltv_expiry_delta + starting_htlc_offset } else { cur_cltv };
 			res[idx] = msgs::OnionHopData {
 				realm: 0,
 				data: msgs::OnionRealm0HopData {
@@ -573,6 +848,79 @@ impl ChannelManager {
 		ChannelManager::encrypt_failure_packet(shared_secret, &failure_packet.encode()[..])
 	}
 
+	/// Peels the layers of encryption off an error packet we got back for one of our own
+	/// payments, returning the decoded failure if some hop's HMAC checked out.
+	fn process_onion_failure(secp_ctx: &Secp256k1, route: &Route, session_priv: &SecretKey, packet: &msgs::OnionErrorPacket) -> Option<DecodedOnionFailure> {
+		let mut packet_decrypted = packet.data.clone();
+		let mut res = None;
+		let mut hop_idx = 0;
+		Self::construct_onion_keys_callback(secp_ctx, route, session_priv, |shared_secret, _, _, route_hop| {
+			if res.is_some() { return; }
+			let this_hop_idx = hop_idx;
+			hop_idx += 1;
+
+			let ammag = ChannelManager::gen_ammag_from_shared_secret(&shared_secret);
+
+			let mut decryption_tmp = Vec::with_capacity(packet_decrypted.len());
+			decryption_tmp.resize(packet_decrypted.len(), 0);
+			let mut chacha = ChaCha20::new(&ammag, &[0u8; 8]);
+			chacha.process(&packet_decrypted, &mut decryption_tmp[..]);
+			packet_decrypted = decryption_tmp;
+
+			if let Ok(err_packet) = msgs::DecodedOnionErrorPacket::decode(&packet_decrypted) {
+				if err_packet.failuremsg.len() >= 2 {
+					let um = ChannelManager::gen_um_from_shared_secret(&shared_secret);
+
+					let mut hmac = Hmac::new(Sha256::new(), &um);
+					hmac.input(&err_packet.encode()[32..]);
+					let mut calc_tag = [0u8; 32];
+					hmac.raw_result(&mut calc_tag);
+					if crypto::util::fixed_time_eq(&calc_tag, &err_packet.hmac) {
+						let failure_code = msgs::HTLCFailureCode::from_u16(byte_utils::slice_to_be16(&err_packet.failuremsg[0..2]));
+						let failure_data = &err_packet.failuremsg[2..];
+						let rejected_by_dest = this_hop_idx == route.hops.len() - 1;
+						// The erring node is telling us about the channel it would have forwarded
+						// over, unless it was the destination, in which case all we have is the
+						// channel into it.
+						let short_channel_id = if rejected_by_dest { route_hop.short_channel_id } else { route.hops[this_hop_idx + 1].short_channel_id };
+
+						let mut channel_update = None;
+						if failure_code.has_channel_update() {
+							if let Some(chan_update) = failure_code.decode_channel_update(failure_data) {
+								// Don't let a node slip us updates for channels it wasn't asked to
+								// forward over
+								if chan_update.contents.short_channel_id == short_channel_id {
+									channel_update = Some(msgs::HTLCFailChannelUpdate::ChannelUpdateMessage {
+										msg: chan_update,
+									});
+								}
+							}
+						} else {
+							match failure_code {
+								msgs::HTLCFailureCode::PermanentChannelFailure|msgs::HTLCFailureCode::RequiredChannelFeatureMissing|msgs::HTLCFailureCode::UnknownNextPeer if !rejected_by_dest => {
+									// We know this came from the current node as the HMAC validated.
+									channel_update = Some(msgs::HTLCFailChannelUpdate::ChannelClosed {
+										short_channel_id,
+									});
+								},
+								_ => {},
+							}
+						}
+
+						res = Some(DecodedOnionFailure {
+							erring_node: route_hop.pubkey,
+							short_channel_id,
+							failure_code,
+							rejected_by_dest,
+							channel_update,
+						});
+					}
+				}
+			}
+		}).unwrap();
+		res
+	}
+
 	/// only fails if the channel does not yet have an assigned short_id
 	fn get_channel_update(&self, chan: &Channel) -> Result<msgs::ChannelUpdate, HandleError> {
 		let short_channel_id = match chan.get_short_channel_id() {
@@ -581,16 +929,17 @@ impl ChannelManager {
 		};
 
 		let were_node_one = PublicKey::from_secret_key(&self.secp_ctx, &self.our_network_key).unwrap().serialize()[..] < chan.get_their_node_id().serialize()[..];
+		let policy = self.get_forwarding_policy(chan);
 
 		let unsigned = msgs::UnsignedChannelUpdate {
 			chain_hash: self.genesis_hash,
 			short_channel_id: short_channel_id,
-			timestamp: chan.get_channel_update_count(),
+			timestamp: chan.get_channel_update_timestamp(),
 			flags: (!were_node_one) as u16 | ((!chan.is_live() as u16) << 1),
-			cltv_expiry_delta: CLTV_EXPIRY_DELTA,
-			htlc_minimum_msat: chan.get_our_htlc_minimum_msat(),
-			fee_base_msat: chan.get_our_fee_base_msat(&*self.fee_estimator),
-			fee_proportional_millionths: self.fee_proportional_millionths,
+			cltv_expiry_delta: policy.cltv_expiry_delta,
+			htlc_minimum_msat: policy.htlc_minimum_msat,
+			fee_base_msat: policy.fee_base_msat,
+			fee_proportional_millionths: policy.fee_proportional_millionths,
 		};
 
 		let msg_hash = Sha256dHash::from_data(&unsigned.encode()[..]);
@@ -608,6 +957,28 @@ impl ChannelManager {
 	/// See-also docs on Channel::send_htlc_and_commit.
 	/// May generate a SendHTLCs event on success, which should be relayed.
 	pub fn send_payment(&self, route: Route, payment_hash: [u8; 32]) -> Result<(), HandleError> {
+		self.send_payment_internal(route, payment_hash, false)
+	}
+
+	/// Probes a route by sending an HTLC for the route's value with a random payment_hash, which
+	/// the destination cannot know the preimage for. Instead of a PaymentFailed event, the
+	/// resulting failure generates a ProbeSuccessful event if the HTLC made it all the way to the
+	/// destination, or a ProbeFailed event naming the hop which failed it otherwise. The event
+	/// should be passed to Router::handle_probe_result so that future routes avoid the failing
+	/// channel (PaymentTracker does this for the events it reads).
+	/// Returns the payment_hash the eventual event will carry.
+	/// May generate a SendHTLCs event on success, which should be relayed.
+	pub fn send_probe(&self, route: Route) -> Result<[u8; 32], HandleError> {
+		if route.hops.last().map(|hop| hop.pubkey) == Some(self.get_our_node_id()) {
+			return Err(HandleError{err: "Cannot probe a route back to ourselves", msg: None});
+		}
+		let mut payment_hash = [0; 32];
+		self.entropy_source.fill_bytes(&mut payment_hash);
+		self.send_payment_internal(route, payment_hash, true)?;
+		Ok(payment_hash)
+	}
+
+	fn send_payment_internal(&self, route: Route, payment_hash: [u8; 32], is_probe: bool) -> Result<(), HandleError> {
 		if route.hops.len() < 1 || route.hops.len() > 20 {
 			return Err(HandleError{err: "Route didn't go anywhere/had bogus size", msg: None});
 		}
@@ -620,17 +991,18 @@ impl ChannelManager {
 
 		let session_priv = secp_call!(SecretKey::from_slice(&self.secp_ctx, &{
 			let mut session_key = [0; 32];
-			rng::fill_bytes(&mut session_key);
+			self.entropy_source.fill_bytes(&mut session_key);
 			session_key
 		}));
 
 		let associated_data = Vec::new(); //TODO: What to put here?
 
 		let onion_keys = ChannelManager::construct_onion_keys(&self.secp_ctx, &route, &session_priv)?;
-		let (onion_payloads, htlc_msat, htlc_cltv) = ChannelManager::build_onion_payloads(&route)?;
+		let cur_height = self.latest_block_height.load(Ordering::Acquire) as u32 + 1;
+		let (onion_payloads, htlc_msat, htlc_cltv) = ChannelManager::build_onion_payloads(&route, cur_height)?;
 		let onion_packet = ChannelManager::construct_onion_packet(onion_payloads, onion_keys, associated_data)?;
 
-		let (first_hop_node_id, (update_add, commitment_signed, chan_monitor)) = {
+		let (first_hop_node_id, update_add, commitment_signed) = {
 			let mut channel_state = self.channel_state.lock().unwrap();
 			let id = match channel_state.short_to_id.get(&route.hops.first().unwrap().short_channel_id) {
 				None => return Err(HandleError{err: "No channel available with first hop!", msg: None}),
@@ -641,7 +1013,32 @@ impl ChannelManager {
 				if chan.get_their_node_id() != route.hops.first().unwrap().pubkey {
 					return Err(HandleError{err: "Node ID mismatch on first hop!", msg: None});
 				}
-				chan.send_htlc_and_commit(htlc_msat, payment_hash.clone(), htlc_cltv, onion_packet)?
+				match chan.send_htlc_and_commit(htlc_msat, payment_hash.clone(), htlc_cltv, onion_packet)? {
+					Some((update_add, commitment_signed, monitor_update)) => {
+						let their_node_id = chan.get_their_node_id();
+						match self.update_channel_monitor(chan, monitor_update) {
+							Ok(true) => Some((update_add, commitment_signed)),
+							Ok(false) => {
+								chan.hold_monitor_pending(None, Some(msgs::CommitmentUpdate {
+									update_add_htlcs: vec![update_add],
+									update_fulfill_htlcs: Vec::new(),
+									update_fail_htlcs: Vec::new(),
+									commitment_signed: commitment_signed,
+								}), Vec::new(), Vec::new(), false);
+								None
+							},
+							Err(msg) => {
+								remove_channel!(channel_state, &id);
+								self.pending_events.lock().unwrap().push(events::Event::SendErrorMessage {
+									node_id: their_node_id,
+									msg: msg,
+								});
+								return Err(HandleError{err: MONITOR_PERMANENT_FAILURE_ERR, msg: None});
+							},
+						}
+					},
+					None => None,
+				}
 			};
 
 			let first_hop_node_id = route.hops.first().unwrap().pubkey;
@@ -649,6 +1046,7 @@ impl ChannelManager {
 			if channel_state.claimable_htlcs.insert(payment_hash, PendingOutboundHTLC::OutboundRoute {
 				route,
 				session_priv,
+				is_probe,
 			}).is_some() {
 				// TODO: We need to track these better, we're not generating these, so a
 				// third-party might make this happen:
@@ -656,15 +1054,11 @@ impl ChannelManager {
 			}
 
 			match res {
-				Some(msgs) => (first_hop_node_id, msgs),
+				Some((update_add, commitment_signed)) => (first_hop_node_id, update_add, commitment_signed),
 				None => return Ok(()),
 			}
 		};
 
-		if let Err(_e) = self.monitor.add_update_monitor(chan_monitor.get_funding_txo().unwrap(), chan_monitor) {
-			unimplemented!(); // maybe remove from claimable_htlcs?
-		}
-
 		let mut events = self.pending_events.lock().unwrap();
 		events.push(events::Event::SendHTLCs {
 			node_id: first_hop_node_id,
@@ -677,7 +1071,7 @@ impl ChannelManager {
 	/// Call this upon creation of a funding transaction for the given channel.
 	/// Panics if a funding transaction has already been provided for this channel.
 	pub fn funding_transaction_generated(&self, temporary_channel_id: &Uint256, funding_txo: OutPoint) {
-		let (chan, msg, chan_monitor) = {
+		let (mut chan, msg, chan_monitor) = {
 			let mut channel_state = self.channel_state.lock().unwrap();
 			match channel_state.by_id.remove(&temporary_channel_id) {
 				Some(mut chan) => {
@@ -694,8 +1088,17 @@ impl ChannelManager {
 				None => return
 			}
 		}; // Release channel lock for install_watch_outpoint call,
-		if let Err(_e) = self.monitor.add_update_monitor(chan_monitor.get_funding_txo().unwrap(), chan_monitor) {
-			unimplemented!(); // maybe remove from claimable_htlcs?
+		// Note that funding_created doesn't rely on our ChannelMonitor having been persisted as our
+		// counterparty can't do anything with it until we broadcast the funding transaction, which
+		// we only allow once the monitor has been persisted (see handle_funding_signed).
+		if let Err(msg) = self.add_channel_monitor(&mut chan, chan_monitor) {
+			// The channel is already closed, so tell our counterparty instead of continuing
+			let mut pending_events = self.pending_events.lock().unwrap();
+			pending_events.push(events::Event::SendErrorMessage {
+				node_id: chan.get_their_node_id(),
+				msg: msg,
+			});
+			return;
 		}
 		{
 			let mut pending_events = self.pending_events.lock().unwrap();
@@ -755,7 +1158,7 @@ impl ChannelManager {
 						match forward_chan.send_htlc(forward_info.amt_to_forward, forward_info.payment_hash, forward_info.outgoing_cltv_value, forward_info.onion_packet.unwrap()) {
 							Err(_e) => {
 								let chan_update = self.get_channel_update(forward_chan).unwrap();
-								failed_forwards.push((forward_info.payment_hash, 0x4000 | 7, Some(chan_update)));
+								failed_forwards.push((forward_info.payment_hash, 0x1000 | 7, Some(chan_update)));
 								continue;
 							},
 							Ok(update_add) => {
@@ -776,25 +1179,50 @@ impl ChannelManager {
 					}
 
 					if !add_htlc_msgs.is_empty() {
-						let (commitment_msg, monitor) = match forward_chan.send_commitment() {
+						let (commitment_msg, monitor_update) = match forward_chan.send_commitment() {
 							Ok(res) => res,
 							Err(_) => {
 								//TODO: Handle...this is bad!
 								continue;
 							},
 						};
-						new_events.push((Some(monitor), events::Event::SendHTLCs {
-							node_id: forward_chan.get_their_node_id(),
-							msgs: add_htlc_msgs,
-							commitment_msg: commitment_msg,
-						}));
+						let their_node_id = forward_chan.get_their_node_id();
+						match self.update_channel_monitor(forward_chan, monitor_update) {
+							Ok(true) => {
+								new_events.push(events::Event::SendHTLCs {
+									node_id: their_node_id,
+									msgs: add_htlc_msgs,
+									commitment_msg: commitment_msg,
+								});
+							},
+							Ok(false) => {
+								forward_chan.hold_monitor_pending(None, Some(msgs::CommitmentUpdate {
+									update_add_htlcs: add_htlc_msgs,
+									update_fulfill_htlcs: Vec::new(),
+									update_fail_htlcs: Vec::new(),
+									commitment_signed: commitment_msg,
+								}), Vec::new(), Vec::new(), false);
+							},
+							Err(msg) => {
+								// The channel is closed and our counterparty never saw these HTLCs,
+								// so fail them backwards.
+								remove_channel!(channel_state, &forward_chan_id);
+								for update_add in add_htlc_msgs {
+									failed_forwards.push((update_add.payment_hash, 0x4000 | 8, None));
+								}
+								new_events.push(events::Event::SendErrorMessage {
+									node_id: their_node_id,
+									msg: msg,
+								});
+							},
+						}
 					}
 				} else {
 					for forward_info in pending_forwards {
-						new_events.push((None, events::Event::PaymentReceived {
+						new_events.push(events::Event::PaymentReceived {
 							payment_hash: forward_info.payment_hash,
 							amt: forward_info.amt_to_forward,
-						}));
+						});
 					}
 				}
 			}
@@ -809,20 +1237,8 @@ impl ChannelManager {
 
 		if new_events.is_empty() { return }
 
-		new_events.retain(|event| {
-			if let &Some(ref monitor) = &event.0 {
-				if let Err(_e) = self.monitor.add_update_monitor(monitor.get_funding_txo().unwrap(), monitor.clone()) {
-					unimplemented!();// but def dont push the event...
-				}
-			}
-			true
-		});
-
 		let mut events = self.pending_events.lock().unwrap();
-		events.reserve(new_events.len());
-		for event in new_events.drain(..) {
-			events.push(event.1);
-		}
+		events.append(&mut new_events);
 	}
 
 	/// Indicates that the preimage for payment_hash is unknown after a PaymentReceived event.
@@ -843,6 +1259,7 @@ impl ChannelManager {
 				channel_state.claimable_htlcs.insert(payment_hash.clone(), PendingOutboundHTLC::OutboundRoute {
 					route,
 					session_priv,
+					is_probe: false,
 				});
 				pending_htlc = PendingOutboundHTLC::IntermediaryHopData { source_short_channel_id, incoming_packet_shared_secret };
 			},
@@ -851,13 +1268,48 @@ impl ChannelManager {
 
 		match pending_htlc {
 			PendingOutboundHTLC::CycledRoute { .. } => { panic!("WAT"); },
-			PendingOutboundHTLC::OutboundRoute { .. } => {
+			PendingOutboundHTLC::OutboundRoute { route, session_priv, is_probe } => {
 				mem::drop(channel_state);
 
+				let (erring_node, short_channel_id, failure_code, rejected_by_dest) = match onion_error {
+					HTLCFailReason::ErrorPacket { err } => {
+						match Self::process_onion_failure(&self.secp_ctx, &route, &session_priv, &err) {
+							Some(failure) => (Some(failure.erring_node), Some(failure.short_channel_id), Some(failure.failure_code), failure.rejected_by_dest),
+							None => (None, None, None, false),
+						}
+					},
+					HTLCFailReason::Reason { failure_code, .. } => {
+						// A bad onion can only have been reported by our direct peer via
+						// update_fail_malformed_htlc, anything else we generated ourselves.
+						let failure_code = msgs::HTLCFailureCode::from_u16(failure_code);
+						let erring_node = if failure_code.is_bad_onion() { route.hops[0].pubkey } else { self.get_our_node_id() };
+						(Some(erring_node), Some(route.hops[0].short_channel_id), Some(failure_code), false)
+					},
+				};
+
 				let mut pending_events = self.pending_events.lock().unwrap();
-				pending_events.push(events::Event::PaymentFailed {
-					payment_hash: payment_hash.clone()
-				});
+				if is_probe && rejected_by_dest {
+					pending_events.push(events::Event::ProbeSuccessful {
+						payment_hash: payment_hash.clone(),
+						route,
+					});
+				} else if is_probe {
+					pending_events.push(events::Event::ProbeFailed {
+						payment_hash: payment_hash.clone(),
+						route,
+						erring_node,
+						short_channel_id,
+						failure_code,
+					});
+				} else {
+					pending_events.push(events::Event::PaymentFailed {
+						payment_hash: payment_hash.clone(),
+						rejected_by_dest,
+						erring_node,
+						short_channel_id,
+						failure_code,
+					});
+				}
 				false
 			},
 			PendingOutboundHTLC::IntermediaryHopData { source_short_channel_id, incoming_packet_shared_secret } => {
@@ -879,7 +1331,31 @@ impl ChannelManager {
 
 					let chan = channel_state.by_id.get_mut(&chan_id).unwrap();
 					match chan.get_update_fail_htlc_and_commit(payment_hash, err_packet) {
-						Ok(msg) => (chan.get_their_node_id(), msg),
+						Ok(Some((msg, commitment_msg, monitor_update))) => {
+							let their_node_id = chan.get_their_node_id();
+							match self.update_channel_monitor(chan, monitor_update) {
+								Ok(true) => (their_node_id, Some((msg, commitment_msg))),
+								Ok(false) => {
+									chan.hold_monitor_pending(None, Some(msgs::CommitmentUpdate {
+										update_add_htlcs: Vec::new(),
+										update_fulfill_htlcs: Vec::new(),
+										update_fail_htlcs: vec![msg],
+										commitment_signed: commitment_msg,
+									}), Vec::new(), Vec::new(), false);
+									(their_node_id, None)
+								},
+								Err(msg) => {
+									remove_channel!(channel_state, &chan_id);
+									mem::drop(channel_state);
+									self.pending_events.lock().unwrap().push(events::Event::SendErrorMessage {
+										node_id: their_node_id,
+										msg: msg,
+									});
+									return false;
+								},
+							}
+						},
+						Ok(None) => (chan.get_their_node_id(), None),
 						Err(_e) => {
 							//TODO: Do something with e?
 							return false;
@@ -888,13 +1364,9 @@ impl ChannelManager {
 				};
 
 				match fail_msgs {
-					Some((msg, commitment_msg, chan_monitor)) => {
+					Some((msg, commitment_msg)) => {
 						mem::drop(channel_state);
 
-						if let Err(_e) = self.monitor.add_update_monitor(chan_monitor.get_funding_txo().unwrap(), chan_monitor) {
-							unimplemented!();// but def dont push the event...
-						}
-
 						let mut pending_events = self.pending_events.lock().unwrap();
 						pending_events.push(events::Event::SendFailHTLC {
 							node_id,
@@ -935,10 +1407,10 @@ impl ChannelManager {
 			PendingOutboundHTLC::CycledRoute { source_short_channel_id, incoming_packet_shared_secret, route, session_priv } => {
 				if from_user { // This was the end hop back to us
 					pending_htlc = PendingOutboundHTLC::IntermediaryHopData { source_short_channel_id, incoming_packet_shared_secret };
-					channel_state.claimable_htlcs.insert(payment_hash, PendingOutboundHTLC::OutboundRoute { route, session_priv });
+					channel_state.claimable_htlcs.insert(payment_hash, PendingOutboundHTLC::OutboundRoute { route, session_priv, is_probe: false });
 				} else { // This came from the first upstream node
 					// Bank error in our favor! Maybe we should tell the user this somehow???
-					pending_htlc = PendingOutboundHTLC::OutboundRoute { route, session_priv };
+					pending_htlc = PendingOutboundHTLC::OutboundRoute { route, session_priv, is_probe: false };
 					channel_state.claimable_htlcs.insert(payment_hash, PendingOutboundHTLC::IntermediaryHopData { source_short_channel_id, incoming_packet_shared_secret });
 				}
 			},
@@ -967,7 +1439,31 @@ impl ChannelManager {
 
 					let chan = channel_state.by_id.get_mut(&chan_id).unwrap();
 					match chan.get_update_fulfill_htlc_and_commit(payment_preimage) {
-						Ok(msg) => (chan.get_their_node_id(), msg),
+						Ok(Some((msg, commitment_msg, monitor_update))) => {
+							let their_node_id = chan.get_their_node_id();
+							match self.update_channel_monitor(chan, monitor_update) {
+								Ok(true) => (their_node_id, Some((msg, commitment_msg))),
+								Ok(false) => {
+									chan.hold_monitor_pending(None, Some(msgs::CommitmentUpdate {
+										update_add_htlcs: Vec::new(),
+										update_fulfill_htlcs: vec![msg],
+										update_fail_htlcs: Vec::new(),
+										commitment_signed: commitment_msg,
+									}), Vec::new(), Vec::new(), false);
+									(their_node_id, None)
+								},
+								Err(msg) => {
+									remove_channel!(channel_state, &chan_id);
+									mem::drop(channel_state);
+									self.pending_events.lock().unwrap().push(events::Event::SendErrorMessage {
+										node_id: their_node_id,
+										msg: msg,
+									});
+									return false;
+								},
+							}
+						},
+						Ok(None) => (chan.get_their_node_id(), None),
 						Err(_e) => {
 							//TODO: Do something with e?
 							return false;
@@ -977,11 +1473,7 @@ impl ChannelManager {
 
 				mem::drop(channel_state);
 				match fulfill_msgs {
-					Some((msg, commitment_msg, chan_monitor)) => {
-						if let Err(_e) = self.monitor.add_update_monitor(chan_monitor.get_funding_txo().unwrap(), chan_monitor) {
-							unimplemented!();// but def dont push the event...
-						}
-
+					Some((msg, commitment_msg)) => {
 						let mut pending_events = self.pending_events.lock().unwrap();
 						pending_events.push(events::Event::SendFulfillHTLC {
 							node_id: node_id,
@@ -1001,133 +1493,400 @@ impl ChannelManager {
 		PublicKey::from_secret_key(&self.secp_ctx, &self.our_network_key).unwrap()
 	}
 
-	/// Used to restore channels to normal operation after a
-	/// ChannelMonitorUpdateErr::TemporaryFailure was returned from a channel monitor update
-	/// operation.
-	pub fn test_restore_channel_monitor(&self) {
-		unimplemented!();
-	}
-}
+	/// Sets the alias, color and addresses we announce for our node, broadcasting a new
+	/// node_announcement with them if we have a public channel (otherwise it is broadcast along
+	/// with the announcement of our first public channel). Our node_announcement is re-broadcast
+	/// every NODE_ANNOUNCEMENT_INTERVAL_BLOCKS blocks thereafter, so this only needs to be called
+	/// again if any of the fields change.
+	/// Addresses are announced in the order BOLT 7 requires (by type), and fails (leaving our
+	/// announcement unchanged) if more than one address of the same type is given.
+	pub fn broadcast_node_announcement(&self, rgb: [u8; 3], alias: [u8; 32], mut addresses: Vec<msgs::NetAddress>) -> Result<(), HandleError> {
+		addresses.sort_by_key(|addr| addr.get_id());
+		for pair in addresses.windows(2) {
+			if pair[0].get_id() == pair[1].get_id() {
+				return Err(HandleError{err: "Only one address of each type may be announced", msg: None});
+			}
+		}
 
-impl events::EventsProvider for ChannelManager {
-	fn get_and_clear_pending_events(&self) -> Vec<events::Event> {
-		let mut pending_events = self.pending_events.lock().unwrap();
-		let mut ret = Vec::new();
-		mem::swap(&mut ret, &mut *pending_events);
-		ret
+		let mut info_lock = self.node_announcement_info.lock().unwrap();
+		let last_timestamp = match *info_lock {
+			Some(ref info) => info.last_timestamp,
+			None => 0,
+		};
+		*info_lock = Some(NodeAnnouncementInfo { rgb, alias, addresses, last_timestamp });
+		if self.have_public_channel() {
+			let announcement = self.get_node_announcement(info_lock.as_mut().unwrap());
+			self.pending_events.lock().unwrap().push(events::Event::BroadcastNodeAnnouncement { msg: announcement });
+		}
+		Ok(())
 	}
-}
 
-impl ChainListener for ChannelManager {
-	fn block_connected(&self, header: &BlockHeader, height: u32, txn_matched: &[&Transaction], indexes_of_txn_matched: &[u32]) {
-		let mut new_events = Vec::new();
-		{
-			let mut channel_state = self.channel_state.lock().unwrap();
-			let mut short_to_ids_to_insert = Vec::new();
-			let mut short_to_ids_to_remove = Vec::new();
-			channel_state.by_id.retain(|_, channel| {
-				if let Some(funding_locked) = channel.block_connected(header, height, txn_matched, indexes_of_txn_matched) {
-					let announcement_sigs = match self.get_announcement_sigs(channel) {
-						Ok(res) => res,
-						Err(_e) => {
-							//TODO: push e on events and blow up the channel (it has bad keys)
-							return true;
-						}
-					};
-					new_events.push(events::Event::SendFundingLocked {
-						node_id: channel.get_their_node_id(),
-						msg: funding_locked,
-						announcement_sigs: announcement_sigs
-					});
-					short_to_ids_to_insert.push((channel.get_short_channel_id().unwrap(), channel.channel_id()));
-				}
-				if let Some(funding_txo) = channel.get_funding_txo() {
-					for tx in txn_matched {
-						for inp in tx.input.iter() {
-							if inp.prev_hash == funding_txo.txid && inp.prev_index == funding_txo.index as u32 {
-								if let Some(short_id) = channel.get_short_channel_id() {
-									short_to_ids_to_remove.push(short_id);
-								}
-								channel.force_shutdown();
-								if let Ok(update) = self.get_channel_update(&channel) {
-									new_events.push(events::Event::BroadcastChannelUpdate {
-										msg: update
-									});
-								}
-								return false;
-							}
-						}
-					}
-				}
-				if channel.channel_monitor().would_broadcast_at_height(height) {
-					if let Some(short_id) = channel.get_short_channel_id() {
-						short_to_ids_to_remove.push(short_id);
-					}
-					channel.force_shutdown();
-					if let Ok(update) = self.get_channel_update(&channel) {
-						new_events.push(events::Event::BroadcastChannelUpdate {
-							msg: update
-						});
-					}
-					return false;
-				}
-				true
-			});
-			for to_remove in short_to_ids_to_remove {
-				channel_state.short_to_id.remove(&to_remove);
-			}
-			for to_insert in short_to_ids_to_insert {
-				channel_state.short_to_id.insert(to_insert.0, to_insert.1);
-			}
+	/// Generates a freshly-signed node_announcement, with a timestamp later than any before it
+	fn get_node_announcement(&self, info: &mut NodeAnnouncementInfo) -> msgs::NodeAnnouncement {
+		let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+			Ok(time) => time.as_secs() as u32,
+			Err(_) => 0,
+		};
+		info.last_timestamp = cmp::max(now, info.last_timestamp + 1);
+
+		let unsigned = msgs::UnsignedNodeAnnouncement {
+			features: msgs::GlobalFeatures::new(),
+			timestamp: info.last_timestamp,
+			node_id: self.get_our_node_id(),
+			rgb: info.rgb,
+			alias: info.alias,
+			addresses: info.addresses.clone(),
+		};
+
+		let msg_hash = Sha256dHash::from_data(&unsigned.encode()[..]);
+		let sig = self.secp_ctx.sign(&Message::from_slice(&msg_hash[..]).unwrap(), &self.our_network_key).unwrap(); //TODO Can we unwrap here?
+
+		msgs::NodeAnnouncement {
+			signature: sig,
+			contents: unsigned
 		}
-		let mut pending_events = self.pending_events.lock().unwrap();
-		for funding_locked in new_events.drain(..) {
-			pending_events.push(funding_locked);
+	}
+
+	/// Gets a new node_announcement to broadcast, if we have one and a public channel to go with it
+	fn get_node_announcement_if_public(&self) -> Option<msgs::NodeAnnouncement> {
+		let mut info_lock = self.node_announcement_info.lock().unwrap();
+		match *info_lock {
+			Some(ref mut info) if self.have_public_channel() => Some(self.get_node_announcement(info)),
+			_ => None,
 		}
 	}
 
-	fn block_disconnected(&self, header: &BlockHeader) {
+	fn have_public_channel(&self) -> bool {
+		let channel_state = self.channel_state.lock().unwrap();
+		channel_state.by_id.values().any(|chan| chan.should_announce() && chan.is_usable())
+	}
+
+	/// Adds the onions recorded by a ChannelMonitor (see ChannelMonitor::get_onion_replay_log) to
+	/// the log we use to reject replayed onions. This must be called with each ChannelMonitor when
+	/// restarting, or a peer may replay an onion across the restart.
+	/// Entries which have already expired are dropped the next time a block is connected.
+	pub fn load_onion_replay_log(&self, monitor: &ChannelMonitor) {
 		let mut channel_state = self.channel_state.lock().unwrap();
-		for channel in channel_state.by_id.values_mut() {
-			if channel.block_disconnected(header) {
-				//TODO Close channel here
-			}
+		for (secret_hash, cltv_expiry) in monitor.get_onion_replay_log() {
+			let existing = channel_state.onion_replay_log.entry(secret_hash).or_insert(cltv_expiry);
+			*existing = cmp::max(*existing, cltv_expiry);
 		}
 	}
-}
 
-impl ChannelMessageHandler for ChannelManager {
-	//TODO: Handle errors and close channel (or so)
-	fn handle_open_channel(&self, their_node_id: &PublicKey, msg: &msgs::OpenChannel) -> Result<msgs::AcceptChannel, HandleError> {
+	/// Calls into our ManyChannelMonitor, noting that this thread is doing so, so that
+	/// update_completed can catch being called from within the monitor (which would deadlock, as
+	/// we generally hold channel_state while updating monitors).
+	fn call_monitor<F: FnOnce() -> Result<(), ChannelMonitorUpdateErr>>(&self, monitor_call: F) -> Result<(), ChannelMonitorUpdateErr> {
+		let thread_id = thread::current().id();
+		self.monitor_callers.lock().unwrap().insert(thread_id);
+		let res = monitor_call();
+		self.monitor_callers.lock().unwrap().remove(&thread_id);
+		res
+	}
+
+	/// Hands the given ChannelMonitorUpdate for chan to our ManyChannelMonitor, freezing the
+	/// channel if it is still in progress. Returns true if the messages which rely on the update
+	/// may be sent immediately, or false if the channel is waiting on a ChannelMonitorUpdate to
+	/// complete, in which case they must be handed to Channel::hold_monitor_pending instead.
+	/// If the update failed permanently the channel is force-closed (see
+	/// force_close_on_monitor_failure) and the error message for our counterparty is returned.
+	/// The caller must then remove chan from channel_state and make sure the message is sent.
+	fn update_channel_monitor(&self, chan: &mut Channel, update: ChannelMonitorUpdate) -> Result<bool, msgs::ErrorMessage> {
+		let update_id = update.update_id;
+		let funding_txo = chan.get_funding_txo().unwrap();
+		match self.call_monitor(|| self.monitor.update_monitor(funding_txo, update)) {
+			Ok(()) => {},
+			Err(ChannelMonitorUpdateErr::InProgress) => chan.monitor_update_in_progress(update_id),
+			Err(ChannelMonitorUpdateErr::PermanentFailure) => return Err(self.force_close_on_monitor_failure(chan)),
+		}
+		Ok(!chan.is_awaiting_monitor_update())
+	}
+
+	/// Hands a newly-funded channel's initial ChannelMonitor to our ManyChannelMonitor, freezing
+	/// the channel if it is still being persisted. Fails as update_channel_monitor does.
+	fn add_channel_monitor(&self, chan: &mut Channel, monitor: ChannelMonitor) -> Result<(), msgs::ErrorMessage> {
+		let funding_txo = monitor.get_funding_txo().unwrap();
+		match self.call_monitor(|| self.monitor.add_monitor(funding_txo, monitor)) {
+			Ok(()) => {},
+			Err(ChannelMonitorUpdateErr::InProgress) => chan.monitor_update_in_progress(0),
+			Err(ChannelMonitorUpdateErr::PermanentFailure) => return Err(self.force_close_on_monitor_failure(chan)),
+		}
+		Ok(())
+	}
+
+	/// Force-closes a channel whose ChannelMonitor can no longer be updated, broadcasting our
+	/// latest local commitment transaction, and gets the error message to send our counterparty.
+	fn force_close_on_monitor_failure(&self, chan: &mut Channel) -> msgs::ErrorMessage {
+		self.finish_force_close_channel(chan);
+		msgs::ErrorMessage {
+			channel_id: chan.channel_id(),
+			data: MONITOR_PERMANENT_FAILURE_ERR.to_string(),
+		}
+	}
+
+	fn forward_htlcs(&self, mut pending_forwards: Vec<PendingForwardHTLCInfo>) {
+		let mut forward_event = None;
+		if !pending_forwards.is_empty() {
+			let mut channel_state = self.channel_state.lock().unwrap();
+			if channel_state.forward_htlcs.is_empty() {
+				forward_event = Some(Instant::now() + Duration::from_millis(((self.entropy_source.rand_f32() * 4.0 + 1.0) * MIN_HTLC_RELAY_HOLDING_CELL_MILLIS as f32) as u64));
+				channel_state.next_forward = forward_event.unwrap();
+			}
+			for forward_info in pending_forwards.drain(..) {
+				match channel_state.forward_htlcs.entry(forward_info.short_channel_id) {
+					hash_map::Entry::Occupied(mut entry) => {
+						entry.get_mut().push(forward_info);
+					},
+					hash_map::Entry::Vacant(entry) => {
+						entry.insert(vec!(forward_info));
+					}
+				}
+			}
+		}
+		match forward_event {
+			Some(time) => {
+				let mut pending_events = self.pending_events.lock().unwrap();
+				pending_events.push(events::Event::PendingHTLCsForwardable {
+					time_forwardable: time
+				});
+			}
+			None => {},
+		}
+	}
+
+	/// Indicates that the ManyChannelMonitor has finished persisting all ChannelMonitorUpdates for
+	/// the channel with the given funding outpoint up to and including update_id (use 0 for the
+	/// initial ChannelMonitor handed to add_monitor), after it returned
+	/// ChannelMonitorUpdateErr::InProgress for one or more of them.
+	/// Once every update which was in progress has completed, the channel is unfrozen: any
+	/// revoke_and_ack/commitment_signed messages we held back are sent (in the order they were
+	/// generated), HTLCs we held back are forwarded or failed backwards, and any HTLC updates
+	/// which were queued up in the mean time are sent.
+	/// This must not be called from within ManyChannelMonitor::add_monitor/update_monitor (as
+	/// ChannelManager generally holds its internal locks while calling them), and will panic if it
+	/// is. Instead, return InProgress and call this once the add_monitor/update_monitor call has
+	/// returned.
+	pub fn update_completed(&self, funding_txo: &OutPoint, update_id: u64) {
+		assert!(!self.monitor_callers.lock().unwrap().contains(&thread::current().id()), "update_completed must not be called from within ManyChannelMonitor::add_monitor/update_monitor");
+		let mut new_events = Vec::new();
+		let (forwards, failures) = {
+			let mut channel_state = self.channel_state.lock().unwrap();
+			// Channels are keyed by the channel_id derived from their funding outpoint as soon as
+			// it is set, which is also the point at which their first monitor is added.
+			let chan = match channel_state.by_id.get_mut(&funding_txo.to_channel_id()) {
+				Some(chan) => {
+					if chan.get_funding_txo() != Some(*funding_txo) {
+						return;
+					}
+					chan
+				},
+				None => return,
+			};
+			let (revoke_and_ack, commitment_update, order, forwards, failures, funding_broadcast_safe) = match chan.monitor_update_completed(update_id) {
+				Some(res) => res,
+				None => return,
+			};
+			let node_id = chan.get_their_node_id();
+
+			if funding_broadcast_safe {
+				new_events.push(events::Event::FundingBroadcastSafe {
+					funding_txo: *funding_txo,
+					user_channel_id: chan.get_user_id(),
+				});
+			}
+			macro_rules! push_revoke_and_ack {
+				() => {
+					if let Some(msg) = revoke_and_ack {
+						new_events.push(events::Event::SendRevokeAndACK {
+							node_id: node_id,
+							msg: msg,
+						});
+					}
+				}
+			}
+			macro_rules! push_commitment_update {
+				($update: expr) => {
+					if let Some(updates) = $update {
+						new_events.push(events::Event::SendCommitmentUpdate {
+							node_id: node_id,
+							updates: updates,
+						});
+					}
+				}
+			}
+			match order {
+				RAACommitmentOrder::RevokeAndACKFirst => {
+					push_revoke_and_ack!();
+					push_commitment_update!(commitment_update);
+				},
+				RAACommitmentOrder::CommitmentFirst => {
+					push_commitment_update!(commitment_update);
+					push_revoke_and_ack!();
+				},
+			}
+
+			match chan.free_holding_cell_after_monitor_update() {
+				Ok(Some((commitment_update, monitor_update))) => {
+					match self.update_channel_monitor(chan, monitor_update) {
+						Ok(true) => push_commitment_update!(Some(commitment_update)),
+						Ok(false) => chan.hold_monitor_pending(None, Some(commitment_update), Vec::new(), Vec::new(), false),
+						Err(msg) => {
+							remove_channel!(channel_state, &msg.channel_id);
+							new_events.push(events::Event::SendErrorMessage {
+								node_id: node_id,
+								msg: msg,
+							});
+						},
+					}
+				},
+				Ok(None) => {},
+				Err(_e) => {
+					//TODO: Do something with e?
+				},
+			}
+			(forwards, failures)
+		};
+
+		{
+			let mut pending_events = self.pending_events.lock().unwrap();
+			pending_events.append(&mut new_events);
+		}
+		for failure in failures {
+			self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), &failure.0, failure.1);
+		}
+		self.forward_htlcs(forwards);
+	}
+}
+
+impl events::EventsProvider for ChannelManager {
+	fn get_and_clear_pending_events(&self) -> Vec<events::Event> {
+		let mut pending_events = self.pending_events.lock().unwrap();
+		let mut ret = Vec::new();
+		mem::swap(&mut ret, &mut *pending_events);
+		ret
+	}
+}
+
+impl ChainListener for ChannelManager {
+	fn block_connected(&self, header: &BlockHeader, height: u32, txn_matched: &[&Transaction], indexes_of_txn_matched: &[u32]) {
+		let mut new_events = Vec::new();
+		{
+			let mut channel_state = self.channel_state.lock().unwrap();
+			let mut short_to_ids_to_insert = Vec::new();
+			let mut short_to_ids_to_remove = Vec::new();
+			channel_state.by_id.retain(|_, channel| {
+				if let Some(funding_locked) = channel.block_connected(header, height, txn_matched, indexes_of_txn_matched) {
+					let announcement_sigs = match self.get_announcement_sigs(channel) {
+						Ok(res) => res,
+						Err(_e) => {
+							//TODO: push e on events and blow up the channel (it has bad keys)
+							return true;
+						}
+					};
+					new_events.push(events::Event::SendFundingLocked {
+						node_id: channel.get_their_node_id(),
+						msg: funding_locked,
+						announcement_sigs: announcement_sigs
+					});
+					short_to_ids_to_insert.push((channel.get_short_channel_id().unwrap(), channel.channel_id()));
+				}
+				if let Some(funding_txo) = channel.get_funding_txo() {
+					for tx in txn_matched {
+						for inp in tx.input.iter() {
+							if inp.prev_hash == funding_txo.txid && inp.prev_index == funding_txo.index as u32 {
+								if let Some(short_id) = channel.get_short_channel_id() {
+									short_to_ids_to_remove.push(short_id);
+								}
+								channel.force_shutdown();
+								if let Ok(update) = self.get_channel_update(&channel) {
+									new_events.push(events::Event::BroadcastChannelUpdate {
+										msg: update
+									});
+								}
+								return false;
+							}
+						}
+					}
+				}
+				if channel.channel_monitor().would_broadcast_at_height(height) {
+					if let Some(short_id) = channel.get_short_channel_id() {
+						short_to_ids_to_remove.push(short_id);
+					}
+					channel.force_shutdown();
+					if let Ok(update) = self.get_channel_update(&channel) {
+						new_events.push(events::Event::BroadcastChannelUpdate {
+							msg: update
+						});
+					}
+					return false;
+				}
+				true
+			});
+			for to_remove in short_to_ids_to_remove {
+				channel_state.short_to_id.remove(&to_remove);
+			}
+			for to_insert in short_to_ids_to_insert {
+				channel_state.short_to_id.insert(to_insert.0, to_insert.1);
+			}
+			// Once an HTLC has expired a replay of its onion will be rejected as expired, so we no
+			// longer need to remember it.
+			channel_state.onion_replay_log.retain(|_, cltv_expiry| *cltv_expiry > height);
+			if height % CHANNEL_UPDATE_REFRESH_INTERVAL_BLOCKS == 0 {
+				for channel in channel_state.by_id.values_mut() {
+					if channel.should_announce() && channel.is_usable() {
+						channel.bump_channel_update_timestamp();
+						if let Ok(update) = self.get_channel_update(channel) {
+							new_events.push(events::Event::BroadcastChannelUpdate {
+								msg: update
+							});
+						}
+					}
+				}
+			}
+		}
+		self.latest_block_height.store(height as usize, Ordering::Release);
+		if height % NODE_ANNOUNCEMENT_INTERVAL_BLOCKS == 0 {
+			if let Some(announcement) = self.get_node_announcement_if_public() {
+				new_events.push(events::Event::BroadcastNodeAnnouncement { msg: announcement });
+			}
+		}
+		let mut pending_events = self.pending_events.lock().unwrap();
+		for funding_locked in new_events.drain(..) {
+			pending_events.push(funding_locked);
+		}
+	}
+
+	fn block_disconnected(&self, header: &BlockHeader) {
+		let mut channel_state = self.channel_state.lock().unwrap();
+		for channel in channel_state.by_id.values_mut() {
+			if channel.block_disconnected(header) {
+				//TODO Close channel here
+			}
+		}
+		let mut height = self.latest_block_height.load(Ordering::Acquire);
+		while height > 0 {
+			match self.latest_block_height.compare_exchange(height, height - 1, Ordering::AcqRel, Ordering::Acquire) {
+				Ok(_) => break,
+				Err(cur_height) => height = cur_height,
+			}
+		}
+	}
+}
+
+impl ChannelMessageHandler for ChannelManager {
+	//TODO: Handle errors and close channel (or so)
+	fn handle_open_channel(&self, their_node_id: &PublicKey, msg: &msgs::OpenChannel) -> Result<msgs::AcceptChannel, HandleError> {
 		if msg.chain_hash != self.genesis_hash {
 			return Err(HandleError{err: "Unknown genesis block hash", msg: None});
 		}
+		let upfront_shutdown_script = self.peer_supports_upfront_shutdown_script(their_node_id);
+		let shutdown_scriptpubkey = self.inbound_shutdown_scriptpubkey.lock().unwrap().clone();
 		let mut channel_state = self.channel_state.lock().unwrap();
 		if channel_state.by_id.contains_key(&msg.temporary_channel_id) {
 			return Err(HandleError{err: "temporary_channel_id collision!", msg: None});
 		}
 
-		let chan_keys = if cfg!(feature = "fuzztarget") {
-			ChannelKeys {
-				funding_key:               SecretKey::from_slice(&self.secp_ctx, &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap(),
-				revocation_base_key:       SecretKey::from_slice(&self.secp_ctx, &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap(),
-				payment_base_key:          SecretKey::from_slice(&self.secp_ctx, &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap(),
-				delayed_payment_base_key:  SecretKey::from_slice(&self.secp_ctx, &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap(),
-				htlc_base_key:             SecretKey::from_slice(&self.secp_ctx, &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap(),
-				channel_close_key:         SecretKey::from_slice(&self.secp_ctx, &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap(),
-				channel_monitor_claim_key: SecretKey::from_slice(&self.secp_ctx, &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap(),
-				commitment_seed: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-			}
-		} else {
-			let mut key_seed = [0u8; 32];
-			rng::fill_bytes(&mut key_seed);
-			match ChannelKeys::new_from_seed(&key_seed) {
-				Ok(key) => key,
-				Err(_) => panic!("RNG is busted!")
-			}
-		};
-
-		let channel = Channel::new_from_req(&*self.fee_estimator, chan_keys, their_node_id.clone(), msg, 0, self.announce_channels_publicly)?;
+		let channel = Channel::new_from_req(&*self.fee_estimator, &self.keys_manager, their_node_id.clone(), msg, 0, self.announce_channels_publicly, shutdown_scriptpubkey, upfront_shutdown_script)?;
 		let accept_msg = channel.get_accept_channel()?;
 		channel_state.by_id.insert(channel.channel_id(), channel);
 		Ok(accept_msg)
@@ -1161,7 +1920,7 @@ impl ChannelMessageHandler for ChannelManager {
 		//TODO: broke this - a node shouldn't be able to get their channel removed by sending a
 		//funding_created a second time, or long after the first, or whatever (note this also
 		//leaves the short_to_id map in a busted state.
-		let (chan, funding_msg, monitor_update) = {
+		let (mut chan, funding_msg, chan_monitor) = {
 			let mut channel_state = self.channel_state.lock().unwrap();
 			match channel_state.by_id.remove(&msg.temporary_channel_id) {
 				Some(mut chan) => {
@@ -1169,8 +1928,8 @@ impl ChannelMessageHandler for ChannelManager {
 						return Err(HandleError{err: "Got a message for a channel from the wrong node!", msg: None})
 					}
 					match chan.funding_created(msg) {
-						Ok((funding_msg, monitor_update)) => {
-							(chan, funding_msg, monitor_update)
+						Ok((funding_msg, chan_monitor)) => {
+							(chan, funding_msg, chan_monitor)
 						},
 						Err(e) => {
 							return Err(e);
@@ -1183,8 +1942,12 @@ impl ChannelMessageHandler for ChannelManager {
 		   // note that this means if the remote end is misbehaving and sends a message for the same
 		   // channel back-to-back with funding_created, we'll end up thinking they sent a message
 		   // for a bogus channel.
-		if let Err(_e) = self.monitor.add_update_monitor(monitor_update.get_funding_txo().unwrap(), monitor_update) {
-			unimplemented!();
+		// Note that we send funding_signed even if our ChannelMonitor is still being persisted as we
+		// have no funds at risk yet: our balance in our counterparty's commitment transaction pays
+		// to us directly, and we hold back anything which relies on the monitor after this point.
+		if let Err(msg) = self.add_channel_monitor(&mut chan, chan_monitor) {
+			// The channel was never added to channel_state, so there's nothing to remove
+			return Err(HandleError{err: MONITOR_PERMANENT_FAILURE_ERR, msg: Some(msgs::ErrorAction::SendErrorMessage{msg})});
 		}
 		let mut channel_state = self.channel_state.lock().unwrap();
 		channel_state.by_id.insert(funding_msg.channel_id, chan);
@@ -1192,22 +1955,25 @@ impl ChannelMessageHandler for ChannelManager {
 	}
 
 	fn handle_funding_signed(&self, their_node_id: &PublicKey, msg: &msgs::FundingSigned) -> Result<(), HandleError> {
-		let (funding_txo, user_id, monitor) = {
+		let (funding_txo, user_id) = {
 			let mut channel_state = self.channel_state.lock().unwrap();
 			match channel_state.by_id.get_mut(&msg.channel_id) {
 				Some(chan) => {
 					if chan.get_their_node_id() != *their_node_id {
 						return Err(HandleError{err: "Got a message for a channel from the wrong node!", msg: None})
 					}
-					let chan_monitor = chan.funding_signed(&msg)?;
-					(chan.get_funding_txo().unwrap(), chan.get_user_id(), chan_monitor)
+					let monitor_update = chan.funding_signed(&msg)?;
+					if !try_monitor_update!(channel_state, self.update_channel_monitor(chan, monitor_update)) {
+						// Broadcasting the funding transaction before our ChannelMonitor has our
+						// commitment transaction would leave our funds at our counterparty's mercy
+						chan.hold_monitor_pending(None, None, Vec::new(), Vec::new(), true);
+						return Ok(());
+					}
+					(chan.get_funding_txo().unwrap(), chan.get_user_id())
 				},
 				None => return Err(HandleError{err: "Failed to find corresponding channel", msg: None})
 			}
 		};
-		if let Err(_e) = self.monitor.add_update_monitor(monitor.get_funding_txo().unwrap(), monitor) {
-			unimplemented!();
-		}
 		let mut pending_events = self.pending_events.lock().unwrap();
 		pending_events.push(events::Event::FundingBroadcastSafe {
 			funding_txo: funding_txo,
@@ -1256,12 +2022,7 @@ impl ChannelMessageHandler for ChannelManager {
 			self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), &payment_hash, HTLCFailReason::Reason { failure_code: 0x4000 | 10, data: Vec::new() });
 		}
 		if let Some(chan) = chan_option {
-			if let Ok(update) = self.get_channel_update(&chan) {
-				let mut events = self.pending_events.lock().unwrap();
-				events.push(events::Event::BroadcastChannelUpdate {
-					msg: update
-				});
-			}
+			self.finish_unfunded_close_channel(&chan);
 		}
 		Ok((res.0, res.1))
 	}
@@ -1275,7 +2036,25 @@ impl ChannelMessageHandler for ChannelManager {
 					if chan_entry.get().get_their_node_id() != *their_node_id {
 						return Err(HandleError{err: "Got a message for a channel from the wrong node!", msg: None})
 					}
-					let res = chan_entry.get_mut().closing_signed(&*self.fee_estimator, &msg)?;
+					let res = match chan_entry.get_mut().closing_signed(&*self.fee_estimator, &msg) {
+						Ok(res) => res,
+						Err(e) => {
+							if chan_entry.get().closing_fee_negotiation_failed() && chan_entry.get().get_closing_fee_params().on_failure == ClosingFeeFailureAction::ForceClose {
+								if let Some(short_id) = chan_entry.get().get_short_channel_id() {
+									channel_state.short_to_id.remove(&short_id);
+								}
+								let mut chan = chan_entry.remove_entry().1;
+								self.finish_force_close_channel(&mut chan);
+								return Err(HandleError{err: e.err, msg: Some(msgs::ErrorAction::SendErrorMessage {
+									msg: msgs::ErrorMessage {
+										channel_id: msg.channel_id,
+										data: e.err.to_string(),
+									},
+								})});
+							}
+							return Err(e);
+						}
+					};
 					if res.1.is_some() {
 						// We're done with this channel, we've got a signed closing transaction and
 						// will send the closing_signed back to the remote peer upon return. This
@@ -1291,25 +2070,27 @@ impl ChannelMessageHandler for ChannelManager {
 				hash_map::Entry::Vacant(_) => return Err(HandleError{err: "Failed to find corresponding channel", msg: None})
 			}
 		};
-		if let Some(broadcast_tx) = res.1 {
-			self.tx_broadcaster.broadcast_transaction(&broadcast_tx);
+		if let Some(ref broadcast_tx) = res.1 {
+			self.tx_broadcaster.broadcast_transaction(broadcast_tx);
 		}
 		if let Some(chan) = chan_option {
+			let mut events = self.pending_events.lock().unwrap();
 			if let Ok(update) = self.get_channel_update(&chan) {
-				let mut events = self.pending_events.lock().unwrap();
 				events.push(events::Event::BroadcastChannelUpdate {
 					msg: update
 				});
 			}
+			let closing_tx = res.1.as_ref().unwrap();
+			events.push(events::Event::ChannelClosed {
+				channel_id: chan.channel_id(),
+				user_channel_id: chan.get_user_id(),
+				closing_fee_satoshis: Some(chan.get_value_satoshis() - closing_tx.output.iter().map(|output| output.value).sum::<u64>()),
+			});
 		}
 		Ok(res.0)
 	}
 
 	fn handle_update_add_htlc(&self, their_node_id: &PublicKey, msg: &msgs::UpdateAddHTLC) -> Result<(), msgs::HandleError> {
-		//TODO: BOLT 4 points out a specific attack where a peer may re-send an onion packet and
-		//determine the state of the payment based on our response/if we forward anything/the time
-		//we take to respond. We should take care to avoid allowing such an attack.
-		//
 		//TODO: There exists a further attack where a node may garble the onion data, forward it to
 		//us repeatedly garbled in different ways, and compare our error messages, which are
 		//encrypted with the same key. Its not immediately obvious how to usefully exploit that,
@@ -1364,6 +2145,24 @@ impl ChannelMessageHandler for ChannelManager {
 			return_err!("HMAC Check failed", 0x8000 | 0x4000 | 5, &get_onion_hash!());
 		}
 
+		let mut channel_state_lock = self.channel_state.lock().unwrap();
+		let channel_state = channel_state_lock.borrow_parts();
+
+		let onion_secret_hash = {
+			let mut sha = Sha256::new();
+			sha.input(&shared_secret[..]);
+			let mut res = [0; 32];
+			sha.result(&mut res);
+			res
+		};
+		if channel_state.onion_replay_log.contains_key(&onion_secret_hash) {
+			// BOLT 4 warns that a peer may replay an onion to see how we (or later hops) respond.
+			// We should never see the same onion twice from an honest peer, so we simply refuse
+			// it, generically enough to not reveal whether we're the final hop. We check this before
+			// anything else in the onion so that a replay learns nothing about our policy either.
+			return_err!("Onion packet was replayed", 0x2000 | 2, &[0;0]);
+		}
+
 		let mut chacha = ChaCha20::new(&rho, &[0u8; 8]);
 		let next_hop_data = {
 			let mut decoded = [0; 65];
@@ -1388,6 +2187,9 @@ impl ChannelMessageHandler for ChannelManager {
 				if next_hop_data.data.outgoing_cltv_value != msg.cltv_expiry {
 					return_err!("Upstream node set CLTV to the wrong value", 18, &byte_utils::be32_to_array(msg.cltv_expiry));
 				}
+				if msg.cltv_expiry as u64 <= self.latest_block_height.load(Ordering::Acquire) as u64 + CLTV_CLAIM_BUFFER as u64 + LATENCY_GRACE_PERIOD_BLOCKS as u64 {
+					return_err!("The final CLTV expiry is too soon to handle", 17, &[0;0]);
+				}
 
 				// Note that we could obviously respond immediately with an update_fulfill_htlc
 				// message, however that would leak that we are the recipient of this payment, so
@@ -1441,8 +2243,6 @@ impl ChannelMessageHandler for ChannelManager {
 					hmac: next_hop_data.hmac.clone(),
 				};
 
-				//TODO: Check amt_to_forward and outgoing_cltv_value are within acceptable ranges!
-
 				PendingForwardHTLCInfo {
 					onion_packet: Some(outgoing_packet),
 					payment_hash: msg.payment_hash.clone(),
@@ -1453,9 +2253,6 @@ impl ChannelMessageHandler for ChannelManager {
 				}
 			};
 
-		let mut channel_state_lock = self.channel_state.lock().unwrap();
-		let channel_state = channel_state_lock.borrow_parts();
-
 		if pending_forward_info.onion_packet.is_some() { // If short_channel_id is 0 here, we'll reject them in the body here
 			let forwarding_id = match channel_state.short_to_id.get(&pending_forward_info.short_channel_id) {
 				None => {
@@ -1466,7 +2263,42 @@ impl ChannelMessageHandler for ChannelManager {
 			let chan = channel_state.by_id.get_mut(&forwarding_id).unwrap();
 			if !chan.is_live() {
 				let chan_update = self.get_channel_update(chan).unwrap();
-				return_err!("Forwarding channel is not in a ready state.", 0x4000 | 7, &chan_update.encode_with_len()[..]);
+				return_err!("Forwarding channel is not in a ready state.", 0x1000 | 7, &chan_update.encode_with_len()[..]);
+			}
+			// Check that we're paid the fee we advertised for the outbound channel and have as much
+			// time to claim the inbound HTLC on-chain as we advertised, too. If the policy was
+			// changed recently, HTLCs which satisfy the previous one are fine as well.
+			let policy = self.get_forwarding_policy(chan);
+			let prev_policy = chan.get_prev_forwarding_policy();
+			if pending_forward_info.amt_to_forward < policy.htlc_minimum_msat && prev_policy.map_or(true, |prev| pending_forward_info.amt_to_forward < prev.htlc_minimum_msat) {
+				let chan_update = self.get_channel_update(chan).unwrap();
+				let mut data = byte_utils::be64_to_array(msg.amount_msat).to_vec();
+				data.extend_from_slice(&chan_update.encode_with_len()[..]);
+				return_err!("HTLC amount was below the htlc_minimum_msat", 0x1000 | 11, &data[..]);
+			}
+			let pays_fee = |policy: &ChannelForwardingPolicy| {
+				let fee = pending_forward_info.amt_to_forward.checked_mul(policy.fee_proportional_millionths as u64)
+					.and_then(|prop_fee| (prop_fee / 1000000).checked_add(policy.fee_base_msat as u64));
+				fee.is_some() && msg.amount_msat >= fee.unwrap() && (msg.amount_msat - fee.unwrap()) >= pending_forward_info.amt_to_forward
+			};
+			if !pays_fee(&policy) && !prev_policy.map_or(false, |prev| pays_fee(prev)) {
+				let chan_update = self.get_channel_update(chan).unwrap();
+				let mut data = byte_utils::be64_to_array(msg.amount_msat).to_vec();
+				data.extend_from_slice(&chan_update.encode_with_len()[..]);
+				return_err!("Prior hop has deviated from specified fees parameters or origin node has obsolete ones", 0x1000 | 12, &data[..]);
+			}
+			let leaves_cltv_delta = |policy: &ChannelForwardingPolicy| {
+				msg.cltv_expiry as u64 >= pending_forward_info.outgoing_cltv_value as u64 + policy.cltv_expiry_delta as u64
+			};
+			if !leaves_cltv_delta(&policy) && !prev_policy.map_or(false, |prev| leaves_cltv_delta(prev)) {
+				let chan_update = self.get_channel_update(chan).unwrap();
+				let mut data = byte_utils::be32_to_array(msg.cltv_expiry).to_vec();
+				data.extend_from_slice(&chan_update.encode_with_len()[..]);
+				return_err!("Forwarding node has tampered with the intended HTLC values or origin node has an obsolete cltv_expiry_delta", 0x1000 | 13, &data[..]);
+			}
+			if msg.cltv_expiry as u64 <= self.latest_block_height.load(Ordering::Acquire) as u64 + CLTV_CLAIM_BUFFER as u64 + LATENCY_GRACE_PERIOD_BLOCKS as u64 {
+				let chan_update = self.get_channel_update(chan).unwrap();
+				return_err!("CLTV expiry is too close", 0x1000 | 14, &chan_update.encode_with_len()[..]);
 			}
 		}
 
@@ -1502,16 +2334,20 @@ impl ChannelMessageHandler for ChannelManager {
 				}
 				let short_channel_id = chan.get_short_channel_id().unwrap();
 				pending_forward_info.prev_short_channel_id = short_channel_id;
-				(short_channel_id, chan.update_add_htlc(&msg, pending_forward_info)?)
+				chan.update_add_htlc(&msg, pending_forward_info)?;
+				// The ChannelMonitor persists the onion along with the HTLC once it's committed, so
+				// that the replay log can be rebuilt after a restart.
+				(short_channel_id, chan.provide_onion_replay_log_entry(onion_secret_hash, msg.cltv_expiry)?)
 			},
 			None => return Err(HandleError{err: "Failed to find corresponding channel", msg: None}), //TODO: panic?
 		};
+		channel_state.onion_replay_log.insert(onion_secret_hash, msg.cltv_expiry);
 
 		match claimable_htlcs_entry {
 			hash_map::Entry::Occupied(mut e) => {
 				let outbound_route = e.get_mut();
 				let (route, session_priv) = match outbound_route {
-					&mut PendingOutboundHTLC::OutboundRoute { ref route, ref session_priv } => {
+					&mut PendingOutboundHTLC::OutboundRoute { ref route, ref session_priv, .. } => {
 						(route.clone(), session_priv.clone())
 					},
 					_ => { panic!("WAT") },
@@ -1540,20 +2376,17 @@ impl ChannelMessageHandler for ChannelManager {
 		// is broken, we may have enough info to get our own money!
 		self.claim_funds_internal(msg.payment_preimage.clone(), false);
 
-		let monitor = {
-			let mut channel_state = self.channel_state.lock().unwrap();
-			match channel_state.by_id.get_mut(&msg.channel_id) {
-				Some(chan) => {
-					if chan.get_their_node_id() != *their_node_id {
-						return Err(HandleError{err: "Got a message for a channel from the wrong node!", msg: None})
-					}
-					chan.update_fulfill_htlc(&msg)?
-				},
-				None => return Err(HandleError{err: "Failed to find corresponding channel", msg: None})
-			}
-		};
-		if let Err(_e) = self.monitor.add_update_monitor(monitor.get_funding_txo().unwrap(), monitor) {
-			unimplemented!();
+		let mut channel_state = self.channel_state.lock().unwrap();
+		match channel_state.by_id.get_mut(&msg.channel_id) {
+			Some(chan) => {
+				if chan.get_their_node_id() != *their_node_id {
+					return Err(HandleError{err: "Got a message for a channel from the wrong node!", msg: None})
+				}
+				let monitor_update = chan.update_fulfill_htlc(&msg)?;
+				// Nothing to send here, so nothing to hold if the update is still in progress
+				try_monitor_update!(channel_state, self.update_channel_monitor(chan, monitor_update));
+			},
+			None => return Err(HandleError{err: "Failed to find corresponding channel", msg: None})
 		}
 		Ok(())
 	}
@@ -1572,59 +2405,9 @@ impl ChannelMessageHandler for ChannelManager {
 
 		if let Some(pending_htlc) = channel_state.claimable_htlcs.get(&payment_hash) {
 			match pending_htlc {
-				&PendingOutboundHTLC::OutboundRoute { ref route, ref session_priv } => {
+				&PendingOutboundHTLC::OutboundRoute { ref route, ref session_priv, .. } => {
 					// Handle packed channel/node updates for passing back for the route handler
-					let mut packet_decrypted = msg.reason.data.clone();
-					let mut res = None;
-					Self::construct_onion_keys_callback(&self.secp_ctx, &route, &session_priv, |shared_secret, _, _, route_hop| {
-						if res.is_some() { return; }
-
-						let ammag = ChannelManager::gen_ammag_from_shared_secret(&shared_secret);
-
-						let mut decryption_tmp = Vec::with_capacity(packet_decrypted.len());
-						decryption_tmp.resize(packet_decrypted.len(), 0);
-						let mut chacha = ChaCha20::new(&ammag, &[0u8; 8]);
-						chacha.process(&packet_decrypted, &mut decryption_tmp[..]);
-						packet_decrypted = decryption_tmp;
-
-						if let Ok(err_packet) = msgs::DecodedOnionErrorPacket::decode(&packet_decrypted) {
-							if err_packet.failuremsg.len() >= 2 {
-								let um = ChannelManager::gen_um_from_shared_secret(&shared_secret);
-
-								let mut hmac = Hmac::new(Sha256::new(), &um);
-								hmac.input(&err_packet.encode()[32..]);
-								let mut calc_tag = [0u8; 32];
-								hmac.raw_result(&mut calc_tag);
-								if crypto::util::fixed_time_eq(&calc_tag, &err_packet.hmac) {
-									const UNKNOWN_CHAN: u16 = 0x4000|10;
-									const TEMP_CHAN_FAILURE: u16 = 0x4000|7;
-									match byte_utils::slice_to_be16(&err_packet.failuremsg[0..2]) {
-										TEMP_CHAN_FAILURE => {
-											if err_packet.failuremsg.len() >= 4 {
-												let update_len = byte_utils::slice_to_be16(&err_packet.failuremsg[2..4]) as usize;
-												if err_packet.failuremsg.len() >= 4 + update_len {
-													if let Ok(chan_update) = msgs::ChannelUpdate::decode(&err_packet.failuremsg[4..4 + update_len]) {
-														res = Some(msgs::HTLCFailChannelUpdate::ChannelUpdateMessage {
-															msg: chan_update,
-														});
-													}
-												}
-											}
-										},
-										UNKNOWN_CHAN => {
-											// No such next-hop. We know this came from the
-											// current node as the HMAC validated.
-											res = Some(msgs::HTLCFailChannelUpdate::ChannelClosed {
-												short_channel_id: route_hop.short_channel_id
-											});
-										},
-										_ => {}, //TODO: Enumerate all of these!
-									}
-								}
-							}
-						}
-					}).unwrap();
-					Ok(res)
+					Ok(Self::process_onion_failure(&self.secp_ctx, route, session_priv, &msg.reason).and_then(|failure| failure.channel_update))
 				},
 				_ => { Ok(None) },
 			}
@@ -1646,73 +2429,55 @@ impl ChannelMessageHandler for ChannelManager {
 		}
 	}
 
-	fn handle_commitment_signed(&self, their_node_id: &PublicKey, msg: &msgs::CommitmentSigned) -> Result<(msgs::RevokeAndACK, Option<msgs::CommitmentSigned>), HandleError> {
-		let (revoke_and_ack, commitment_signed, chan_monitor) = {
-			let mut channel_state = self.channel_state.lock().unwrap();
-			match channel_state.by_id.get_mut(&msg.channel_id) {
-				Some(chan) => {
-					if chan.get_their_node_id() != *their_node_id {
-						return Err(HandleError{err: "Got a message for a channel from the wrong node!", msg: None})
-					}
-					chan.commitment_signed(&msg)?
-				},
-				None => return Err(HandleError{err: "Failed to find corresponding channel", msg: None})
-			}
-		};
-		if let Err(_e) = self.monitor.add_update_monitor(chan_monitor.get_funding_txo().unwrap(), chan_monitor) {
-			unimplemented!();
+	fn handle_commitment_signed(&self, their_node_id: &PublicKey, msg: &msgs::CommitmentSigned) -> Result<(Option<msgs::RevokeAndACK>, Option<msgs::CommitmentSigned>), HandleError> {
+		let mut channel_state = self.channel_state.lock().unwrap();
+		match channel_state.by_id.get_mut(&msg.channel_id) {
+			Some(chan) => {
+				if chan.get_their_node_id() != *their_node_id {
+					return Err(HandleError{err: "Got a message for a channel from the wrong node!", msg: None})
+				}
+				let (revoke_and_ack, commitment_signed, monitor_update) = chan.commitment_signed(&msg)?;
+				if try_monitor_update!(channel_state, self.update_channel_monitor(chan, monitor_update)) {
+					Ok((Some(revoke_and_ack), commitment_signed))
+				} else {
+					chan.hold_monitor_pending(Some(revoke_and_ack), commitment_signed.map(|commitment_signed| msgs::CommitmentUpdate {
+						update_add_htlcs: Vec::new(),
+						update_fulfill_htlcs: Vec::new(),
+						update_fail_htlcs: Vec::new(),
+						commitment_signed: commitment_signed,
+					}), Vec::new(), Vec::new(), false);
+					Ok((None, None))
+				}
+			},
+			None => return Err(HandleError{err: "Failed to find corresponding channel", msg: None})
 		}
-
-		Ok((revoke_and_ack, commitment_signed))
 	}
 
 	fn handle_revoke_and_ack(&self, their_node_id: &PublicKey, msg: &msgs::RevokeAndACK) -> Result<Option<msgs::CommitmentUpdate>, HandleError> {
-		let (res, mut pending_forwards, mut pending_failures, chan_monitor) = {
+		let (res, pending_forwards, mut pending_failures) = {
 			let mut channel_state = self.channel_state.lock().unwrap();
 			match channel_state.by_id.get_mut(&msg.channel_id) {
 				Some(chan) => {
 					if chan.get_their_node_id() != *their_node_id {
 						return Err(HandleError{err: "Got a message for a channel from the wrong node!", msg: None})
 					}
-					chan.revoke_and_ack(&msg)?
+					let (res, pending_forwards, pending_failures, monitor_update) = chan.revoke_and_ack(&msg)?;
+					if try_monitor_update!(channel_state, self.update_channel_monitor(chan, monitor_update)) {
+						(res, pending_forwards, pending_failures)
+					} else {
+						// We can't forward (or fail back) HTLCs until our ChannelMonitor knows
+						// they've been irrevocably committed to.
+						chan.hold_monitor_pending(None, res, pending_forwards, pending_failures, false);
+						return Ok(None);
+					}
 				},
 				None => return Err(HandleError{err: "Failed to find corresponding channel", msg: None})
 			}
 		};
-		if let Err(_e) = self.monitor.add_update_monitor(chan_monitor.get_funding_txo().unwrap(), chan_monitor) {
-			unimplemented!();
-		}
 		for failure in pending_failures.drain(..) {
 			self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), &failure.0, failure.1);
 		}
-
-		let mut forward_event = None;
-		if !pending_forwards.is_empty() {
-			let mut channel_state = self.channel_state.lock().unwrap();
-			if channel_state.forward_htlcs.is_empty() {
-				forward_event = Some(Instant::now() + Duration::from_millis(((rng::rand_f32() * 4.0 + 1.0) * MIN_HTLC_RELAY_HOLDING_CELL_MILLIS as f32) as u64));
-				channel_state.next_forward = forward_event.unwrap();
-			}
-			for forward_info in pending_forwards.drain(..) {
-				match channel_state.forward_htlcs.entry(forward_info.short_channel_id) {
-					hash_map::Entry::Occupied(mut entry) => {
-						entry.get_mut().push(forward_info);
-					},
-					hash_map::Entry::Vacant(entry) => {
-						entry.insert(vec!(forward_info));
-					}
-				}
-			}
-		}
-		match forward_event {
-			Some(time) => {
-				let mut pending_events = self.pending_events.lock().unwrap();
-				pending_events.push(events::Event::PendingHTLCsForwardable {
-					time_forwardable: time
-				});
-			}
-			None => {},
-		}
+		self.forward_htlcs(pending_forwards);
 
 		Ok(res)
 	}
@@ -1763,12 +2528,23 @@ impl ChannelMessageHandler for ChannelManager {
 				None => return Err(HandleError{err: "Failed to find corresponding channel", msg: None})
 			}
 		};
+		// Peers will only accept our node_announcement once they know of a channel of ours, so
+		// (re-)send it along with each channel_announcement.
+		let node_announcement = self.get_node_announcement_if_public();
 		let mut pending_events = self.pending_events.lock().unwrap();
 		pending_events.push(events::Event::BroadcastChannelAnnouncement { msg: chan_announcement, update_msg: chan_update });
+		if let Some(announcement) = node_announcement {
+			pending_events.push(events::Event::BroadcastNodeAnnouncement { msg: announcement });
+		}
 		Ok(())
 	}
 
+	fn peer_connected(&self, their_node_id: &PublicKey, their_local_features: &msgs::LocalFeatures) {
+		self.peer_features.lock().unwrap().insert(their_node_id.clone(), their_local_features.clone());
+	}
+
 	fn peer_disconnected(&self, their_node_id: &PublicKey, no_connection_possible: bool) {
+		self.peer_features.lock().unwrap().remove(their_node_id);
 		let mut new_events = Vec::new();
 		{
 			let mut channel_state_lock = self.channel_state.lock().unwrap();
@@ -1816,18 +2592,25 @@ impl ChannelMessageHandler for ChannelManager {
 #[cfg(test)]
 mod tests {
 	use chain::chaininterface;
+	use chain::keysinterface::KeysInterface;
 	use chain::transaction::OutPoint;
-	use ln::channelmanager::{ChannelManager,OnionKeys};
+	use ln::channelmanager::{ChannelManager,ChannelForwardingPolicy,ClosingFeeParameters,ClosingFeeFailureAction,OnionKeys,PendingOutboundHTLC,CLTV_EXPIRY_DELTA,LATENCY_GRACE_PERIOD_BLOCKS,NODE_ANNOUNCEMENT_INTERVAL_BLOCKS,CHANNEL_UPDATE_REFRESH_INTERVAL_BLOCKS};
+	use ln::channelmonitor::{ANTI_REORG_DELAY,CLTV_CLAIM_BUFFER,ChannelMonitorUpdateErr,ManyChannelMonitor};
+	use ln::watchtower::{WatchtowerClient,WatchtowerServer};
 	use ln::router::{Route, RouteHop, Router};
+	use ln::payment_tracker::PaymentTracker;
 	use ln::msgs;
-	use ln::msgs::{MsgEncodable,ChannelMessageHandler,RoutingMessageHandler};
+	use ln::msgs::{MsgEncodable,MsgDecodable,ChannelMessageHandler,RoutingMessageHandler};
 	use util::test_utils;
+	use util::rng::{EntropySource, SeededEntropySource};
 	use util::events::{Event, EventsProvider};
 
 	use bitcoin::util::misc::hex_bytes;
 	use bitcoin::util::hash::Sha256dHash;
 	use bitcoin::util::uint::Uint256;
 	use bitcoin::blockdata::block::BlockHeader;
+	use bitcoin::blockdata::opcodes;
+	use bitcoin::blockdata::script::{Builder, Script};
 	use bitcoin::blockdata::transaction::{Transaction, TxOut};
 	use bitcoin::network::constants::Network;
 	use bitcoin::network::serialize::serialize;
@@ -1839,12 +2622,12 @@ mod tests {
 	use crypto::sha2::Sha256;
 	use crypto::digest::Digest;
 
-	use rand::{thread_rng,Rng};
 
 	use std::collections::HashMap;
 	use std::default::Default;
 	use std::sync::{Arc, Mutex};
-	use std::time::Instant;
+	use std::sync::atomic::Ordering;
+	use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 	use std::mem;
 
 	fn build_test_onion_keys() -> Vec<OnionKeys> {
@@ -2013,12 +2796,12 @@ mod tests {
 		chan_monitor: Arc<test_utils::TestChannelMonitor>,
 		node_id: SecretKey,
 		node: Arc<ChannelManager>,
-		router: Router,
+		router: Arc<Router>,
 	}
 
 	static mut CHAN_COUNT: u32 = 0;
-	fn create_chan_between_nodes(node_a: &Node, node_b: &Node) -> (msgs::ChannelAnnouncement, msgs::ChannelUpdate, msgs::ChannelUpdate, Uint256, Transaction) {
-		node_a.node.create_channel(node_b.node.get_our_node_id(), 100000, 42).unwrap();
+	fn create_chan_between_nodes(node_a: &Node, node_b: &Node, shutdown_scriptpubkey: Option<Script>) -> (msgs::ChannelAnnouncement, msgs::ChannelUpdate, msgs::ChannelUpdate, Uint256, Transaction) {
+		node_a.node.create_channel(node_b.node.get_our_node_id(), 100000, 42, shutdown_scriptpubkey).unwrap();
 
 		let events_1 = node_a.node.get_and_clear_pending_events();
 		assert_eq!(events_1.len(), 1);
@@ -2146,7 +2929,7 @@ mod tests {
 	}
 
 	fn create_announced_chan_between_nodes(nodes: &Vec<Node>, a: usize, b: usize) -> (msgs::ChannelUpdate, msgs::ChannelUpdate, Uint256, Transaction) {
-		let chan_announcement = create_chan_between_nodes(&nodes[a], &nodes[b]);
+		let chan_announcement = create_chan_between_nodes(&nodes[a], &nodes[b], None);
 		for node in nodes {
 			assert!(node.router.handle_channel_announcement(&chan_announcement.0).unwrap());
 			node.router.handle_channel_update(&chan_announcement.1).unwrap();
@@ -2155,12 +2938,12 @@ mod tests {
 		(chan_announcement.1, chan_announcement.2, chan_announcement.3, chan_announcement.4)
 	}
 
-	fn close_channel(outbound_node: &Node, inbound_node: &Node, channel_id: &Uint256, funding_tx: Transaction, close_inbound_first: bool) -> (msgs::ChannelUpdate, msgs::ChannelUpdate) {
+	fn close_channel(outbound_node: &Node, inbound_node: &Node, channel_id: &Uint256, funding_tx: Transaction, close_inbound_first: bool, shutdown_scriptpubkey: Option<Script>, fee_params: Option<ClosingFeeParameters>) -> (msgs::ChannelUpdate, msgs::ChannelUpdate, Transaction) {
 		let (node_a, broadcaster_a) = if close_inbound_first { (&inbound_node.node, &inbound_node.tx_broadcaster) } else { (&outbound_node.node, &outbound_node.tx_broadcaster) };
 		let (node_b, broadcaster_b) = if close_inbound_first { (&outbound_node.node, &outbound_node.tx_broadcaster) } else { (&inbound_node.node, &inbound_node.tx_broadcaster) };
 		let (tx_a, tx_b);
 
-		let shutdown_a = node_a.close_channel(channel_id).unwrap();
+		let shutdown_a = node_a.close_channel(channel_id, shutdown_scriptpubkey, fee_params).unwrap();
 		let (shutdown_b, mut closing_signed_b) = node_b.handle_shutdown(&node_a.get_our_node_id(), &shutdown_a).unwrap();
 		if !close_inbound_first {
 			assert!(closing_signed_b.is_none());
@@ -2188,29 +2971,51 @@ mod tests {
 			tx_a = broadcaster_a.txn_broadcasted.lock().unwrap().remove(0);
 		}
 		assert_eq!(tx_a, tx_b);
+		let closing_fee = funding_tx.output[0].value - tx_a.output.iter().map(|output| output.value).sum::<u64>();
 		let mut funding_tx_map = HashMap::new();
 		funding_tx_map.insert(funding_tx.txid(), funding_tx);
 		tx_a.verify(&funding_tx_map).unwrap();
 
 		let events_1 = node_a.get_and_clear_pending_events();
-		assert_eq!(events_1.len(), 1);
+		assert_eq!(events_1.len(), 2);
 		let as_update = match events_1[0] {
 			Event::BroadcastChannelUpdate { ref msg } => {
 				msg.clone()
 			},
 			_ => panic!("Unexpected event"),
 		};
+		match events_1[1] {
+			Event::ChannelClosed { closing_fee_satoshis, .. } => assert_eq!(closing_fee_satoshis, Some(closing_fee)),
+			_ => panic!("Unexpected event"),
+		}
 
 		let events_2 = node_b.get_and_clear_pending_events();
-		assert_eq!(events_2.len(), 1);
+		assert_eq!(events_2.len(), 2);
 		let bs_update = match events_2[0] {
 			Event::BroadcastChannelUpdate { ref msg } => {
 				msg.clone()
 			},
 			_ => panic!("Unexpected event"),
 		};
+		match events_2[1] {
+			Event::ChannelClosed { closing_fee_satoshis, .. } => assert_eq!(closing_fee_satoshis, Some(closing_fee)),
+			_ => panic!("Unexpected event"),
+		}
 
-		(as_update, bs_update)
+		(as_update, bs_update, tx_a)
+	}
+
+	fn check_unfunded_channel_closed(node: &Node, channel_id: &Uint256, user_id: u64) {
+		let events = node.node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::ChannelClosed { channel_id: ref closed_channel_id, user_channel_id, closing_fee_satoshis } => {
+				assert_eq!(closed_channel_id, channel_id);
+				assert_eq!(user_channel_id, user_id);
+				assert!(closing_fee_satoshis.is_none());
+			},
+			_ => panic!("Unexpected event"),
+		}
 	}
 
 	struct SendEvent {
@@ -2230,6 +3035,19 @@ mod tests {
 	}
 
 	static mut PAYMENT_COUNT: u8 = 0;
+	fn get_payment_preimage_hash() -> ([u8; 32], [u8; 32]) {
+		let our_payment_preimage = unsafe { [PAYMENT_COUNT; 32] };
+		unsafe { PAYMENT_COUNT += 1 };
+		let our_payment_hash = {
+			let mut sha = Sha256::new();
+			sha.input(&our_payment_preimage[..]);
+			let mut ret = [0; 32];
+			sha.result(&mut ret);
+			ret
+		};
+		(our_payment_preimage, our_payment_hash)
+	}
+
 	fn send_along_route(origin_node: &Node, route: Route, expected_route: &[&Node], recv_value: u64) -> ([u8; 32], [u8; 32]) {
 		let our_payment_preimage = unsafe { [PAYMENT_COUNT; 32] };
 		unsafe { PAYMENT_COUNT += 1 };
@@ -2241,7 +3059,7 @@ mod tests {
 			ret
 		};
 
-		let mut payment_event = {
+		let payment_event = {
 			origin_node.node.send_payment(route, our_payment_hash).unwrap();
 			{
 				let mut added_monitors = origin_node.chan_monitor.added_monitors.lock().unwrap();
@@ -2253,6 +3071,12 @@ mod tests {
 			assert_eq!(events.len(), 1);
 			SendEvent::from_event(events.remove(0))
 		};
+		pass_along_route(origin_node, payment_event, expected_route, recv_value, our_payment_hash);
+
+		(our_payment_preimage, our_payment_hash)
+	}
+
+	fn pass_along_route(origin_node: &Node, mut payment_event: SendEvent, expected_route: &[&Node], recv_value: u64, our_payment_hash: [u8; 32]) {
 		let mut prev_node = origin_node;
 
 		for (idx, &node) in expected_route.iter().enumerate() {
@@ -2270,14 +3094,14 @@ mod tests {
 				assert_eq!(added_monitors.len(), 1);
 				added_monitors.clear();
 			}
-			assert!(prev_node.node.handle_revoke_and_ack(&node.node.get_our_node_id(), &revoke_and_ack.0).unwrap().is_none());
+			assert!(prev_node.node.handle_revoke_and_ack(&node.node.get_our_node_id(), &revoke_and_ack.0.unwrap()).unwrap().is_none());
 			let prev_revoke_and_ack = prev_node.node.handle_commitment_signed(&node.node.get_our_node_id(), &revoke_and_ack.1.unwrap()).unwrap();
 			{
 				let mut added_monitors = prev_node.chan_monitor.added_monitors.lock().unwrap();
 				assert_eq!(added_monitors.len(), 2);
 				added_monitors.clear();
 			}
-			assert!(node.node.handle_revoke_and_ack(&prev_node.node.get_our_node_id(), &prev_revoke_and_ack.0).unwrap().is_none());
+			assert!(node.node.handle_revoke_and_ack(&prev_node.node.get_our_node_id(), &prev_revoke_and_ack.0.unwrap()).unwrap().is_none());
 			assert!(prev_revoke_and_ack.1.is_none());
 			{
 				let mut added_monitors = node.chan_monitor.added_monitors.lock().unwrap();
@@ -2317,11 +3141,22 @@ mod tests {
 
 			prev_node = node;
 		}
-
-		(our_payment_preimage, our_payment_hash)
 	}
 
 	fn claim_payment(origin_node: &Node, expected_route: &[&Node], our_payment_preimage: [u8; 32]) {
+		claim_payment_along_route(origin_node, expected_route, our_payment_preimage);
+
+		let events = origin_node.node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::PaymentSent { payment_preimage } => {
+				assert_eq!(payment_preimage, our_payment_preimage);
+			},
+			_ => panic!("Unexpected event"),
+		}
+	}
+
+	fn claim_payment_along_route(origin_node: &Node, expected_route: &[&Node], our_payment_preimage: [u8; 32]) {
 		assert!(expected_route.last().unwrap().node.claim_funds(our_payment_preimage));
 		{
 			let mut added_monitors = expected_route.last().unwrap().chan_monitor.added_monitors.lock().unwrap();
@@ -2350,7 +3185,7 @@ mod tests {
 						assert_eq!(added_monitors.len(), 1);
 						added_monitors.clear();
 					}
-					assert!($prev_node.node.handle_revoke_and_ack(&$node.node.get_our_node_id(), &revoke_and_commit.0).unwrap().is_none());
+					assert!($prev_node.node.handle_revoke_and_ack(&$node.node.get_our_node_id(), &revoke_and_commit.0.unwrap()).unwrap().is_none());
 					let revoke_and_ack = $prev_node.node.handle_commitment_signed(&$node.node.get_our_node_id(), &revoke_and_commit.1.unwrap()).unwrap();
 					assert!(revoke_and_ack.1.is_none());
 					{
@@ -2358,7 +3193,7 @@ mod tests {
 						assert_eq!(added_monitors.len(), 2);
 						added_monitors.clear();
 					}
-					assert!($node.node.handle_revoke_and_ack(&$prev_node.node.get_our_node_id(), &revoke_and_ack.0).unwrap().is_none());
+					assert!($node.node.handle_revoke_and_ack(&$prev_node.node.get_our_node_id(), &revoke_and_ack.0.unwrap()).unwrap().is_none());
 					{
 						let mut added_monitors = $node.chan_monitor.added_monitors.lock().unwrap();
 						assert_eq!(added_monitors.len(), 1);
@@ -2391,15 +3226,6 @@ mod tests {
 
 		assert_eq!(expected_next_node, origin_node.node.get_our_node_id());
 		update_fulfill_dance!(origin_node, expected_route.first().unwrap(), true);
-
-		let events = origin_node.node.get_and_clear_pending_events();
-		assert_eq!(events.len(), 1);
-		match events[0] {
-			Event::PaymentSent { payment_preimage } => {
-				assert_eq!(payment_preimage, our_payment_preimage);
-			},
-			_ => panic!("Unexpected event"),
-		}
 	}
 
 	const TEST_FINAL_CLTV: u32 = 32;
@@ -2441,6 +3267,23 @@ mod tests {
 	}
 
 	fn fail_payment(origin_node: &Node, expected_route: &[&Node], our_payment_hash: [u8; 32]) {
+		fail_payment_along_route(origin_node, expected_route, our_payment_hash);
+
+		let events = origin_node.node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::PaymentFailed { payment_hash, rejected_by_dest, erring_node, short_channel_id, failure_code } => {
+				assert_eq!(payment_hash, our_payment_hash);
+				assert!(rejected_by_dest);
+				assert_eq!(erring_node.unwrap(), expected_route.last().unwrap().node.get_our_node_id());
+				assert!(short_channel_id.is_some());
+				assert_eq!(failure_code.unwrap(), msgs::HTLCFailureCode::UnknownPaymentHash);
+			},
+			_ => panic!("Unexpected event"),
+		}
+	}
+
+	fn fail_payment_along_route(origin_node: &Node, expected_route: &[&Node], our_payment_hash: [u8; 32]) {
 		assert!(expected_route.last().unwrap().node.fail_htlc_backwards(&our_payment_hash));
 		{
 			let mut added_monitors = expected_route.last().unwrap().chan_monitor.added_monitors.lock().unwrap();
@@ -2460,7 +3303,7 @@ mod tests {
 						assert_eq!(added_monitors.len(), 1);
 						added_monitors.clear();
 					}
-					assert!($prev_node.node.handle_revoke_and_ack(&$node.node.get_our_node_id(), &revoke_and_commit.0).unwrap().is_none());
+					assert!($prev_node.node.handle_revoke_and_ack(&$node.node.get_our_node_id(), &revoke_and_commit.0.unwrap()).unwrap().is_none());
 					{
 						let mut added_monitors = $prev_node.chan_monitor.added_monitors.lock().unwrap();
 						assert_eq!(added_monitors.len(), 1);
@@ -2474,7 +3317,7 @@ mod tests {
 					}
 					assert!(revoke_and_ack.1.is_none());
 					assert!($node.node.get_and_clear_pending_events().is_empty());
-					assert!($node.node.handle_revoke_and_ack(&$prev_node.node.get_our_node_id(), &revoke_and_ack.0).unwrap().is_none());
+					assert!($node.node.handle_revoke_and_ack(&$prev_node.node.get_our_node_id(), &revoke_and_ack.0.unwrap()).unwrap().is_none());
 					{
 						let mut added_monitors = $node.chan_monitor.added_monitors.lock().unwrap();
 						if $last_node {
@@ -2512,37 +3355,41 @@ mod tests {
 
 		assert_eq!(expected_next_node, origin_node.node.get_our_node_id());
 		update_fail_dance!(origin_node, expected_route.first().unwrap(), true);
-
-		let events = origin_node.node.get_and_clear_pending_events();
-		assert_eq!(events.len(), 1);
-		match events[0] {
-			Event::PaymentFailed { payment_hash } => {
-				assert_eq!(payment_hash, our_payment_hash);
-			},
-			_ => panic!("Unexpected event"),
-		}
 	}
 
 	fn create_network(node_count: usize) -> Vec<Node> {
 		let mut nodes = Vec::new();
-		let mut rng = thread_rng();
 		let secp_ctx = Secp256k1::new();
 
-		for _ in 0..node_count {
+		for i in 0..node_count {
 			let feeest = Arc::new(test_utils::TestFeeEstimator { sat_per_vbyte: 1 });
-			let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new());
+			let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet));
 			let tx_broadcaster = Arc::new(test_utils::TestBroadcaster{txn_broadcasted: Mutex::new(Vec::new())});
-			let chan_monitor = Arc::new(test_utils::TestChannelMonitor::new(chain_monitor.clone(), tx_broadcaster.clone()));
-			let node_id = {
-				let mut key_slice = [0; 32];
-				rng.fill_bytes(&mut key_slice);
-				SecretKey::from_slice(&secp_ctx, &key_slice).unwrap()
+			let chan_monitor = Arc::new(test_utils::TestChannelMonitor::new(chain_monitor.clone(), tx_broadcaster.clone(), feeest.clone()));
+			// Everything is derived from the node's index, so that test runs are reproducible
+			let entropy_source = Arc::new(SeededEntropySource::new([i as u8; 32]));
+			let keys_manager = {
+				let mut seed = [0; 32];
+				entropy_source.fill_bytes(&mut seed);
+				Arc::new(test_utils::TestKeysInterface::new(&seed, Network::Testnet))
 			};
-			let node = ChannelManager::new(node_id.clone(), 0, true, Network::Testnet, feeest.clone(), chan_monitor.clone(), chain_monitor.clone(), tx_broadcaster.clone()).unwrap();
-			let router = Router::new(PublicKey::from_secret_key(&secp_ctx, &node_id).unwrap());
+			let node_id = keys_manager.get_node_secret();
+			let node = ChannelManager::new(keys_manager.clone(), entropy_source.clone(), 0, true, Network::Testnet, feeest.clone(), chan_monitor.clone(), chain_monitor.clone(), tx_broadcaster.clone()).unwrap();
+			let router = Router::new(PublicKey::from_secret_key(&secp_ctx, &node_id).unwrap(), chain_monitor.clone(), entropy_source);
 			nodes.push(Node { feeest, chain_monitor, tx_broadcaster, chan_monitor, node_id, node, router });
 		}
 
+		// Every node "connects" to every other, advertising the same features a PeerManager would
+		let mut local_features = msgs::LocalFeatures::new();
+		local_features.set_supports_upfront_shutdown_script();
+		for node_a in nodes.iter() {
+			for node_b in nodes.iter() {
+				if node_a.node.get_our_node_id() != node_b.node.get_our_node_id() {
+					node_a.node.peer_connected(&node_b.node.get_our_node_id(), &local_features);
+				}
+			}
+		}
+
 		nodes
 	}
 
@@ -2651,11 +3498,11 @@ mod tests {
 		claim_payment(&nodes[0], &vec!(&nodes[1], &nodes[3])[..], payment_preimage_5);
 
 		// Close down the channels...
-		close_channel(&nodes[0], &nodes[1], &chan_1.2, chan_1.3, true);
-		close_channel(&nodes[1], &nodes[2], &chan_2.2, chan_2.3, false);
-		close_channel(&nodes[2], &nodes[3], &chan_3.2, chan_3.3, true);
-		close_channel(&nodes[1], &nodes[3], &chan_4.2, chan_4.3, false);
-		close_channel(&nodes[1], &nodes[3], &chan_5.2, chan_5.3, false);
+		close_channel(&nodes[0], &nodes[1], &chan_1.2, chan_1.3, true, None, None);
+		close_channel(&nodes[1], &nodes[2], &chan_2.2, chan_2.3, false, None, None);
+		close_channel(&nodes[2], &nodes[3], &chan_3.2, chan_3.3, true, None, None);
+		close_channel(&nodes[1], &nodes[3], &chan_4.2, chan_4.3, false, None, None);
+		close_channel(&nodes[1], &nodes[3], &chan_5.2, chan_5.3, false, None, None);
 
 		// Check that we processed all pending events
 		for node in nodes {
@@ -2664,6 +3511,234 @@ mod tests {
 		}
 	}
 
+	#[test]
+	fn shutdown_scriptpubkey_test() {
+		// Tests that we pay to the scriptpubkey committed to via upfront_shutdown_script or provided
+		// at close time, and that nonstandard or mismatched scripts are rejected.
+		let nodes = create_network(2);
+		let p2wpkh_script = Builder::new().push_opcode(opcodes::All::OP_PUSHBYTES_0).push_slice(&[42; 20]).into_script();
+		let p2wsh_script = Builder::new().push_opcode(opcodes::All::OP_PUSHBYTES_0).push_slice(&[43; 32]).into_script();
+		let nonstandard_script = Builder::new().push_opcode(opcodes::All::OP_PUSHNUM_1).into_script();
+
+		assert_eq!(nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 100000, 42, Some(nonstandard_script.clone())).err().unwrap().err,
+			"Provided shutdown_scriptpubkey is of a nonstandard form");
+
+		nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 100000, 42, None).unwrap();
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::SendOpenChannel { ref msg, .. } => {
+				// Without a script we still include the (empty) field, opting out of the commitment
+				assert_eq!(msg.shutdown_scriptpubkey, Some(Script::new()));
+				let mut bogus_open = msgs::OpenChannel::decode(&msg.encode()).unwrap();
+				bogus_open.shutdown_scriptpubkey = Some(nonstandard_script.clone());
+				assert_eq!(nodes[1].node.handle_open_channel(&nodes[0].node.get_our_node_id(), &bogus_open).err().unwrap().err,
+					"Peer's upfront_shutdown_script was of a nonstandard form");
+				nodes[0].node.close_channel(&msg.temporary_channel_id, None, None).unwrap();
+				check_unfunded_channel_closed(&nodes[0], &msg.temporary_channel_id, 42);
+			},
+			_ => panic!("Unexpected event"),
+		}
+
+		let chan_1 = create_chan_between_nodes(&nodes[0], &nodes[1], Some(p2wpkh_script.clone()));
+		assert_eq!(nodes[0].node.close_channel(&chan_1.3, Some(p2wsh_script.clone()), None).err().unwrap().err,
+			"Provided shutdown_scriptpubkey does not match the one committed to at channel open");
+		// nodes[1] holds nodes[0] to the script it committed to
+		let bogus_shutdown = msgs::Shutdown {
+			channel_id: chan_1.3,
+			scriptpubkey: p2wsh_script.clone(),
+		};
+		assert_eq!(nodes[1].node.handle_shutdown(&nodes[0].node.get_our_node_id(), &bogus_shutdown).err().unwrap().err,
+			"Got shutdown request with a scriptpubkey which did not match their previous scriptpubkey");
+		let closing_tx = close_channel(&nodes[0], &nodes[1], &chan_1.3, chan_1.4, false, None, None).2;
+		assert_eq!(closing_tx.output.len(), 1);
+		assert_eq!(closing_tx.output[0].script_pubkey, p2wpkh_script);
+
+		let chan_2 = create_chan_between_nodes(&nodes[0], &nodes[1], None);
+		assert_eq!(nodes[0].node.close_channel(&chan_2.3, Some(nonstandard_script.clone()), None).err().unwrap().err,
+			"Provided shutdown_scriptpubkey is of a nonstandard form");
+		let closing_tx = close_channel(&nodes[0], &nodes[1], &chan_2.3, chan_2.4, false, Some(p2wsh_script.clone()), None).2;
+		assert_eq!(closing_tx.output.len(), 1);
+		assert_eq!(closing_tx.output[0].script_pubkey, p2wsh_script);
+
+		// The inbound side may commit to a script as well
+		assert_eq!(nodes[1].node.set_inbound_shutdown_scriptpubkey(Some(nonstandard_script.clone())).err().unwrap().err,
+			"Provided shutdown_scriptpubkey is of a nonstandard form");
+		nodes[1].node.set_inbound_shutdown_scriptpubkey(Some(p2wsh_script.clone())).unwrap();
+		let chan_3 = create_chan_between_nodes(&nodes[0], &nodes[1], None);
+		nodes[1].node.set_inbound_shutdown_scriptpubkey(None).unwrap();
+		assert_eq!(nodes[1].node.close_channel(&chan_3.3, Some(p2wpkh_script.clone()), None).err().unwrap().err,
+			"Provided shutdown_scriptpubkey does not match the one committed to at channel open");
+		let bogus_shutdown = msgs::Shutdown {
+			channel_id: chan_3.3,
+			scriptpubkey: p2wpkh_script.clone(),
+		};
+		assert_eq!(nodes[0].node.handle_shutdown(&nodes[1].node.get_our_node_id(), &bogus_shutdown).err().unwrap().err,
+			"Got shutdown request with a scriptpubkey which did not match their previous scriptpubkey");
+		close_channel(&nodes[0], &nodes[1], &chan_3.3, chan_3.4, true, None, None);
+
+		// If either side didn't advertise option_upfront_shutdown_script, we neither send nor enforce
+		// the field, though we still close to the script provided at channel open by default.
+		nodes[0].node.peer_connected(&nodes[1].node.get_our_node_id(), &msgs::LocalFeatures::new());
+		nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 100000, 42, Some(p2wpkh_script.clone())).unwrap();
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::SendOpenChannel { ref msg, .. } => {
+				assert!(msg.shutdown_scriptpubkey.is_none());
+				nodes[0].node.close_channel(&msg.temporary_channel_id, None, None).unwrap();
+				check_unfunded_channel_closed(&nodes[0], &msg.temporary_channel_id, 42);
+			},
+			_ => panic!("Unexpected event"),
+		}
+		nodes[1].node.peer_connected(&nodes[0].node.get_our_node_id(), &msgs::LocalFeatures::new());
+		nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 100000, 42, None).unwrap();
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::SendOpenChannel { ref msg, .. } => {
+				// A script from a peer which didn't advertise support is ignored, even a bogus one
+				let mut bogus_open = msgs::OpenChannel::decode(&msg.encode()).unwrap();
+				bogus_open.shutdown_scriptpubkey = Some(nonstandard_script.clone());
+				let accept_msg = nodes[1].node.handle_open_channel(&nodes[0].node.get_our_node_id(), &bogus_open).unwrap();
+				assert!(accept_msg.shutdown_scriptpubkey.is_none());
+				nodes[0].node.close_channel(&msg.temporary_channel_id, None, None).unwrap();
+				check_unfunded_channel_closed(&nodes[0], &msg.temporary_channel_id, 42);
+				nodes[1].node.close_channel(&msg.temporary_channel_id, None, None).unwrap();
+				check_unfunded_channel_closed(&nodes[1], &msg.temporary_channel_id, 0);
+			},
+			_ => panic!("Unexpected event"),
+		}
+		let chan_4 = create_chan_between_nodes(&nodes[0], &nodes[1], Some(p2wpkh_script.clone()));
+		let closing_tx = close_channel(&nodes[0], &nodes[1], &chan_4.3, chan_4.4.clone(), false, Some(p2wsh_script.clone()), None).2;
+		assert_eq!(closing_tx.output.len(), 1);
+		assert_eq!(closing_tx.output[0].script_pubkey, p2wsh_script);
+	}
+
+	#[test]
+	fn unfunded_channel_close_test() {
+		// Tests that a channel which is closed before it was funded, either by us or upon receiving
+		// our counterparty's shutdown, generates a ChannelClosed event.
+		let nodes = create_network(2);
+
+		nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 100000, 42, None).unwrap();
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let temporary_channel_id = match events[0] {
+			Event::SendOpenChannel { ref msg, .. } => {
+				let accept_msg = nodes[1].node.handle_open_channel(&nodes[0].node.get_our_node_id(), msg).unwrap();
+				nodes[0].node.handle_accept_channel(&nodes[1].node.get_our_node_id(), &accept_msg).unwrap();
+				msg.temporary_channel_id
+			},
+			_ => panic!("Unexpected event"),
+		};
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::FundingGenerationReady { .. } => {},
+			_ => panic!("Unexpected event"),
+		}
+
+		let shutdown = nodes[1].node.close_channel(&temporary_channel_id, None, None).unwrap();
+		check_unfunded_channel_closed(&nodes[1], &temporary_channel_id, 0);
+		let (shutdown_resp, closing_signed) = nodes[0].node.handle_shutdown(&nodes[1].node.get_our_node_id(), &shutdown).unwrap();
+		assert!(shutdown_resp.is_none());
+		assert!(closing_signed.is_none());
+		check_unfunded_channel_closed(&nodes[0], &temporary_channel_id, 42);
+		assert!(nodes[0].node.list_channels().is_empty());
+		assert!(nodes[1].node.list_channels().is_empty());
+	}
+
+	#[test]
+	fn closing_fee_negotiation_test() {
+		// Tests that we propose the target feerate passed to close_channel, and that if our
+		// counterparty wants more than our maximum fee we either leave the channel be or force-close
+		// it, as requested.
+		let nodes = create_network(2);
+
+		let chan_1 = create_chan_between_nodes(&nodes[0], &nodes[1], None);
+		let default_closing_tx = close_channel(&nodes[0], &nodes[1], &chan_1.3, chan_1.4.clone(), false, None, None).2;
+		// Both sides use P2WPKH shutdown scripts, so fees are calculated from a (maximum) closing
+		// transaction weight of 602, at the test fee estimator's 1 sat/vbyte by default.
+		let default_fee = chan_1.4.output[0].value - default_closing_tx.output[0].value;
+		assert_eq!(default_fee, 602 / 4);
+
+		let chan_2 = create_chan_between_nodes(&nodes[0], &nodes[1], None);
+		let mut fee_params = ClosingFeeParameters::new();
+		fee_params.target_feerate = Some(3);
+		let closing_tx = close_channel(&nodes[0], &nodes[1], &chan_2.3, chan_2.4.clone(), false, None, Some(fee_params)).2;
+		assert_eq!(chan_2.4.output[0].value - closing_tx.output[0].value, 3 * 602 / 4);
+
+		macro_rules! fail_fee_negotiation {
+			($chan: expr, $fee_params: expr) => { {
+				let shutdown_a = nodes[0].node.close_channel(&$chan.3, None, Some($fee_params)).unwrap();
+				let (shutdown_b, closing_signed_b) = nodes[1].node.handle_shutdown(&nodes[0].node.get_our_node_id(), &shutdown_a).unwrap();
+				assert!(closing_signed_b.is_none());
+				let (empty_a, closing_signed_a) = nodes[0].node.handle_shutdown(&nodes[1].node.get_our_node_id(), &shutdown_b.unwrap()).unwrap();
+				assert!(empty_a.is_none());
+				let closing_signed_a = closing_signed_a.unwrap();
+				assert_eq!(closing_signed_a.fee_satoshis, 10);
+				let closing_signed_b = nodes[1].node.handle_closing_signed(&nodes[0].node.get_our_node_id(), &closing_signed_a).unwrap().unwrap();
+				assert!(closing_signed_b.fee_satoshis > 10);
+				let err = nodes[0].node.handle_closing_signed(&nodes[1].node.get_our_node_id(), &closing_signed_b).err().unwrap();
+				assert_eq!(err.err, "Unable to come to consensus about closing fee, remote wanted more than our maximum fee");
+				err
+			} }
+		}
+		macro_rules! check_force_closed {
+			($chan: expr) => { {
+				{
+					let mut node_txn = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap();
+					assert_eq!(node_txn.len(), 1);
+					assert_eq!(node_txn[0].input[0].prev_hash, $chan.4.txid());
+					node_txn.clear();
+				}
+				let events = nodes[0].node.get_and_clear_pending_events();
+				assert_eq!(events.len(), 2);
+				match events[0] {
+					Event::BroadcastChannelUpdate { .. } => {},
+					_ => panic!("Unexpected event"),
+				}
+				match events[1] {
+					Event::ChannelClosed { channel_id, user_channel_id, closing_fee_satoshis } => {
+						assert_eq!(channel_id, $chan.3);
+						assert_eq!(user_channel_id, 42);
+						assert!(closing_fee_satoshis.is_none());
+					},
+					_ => panic!("Unexpected event"),
+				}
+			} }
+		}
+
+		// By default, we just leave the channel be...
+		let chan_3 = create_chan_between_nodes(&nodes[0], &nodes[1], None);
+		let mut fee_params = ClosingFeeParameters::new();
+		fee_params.max_fee_satoshis = Some(10);
+		assert!(fail_fee_negotiation!(chan_3, fee_params.clone()).msg.is_none());
+		assert!(nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+		assert_eq!(nodes[0].node.list_channels().len(), 1);
+
+		// ...which we can force-close later...
+		nodes[0].node.force_close_channel(&chan_3.3).unwrap();
+		check_force_closed!(chan_3);
+		assert!(nodes[0].node.list_channels().is_empty());
+		assert_eq!(nodes[0].node.force_close_channel(&chan_3.3).err().unwrap().err, "No such channel");
+
+		// ...but can be asked to force-close it immediately instead, telling our counterparty why
+		let chan_4 = create_chan_between_nodes(&nodes[0], &nodes[1], None);
+		fee_params.on_failure = ClosingFeeFailureAction::ForceClose;
+		match fail_fee_negotiation!(chan_4, fee_params).msg {
+			Some(msgs::ErrorAction::SendErrorMessage { msg }) => {
+				assert_eq!(msg.channel_id, chan_4.3);
+				assert_eq!(msg.data, "Unable to come to consensus about closing fee, remote wanted more than our maximum fee");
+			},
+			_ => panic!("Expected an error message for our counterparty"),
+		}
+		check_force_closed!(chan_4);
+		assert!(nodes[0].node.list_channels().is_empty());
+	}
+
 	#[derive(PartialEq)]
 	enum HTLCType { NONE, TIMEOUT, SUCCESS }
 	fn test_txn_broadcast(node: &Node, chan: &(msgs::ChannelUpdate, msgs::ChannelUpdate, Uint256, Transaction), commitment_tx: Option<Transaction>, has_htlc_tx: HTLCType) -> Vec<Transaction> {
@@ -2782,7 +3857,7 @@ mod tests {
 		{
 			let node_txn = test_txn_broadcast(&nodes[1], &chan_1, None, HTLCType::NONE);
 			let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
-			nodes[0].chain_monitor.block_connected_checked(&header, 1, &[&node_txn[0]; 1], &[4; 1]);
+			nodes[0].chain_monitor.block_connected_checked(&header, 100, &[&node_txn[0]; 1], &[4; 1]);
 			assert_eq!(nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().len(), 0);
 		}
 		get_announce_close_broadcast_events(&nodes, 0, 1);
@@ -2797,7 +3872,7 @@ mod tests {
 		{
 			let node_txn = test_txn_broadcast(&nodes[1], &chan_2, None, HTLCType::TIMEOUT);
 			let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
-			nodes[2].chain_monitor.block_connected_checked(&header, 1, &[&node_txn[0]; 1], &[4; 1]);
+			nodes[2].chain_monitor.block_connected_checked(&header, 100, &[&node_txn[0]; 1], &[4; 1]);
 			assert_eq!(nodes[2].tx_broadcaster.txn_broadcasted.lock().unwrap().len(), 0);
 		}
 		get_announce_close_broadcast_events(&nodes, 1, 2);
@@ -2836,7 +3911,7 @@ mod tests {
 			claim_funds!(nodes[3], nodes[2], payment_preimage_1);
 
 			let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
-			nodes[3].chain_monitor.block_connected_checked(&header, 1, &[&node_txn[0]; 1], &[4; 1]);
+			nodes[3].chain_monitor.block_connected_checked(&header, 100, &[&node_txn[0]; 1], &[4; 1]);
 
 			check_preimage_claim(&nodes[3], &node_txn);
 		}
@@ -2849,8 +3924,8 @@ mod tests {
 
 		{
 			let mut header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
-			nodes[3].chain_monitor.block_connected_checked(&header, 1, &Vec::new()[..], &[0; 0]);
-			for i in 2..TEST_FINAL_CLTV - 5 {
+			nodes[3].chain_monitor.block_connected_checked(&header, 100, &Vec::new()[..], &[0; 0]);
+			for i in 101..100 + 1 + TEST_FINAL_CLTV - 5 {
 				header = BlockHeader { version: 0x20000000, prev_blockhash: header.bitcoin_hash(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
 				nodes[3].chain_monitor.block_connected_checked(&header, i, &Vec::new()[..], &[0; 0]);
 			}
@@ -2861,8 +3936,8 @@ mod tests {
 			claim_funds!(nodes[4], nodes[3], payment_preimage_2);
 
 			header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
-			nodes[4].chain_monitor.block_connected_checked(&header, 1, &Vec::new()[..], &[0; 0]);
-			for i in 2..TEST_FINAL_CLTV - 5 {
+			nodes[4].chain_monitor.block_connected_checked(&header, 100, &Vec::new()[..], &[0; 0]);
+			for i in 101..100 + 1 + TEST_FINAL_CLTV - 5 {
 				header = BlockHeader { version: 0x20000000, prev_blockhash: header.bitcoin_hash(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
 				nodes[4].chain_monitor.block_connected_checked(&header, i, &Vec::new()[..], &[0; 0]);
 			}
@@ -2870,7 +3945,7 @@ mod tests {
 			test_txn_broadcast(&nodes[4], &chan_4, None, HTLCType::SUCCESS);
 
 			header = BlockHeader { version: 0x20000000, prev_blockhash: header.bitcoin_hash(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
-			nodes[4].chain_monitor.block_connected_checked(&header, TEST_FINAL_CLTV - 5, &[&node_txn[0]; 1], &[4; 1]);
+			nodes[4].chain_monitor.block_connected_checked(&header, 100 + 1 + TEST_FINAL_CLTV - 5, &[&node_txn[0]; 1], &[4; 1]);
 
 			check_preimage_claim(&nodes[4], &node_txn);
 		}
@@ -2890,7 +3965,7 @@ mod tests {
 
 		{
 			let mut header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
-			nodes[1].chain_monitor.block_connected_checked(&header, 1, &vec![&revoked_local_txn[0]; 1], &[4; 1]);
+			nodes[1].chain_monitor.block_connected_checked(&header, 100, &vec![&revoked_local_txn[0]; 1], &[4; 1]);
 			{
 				let mut node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap();
 				assert_eq!(node_txn.len(), 1);
@@ -2902,10 +3977,10 @@ mod tests {
 				node_txn.clear();
 			}
 
-			nodes[0].chain_monitor.block_connected_checked(&header, 1, &vec![&revoked_local_txn[0]; 1], &[4; 0]);
+			nodes[0].chain_monitor.block_connected_checked(&header, 100, &vec![&revoked_local_txn[0]; 1], &[4; 0]);
 			let node_txn = test_txn_broadcast(&nodes[0], &chan_5, Some(revoked_local_txn[0].clone()), HTLCType::TIMEOUT);
 			header = BlockHeader { version: 0x20000000, prev_blockhash: header.bitcoin_hash(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
-			nodes[1].chain_monitor.block_connected_checked(&header, 1, &[&node_txn[1]; 1], &[4; 1]);
+			nodes[1].chain_monitor.block_connected_checked(&header, 100, &[&node_txn[1]; 1], &[4; 1]);
 
 			//TODO: At this point nodes[1] should claim the revoked HTLC-Timeout output, but that's
 			//not yet implemented in ChannelMonitor
@@ -2920,4 +3995,1076 @@ mod tests {
 			assert_eq!(node.chan_monitor.added_monitors.lock().unwrap().len(), 0);
 		}
 	}
+
+	#[test]
+	fn watchtower_test() {
+		// Back up nodes[1]'s monitor updates to a tower through a WatchtowerClient, then check
+		// that the tower broadcasts a valid justice transaction when nodes[0] broadcasts a
+		// revoked commitment transaction, rebroadcasting it until it's ANTI_REORG_DELAY blocks
+		// deep and handling reorgs of either transaction.
+		let nodes = create_network(2);
+		create_announced_chan_between_nodes(&nodes, 0, 1);
+
+		let tower_chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet));
+		let tower_broadcaster = Arc::new(test_utils::TestBroadcaster{txn_broadcasted: Mutex::new(Vec::new())});
+		let tower = WatchtowerServer::new(tower_chain_monitor.clone(), tower_broadcaster.clone());
+		let client_chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet));
+		let client_broadcaster = Arc::new(test_utils::TestBroadcaster{txn_broadcasted: Mutex::new(Vec::new())});
+		let client_feeest = Arc::new(test_utils::TestFeeEstimator { sat_per_vbyte: 1 });
+		let client = WatchtowerClient::new(Arc::new(test_utils::TestChannelMonitor::new(client_chain_monitor, client_broadcaster, client_feeest.clone())), tower, client_feeest);
+
+		let monitor = nodes[1].node.channel_state.lock().unwrap().by_id.iter().next().unwrap().1.channel_monitor().clone();
+		assert_eq!(monitor.get_latest_update_id(), 0);
+		assert!(client.add_monitor(monitor.get_funding_txo().unwrap(), monitor).is_ok());
+
+		// Replay any ChannelMonitorUpdates nodes[1] has generated since we last updated the tower
+		let mut latest_update_id = 0;
+		macro_rules! update_tower {
+			() => {
+				for &(funding_txo, ref update) in nodes[1].chan_monitor.monitor_updates.lock().unwrap().iter() {
+					if update.update_id > latest_update_id {
+						assert!(client.update_monitor(funding_txo, update.clone()).is_ok());
+						latest_update_id = update.update_id;
+					}
+				}
+			}
+		}
+
+		// A pending HTLC which will be revoked:
+		let payment_preimage = route_payment(&nodes[0], &vec!(&nodes[1])[..], 3000000).0;
+		update_tower!();
+		let revoked_local_txn = nodes[0].node.channel_state.lock().unwrap().by_id.iter().next().unwrap().1.last_local_commitment_txn.clone();
+		claim_payment(&nodes[0], &vec!(&nodes[1])[..], payment_preimage);
+		update_tower!();
+
+		macro_rules! header {
+			($nonce: expr) => {
+				BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: $nonce }
+			}
+		}
+		// Checks the tower broadcast exactly the given justice transaction (or nothing, if None)
+		macro_rules! check_tower_broadcast {
+			($justice_tx: expr) => {
+				{
+					let mut tower_txn = tower_broadcaster.txn_broadcasted.lock().unwrap();
+					match $justice_tx {
+						Some(justice_tx) => {
+							assert_eq!(tower_txn.len(), 1);
+							assert_eq!(tower_txn[0], *justice_tx);
+						},
+						None => assert!(tower_txn.is_empty()),
+					}
+					tower_txn.clear();
+				}
+			}
+		}
+
+		// The current commitment transaction isn't revoked, so the tower shouldn't do anything
+		let current_local_txn = nodes[0].node.channel_state.lock().unwrap().by_id.iter().next().unwrap().1.last_local_commitment_txn.clone();
+		tower_chain_monitor.block_connected_checked(&header!(1), 1, &[&current_local_txn[0]; 1], &[1; 1]);
+		check_tower_broadcast!(None::<&Transaction>);
+
+		tower_chain_monitor.block_connected_checked(&header!(2), 2, &[&revoked_local_txn[0]; 1], &[1; 1]);
+		let justice_tx = {
+			let mut tower_txn = tower_broadcaster.txn_broadcasted.lock().unwrap();
+			assert_eq!(tower_txn.len(), 1);
+			assert_eq!(tower_txn[0].input[0].prev_hash, revoked_local_txn[0].txid());
+
+			let mut funding_tx_map = HashMap::new();
+			funding_tx_map.insert(revoked_local_txn[0].txid(), revoked_local_txn[0].clone());
+			tower_txn[0].verify(&funding_tx_map).unwrap();
+			// The justice transaction pays a fee at 1 sat/vbyte (give or take the one-byte difference
+			// in signature lengths between the signing used to estimate its weight and the final one)
+			let input_value: u64 = tower_txn[0].input.iter().map(|txin| revoked_local_txn[0].output[txin.prev_index as usize].value).sum();
+			let fee = input_value - tower_txn[0].output[0].value;
+			let vsize = tower_txn[0].get_weight() / 4;
+			assert!(fee + 1 >= vsize && fee <= vsize + 1);
+
+			// ...and it should be exactly what nodes[1] would have broadcast itself
+			nodes[1].chain_monitor.block_connected_checked(&header!(2), 2, &[&revoked_local_txn[0]; 1], &[1; 1]);
+			let mut node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap();
+			assert_eq!(node_txn.len(), 1);
+			assert_eq!(node_txn[0], tower_txn[0]);
+			node_txn.clear();
+			tower_txn.remove(0)
+		};
+
+		// Until the justice transaction confirms, the tower rebroadcasts it each block
+		tower_chain_monitor.block_connected_checked(&header!(3), 3, &[], &[]);
+		check_tower_broadcast!(Some(&justice_tx));
+
+		// If the revoked commitment transaction is reorged out, the tower goes back to waiting for
+		// it, and matches it again once it's reconfirmed.
+		tower_chain_monitor.block_disconnected(&header!(3));
+		tower_chain_monitor.block_disconnected(&header!(2));
+		tower_chain_monitor.block_connected_checked(&header!(102), 2, &[], &[]);
+		check_tower_broadcast!(None::<&Transaction>);
+		tower_chain_monitor.block_connected_checked(&header!(103), 3, &[&revoked_local_txn[0]; 1], &[1; 1]);
+		check_tower_broadcast!(Some(&justice_tx));
+
+		// Once the justice transaction confirms we stop rebroadcasting it...
+		tower_chain_monitor.block_connected_checked(&header!(104), 4, &[&justice_tx; 1], &[1; 1]);
+		check_tower_broadcast!(None::<&Transaction>);
+		tower_chain_monitor.block_connected_checked(&header!(105), 5, &[], &[]);
+		check_tower_broadcast!(None::<&Transaction>);
+
+		// ...unless it's reorged out
+		tower_chain_monitor.block_disconnected(&header!(105));
+		tower_chain_monitor.block_disconnected(&header!(104));
+		tower_chain_monitor.block_connected_checked(&header!(204), 4, &[], &[]);
+		check_tower_broadcast!(Some(&justice_tx));
+
+		// Once it's ANTI_REORG_DELAY blocks deep the tower forgets about it, so even a (deep) reorg
+		// doesn't cause it to be rebroadcast.
+		tower_chain_monitor.block_connected_checked(&header!(205), 5, &[&justice_tx; 1], &[1; 1]);
+		for height in 6..5 + ANTI_REORG_DELAY + 1 {
+			tower_chain_monitor.block_connected_checked(&header!(200 + height), height, &[], &[]);
+			check_tower_broadcast!(None::<&Transaction>);
+		}
+		for height in (5..5 + ANTI_REORG_DELAY + 1).rev() {
+			tower_chain_monitor.block_disconnected(&header!(200 + height));
+		}
+		tower_chain_monitor.block_connected_checked(&header!(305), 5, &[], &[]);
+		check_tower_broadcast!(None::<&Transaction>);
+	}
+
+	#[test]
+	fn monitor_update_ordering_test() {
+		// Tests that each ChannelMonitorUpdate gets the next update_id and that out-of-order (or
+		// replayed) updates are rejected.
+		let nodes = create_network(2);
+		create_announced_chan_between_nodes(&nodes, 0, 1);
+		send_payment(&nodes[0], &vec!(&nodes[1])[..], 1000000);
+
+		let updates = nodes[1].chan_monitor.monitor_updates.lock().unwrap().clone();
+		assert!(!updates.is_empty());
+		for (i, &(_, ref update)) in updates.iter().enumerate() {
+			assert_eq!(update.update_id, i as u64 + 1);
+		}
+
+		let (funding_txo, ref latest_update) = *updates.last().unwrap();
+		match nodes[1].chan_monitor.simple_monitor.update_monitor(funding_txo, latest_update.clone()) {
+			Err(ChannelMonitorUpdateErr::PermanentFailure) => {},
+			_ => panic!("Replayed ChannelMonitorUpdate was accepted"),
+		}
+		let mut skipped_update = latest_update.clone();
+		skipped_update.update_id += 2;
+		match nodes[1].chan_monitor.simple_monitor.update_monitor(funding_txo, skipped_update) {
+			Err(ChannelMonitorUpdateErr::PermanentFailure) => {},
+			_ => panic!("Out-of-order ChannelMonitorUpdate was accepted"),
+		}
+
+		// The rejected updates shouldn't have broken anything
+		send_payment(&nodes[0], &vec!(&nodes[1])[..], 1000000);
+		assert!(nodes[1].chan_monitor.monitor_updates.lock().unwrap().len() > updates.len());
+	}
+
+	#[test]
+	fn monitor_update_in_progress_test() {
+		// Tests that while a ChannelMonitorUpdate is in progress we hold back the messages and HTLC
+		// forwards which rely on it, releasing them (in order) once update_completed is called.
+		let nodes = create_network(2);
+		let chan = create_announced_chan_between_nodes(&nodes, 0, 1);
+		let funding_txo = nodes[0].node.channel_state.lock().unwrap().by_id.get(&chan.2).unwrap().get_funding_txo().unwrap();
+
+		macro_rules! get_in_progress_update_id {
+			($node: expr) => {
+				{
+					let mut added_monitors = $node.chan_monitor.added_monitors.lock().unwrap();
+					assert_eq!(added_monitors.len(), 1);
+					let update_id = added_monitors[0].1;
+					added_monitors.clear();
+					update_id
+				}
+			}
+		}
+
+		// nodes[0] doesn't send a new HTLC until its monitor has the new remote commitment transaction
+		let (our_payment_preimage, our_payment_hash) = get_payment_preimage_hash();
+		let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		*nodes[0].chan_monitor.update_ret.lock().unwrap() = Err(ChannelMonitorUpdateErr::InProgress);
+		nodes[0].node.send_payment(route, our_payment_hash).unwrap();
+		let update_id = get_in_progress_update_id!(nodes[0]);
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+
+		// Completing an earlier update doesn't release anything
+		nodes[0].node.update_completed(&funding_txo, update_id - 1);
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+
+		*nodes[0].chan_monitor.update_ret.lock().unwrap() = Ok(());
+		nodes[0].node.update_completed(&funding_txo, update_id);
+		let mut events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let payment_event = match events.remove(0) {
+			Event::SendCommitmentUpdate { node_id, updates } => {
+				assert_eq!(updates.update_add_htlcs.len(), 1);
+				assert!(updates.update_fulfill_htlcs.is_empty() && updates.update_fail_htlcs.is_empty());
+				SendEvent { node_id: node_id, msgs: updates.update_add_htlcs, commitment_msg: updates.commitment_signed }
+			},
+			_ => panic!("Unexpected event"),
+		};
+		pass_along_route(&nodes[0], payment_event, &[&nodes[1]], 1000000, our_payment_hash);
+		claim_payment(&nodes[0], &[&nodes[1]], our_payment_preimage);
+
+		// nodes[1] mustn't revoke its old state until its monitor has the new one...
+		let (our_payment_preimage, our_payment_hash) = get_payment_preimage_hash();
+		let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		nodes[0].node.send_payment(route, our_payment_hash).unwrap();
+		nodes[0].chan_monitor.added_monitors.lock().unwrap().clear();
+		let mut events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let payment_event = SendEvent::from_event(events.remove(0));
+
+		*nodes[1].chan_monitor.update_ret.lock().unwrap() = Err(ChannelMonitorUpdateErr::InProgress);
+		nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]).unwrap();
+		let resps = nodes[1].node.handle_commitment_signed(&nodes[0].node.get_our_node_id(), &payment_event.commitment_msg).unwrap();
+		assert!(resps.0.is_none() && resps.1.is_none());
+		let update_id = get_in_progress_update_id!(nodes[1]);
+		assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
+
+		*nodes[1].chan_monitor.update_ret.lock().unwrap() = Ok(());
+		nodes[1].node.update_completed(&funding_txo, update_id);
+		let mut events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 2);
+		let revoke_and_ack = match events.remove(0) {
+			Event::SendRevokeAndACK { node_id, msg } => {
+				assert_eq!(node_id, nodes[0].node.get_our_node_id());
+				msg
+			},
+			_ => panic!("Unexpected event"),
+		};
+		let commitment_signed = match events.remove(0) {
+			Event::SendCommitmentUpdate { node_id, updates } => {
+				assert_eq!(node_id, nodes[0].node.get_our_node_id());
+				assert!(updates.update_add_htlcs.is_empty() && updates.update_fulfill_htlcs.is_empty() && updates.update_fail_htlcs.is_empty());
+				updates.commitment_signed
+			},
+			_ => panic!("Unexpected event"),
+		};
+		assert!(nodes[0].node.handle_revoke_and_ack(&nodes[1].node.get_our_node_id(), &revoke_and_ack).unwrap().is_none());
+		let prev_revoke_and_ack = nodes[0].node.handle_commitment_signed(&nodes[1].node.get_our_node_id(), &commitment_signed).unwrap();
+		assert!(prev_revoke_and_ack.1.is_none());
+		nodes[0].chan_monitor.added_monitors.lock().unwrap().clear();
+
+		// ...and doesn't forward (here, receive) the HTLC until its monitor knows it was committed to
+		*nodes[1].chan_monitor.update_ret.lock().unwrap() = Err(ChannelMonitorUpdateErr::InProgress);
+		assert!(nodes[1].node.handle_revoke_and_ack(&nodes[0].node.get_our_node_id(), &prev_revoke_and_ack.0.unwrap()).unwrap().is_none());
+		let update_id = get_in_progress_update_id!(nodes[1]);
+		assert!(nodes[1].node.get_and_clear_pending_events().is_empty());
+		assert!(nodes[1].node.channel_state.lock().unwrap().forward_htlcs.is_empty());
+
+		*nodes[1].chan_monitor.update_ret.lock().unwrap() = Ok(());
+		nodes[1].node.update_completed(&funding_txo, update_id);
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::PendingHTLCsForwardable { .. } => { },
+			_ => panic!("Unexpected event"),
+		};
+		nodes[1].node.channel_state.lock().unwrap().next_forward = Instant::now();
+		nodes[1].node.process_pending_htlc_forward();
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::PaymentReceived { ref payment_hash, amt } => {
+				assert_eq!(our_payment_hash, *payment_hash);
+				assert_eq!(amt, 1000000);
+			},
+			_ => panic!("Unexpected event"),
+		};
+		claim_payment(&nodes[0], &[&nodes[1]], our_payment_preimage);
+
+		// If we get a commitment_signed while our own commitment update is held, our
+		// revoke_and_ack for it must be sent after the commitment update it was generated after
+		let (payment_preimage_0, payment_hash_0) = get_payment_preimage_hash();
+		let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		*nodes[0].chan_monitor.update_ret.lock().unwrap() = Err(ChannelMonitorUpdateErr::InProgress);
+		nodes[0].node.send_payment(route, payment_hash_0).unwrap();
+		get_in_progress_update_id!(nodes[0]);
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+
+		let (payment_preimage_1, payment_hash_1) = get_payment_preimage_hash();
+		let route = nodes[1].router.get_route(&nodes[0].node.get_our_node_id(), &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		nodes[1].node.send_payment(route, payment_hash_1).unwrap();
+		nodes[1].chan_monitor.added_monitors.lock().unwrap().clear();
+		let mut events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let payment_event = SendEvent::from_event(events.remove(0));
+		nodes[0].node.handle_update_add_htlc(&nodes[1].node.get_our_node_id(), &payment_event.msgs[0]).unwrap();
+		let resps = nodes[0].node.handle_commitment_signed(&nodes[1].node.get_our_node_id(), &payment_event.commitment_msg).unwrap();
+		assert!(resps.0.is_none() && resps.1.is_none());
+		let update_id = get_in_progress_update_id!(nodes[0]);
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+
+		*nodes[0].chan_monitor.update_ret.lock().unwrap() = Ok(());
+		nodes[0].node.update_completed(&funding_txo, update_id);
+		let mut events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 2);
+		let payment_event = match events.remove(0) {
+			Event::SendCommitmentUpdate { node_id, updates } => {
+				assert_eq!(updates.update_add_htlcs.len(), 1);
+				SendEvent { node_id: node_id, msgs: updates.update_add_htlcs, commitment_msg: updates.commitment_signed }
+			},
+			_ => panic!("Unexpected event"),
+		};
+		let revoke_and_ack = match events.remove(0) {
+			Event::SendRevokeAndACK { node_id, msg } => {
+				assert_eq!(node_id, nodes[1].node.get_our_node_id());
+				msg
+			},
+			_ => panic!("Unexpected event"),
+		};
+
+		nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]).unwrap();
+		let resps_1 = nodes[1].node.handle_commitment_signed(&nodes[0].node.get_our_node_id(), &payment_event.commitment_msg).unwrap();
+		assert!(resps_1.1.is_none());
+		let commitment_update_1 = nodes[1].node.handle_revoke_and_ack(&nodes[0].node.get_our_node_id(), &revoke_and_ack).unwrap().unwrap();
+		let commitment_update_0 = nodes[0].node.handle_revoke_and_ack(&nodes[1].node.get_our_node_id(), &resps_1.0.unwrap()).unwrap().unwrap();
+		let resps_0 = nodes[0].node.handle_commitment_signed(&nodes[1].node.get_our_node_id(), &commitment_update_1.commitment_signed).unwrap();
+		assert!(resps_0.1.is_none());
+		let resps_1 = nodes[1].node.handle_commitment_signed(&nodes[0].node.get_our_node_id(), &commitment_update_0.commitment_signed).unwrap();
+		assert!(resps_1.1.is_none());
+		assert!(nodes[0].node.handle_revoke_and_ack(&nodes[1].node.get_our_node_id(), &resps_1.0.unwrap()).unwrap().is_none());
+		assert!(nodes[1].node.handle_revoke_and_ack(&nodes[0].node.get_our_node_id(), &resps_0.0.unwrap()).unwrap().is_none());
+		nodes[0].chan_monitor.added_monitors.lock().unwrap().clear();
+		nodes[1].chan_monitor.added_monitors.lock().unwrap().clear();
+
+		for &(node, payment_hash) in [(&nodes[0], payment_hash_1), (&nodes[1], payment_hash_0)].iter() {
+			let events = node.node.get_and_clear_pending_events();
+			assert_eq!(events.len(), 1);
+			match events[0] {
+				Event::PendingHTLCsForwardable { .. } => { },
+				_ => panic!("Unexpected event"),
+			};
+			node.node.channel_state.lock().unwrap().next_forward = Instant::now();
+			node.node.process_pending_htlc_forward();
+			let events = node.node.get_and_clear_pending_events();
+			assert_eq!(events.len(), 1);
+			match events[0] {
+				Event::PaymentReceived { payment_hash: ref received_hash, amt } => {
+					assert_eq!(payment_hash, *received_hash);
+					assert_eq!(amt, 1000000);
+				},
+				_ => panic!("Unexpected event"),
+			};
+		}
+		claim_payment(&nodes[0], &[&nodes[1]], payment_preimage_0);
+		claim_payment(&nodes[1], &[&nodes[0]], payment_preimage_1);
+
+		// HTLCs we try to send while frozen go in the holding cell, and are sent once the freeze is
+		// lifted if we aren't otherwise waiting on our counterparty's revoke_and_ack
+		let (payment_preimage_0, payment_hash_0) = get_payment_preimage_hash();
+		let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		nodes[0].node.send_payment(route, payment_hash_0).unwrap();
+		nodes[0].chan_monitor.added_monitors.lock().unwrap().clear();
+		let mut events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let payment_event = SendEvent::from_event(events.remove(0));
+		nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]).unwrap();
+		let resps_1 = nodes[1].node.handle_commitment_signed(&nodes[0].node.get_our_node_id(), &payment_event.commitment_msg).unwrap();
+		nodes[1].chan_monitor.added_monitors.lock().unwrap().clear();
+
+		*nodes[0].chan_monitor.update_ret.lock().unwrap() = Err(ChannelMonitorUpdateErr::InProgress);
+		assert!(nodes[0].node.handle_revoke_and_ack(&nodes[1].node.get_our_node_id(), &resps_1.0.unwrap()).unwrap().is_none());
+		let update_id = get_in_progress_update_id!(nodes[0]);
+
+		let (payment_preimage_1, payment_hash_1) = get_payment_preimage_hash();
+		let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		nodes[0].node.send_payment(route, payment_hash_1).unwrap();
+		assert!(nodes[0].chan_monitor.added_monitors.lock().unwrap().is_empty());
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+
+		*nodes[0].chan_monitor.update_ret.lock().unwrap() = Ok(());
+		nodes[0].node.update_completed(&funding_txo, update_id);
+		assert_eq!(nodes[0].chan_monitor.added_monitors.lock().unwrap().len(), 1);
+		nodes[0].chan_monitor.added_monitors.lock().unwrap().clear();
+		let mut events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let payment_event = match events.remove(0) {
+			Event::SendCommitmentUpdate { node_id, updates } => {
+				assert_eq!(node_id, nodes[1].node.get_our_node_id());
+				assert_eq!(updates.update_add_htlcs.len(), 1);
+				SendEvent { node_id: node_id, msgs: updates.update_add_htlcs, commitment_msg: updates.commitment_signed }
+			},
+			_ => panic!("Unexpected event"),
+		};
+
+		let resps_0 = nodes[0].node.handle_commitment_signed(&nodes[1].node.get_our_node_id(), &resps_1.1.unwrap()).unwrap();
+		assert!(resps_0.1.is_none());
+		nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]).unwrap();
+		let resps_1 = nodes[1].node.handle_commitment_signed(&nodes[0].node.get_our_node_id(), &payment_event.commitment_msg).unwrap();
+		assert!(resps_1.1.is_none());
+		let commitment_update = nodes[1].node.handle_revoke_and_ack(&nodes[0].node.get_our_node_id(), &resps_0.0.unwrap()).unwrap().unwrap();
+		assert!(nodes[0].node.handle_revoke_and_ack(&nodes[1].node.get_our_node_id(), &resps_1.0.unwrap()).unwrap().is_none());
+		let resps_0 = nodes[0].node.handle_commitment_signed(&nodes[1].node.get_our_node_id(), &commitment_update.commitment_signed).unwrap();
+		assert!(resps_0.1.is_none());
+		assert!(nodes[1].node.handle_revoke_and_ack(&nodes[0].node.get_our_node_id(), &resps_0.0.unwrap()).unwrap().is_none());
+		nodes[0].chan_monitor.added_monitors.lock().unwrap().clear();
+		nodes[1].chan_monitor.added_monitors.lock().unwrap().clear();
+
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::PendingHTLCsForwardable { .. } => { },
+			_ => panic!("Unexpected event"),
+		};
+		nodes[1].node.channel_state.lock().unwrap().next_forward = Instant::now();
+		nodes[1].node.process_pending_htlc_forward();
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 2);
+		for (event, payment_hash) in events.iter().zip([payment_hash_0, payment_hash_1].iter()) {
+			match *event {
+				Event::PaymentReceived { payment_hash: ref received_hash, amt } => {
+					assert_eq!(*payment_hash, *received_hash);
+					assert_eq!(amt, 1000000);
+				},
+				_ => panic!("Unexpected event"),
+			};
+		}
+		claim_payment(&nodes[0], &[&nodes[1]], payment_preimage_0);
+		claim_payment(&nodes[0], &[&nodes[1]], payment_preimage_1);
+
+		// Further updates don't need update_completed
+		send_payment(&nodes[0], &vec!(&nodes[1])[..], 1000000);
+	}
+
+	#[test]
+	fn monitor_update_permanent_failure_test() {
+		// Tests that if a ChannelMonitorUpdate fails permanently we force-close the channel,
+		// broadcasting our latest commitment transaction and telling our counterparty why, both
+		// when handling one of their messages and otherwise.
+		let nodes = create_network(3);
+		let chan_1 = create_announced_chan_between_nodes(&nodes, 0, 1);
+		let chan_2 = create_announced_chan_between_nodes(&nodes, 0, 2);
+
+		macro_rules! check_force_closed {
+			($node: expr, $chan: expr) => { {
+				{
+					let mut node_txn = $node.tx_broadcaster.txn_broadcasted.lock().unwrap();
+					assert_eq!(node_txn.len(), 1);
+					assert_eq!(node_txn[0].input[0].prev_hash, $chan.3.txid());
+					node_txn.clear();
+				}
+				let mut events = $node.node.get_and_clear_pending_events();
+				assert!(events.len() >= 2);
+				match events.remove(0) {
+					Event::BroadcastChannelUpdate { .. } => {},
+					_ => panic!("Unexpected event"),
+				}
+				match events.remove(0) {
+					Event::ChannelClosed { channel_id, closing_fee_satoshis, .. } => {
+						assert_eq!(channel_id, $chan.2);
+						assert!(closing_fee_satoshis.is_none());
+					},
+					_ => panic!("Unexpected event"),
+				}
+				assert!($node.node.list_channels().iter().all(|chan| chan.channel_id != $chan.2));
+				events
+			} }
+		}
+
+		// nodes[1] fails to persist the new local commitment transaction from nodes[0]'s
+		// commitment_signed, so errors back instead of revoking its old state
+		let (_, our_payment_hash) = get_payment_preimage_hash();
+		let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		nodes[0].node.send_payment(route, our_payment_hash).unwrap();
+		let mut events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let payment_event = SendEvent::from_event(events.remove(0));
+
+		*nodes[1].chan_monitor.update_ret.lock().unwrap() = Err(ChannelMonitorUpdateErr::PermanentFailure);
+		nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]).unwrap();
+		let err = nodes[1].node.handle_commitment_signed(&nodes[0].node.get_our_node_id(), &payment_event.commitment_msg).err().unwrap();
+		assert_eq!(err.err, "ChannelMonitor update failed permanently, force-closed channel");
+		match err.msg {
+			Some(msgs::ErrorAction::SendErrorMessage { msg }) => {
+				assert_eq!(msg.channel_id, chan_1.2);
+				assert_eq!(msg.data, err.err);
+			},
+			_ => panic!("Unexpected error action"),
+		}
+		assert!(check_force_closed!(nodes[1], chan_1).is_empty());
+		assert!(nodes[1].node.channel_state.lock().unwrap().short_to_id.is_empty());
+
+		// nodes[0] fails to persist the new remote commitment transaction when sending a payment,
+		// so doesn't send it, telling nodes[2] why via an event instead
+		let (_, our_payment_hash) = get_payment_preimage_hash();
+		let route = nodes[0].router.get_route(&nodes[2].node.get_our_node_id(), &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		*nodes[0].chan_monitor.update_ret.lock().unwrap() = Err(ChannelMonitorUpdateErr::PermanentFailure);
+		assert_eq!(nodes[0].node.send_payment(route, our_payment_hash).err().unwrap().err, "ChannelMonitor update failed permanently, force-closed channel");
+		let events = check_force_closed!(nodes[0], chan_2);
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::SendErrorMessage { ref node_id, ref msg } => {
+				assert_eq!(*node_id, nodes[2].node.get_our_node_id());
+				assert_eq!(msg.channel_id, chan_2.2);
+			},
+			_ => panic!("Unexpected event"),
+		}
+		assert!(!nodes[0].node.channel_state.lock().unwrap().claimable_htlcs.contains_key(&our_payment_hash));
+		assert_eq!(nodes[0].node.list_channels().len(), 1);
+	}
+
+	#[test]
+	#[should_panic(expected = "update_completed must not be called from within ManyChannelMonitor::add_monitor/update_monitor")]
+	fn monitor_update_completed_reentrancy_test() {
+		// Calling update_completed from within update_monitor would deadlock, so we panic instead
+		let nodes = create_network(2);
+		let chan = create_announced_chan_between_nodes(&nodes, 0, 1);
+		let funding_txo = nodes[0].node.channel_state.lock().unwrap().by_id.get(&chan.2).unwrap().get_funding_txo().unwrap();
+		let node = Arc::downgrade(&nodes[0].node);
+		*nodes[0].chan_monitor.call_hook.lock().unwrap() = Some(Box::new(move || {
+			node.upgrade().unwrap().update_completed(&funding_txo, 1);
+		}));
+		send_payment(&nodes[0], &vec!(&nodes[1])[..], 1000000);
+	}
+
+	fn fail_attempt_at_node_1(nodes: &Vec<Node>, payment_event: SendEvent, failing_short_channel_id: u64) {
+		// Delivers the HTLC to nodes[1], which fails to forward it over the given channel
+		nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]).unwrap();
+		let revoke_and_ack = nodes[1].node.handle_commitment_signed(&nodes[0].node.get_our_node_id(), &payment_event.commitment_msg).unwrap();
+		assert!(nodes[0].node.handle_revoke_and_ack(&nodes[1].node.get_our_node_id(), &revoke_and_ack.0.unwrap()).unwrap().is_none());
+		let prev_revoke_and_ack = nodes[0].node.handle_commitment_signed(&nodes[1].node.get_our_node_id(), &revoke_and_ack.1.unwrap()).unwrap();
+		assert!(nodes[1].node.handle_revoke_and_ack(&nodes[0].node.get_our_node_id(), &prev_revoke_and_ack.0.unwrap()).unwrap().is_none());
+
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::PendingHTLCsForwardable { .. } => { },
+			_ => panic!("Unexpected event"),
+		};
+
+		nodes[1].node.channel_state.lock().unwrap().next_forward = Instant::now();
+		nodes[1].node.process_pending_htlc_forward();
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let (update_fail, commitment_signed) = match events[0] {
+			Event::SendFailHTLC { ref node_id, ref msg, ref commitment_msg } => {
+				assert_eq!(*node_id, nodes[0].node.get_our_node_id());
+				(msg.clone(), commitment_msg.clone())
+			},
+			_ => panic!("Unexpected event"),
+		};
+
+		match nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &update_fail).unwrap() {
+			Some(msgs::HTLCFailChannelUpdate::ChannelUpdateMessage { ref msg }) => {
+				assert_eq!(msg.contents.short_channel_id, failing_short_channel_id);
+			},
+			_ => panic!("Expected a channel_update for the failing channel"),
+		}
+		let revoke_and_commit = nodes[0].node.handle_commitment_signed(&nodes[1].node.get_our_node_id(), &commitment_signed).unwrap();
+		assert!(nodes[1].node.handle_revoke_and_ack(&nodes[0].node.get_our_node_id(), &revoke_and_commit.0.unwrap()).unwrap().is_none());
+		let revoke_and_ack = nodes[1].node.handle_commitment_signed(&nodes[0].node.get_our_node_id(), &revoke_and_commit.1.unwrap()).unwrap();
+		assert!(revoke_and_ack.1.is_none());
+		assert!(nodes[0].node.handle_revoke_and_ack(&nodes[1].node.get_our_node_id(), &revoke_and_ack.0.unwrap()).unwrap().is_none());
+		nodes[0].chan_monitor.added_monitors.lock().unwrap().clear();
+		nodes[1].chan_monitor.added_monitors.lock().unwrap().clear();
+	}
+
+	#[test]
+	fn payment_tracker_retry_test() {
+		// Tests that PaymentTracker retries a payment which failed at an intermediate hop over a
+		// route which avoids the failing channel, and only hands us the final result.
+		let nodes = create_network(5);
+
+		create_announced_chan_between_nodes(&nodes, 0, 1);
+		// nodes[1] has no balance in this channel, so cannot forward anything over it
+		let chan_2 = create_announced_chan_between_nodes(&nodes, 2, 1);
+		// A longer (and thus more expensive) route which does work
+		create_announced_chan_between_nodes(&nodes, 0, 3);
+		create_announced_chan_between_nodes(&nodes, 3, 4);
+		create_announced_chan_between_nodes(&nodes, 4, 2);
+
+		let tracker = PaymentTracker::new(nodes[0].node.clone(), nodes[0].router.clone(), nodes[0].node.clone(), 3, Duration::from_secs(60));
+
+		let our_payment_preimage = unsafe { [PAYMENT_COUNT; 32] };
+		unsafe { PAYMENT_COUNT += 1 };
+		let our_payment_hash = {
+			let mut sha = Sha256::new();
+			sha.input(&our_payment_preimage[..]);
+			let mut ret = [0; 32];
+			sha.result(&mut ret);
+			ret
+		};
+
+		let payment_id = tracker.send_payment(nodes[2].node.get_our_node_id(), Vec::new(), 1000000, TEST_FINAL_CLTV, our_payment_hash).unwrap();
+		assert_eq!(tracker.send_payment(nodes[2].node.get_our_node_id(), Vec::new(), 1000000, TEST_FINAL_CLTV, our_payment_hash).err().unwrap().err, "Payment with the given hash is already in progress");
+		assert_eq!(tracker.pending_payment_count(), 1);
+		{
+			let mut added_monitors = nodes[0].chan_monitor.added_monitors.lock().unwrap();
+			assert_eq!(added_monitors.len(), 1);
+			added_monitors.clear();
+		}
+
+		// First attempt goes via nodes[1]...
+		let mut events = tracker.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let payment_event = SendEvent::from_event(events.remove(0));
+		assert_eq!(payment_event.node_id, nodes[1].node.get_our_node_id());
+		fail_attempt_at_node_1(&nodes, payment_event, chan_2.0.contents.short_channel_id);
+
+		// ...so the PaymentFailed is swallowed and the payment retried via nodes[3] and nodes[4]
+		let mut events = tracker.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		{
+			let mut added_monitors = nodes[0].chan_monitor.added_monitors.lock().unwrap();
+			assert_eq!(added_monitors.len(), 1);
+			added_monitors.clear();
+		}
+		let payment_event = SendEvent::from_event(events.remove(0));
+		pass_along_route(&nodes[0], payment_event, &[&nodes[3], &nodes[4], &nodes[2]], 1000000, our_payment_hash);
+		claim_payment_along_route(&nodes[0], &[&nodes[3], &nodes[4], &nodes[2]], our_payment_preimage);
+
+		let events = tracker.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::TrackedPaymentSent { payment_id: id, payment_preimage, attempts } => {
+				assert_eq!(id, payment_id);
+				assert_eq!(payment_preimage, our_payment_preimage);
+				assert_eq!(attempts, 2);
+			},
+			_ => panic!("Unexpected event"),
+		}
+		assert_eq!(tracker.pending_payment_count(), 0);
+		assert_eq!(tracker.send_payment(nodes[2].node.get_our_node_id(), Vec::new(), 1000000, TEST_FINAL_CLTV, our_payment_hash).err().unwrap().err, "Payment with the given hash has already been completed");
+	}
+
+	#[test]
+	fn payment_tracker_timeout_test() {
+		// Tests that once a payment times out, its in-flight attempt is no longer retried if it
+		// fails, but that we wait for it to resolve (keeping its payment_hash reserved) before
+		// reporting the payment's final result, as it may yet succeed.
+		let nodes = create_network(5);
+		create_announced_chan_between_nodes(&nodes, 0, 1);
+		// nodes[1] has no balance in this channel, so cannot forward anything over it
+		let chan_2 = create_announced_chan_between_nodes(&nodes, 2, 1);
+		// A route to nodes[2] we would otherwise retry over
+		create_announced_chan_between_nodes(&nodes, 0, 3);
+		create_announced_chan_between_nodes(&nodes, 3, 4);
+		create_announced_chan_between_nodes(&nodes, 4, 2);
+
+		let tracker = PaymentTracker::new(nodes[0].node.clone(), nodes[0].router.clone(), nodes[0].node.clone(), 3, Duration::from_secs(0));
+
+		// A timed-out payment whose attempt succeeds is reported as sent
+		let (our_payment_preimage, our_payment_hash) = get_payment_preimage_hash();
+		let payment_id = tracker.send_payment(nodes[1].node.get_our_node_id(), Vec::new(), 1000000, TEST_FINAL_CLTV, our_payment_hash).unwrap();
+		nodes[0].chan_monitor.added_monitors.lock().unwrap().clear();
+		let mut events = tracker.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let payment_event = SendEvent::from_event(events.remove(0));
+
+		tracker.timer_tick_occurred();
+		assert!(tracker.get_and_clear_pending_events().is_empty());
+		assert_eq!(tracker.pending_payment_count(), 1);
+		assert_eq!(tracker.send_payment(nodes[1].node.get_our_node_id(), Vec::new(), 1000000, TEST_FINAL_CLTV, our_payment_hash).err().unwrap().err, "Payment with the given hash is already in progress");
+
+		pass_along_route(&nodes[0], payment_event, &[&nodes[1]], 1000000, our_payment_hash);
+		claim_payment_along_route(&nodes[0], &[&nodes[1]], our_payment_preimage);
+		let events = tracker.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::TrackedPaymentSent { payment_id: id, payment_preimage, attempts } => {
+				assert_eq!(id, payment_id);
+				assert_eq!(payment_preimage, our_payment_preimage);
+				assert_eq!(attempts, 1);
+			},
+			_ => panic!("Unexpected event"),
+		}
+		assert_eq!(tracker.pending_payment_count(), 0);
+
+		// A timed-out payment whose attempt fails is reported as failed rather than retried
+		let (_, our_payment_hash) = get_payment_preimage_hash();
+		let payment_id = tracker.send_payment(nodes[2].node.get_our_node_id(), Vec::new(), 1000000, TEST_FINAL_CLTV, our_payment_hash).unwrap();
+		nodes[0].chan_monitor.added_monitors.lock().unwrap().clear();
+		let mut events = tracker.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let payment_event = SendEvent::from_event(events.remove(0));
+		assert_eq!(payment_event.node_id, nodes[1].node.get_our_node_id());
+
+		tracker.timer_tick_occurred();
+		assert!(tracker.get_and_clear_pending_events().is_empty());
+		assert_eq!(tracker.pending_payment_count(), 1);
+
+		fail_attempt_at_node_1(&nodes, payment_event, chan_2.0.contents.short_channel_id);
+		let events = tracker.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::TrackedPaymentFailed { payment_id: id, payment_hash, rejected_by_dest, attempts } => {
+				assert_eq!(id, payment_id);
+				assert_eq!(payment_hash, our_payment_hash);
+				assert!(!rejected_by_dest);
+				assert_eq!(attempts, 1);
+			},
+			_ => panic!("Unexpected event"),
+		}
+		assert!(nodes[0].chan_monitor.added_monitors.lock().unwrap().is_empty());
+		assert_eq!(tracker.pending_payment_count(), 0);
+	}
+
+	#[test]
+	fn probe_test() {
+		// Tests that probes which are rejected by their destination generate ProbeSuccessful and
+		// those which fail at an intermediate hop generate ProbeFailed naming the failing channel,
+		// which the router then avoids sending as much over.
+		let nodes = create_network(4);
+		create_announced_chan_between_nodes(&nodes, 0, 1);
+		create_announced_chan_between_nodes(&nodes, 1, 2);
+		// nodes[1] has no balance in this channel, so cannot forward anything over it
+		let chan_3 = create_announced_chan_between_nodes(&nodes, 3, 1);
+
+		let route = nodes[0].router.get_route(&nodes[2].node.get_our_node_id(), &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		let probe_hash = nodes[0].node.send_probe(route.clone()).unwrap();
+		nodes[0].chan_monitor.added_monitors.lock().unwrap().clear();
+		let mut events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		pass_along_route(&nodes[0], SendEvent::from_event(events.remove(0)), &[&nodes[1], &nodes[2]], 1000000, probe_hash);
+		fail_payment_along_route(&nodes[0], &[&nodes[1], &nodes[2]], probe_hash);
+
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::ProbeSuccessful { payment_hash, route: ref probed_route } => {
+				assert_eq!(payment_hash, probe_hash);
+				assert_eq!(probed_route.hops.len(), route.hops.len());
+			},
+			_ => panic!("Unexpected event"),
+		}
+
+		let route = nodes[0].router.get_route(&nodes[3].node.get_our_node_id(), &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		let probe_hash = nodes[0].node.send_probe(route.clone()).unwrap();
+		nodes[0].chan_monitor.added_monitors.lock().unwrap().clear();
+		let mut events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let payment_event = SendEvent::from_event(events.remove(0));
+
+		nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]).unwrap();
+		let revoke_and_ack = nodes[1].node.handle_commitment_signed(&nodes[0].node.get_our_node_id(), &payment_event.commitment_msg).unwrap();
+		assert!(nodes[0].node.handle_revoke_and_ack(&nodes[1].node.get_our_node_id(), &revoke_and_ack.0.unwrap()).unwrap().is_none());
+		let prev_revoke_and_ack = nodes[0].node.handle_commitment_signed(&nodes[1].node.get_our_node_id(), &revoke_and_ack.1.unwrap()).unwrap();
+		assert!(nodes[1].node.handle_revoke_and_ack(&nodes[0].node.get_our_node_id(), &prev_revoke_and_ack.0.unwrap()).unwrap().is_none());
+		assert_eq!(nodes[1].node.get_and_clear_pending_events().len(), 1);
+
+		nodes[1].node.channel_state.lock().unwrap().next_forward = Instant::now();
+		nodes[1].node.process_pending_htlc_forward();
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let (update_fail, commitment_signed) = match events[0] {
+			Event::SendFailHTLC { ref msg, ref commitment_msg, .. } => (msg.clone(), commitment_msg.clone()),
+			_ => panic!("Unexpected event"),
+		};
+		nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &update_fail).unwrap();
+		let revoke_and_commit = nodes[0].node.handle_commitment_signed(&nodes[1].node.get_our_node_id(), &commitment_signed).unwrap();
+		assert!(nodes[1].node.handle_revoke_and_ack(&nodes[0].node.get_our_node_id(), &revoke_and_commit.0.unwrap()).unwrap().is_none());
+		let revoke_and_ack = nodes[1].node.handle_commitment_signed(&nodes[0].node.get_our_node_id(), &revoke_and_commit.1.unwrap()).unwrap();
+		assert!(nodes[0].node.handle_revoke_and_ack(&nodes[1].node.get_our_node_id(), &revoke_and_ack.0.unwrap()).unwrap().is_none());
+
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::ProbeFailed { payment_hash, erring_node, short_channel_id, failure_code, .. } => {
+				assert_eq!(payment_hash, probe_hash);
+				assert_eq!(erring_node.unwrap(), nodes[1].node.get_our_node_id());
+				assert_eq!(short_channel_id.unwrap(), chan_3.0.contents.short_channel_id);
+				assert_eq!(failure_code.unwrap(), msgs::HTLCFailureCode::TemporaryChannelFailure);
+			},
+			_ => panic!("Unexpected event"),
+		}
+		nodes[0].router.handle_probe_result(&events[0]);
+		assert_eq!(nodes[0].router.get_route(&nodes[3].node.get_our_node_id(), &Vec::new(), 1000000, TEST_FINAL_CLTV).err().unwrap().err,
+			"Failed to find a path to the given destination");
+		nodes[0].router.get_route(&nodes[3].node.get_our_node_id(), &Vec::new(), 500000, TEST_FINAL_CLTV).unwrap();
+		// Until a probe gets as much through the channel
+		nodes[0].router.handle_probe_result(&Event::ProbeSuccessful { payment_hash: [0; 32], route });
+		nodes[0].router.get_route(&nodes[3].node.get_our_node_id(), &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+
+		let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		let mut loop_route = route.clone();
+		loop_route.hops[0].pubkey = nodes[0].node.get_our_node_id();
+		assert_eq!(nodes[0].node.send_probe(loop_route).err().unwrap().err, "Cannot probe a route back to ourselves");
+	}
+
+	#[test]
+	fn onion_replay_test() {
+		// Tests that we reject an HTLC carrying an onion we've already accepted, that the onion is
+		// persisted in the ChannelMonitor so that replays are still rejected after a restart, and
+		// that entries are dropped once their HTLC has expired.
+		let nodes = create_network(2);
+		let chan = create_announced_chan_between_nodes(&nodes, 0, 1);
+		let funding_txo = OutPoint::new(chan.3.txid(), 0);
+
+		let route = nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		let (our_payment_preimage, our_payment_hash) = get_payment_preimage_hash();
+		nodes[0].node.send_payment(route, our_payment_hash).unwrap();
+		nodes[0].chan_monitor.added_monitors.lock().unwrap().clear();
+		let mut events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let payment_event = SendEvent::from_event(events.remove(0));
+		let mut replayed_htlc = payment_event.msgs[0].clone();
+		pass_along_route(&nodes[0], payment_event, &[&nodes[1]], 1000000, our_payment_hash);
+
+		macro_rules! check_replay_rejected {
+			() => { {
+				replayed_htlc.htlc_id += 1;
+				let err = nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &replayed_htlc).err().unwrap();
+				assert_eq!(err.err, "Onion packet was replayed");
+				match err.msg {
+					Some(msgs::ErrorAction::UpdateFailHTLC { msg }) => assert_eq!(msg.htlc_id, replayed_htlc.htlc_id),
+					_ => panic!("Expected an update_fail_htlc"),
+				}
+			} }
+		}
+		check_replay_rejected!();
+
+		// Even a replay which would otherwise fail our CLTV checks gets the replay error
+		let mut late_replayed_htlc = replayed_htlc.clone();
+		late_replayed_htlc.cltv_expiry = 1;
+		assert_eq!(nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &late_replayed_htlc).err().unwrap().err, "Onion packet was replayed");
+
+		// Simulate a restart, reloading the log from the ChannelMonitor
+		let monitor = nodes[1].chan_monitor.simple_monitor.get_monitor_by_key(&funding_txo).unwrap();
+		assert_eq!(monitor.get_onion_replay_log().len(), 1);
+		assert_eq!(monitor.get_onion_replay_log(), nodes[1].node.channel_state.lock().unwrap().onion_replay_log);
+		nodes[1].node.channel_state.lock().unwrap().onion_replay_log.clear();
+		nodes[1].node.load_onion_replay_log(&monitor);
+		check_replay_rejected!();
+
+		claim_payment(&nodes[0], &[&nodes[1]], our_payment_preimage);
+
+		let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+		nodes[1].chain_monitor.block_connected_checked(&header, replayed_htlc.cltv_expiry - 1, &[], &[]);
+		assert_eq!(nodes[1].node.channel_state.lock().unwrap().onion_replay_log.len(), 1);
+		assert_eq!(nodes[1].chan_monitor.simple_monitor.get_monitor_by_key(&funding_txo).unwrap().get_onion_replay_log().len(), 1);
+		nodes[1].chain_monitor.block_connected_checked(&header, replayed_htlc.cltv_expiry, &[], &[]);
+		assert!(nodes[1].node.channel_state.lock().unwrap().onion_replay_log.is_empty());
+		assert!(nodes[1].chan_monitor.simple_monitor.get_monitor_by_key(&funding_txo).unwrap().get_onion_replay_log().is_empty());
+	}
+
+	#[test]
+	fn forwarding_policy_test() {
+		// Tests that we fail HTLCs which don't pay us our advertised fee, don't leave us our
+		// advertised CLTV delta or expire too soon, with the right failure code and our
+		// channel_update attached.
+		macro_rules! check_forward_failure {
+			($mutate_route: expr, $height: expr, $err: expr, $failure_code: expr) => { {
+				let nodes = create_network(3);
+				create_announced_chan_between_nodes(&nodes, 0, 1);
+				create_announced_chan_between_nodes(&nodes, 1, 2);
+				// $height is relative to the height nodes[0] sends the HTLC at
+				let cur_height = nodes[0].node.latest_block_height.load(Ordering::Acquire) + 1;
+				nodes[1].node.latest_block_height.store(cur_height + $height, Ordering::Release);
+
+				let mut route = nodes[0].router.get_route(&nodes[2].node.get_our_node_id(), &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+				$mutate_route(&mut route);
+				let payment_hash = [42; 32];
+				nodes[0].node.send_payment(route, payment_hash).unwrap();
+				let mut events = nodes[0].node.get_and_clear_pending_events();
+				assert_eq!(events.len(), 1);
+				let payment_event = SendEvent::from_event(events.remove(0));
+
+				let err = nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]).err().unwrap();
+				assert_eq!(err.err, $err);
+				let reason = match err.msg {
+					Some(msgs::ErrorAction::UpdateFailHTLC { msg }) => msg.reason,
+					_ => panic!("Expected an update_fail_htlc"),
+				};
+				let channel_state = nodes[0].node.channel_state.lock().unwrap();
+				match channel_state.claimable_htlcs.get(&payment_hash).unwrap() {
+					&PendingOutboundHTLC::OutboundRoute { ref route, ref session_priv, .. } => {
+						let failure = ChannelManager::process_onion_failure(&nodes[0].node.secp_ctx, route, session_priv, &reason).unwrap();
+						assert_eq!(failure.erring_node, nodes[1].node.get_our_node_id());
+						assert_eq!(failure.short_channel_id, route.hops[1].short_channel_id);
+						assert_eq!(failure.failure_code, $failure_code);
+						assert!(!failure.rejected_by_dest);
+						match failure.channel_update {
+							Some(msgs::HTLCFailChannelUpdate::ChannelUpdateMessage { .. }) => {},
+							_ => panic!("Expected a channel_update"),
+						}
+					},
+					_ => panic!("Unexpected pending HTLC type"),
+				}
+			} }
+		}
+
+		check_forward_failure!(|route: &mut Route| { route.hops[0].fee_msat -= 1; }, 0,
+			"Prior hop has deviated from specified fees parameters or origin node has obsolete ones", msgs::HTLCFailureCode::FeeInsufficient);
+		check_forward_failure!(|route: &mut Route| { route.hops[0].cltv_expiry_delta -= 1; }, 0,
+			"Forwarding node has tampered with the intended HTLC values or origin node has an obsolete cltv_expiry_delta", msgs::HTLCFailureCode::IncorrectCLTVExpiry);
+		check_forward_failure!(|_: &mut Route| {}, (TEST_FINAL_CLTV + CLTV_EXPIRY_DELTA as u32 - CLTV_CLAIM_BUFFER - LATENCY_GRACE_PERIOD_BLOCKS) as usize,
+			"CLTV expiry is too close", msgs::HTLCFailureCode::ExpiryTooSoon);
+	}
+
+	#[test]
+	fn forwarding_policy_update_test() {
+		// Tests that a policy set with update_forwarding_policy is advertised, enforced, and that
+		// HTLCs paying according to the old one are accepted until the grace period ends.
+		let nodes = create_network(3);
+		create_announced_chan_between_nodes(&nodes, 0, 1);
+		let chan_2 = create_announced_chan_between_nodes(&nodes, 1, 2);
+
+		let old_route = nodes[0].router.get_route(&nodes[2].node.get_our_node_id(), &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		let old_policy = nodes[1].node.list_channels().iter().find(|chan| chan.channel_id == chan_2.2).unwrap().forwarding_policy.clone();
+		let new_policy = ChannelForwardingPolicy {
+			fee_base_msat: old_policy.fee_base_msat + 1000,
+			fee_proportional_millionths: 100,
+			cltv_expiry_delta: CLTV_EXPIRY_DELTA + 6,
+			htlc_minimum_msat: 1000,
+		};
+
+		assert_eq!(nodes[1].node.update_forwarding_policy(Some(&Uint256::from_u64(42).unwrap()), new_policy.clone()).unwrap_err().err, "No such channel");
+		nodes[1].node.update_forwarding_policy(None, new_policy.clone()).unwrap();
+		for chan in nodes[1].node.list_channels() {
+			assert_eq!(chan.forwarding_policy, new_policy);
+		}
+		let events = nodes[1].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 2);
+		for event in events {
+			match event {
+				Event::BroadcastChannelUpdate { msg } => {
+					assert_eq!(msg.contents.fee_base_msat, new_policy.fee_base_msat);
+					assert_eq!(msg.contents.fee_proportional_millionths, new_policy.fee_proportional_millionths);
+					assert_eq!(msg.contents.cltv_expiry_delta, new_policy.cltv_expiry_delta);
+					assert_eq!(msg.contents.htlc_minimum_msat, new_policy.htlc_minimum_msat);
+					for node in nodes.iter() {
+						node.router.handle_channel_update(&msg).unwrap();
+					}
+				},
+				_ => panic!("Unexpected event"),
+			}
+		}
+
+		// Still within the grace period, so the old policy is fine...
+		let payment_preimage = send_along_route(&nodes[0], old_route.clone(), &[&nodes[1], &nodes[2]], 1000000).0;
+		claim_payment(&nodes[0], &[&nodes[1], &nodes[2]], payment_preimage);
+
+		// ...as is the new one, which our routers have picked up
+		let new_route = nodes[0].router.get_route(&nodes[2].node.get_our_node_id(), &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+		assert_eq!(new_route.hops[0].fee_msat, new_policy.fee_base_msat as u64 + 1000000 * new_policy.fee_proportional_millionths as u64 / 1000000);
+		assert_eq!(new_route.hops[0].cltv_expiry_delta, new_policy.cltv_expiry_delta as u32);
+		let payment_preimage = send_along_route(&nodes[0], new_route, &[&nodes[1], &nodes[2]], 1000000).0;
+		claim_payment(&nodes[0], &[&nodes[1], &nodes[2]], payment_preimage);
+
+		// Once the grace period has passed, HTLCs paying the old fee are rejected
+		{
+			let mut channel_state = nodes[1].node.channel_state.lock().unwrap();
+			let chan = channel_state.by_id.get_mut(&chan_2.2).unwrap();
+			chan.set_forwarding_policy(new_policy.clone(), old_policy.clone(), Instant::now());
+		}
+		nodes[0].node.send_payment(old_route, [42; 32]).unwrap();
+		let mut events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		let payment_event = SendEvent::from_event(events.remove(0));
+		let err = nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]).unwrap_err();
+		assert_eq!(err.err, "Prior hop has deviated from specified fees parameters or origin node has obsolete ones");
+	}
+
+	#[test]
+	fn node_announcement_test() {
+		// Tests that we only announce our node once we have a public channel, that changes are
+		// broadcast immediately with increasing timestamps, that we re-announce periodically, and
+		// that we only announce one address of each type, in the order BOLT 7 requires.
+		let nodes = create_network(3);
+		create_announced_chan_between_nodes(&nodes, 0, 1);
+
+		let ipv4 = msgs::NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 };
+		let ipv6 = msgs::NetAddress::IPv6 { addr: [1; 16], port: 9735 };
+		let addresses = vec![ipv4.clone(), ipv6.clone()];
+		nodes[2].node.broadcast_node_announcement([1, 2, 3], [2; 32], addresses.clone()).unwrap();
+		assert!(nodes[2].node.get_and_clear_pending_events().is_empty());
+
+		let mut last_timestamp = 0;
+		for alias in [[0x42; 32], [0x43; 32]].iter() {
+			nodes[0].node.broadcast_node_announcement([1, 2, 3], *alias, vec![ipv6.clone(), ipv4.clone()]).unwrap();
+			let events = nodes[0].node.get_and_clear_pending_events();
+			assert_eq!(events.len(), 1);
+			match events[0] {
+				Event::BroadcastNodeAnnouncement { ref msg } => {
+					assert_eq!(msg.contents.node_id, nodes[0].node.get_our_node_id());
+					assert_eq!(msg.contents.alias, *alias);
+					assert!(msg.contents.addresses == addresses);
+					assert!(msg.contents.timestamp > last_timestamp);
+					last_timestamp = msg.contents.timestamp;
+					nodes[1].router.handle_node_announcement(msg).unwrap();
+				},
+				_ => panic!("Unexpected event"),
+			}
+		}
+
+		let second_ipv4 = msgs::NetAddress::IPv4 { addr: [10, 0, 0, 1], port: 9735 };
+		assert_eq!(nodes[0].node.broadcast_node_announcement([1, 2, 3], [0x44; 32], vec![ipv4.clone(), ipv6.clone(), second_ipv4]).err().unwrap().err, "Only one address of each type may be announced");
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+
+		let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+		nodes[0].chain_monitor.block_connected_checked(&header, NODE_ANNOUNCEMENT_INTERVAL_BLOCKS - 1, &[], &[]);
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+		nodes[0].chain_monitor.block_connected_checked(&header, NODE_ANNOUNCEMENT_INTERVAL_BLOCKS, &[], &[]);
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::BroadcastNodeAnnouncement { ref msg } => {
+				assert_eq!(msg.contents.alias, [0x43; 32]);
+				assert!(msg.contents.timestamp > last_timestamp);
+				nodes[1].router.handle_node_announcement(msg).unwrap();
+			},
+			_ => panic!("Unexpected event"),
+		}
+	}
+
+	#[test]
+	fn channel_update_timestamp_test() {
+		// Tests that our channel_updates carry the current time, so that routers which prune
+		// channels using real block times don't consider our channels stale, and that our own
+		// channels are never pruned.
+		let nodes = create_network(3);
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+		create_announced_chan_between_nodes(&nodes, 0, 1);
+		let (update_a, update_b, chan_id, funding_tx) = create_announced_chan_between_nodes(&nodes, 1, 2);
+		for update in [&update_a, &update_b].iter() {
+			assert!(update.contents.timestamp >= now);
+			assert!(update.contents.timestamp < now + 60);
+		}
+
+		// A block thirteen days from now leaves the channels alone...
+		let mut header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: now + 60 * 60 * 24 * 13, bits: 42, nonce: 42 };
+		nodes[0].chain_monitor.block_connected_checked(&header, 100, &[], &[]);
+		nodes[0].router.get_route(&nodes[2].node.get_our_node_id(), &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+
+		// ...and changes to the channel are sent with later timestamps, even within the same
+		// second, so they're accepted.
+		let (close_update_a, close_update_b, _) = close_channel(&nodes[1], &nodes[2], &chan_id, funding_tx, true, None, None);
+		assert!(close_update_a.contents.timestamp > update_a.contents.timestamp);
+		assert!(close_update_b.contents.timestamp > update_b.contents.timestamp);
+		nodes[1].router.handle_channel_update(&close_update_a).unwrap();
+		nodes[1].router.handle_channel_update(&close_update_b).unwrap();
+
+		// ...while one fifteen days from now, with no newer updates, prunes the channel between
+		// our peers but leaves our own channel alone.
+		header = BlockHeader { version: 0x20000000, prev_blockhash: header.bitcoin_hash(), merkle_root: Default::default(), time: now + 60 * 60 * 24 * 15, bits: 42, nonce: 42 };
+		nodes[0].chain_monitor.block_connected_checked(&header, 101, &[], &[]);
+		assert!(nodes[0].router.get_route(&nodes[2].node.get_our_node_id(), &Vec::new(), 1000000, TEST_FINAL_CLTV).is_err());
+		nodes[0].router.get_route(&nodes[1].node.get_our_node_id(), &Vec::new(), 1000000, TEST_FINAL_CLTV).unwrap();
+	}
+
+	#[test]
+	fn channel_update_refresh_test() {
+		// Tests that we periodically re-sign and re-broadcast the channel_updates for our public
+		// channels, with a new timestamp, so that they aren't pruned as stale.
+		let nodes = create_network(2);
+		let (update_a, _, _, _) = create_announced_chan_between_nodes(&nodes, 0, 1);
+		let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+		nodes[0].chain_monitor.block_connected_checked(&header, CHANNEL_UPDATE_REFRESH_INTERVAL_BLOCKS - 1, &[], &[]);
+		assert!(nodes[0].node.get_and_clear_pending_events().is_empty());
+
+		nodes[0].chain_monitor.block_connected_checked(&header, CHANNEL_UPDATE_REFRESH_INTERVAL_BLOCKS, &[], &[]);
+		let events = nodes[0].node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::BroadcastChannelUpdate { ref msg } => {
+				assert_eq!(msg.contents.short_channel_id, update_a.contents.short_channel_id);
+				assert_eq!(msg.contents.flags, update_a.contents.flags);
+				assert!(msg.contents.timestamp > update_a.contents.timestamp);
+				nodes[1].router.handle_channel_update(msg).unwrap();
+			},
+			_ => panic!("Unexpected event"),
+		}
+	}
 }
@@ -1,50 +1,334 @@
 use bitcoin::blockdata::block::BlockHeader;
 use bitcoin::blockdata::transaction::{TxIn,TxOut,SigHashType,Transaction};
 use bitcoin::blockdata::script::Script;
+use bitcoin::network::serialize::{deserialize, serialize, BitcoinHash};
 use bitcoin::util::hash::Sha256dHash;
-use bitcoin::util::bip143;
 
 use crypto::digest::Digest;
 
-use secp256k1::{Secp256k1,Message,Signature};
+use secp256k1::{Secp256k1,Signature};
 use secp256k1::key::{SecretKey,PublicKey};
 
-use ln::msgs::HandleError;
+use ln::msgs::{DecodeError, HandleError};
 use ln::chan_utils;
 use ln::chan_utils::HTLCOutputInCommitment;
-use chain::chaininterface::{ChainListener, ChainWatchInterface, BroadcasterInterface};
+use chain::chaininterface::{ChainListener, ChainWatchInterface, BroadcasterInterface, FeeEstimator, ConfirmationTarget};
+use chain::keysinterface::ChannelSigner;
 use chain::transaction::OutPoint;
+use util::byte_utils;
 use util::sha2::Sha256;
 
 use std::collections::HashMap;
 use std::sync::{Arc,Mutex};
 use std::{hash,cmp};
 
+const SERIALIZATION_VERSION: u8 = 1;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum ChannelMonitorUpdateErr {
-	/// Used to indicate a temporary failure (eg connection to a watchtower failed, but is expected
-	/// to succeed at some point in the future).
-	/// Such a failure will "freeze" a channel, preventing us from revoking old states or
-	/// submitting new commitment transactions to the remote party.
-	/// ChannelManager::test_restore_channel_monitor can be used to retry the update(s) and restore
-	/// the channel to an operational state.
-	TemporaryFailure,
+	/// Used to indicate that the update has not (yet) been durably persisted, eg because it is
+	/// being written asynchronously or because the connection to a watchtower failed but is
+	/// expected to succeed at some point in the future.
+	/// This will "freeze" the channel, preventing us from revoking old states, submitting new
+	/// commitment transactions to the remote party or forwarding HTLCs until the ManyChannelMonitor
+	/// has finished (or retried) the update(s) and called ChannelManager::update_completed, at
+	/// which point any messages we held back are sent and the channel returns to an operational
+	/// state. update_completed must only be called after the add_monitor/update_monitor call
+	/// which returned InProgress has returned, never from within it.
+	/// Note that updates given to the ManyChannelMonitor after this one must still be applied (in
+	/// order) to its copy of the ChannelMonitor, even if they cannot be persisted yet.
+	InProgress,
 	/// Used to indicate no further channel monitor updates will be allowed (eg we've moved on to a
 	/// different watchtower and cannot update with all watchtowers that were previously informed
-	/// of this channel). This will force-close the channel in question.
+	/// of this channel). This will force-close the channel in question, broadcasting our latest
+	/// local commitment transaction and sending our counterparty an error message.
 	PermanentFailure,
 }
 
+/// A single change to a ChannelMonitor's state, as applied by ChannelMonitor::update_monitor.
+#[derive(Clone, PartialEq)]
+pub enum ChannelMonitorUpdateStep {
+	/// See ChannelMonitor::provide_latest_local_commitment_tx_info
+	LatestLocalCommitmentTXInfo {
+		signed_commitment_tx: Transaction,
+		local_keys: chan_utils::TxCreationKeys,
+		feerate_per_kw: u64,
+		htlc_outputs: Vec<(HTLCOutputInCommitment, Signature, Signature)>,
+	},
+	/// See ChannelMonitor::provide_latest_remote_commitment_tx_info
+	LatestRemoteCommitmentTXInfo {
+		unsigned_commitment_tx: Transaction,
+		htlc_outputs: Vec<HTLCOutputInCommitment>,
+		commitment_number: u64,
+	},
+	/// See ChannelMonitor::provide_payment_preimage
+	PaymentPreimage {
+		payment_hash: [u8; 32],
+		payment_preimage: [u8; 32],
+	},
+	/// See ChannelMonitor::provide_secret
+	CommitmentSecret {
+		idx: u64,
+		secret: [u8; 32],
+		their_next_revocation_point: Option<(u64, PublicKey)>,
+	},
+	/// See ChannelMonitor::provide_onion_replay_log_entry
+	OnionReplayLogEntry {
+		onion_secret_hash: [u8; 32],
+		cltv_expiry: u32,
+	},
+}
+
+/// An update to a ChannelMonitor, carrying only the state which changed since the previous
+/// update (ie new revocation secrets, commitment transactions and payment preimages) so that it
+/// is cheap to generate and to hand to remote monitors.
+/// Updates for a given channel must be applied in order, which is enforced using update_id.
+/// Updates can be written with serialize (and read with from_serialized) to hand them to remote
+/// monitors or to persist them alongside the ChannelMonitor they apply to.
+#[derive(Clone, PartialEq)]
+pub struct ChannelMonitorUpdate {
+	/// The changes to apply, in order.
+	pub updates: Vec<ChannelMonitorUpdateStep>,
+	/// The sequence number of this update. Updates for a channel are numbered sequentially,
+	/// starting at 1 for the first update after the ChannelMonitor was added, and a
+	/// ChannelMonitor will refuse any update which does not immediately follow the last one it
+	/// applied.
+	pub update_id: u64,
+}
+
+impl ChannelMonitorUpdate {
+	/// Serializes this update so that it can be reloaded with from_serialized.
+	pub fn serialize(&self) -> Vec<u8> {
+		let secp_ctx = Secp256k1::without_caps();
+		let mut res = Vec::new();
+		res.push(SERIALIZATION_VERSION);
+		res.extend_from_slice(&byte_utils::be64_to_array(self.update_id));
+
+		macro_rules! write_tx {
+			( $tx: expr ) => {
+				let encoded = serialize($tx).unwrap();
+				res.extend_from_slice(&byte_utils::be32_to_array(encoded.len() as u32));
+				res.extend_from_slice(&encoded[..]);
+			}
+		}
+		macro_rules! write_htlc_output {
+			( $htlc: expr ) => {
+				res.push(if $htlc.offered { 1 } else { 0 });
+				res.extend_from_slice(&byte_utils::be64_to_array($htlc.amount_msat));
+				res.extend_from_slice(&byte_utils::be32_to_array($htlc.cltv_expiry));
+				res.extend_from_slice(&$htlc.payment_hash);
+				res.extend_from_slice(&byte_utils::be32_to_array($htlc.transaction_output_index));
+			}
+		}
+
+		res.extend_from_slice(&byte_utils::be64_to_array(self.updates.len() as u64));
+		for step in self.updates.iter() {
+			match step {
+				&ChannelMonitorUpdateStep::LatestLocalCommitmentTXInfo { ref signed_commitment_tx, ref local_keys, feerate_per_kw, ref htlc_outputs } => {
+					res.push(0);
+					write_tx!(signed_commitment_tx);
+					for key in [&local_keys.per_commitment_point, &local_keys.revocation_key, &local_keys.a_htlc_key, &local_keys.b_htlc_key, &local_keys.a_delayed_payment_key, &local_keys.b_payment_key].iter() {
+						res.extend_from_slice(&key.serialize());
+					}
+					res.extend_from_slice(&byte_utils::be64_to_array(feerate_per_kw));
+					res.extend_from_slice(&byte_utils::be64_to_array(htlc_outputs.len() as u64));
+					for &(ref htlc, ref their_sig, ref our_sig) in htlc_outputs.iter() {
+						write_htlc_output!(htlc);
+						res.extend_from_slice(&their_sig.serialize_compact(&secp_ctx));
+						res.extend_from_slice(&our_sig.serialize_compact(&secp_ctx));
+					}
+				},
+				&ChannelMonitorUpdateStep::LatestRemoteCommitmentTXInfo { ref unsigned_commitment_tx, ref htlc_outputs, commitment_number } => {
+					res.push(1);
+					write_tx!(unsigned_commitment_tx);
+					res.extend_from_slice(&byte_utils::be64_to_array(htlc_outputs.len() as u64));
+					for htlc in htlc_outputs.iter() {
+						write_htlc_output!(htlc);
+					}
+					res.extend_from_slice(&byte_utils::be64_to_array(commitment_number));
+				},
+				&ChannelMonitorUpdateStep::PaymentPreimage { ref payment_hash, ref payment_preimage } => {
+					res.push(2);
+					res.extend_from_slice(payment_hash);
+					res.extend_from_slice(payment_preimage);
+				},
+				&ChannelMonitorUpdateStep::CommitmentSecret { idx, ref secret, ref their_next_revocation_point } => {
+					res.push(3);
+					res.extend_from_slice(&byte_utils::be64_to_array(idx));
+					res.extend_from_slice(secret);
+					match their_next_revocation_point {
+						&Some((point_idx, ref point)) => {
+							res.push(1);
+							res.extend_from_slice(&byte_utils::be64_to_array(point_idx));
+							res.extend_from_slice(&point.serialize());
+						},
+						&None => res.push(0),
+					}
+				},
+				&ChannelMonitorUpdateStep::OnionReplayLogEntry { ref onion_secret_hash, cltv_expiry } => {
+					res.push(4);
+					res.extend_from_slice(onion_secret_hash);
+					res.extend_from_slice(&byte_utils::be32_to_array(cltv_expiry));
+				},
+			}
+		}
+		res
+	}
+
+	/// Loads an update previously written by serialize.
+	/// Note that this only checks that the data is well-formed, the update is only checked
+	/// against the monitor's state once it is applied with ChannelMonitor::update_monitor.
+	pub fn from_serialized(data: &[u8]) -> Result<ChannelMonitorUpdate, DecodeError> {
+		let secp_ctx = Secp256k1::without_caps();
+		let mut read_pos = 0;
+		macro_rules! read_bytes {
+			( $len: expr ) => {
+				{
+					let len = $len;
+					if data.len() < read_pos + len {
+						return Err(DecodeError::WrongLength);
+					}
+					read_pos += len;
+					&data[read_pos - len..read_pos]
+				}
+			}
+		}
+		macro_rules! read_32_bytes {
+			() => {
+				{
+					let mut res = [0; 32];
+					res.copy_from_slice(read_bytes!(32));
+					res
+				}
+			}
+		}
+		macro_rules! read_pubkey {
+			() => {
+				match PublicKey::from_slice(&secp_ctx, read_bytes!(33)) {
+					Ok(key) => key,
+					Err(_) => return Err(DecodeError::BadPublicKey),
+				}
+			}
+		}
+		macro_rules! read_signature {
+			() => {
+				match Signature::from_compact(&secp_ctx, read_bytes!(64)) {
+					Ok(sig) => sig,
+					Err(_) => return Err(DecodeError::BadSignature),
+				}
+			}
+		}
+		macro_rules! read_tx {
+			() => {
+				{
+					let len = byte_utils::slice_to_be32(read_bytes!(4)) as usize;
+					let tx: Transaction = match deserialize(read_bytes!(len)) {
+						Ok(tx) => tx,
+						Err(_) => return Err(DecodeError::InvalidValue),
+					};
+					tx
+				}
+			}
+		}
+		macro_rules! read_htlc_output {
+			() => {
+				HTLCOutputInCommitment {
+					offered: match read_bytes!(1)[0] {
+						0 => false,
+						1 => true,
+						_ => return Err(DecodeError::InvalidValue),
+					},
+					amount_msat: byte_utils::slice_to_be64(read_bytes!(8)),
+					cltv_expiry: byte_utils::slice_to_be32(read_bytes!(4)),
+					payment_hash: read_32_bytes!(),
+					transaction_output_index: byte_utils::slice_to_be32(read_bytes!(4)),
+				}
+			}
+		}
+
+		if read_bytes!(1)[0] != SERIALIZATION_VERSION {
+			return Err(DecodeError::InvalidValue);
+		}
+		let update_id = byte_utils::slice_to_be64(read_bytes!(8));
+
+		let mut updates = Vec::new();
+		let step_count = byte_utils::slice_to_be64(read_bytes!(8));
+		for _ in 0..step_count {
+			updates.push(match read_bytes!(1)[0] {
+				0 => {
+					let signed_commitment_tx = read_tx!();
+					let local_keys = chan_utils::TxCreationKeys {
+						per_commitment_point: read_pubkey!(),
+						revocation_key: read_pubkey!(),
+						a_htlc_key: read_pubkey!(),
+						b_htlc_key: read_pubkey!(),
+						a_delayed_payment_key: read_pubkey!(),
+						b_payment_key: read_pubkey!(),
+					};
+					let feerate_per_kw = byte_utils::slice_to_be64(read_bytes!(8));
+					let htlc_count = byte_utils::slice_to_be64(read_bytes!(8));
+					let mut htlc_outputs = Vec::new();
+					for _ in 0..htlc_count {
+						htlc_outputs.push((read_htlc_output!(), read_signature!(), read_signature!()));
+					}
+					ChannelMonitorUpdateStep::LatestLocalCommitmentTXInfo { signed_commitment_tx, local_keys, feerate_per_kw, htlc_outputs }
+				},
+				1 => {
+					let unsigned_commitment_tx = read_tx!();
+					let htlc_count = byte_utils::slice_to_be64(read_bytes!(8));
+					let mut htlc_outputs = Vec::new();
+					for _ in 0..htlc_count {
+						htlc_outputs.push(read_htlc_output!());
+					}
+					let commitment_number = byte_utils::slice_to_be64(read_bytes!(8));
+					ChannelMonitorUpdateStep::LatestRemoteCommitmentTXInfo { unsigned_commitment_tx, htlc_outputs, commitment_number }
+				},
+				2 => ChannelMonitorUpdateStep::PaymentPreimage {
+					payment_hash: read_32_bytes!(),
+					payment_preimage: read_32_bytes!(),
+				},
+				3 => ChannelMonitorUpdateStep::CommitmentSecret {
+					idx: byte_utils::slice_to_be64(read_bytes!(8)),
+					secret: read_32_bytes!(),
+					their_next_revocation_point: match read_bytes!(1)[0] {
+						0 => None,
+						1 => Some((byte_utils::slice_to_be64(read_bytes!(8)), read_pubkey!())),
+						_ => return Err(DecodeError::InvalidValue),
+					},
+				},
+				4 => ChannelMonitorUpdateStep::OnionReplayLogEntry {
+					onion_secret_hash: read_32_bytes!(),
+					cltv_expiry: byte_utils::slice_to_be32(read_bytes!(4)),
+				},
+				_ => return Err(DecodeError::InvalidValue),
+			});
+		}
+		if read_pos != data.len() {
+			return Err(DecodeError::WrongLength);
+		}
+
+		Ok(ChannelMonitorUpdate { updates, update_id })
+	}
+}
+
 /// Simple trait indicating ability to track a set of ChannelMonitors and multiplex events between
 /// them. Generally should be implemented by keeping a local SimpleManyChannelMonitor and passing
-/// events to it, while also taking any add_update_monitor events and passing them to some remote
-/// server(s).
+/// events to it, while also taking any add_monitor/update_monitor events and passing them to some
+/// remote server(s). ln::watchtower::WatchtowerClient is one such implementation, which hands a
+/// watchtower only encrypted justice transactions instead of full ChannelMonitors.
 /// Note that any updates to a channel's monitor *must* be applied to each instance of the
 /// channel's monitor everywhere (including remote watchtowers) *before* this function returns. If
 /// an update occurs and a remote watchtower is left with old state, it may broadcast transactions
 /// which we have revoked, allowing our counterparty to claim all funds in the channel!
 pub trait ManyChannelMonitor: Send + Sync {
-	/// Adds or updates a monitor for the given `funding_txo`.
-	fn add_update_monitor(&self, funding_txo: OutPoint, monitor: ChannelMonitor) -> Result<(), ChannelMonitorUpdateErr>;
+	/// Adds a monitor for the given `funding_txo`. This is called once per channel, when its
+	/// funding transaction is created.
+	fn add_monitor(&self, funding_txo: OutPoint, monitor: ChannelMonitor) -> Result<(), ChannelMonitorUpdateErr>;
+
+	/// Applies an update to the monitor for the given `funding_txo` (see
+	/// ChannelMonitor::update_monitor). Implementations should return PermanentFailure if the
+	/// update does not apply, eg because it is out-of-order.
+	fn update_monitor(&self, funding_txo: OutPoint, update: ChannelMonitorUpdate) -> Result<(), ChannelMonitorUpdateErr>;
 }
 
 /// A simple implementation of a ManyChannelMonitor and ChainListener. Can be used to create a
@@ -55,53 +339,251 @@ pub trait ManyChannelMonitor: Send + Sync {
 /// index by a PublicKey which is required to sign any updates.
 /// If you're using this for local monitoring of your own channels, you probably want to use
 /// `OutPoint` as the key, which will give you a ManyChannelMonitor implementation.
+/// Transactions in connected blocks are only handed to the monitors they are relevant to (looked
+/// up by the outpoints they spend), so the cost of a block does not grow with the number of
+/// channels being watched (except for monitors which were added without funding info, which must
+/// see every transaction).
 pub struct SimpleManyChannelMonitor<Key> {
 	monitors: Mutex<HashMap<Key, ChannelMonitor>>,
+	/// The key(s) of the monitor(s) for each funding outpoint. Note that we cannot assume there is
+	/// only one, as a watchtower's users could otherwise hide a channel by providing a second
+	/// monitor for its funding outpoint.
+	funding_outpoints: Mutex<HashMap<OutPoint, Vec<Key>>>,
+	/// Each transaction we've seen spend a funding outpoint (ie commitment transactions), so that
+	/// second-stage transactions spending their outputs can be found. Entries are pruned once the
+	/// transaction is resolved (see CommitmentTxInfo::is_resolved).
+	commitment_txids: Mutex<HashMap<Sha256dHash, CommitmentTxInfo<Key>>>,
 	chain_monitor: Arc<ChainWatchInterface>,
-	broadcaster: Arc<BroadcasterInterface>
+	broadcaster: Arc<BroadcasterInterface>,
+	fee_estimator: Arc<FeeEstimator>,
+	// The txids of the transactions handed to each monitor in block_connected
+	#[cfg(test)]
+	txn_dispatched: Mutex<HashMap<Key, Vec<Sha256dHash>>>,
+}
+
+/// Once a commitment transaction and all the spends of its outputs are this many blocks deep we
+/// assume they will not be reorged out and stop tracking it.
+pub(super) const ANTI_REORG_DELAY: u32 = 6;
+
+/// Commitment transactions whose watched outputs are still unspent this many blocks after they
+/// confirmed are no longer tracked. This is well past the largest to_self_delay we accept and the
+/// CLTV expiry of any HTLC we'd have forwarded, so if an output still hasn't been spent it is not
+/// one we can do anything about (eg the output of a cooperative closing transaction).
+const COMMITMENT_TX_MAX_WATCH_DEPTH: u32 = 6 * 24 * 28;
+
+/// A transaction which spent a funding outpoint we're watching (generally a commitment
+/// transaction), tracked until the outputs we care about have been spent.
+struct CommitmentTxInfo<Key> {
+	/// The key(s) of the monitor(s) for the funding outpoint(s) it spends
+	keys: Vec<Key>,
+	/// The hash and height of the block which included it
+	block_hash: Sha256dHash,
+	height: u32,
+	/// For each P2WSH output (ie the HTLC and revocable to_local outputs of a commitment
+	/// transaction), by index, the hash and height of the block which included its spend, if any.
+	/// The to_remote output pays directly to a key, so we never need to know about its spend.
+	output_spends: HashMap<u32, Option<(Sha256dHash, u32)>>,
 }
 
-impl<Key : Send + cmp::Eq + hash::Hash> ChainListener for SimpleManyChannelMonitor<Key> {
-	fn block_connected(&self, _header: &BlockHeader, height: u32, txn_matched: &[&Transaction], _indexes_of_txn_matched: &[u32]) {
+impl<Key> CommitmentTxInfo<Key> {
+	/// Whether all of our P2WSH outputs have been spent and both we and all the spends are at
+	/// least ANTI_REORG_DELAY blocks deep at the given height, ie no further transactions we care
+	/// about can spend our outputs, or we are COMMITMENT_TX_MAX_WATCH_DEPTH blocks deep.
+	fn is_resolved(&self, height: u32) -> bool {
+		if height >= self.height + COMMITMENT_TX_MAX_WATCH_DEPTH {
+			return true;
+		}
+		let mut resolved_height = self.height;
+		for spend in self.output_spends.values() {
+			match spend {
+				&Some((_, spend_height)) => resolved_height = cmp::max(resolved_height, spend_height),
+				&None => return false,
+			}
+		}
+		height >= resolved_height + ANTI_REORG_DELAY
+	}
+}
+
+impl<Key : Send + cmp::Eq + hash::Hash + Clone> ChainListener for SimpleManyChannelMonitor<Key> {
+	fn block_connected(&self, header: &BlockHeader, height: u32, txn_matched: &[&Transaction], _indexes_of_txn_matched: &[u32]) {
+		let block_hash = header.bitcoin_hash();
 		let monitors = self.monitors.lock().unwrap();
-		for monitor in monitors.values() {
-			monitor.block_connected(txn_matched, height, &*self.broadcaster);
+		let funding_outpoints = self.funding_outpoints.lock().unwrap();
+		let mut commitment_txids = self.commitment_txids.lock().unwrap();
+
+		// Monitors without funding info need to see every transaction, otherwise we only need to
+		// calculate the txids of (and dispatch) transactions which spend an outpoint we know about.
+		let have_unfunded_monitors = monitors.values().any(|monitor| monitor.funding_txo.is_none());
+		let mut relevant_txn = Vec::new();
+		for tx in txn_matched.iter() {
+			let mut tx_keys = Vec::new();
+			let mut spends_funding = false;
+			macro_rules! add_keys {
+				($keys: expr) => {
+					for key in $keys.iter() {
+						if !tx_keys.contains(key) {
+							tx_keys.push(key.clone());
+						}
+					}
+				}
+			}
+			for txin in tx.input.iter() {
+				if txin.prev_index < (1 << 16) {
+					if let Some(keys) = funding_outpoints.get(&OutPoint::new(txin.prev_hash, txin.prev_index as u16)) {
+						spends_funding = true;
+						add_keys!(keys);
+						continue;
+					}
+				}
+				if let Some(info) = commitment_txids.get_mut(&txin.prev_hash) {
+					if let Some(spend) = info.output_spends.get_mut(&txin.prev_index) {
+						*spend = Some((block_hash, height));
+					}
+					add_keys!(info.keys);
+				}
+			}
+			if tx_keys.is_empty() && !have_unfunded_monitors {
+				continue;
+			}
+			let txid = tx.txid();
+			if spends_funding {
+				// Any transaction spending a funding outpoint is (generally) a commitment
+				// transaction, so index it to find any second-stage transactions spending its
+				// outputs later in this block or in a later one, and make sure we're told about
+				// those transactions.
+				let chain_monitor = &self.chain_monitor;
+				let info = commitment_txids.entry(txid.clone()).or_insert_with(|| {
+					let mut output_spends = HashMap::new();
+					for (idx, output) in tx.output.iter().enumerate() {
+						if output.script_pubkey.is_v0_p2wsh() {
+							chain_monitor.install_watch_outpoint((txid.clone(), idx as u32));
+							output_spends.insert(idx as u32, None);
+						}
+					}
+					CommitmentTxInfo {
+						keys: Vec::new(),
+						block_hash,
+						height,
+						output_spends,
+					}
+				});
+				for key in tx_keys.iter() {
+					if !info.keys.contains(key) {
+						info.keys.push(key.clone());
+					}
+				}
+			}
+			relevant_txn.push((*tx, txid, tx_keys));
+		}
+		// Once a closed channel is resolved nothing further can spend its commitment transaction's
+		// outputs, so we no longer need to look for spends of it.
+		commitment_txids.retain(|_, info| !info.is_resolved(height));
+
+		let mut txn_by_key: HashMap<Key, Vec<(&Transaction, &Sha256dHash)>> = HashMap::new();
+		let mut all_txn = Vec::new();
+		for &(tx, ref txid, ref tx_keys) in relevant_txn.iter() {
+			for key in tx_keys.iter() {
+				txn_by_key.entry(key.clone()).or_insert(Vec::new()).push((tx, txid));
+			}
+			if have_unfunded_monitors {
+				all_txn.push((tx, txid));
+			}
+		}
+
+		let no_txn = Vec::new();
+		for (key, monitor) in monitors.iter() {
+			let monitor_txn = match monitor.funding_txo {
+				None => &all_txn,
+				Some(_) => txn_by_key.get(key).unwrap_or(&no_txn),
+			};
+			#[cfg(test)]
+			{
+				if !monitor_txn.is_empty() {
+					self.txn_dispatched.lock().unwrap().entry(key.clone()).or_insert(Vec::new()).extend(monitor_txn.iter().map(|&(_, txid)| txid.clone()));
+				}
+			}
+			monitor.block_connected(&monitor_txn[..], height, &*self.broadcaster, &*self.fee_estimator);
 		}
 	}
 
-	fn block_disconnected(&self, _: &BlockHeader) { }
+	fn block_disconnected(&self, header: &BlockHeader) {
+		// Forget any commitment transactions (and spends of their outputs) which were included in
+		// the disconnected block, as they may not be included in the new chain.
+		let block_hash = header.bitcoin_hash();
+		let mut commitment_txids = self.commitment_txids.lock().unwrap();
+		commitment_txids.retain(|_, info| info.block_hash != block_hash);
+		for info in commitment_txids.values_mut() {
+			for spend in info.output_spends.values_mut() {
+				if spend.map(|(spend_block_hash, _)| spend_block_hash == block_hash).unwrap_or(false) {
+					*spend = None;
+				}
+			}
+		}
+	}
 }
 
-impl<Key : Send + cmp::Eq + hash::Hash + 'static> SimpleManyChannelMonitor<Key> {
-	pub fn new(chain_monitor: Arc<ChainWatchInterface>, broadcaster: Arc<BroadcasterInterface>) -> Arc<SimpleManyChannelMonitor<Key>> {
+impl<Key : Send + cmp::Eq + hash::Hash + Clone + 'static> SimpleManyChannelMonitor<Key> {
+	/// fee_estimator is used to pick the fee paid by the transactions we broadcast to claim
+	/// funds from revoked commitment transactions.
+	pub fn new(chain_monitor: Arc<ChainWatchInterface>, broadcaster: Arc<BroadcasterInterface>, fee_estimator: Arc<FeeEstimator>) -> Arc<SimpleManyChannelMonitor<Key>> {
 		let res = Arc::new(SimpleManyChannelMonitor {
 			monitors: Mutex::new(HashMap::new()),
+			funding_outpoints: Mutex::new(HashMap::new()),
+			commitment_txids: Mutex::new(HashMap::new()),
 			chain_monitor,
-			broadcaster
+			broadcaster,
+			fee_estimator,
+			#[cfg(test)]
+			txn_dispatched: Mutex::new(HashMap::new()),
 		});
 		let weak_res = Arc::downgrade(&res);
 		res.chain_monitor.register_listener(weak_res);
 		res
 	}
 
-	pub fn add_update_monitor_by_key(&self, key: Key, monitor: ChannelMonitor) -> Result<(), HandleError> {
+	/// Adds a monitor under the given key, failing if one already exists.
+	pub fn add_monitor_by_key(&self, key: Key, monitor: ChannelMonitor) -> Result<(), HandleError> {
 		let mut monitors = self.monitors.lock().unwrap();
-		match monitors.get_mut(&key) {
-			Some(orig_monitor) => return orig_monitor.insert_combine(monitor),
-			None => {}
-		};
+		if monitors.contains_key(&key) {
+			return Err(HandleError{err: "Channel monitor for given key is already present", msg: None});
+		}
 		match monitor.funding_txo {
 			None => self.chain_monitor.watch_all_txn(),
-			Some(outpoint) => self.chain_monitor.install_watch_outpoint((outpoint.txid, outpoint.index as u32)),
+			Some(outpoint) => {
+				self.chain_monitor.install_watch_outpoint((outpoint.txid, outpoint.index as u32));
+				self.funding_outpoints.lock().unwrap().entry(outpoint).or_insert(Vec::new()).push(key.clone());
+			},
 		}
 		monitors.insert(key, monitor);
 		Ok(())
 	}
+
+	/// Gets a copy of the monitor with the given key, eg to reload ChannelManager state from it on
+	/// startup (see ChannelManager::load_onion_replay_log).
+	pub fn get_monitor_by_key(&self, key: &Key) -> Option<ChannelMonitor> {
+		self.monitors.lock().unwrap().get(key).cloned()
+	}
+
+	/// Applies an update to the monitor with the given key.
+	pub fn update_monitor_by_key(&self, key: Key, update: ChannelMonitorUpdate) -> Result<(), HandleError> {
+		let mut monitors = self.monitors.lock().unwrap();
+		match monitors.get_mut(&key) {
+			Some(orig_monitor) => orig_monitor.update_monitor(&update),
+			None => Err(HandleError{err: "No such channel monitor registered", msg: None}),
+		}
+	}
 }
 
 impl ManyChannelMonitor for SimpleManyChannelMonitor<OutPoint> {
-	fn add_update_monitor(&self, funding_txo: OutPoint, monitor: ChannelMonitor) -> Result<(), ChannelMonitorUpdateErr> {
-		match self.add_update_monitor_by_key(funding_txo, monitor) {
+	fn add_monitor(&self, funding_txo: OutPoint, monitor: ChannelMonitor) -> Result<(), ChannelMonitorUpdateErr> {
+		match self.add_monitor_by_key(funding_txo, monitor) {
+			Ok(_) => Ok(()),
+			Err(_) => Err(ChannelMonitorUpdateErr::PermanentFailure),
+		}
+	}
+
+	fn update_monitor(&self, funding_txo: OutPoint, update: ChannelMonitorUpdate) -> Result<(), ChannelMonitorUpdateErr> {
+		match self.update_monitor_by_key(funding_txo, update) {
 			Ok(_) => Ok(()),
 			Err(_) => Err(ChannelMonitorUpdateErr::PermanentFailure),
 		}
@@ -113,13 +595,12 @@ impl ManyChannelMonitor for SimpleManyChannelMonitor<OutPoint> {
 const CLTV_SHARED_CLAIM_BUFFER: u32 = 12;
 /// If an HTLC expires within this many blocks, force-close the channel to broadcast the
 /// HTLC-Success transaction.
-const CLTV_CLAIM_BUFFER: u32 = 6;
+pub(crate) const CLTV_CLAIM_BUFFER: u32 = 6;
 
 #[derive(Clone)]
 enum KeyStorage {
-	PrivMode {
-		revocation_base_key: SecretKey,
-		htlc_base_key: SecretKey,
+	SignerMode {
+		signer: Arc<ChannelSigner>,
 	},
 	SigsMode {
 		revocation_base_key: PublicKey,
@@ -141,6 +622,7 @@ struct LocalSignedTx {
 }
 
 pub struct ChannelMonitor {
+	latest_update_id: u64,
 	funding_txo: Option<OutPoint>,
 	commitment_transaction_number_obscure_factor: u64,
 
@@ -159,6 +641,10 @@ pub struct ChannelMonitor {
 	//hash to commitment number mapping use to determine the state of transaction owning it
 	// (revoked/non-revoked) and so lightnen pruning
 	remote_hash_commitment_number: HashMap<[u8; 32], u64>,
+	// The unsigned remote commitment transactions which have not yet been revoked, plus the most
+	// recently revoked one, by commitment number. The revoked one is kept until the next
+	// revocation so that its justice transactions can be handed to a watchtower.
+	remote_commitment_txn: HashMap<u64, Transaction>,
 
 	// We store two local commitment transactions to avoid any race conditions where we may update
 	// some monitors (potentially on watchtowers) but then fail to update others, resulting in the
@@ -168,6 +654,9 @@ pub struct ChannelMonitor {
 	current_local_signed_commitment_tx: Option<LocalSignedTx>,
 
 	payment_preimages: HashMap<[u8; 32], [u8; 32]>,
+	// SHA256(onion shared secret) -> cltv_expiry for each HTLC we've accepted over this channel,
+	// pruned once the HTLC has expired (see provide_onion_replay_log_entry)
+	onion_replay_log: Mutex<HashMap<[u8; 32], u32>>,
 
 	destination_script: Script,
 	secp_ctx: Secp256k1, //TODO: dedup this a bit...
@@ -175,6 +664,7 @@ pub struct ChannelMonitor {
 impl Clone for ChannelMonitor {
 	fn clone(&self) -> Self {
 		ChannelMonitor {
+			latest_update_id: self.latest_update_id,
 			funding_txo: self.funding_txo.clone(),
 			commitment_transaction_number_obscure_factor: self.commitment_transaction_number_obscure_factor.clone(),
 
@@ -190,11 +680,13 @@ impl Clone for ChannelMonitor {
 			remote_claimable_outpoints: self.remote_claimable_outpoints.clone(),
 			remote_htlc_outputs_on_chain: Mutex::new((*self.remote_htlc_outputs_on_chain.lock().unwrap()).clone()),
 			remote_hash_commitment_number: self.remote_hash_commitment_number.clone(),
+			remote_commitment_txn: self.remote_commitment_txn.clone(),
 
 			prev_local_signed_commitment_tx: self.prev_local_signed_commitment_tx.clone(),
 			current_local_signed_commitment_tx: self.current_local_signed_commitment_tx.clone(),
 
 			payment_preimages: self.payment_preimages.clone(),
+			onion_replay_log: Mutex::new(self.onion_replay_log.lock().unwrap().clone()),
 
 			destination_script: self.destination_script.clone(),
 			secp_ctx: self.secp_ctx.clone(),
@@ -203,16 +695,20 @@ impl Clone for ChannelMonitor {
 }
 
 impl ChannelMonitor {
-	pub fn new(revocation_base_key: &SecretKey, delayed_payment_base_key: &PublicKey, htlc_base_key: &SecretKey, our_to_self_delay: u16, destination_script: Script) -> ChannelMonitor {
+	/// Creates a new ChannelMonitor which uses the given signer (which must be the same one as is
+	/// used by the corresponding Channel) to sign any claim transactions.
+	pub fn new(signer: &Arc<ChannelSigner>, our_to_self_delay: u16, destination_script: Script) -> ChannelMonitor {
+		let secp_ctx = Secp256k1::new();
+		let delayed_payment_base_key = signer.pubkeys(&secp_ctx).delayed_payment_basepoint;
 		ChannelMonitor {
+			latest_update_id: 0,
 			funding_txo: None,
 			commitment_transaction_number_obscure_factor: 0,
 
-			key_storage: KeyStorage::PrivMode {
-				revocation_base_key: revocation_base_key.clone(),
-				htlc_base_key: htlc_base_key.clone(),
+			key_storage: KeyStorage::SignerMode {
+				signer: signer.clone(),
 			},
-			delayed_payment_base_key: delayed_payment_base_key.clone(),
+			delayed_payment_base_key: delayed_payment_base_key,
 			their_htlc_base_key: None,
 			their_cur_revocation_points: None,
 
@@ -223,14 +719,16 @@ impl ChannelMonitor {
 			remote_claimable_outpoints: HashMap::new(),
 			remote_htlc_outputs_on_chain: Mutex::new(HashMap::new()),
 			remote_hash_commitment_number: HashMap::new(),
+			remote_commitment_txn: HashMap::new(),
 
 			prev_local_signed_commitment_tx: None,
 			current_local_signed_commitment_tx: None,
 
 			payment_preimages: HashMap::new(),
+			onion_replay_log: Mutex::new(HashMap::new()),
 
 			destination_script: destination_script,
-			secp_ctx: Secp256k1::new(),
+			secp_ctx: secp_ctx,
 		}
 	}
 
@@ -244,6 +742,19 @@ impl ChannelMonitor {
 		48
 	}
 
+	/// Checks that secret is consistent with the secrets in old_secrets which it should be able
+	/// to derive.
+	fn check_secret(old_secrets: &[([u8; 32], u64); 49], idx: u64, secret: [u8; 32]) -> Result<(), HandleError> {
+		let pos = ChannelMonitor::place_secret(idx);
+		for i in 0..pos {
+			let (old_secret, old_idx) = old_secrets[i as usize];
+			if ChannelMonitor::derive_secret(secret, pos, old_idx) != old_secret {
+				return Err(HandleError{err: "Previous secret did not match new one", msg: None})
+			}
+		}
+		Ok(())
+	}
+
 	#[inline]
 	fn derive_secret(secret: [u8; 32], bits: u8, idx: u64) -> [u8; 32] {
 		let mut res: [u8; 32] = secret;
@@ -265,14 +776,10 @@ impl ChannelMonitor {
 	/// needed by local commitment transactions HTCLs nor by remote ones. Unless we haven't already seen remote
 	/// commitment transaction's secret, they are de facto pruned (we can use revocation key).
 	pub fn provide_secret(&mut self, idx: u64, secret: [u8; 32], their_next_revocation_point: Option<(u64, PublicKey)>) -> Result<(), HandleError> {
+		ChannelMonitor::check_secret(&self.old_secrets, idx, secret)?;
 		let pos = ChannelMonitor::place_secret(idx);
-		for i in 0..pos {
-			let (old_secret, old_idx) = self.old_secrets[i as usize];
-			if ChannelMonitor::derive_secret(secret, pos, old_idx) != old_secret {
-				return Err(HandleError{err: "Previous secret did not match new one", msg: None})
-			}
-		}
 		self.old_secrets[pos as usize] = (secret, idx);
+		self.remote_commitment_txn.retain(|&commitment_number, _| commitment_number <= idx);
 
 		if let Some(new_revocation_point) = their_next_revocation_point {
 			match self.their_cur_revocation_points {
@@ -335,13 +842,13 @@ impl ChannelMonitor {
 	/// possibly future revocation/preimage information) to claim outputs where possible.
 	/// We cache also the mapping hash:commitment number to lighten pruning of old preimages by watchtowers.
 	pub fn provide_latest_remote_commitment_tx_info(&mut self, unsigned_commitment_tx: &Transaction, htlc_outputs: Vec<HTLCOutputInCommitment>, commitment_number: u64) {
-		// TODO: Encrypt the htlc_outputs data with the single-hash of the commitment transaction
-		// so that a remote monitor doesn't learn anything unless there is a malicious close.
-		// (only maybe, sadly we cant do the same for local info, as we need to be aware of
-		// timeouts)
+		// Note that a full remote monitor learns about all of our HTLCs here. If that is a
+		// concern, use ln::watchtower instead, which only hands the tower justice transactions
+		// encrypted with the (single-hash of the) commitment transaction's txid.
 		for htlc in &htlc_outputs {
 			self.remote_hash_commitment_number.insert(htlc.payment_hash, commitment_number);
 		}
+		self.remote_commitment_txn.insert(commitment_number, unsigned_commitment_tx.clone());
 		self.remote_claimable_outpoints.insert(unsigned_commitment_tx.txid(), htlc_outputs);
 	}
 
@@ -371,36 +878,99 @@ impl ChannelMonitor {
 		self.payment_preimages.insert(payment_hash.clone(), payment_preimage.clone());
 	}
 
-	pub fn insert_combine(&mut self, mut other: ChannelMonitor) -> Result<(), HandleError> {
-		match self.funding_txo {
-			Some(txo) => if other.funding_txo.is_some() && other.funding_txo.unwrap() != txo {
-				return Err(HandleError{err: "Funding transaction outputs are not identical!", msg: None});
-			},
-			None => if other.funding_txo.is_some() {
-				self.funding_txo = other.funding_txo;
+	/// Records the onion of an HTLC we accepted over this channel, so that ChannelManager can
+	/// reject replays of it even after a restart (see ChannelManager::load_onion_replay_log).
+	/// Entries are dropped once a block at cltv_expiry is connected, at which point the HTLC has
+	/// expired.
+	pub fn provide_onion_replay_log_entry(&mut self, onion_secret_hash: [u8; 32], cltv_expiry: u32) {
+		let mut onion_replay_log = self.onion_replay_log.lock().unwrap();
+		let existing = onion_replay_log.entry(onion_secret_hash).or_insert(cltv_expiry);
+		*existing = cmp::max(*existing, cltv_expiry);
+	}
+
+	/// Gets the SHA256(onion shared secret) -> cltv_expiry entries for the HTLCs we've accepted
+	/// over this channel which have not yet expired.
+	pub fn get_onion_replay_log(&self) -> HashMap<[u8; 32], u32> {
+		self.onion_replay_log.lock().unwrap().clone()
+	}
+
+	/// Drops onion replay log entries whose HTLCs have expired at the given height.
+	pub(super) fn prune_onion_replay_log(&self, height: u32) {
+		self.onion_replay_log.lock().unwrap().retain(|_, cltv_expiry| *cltv_expiry > height);
+	}
+
+	/// Applies an update to this monitor, failing if it is not the update immediately following
+	/// the last one applied or if any of its contents are invalid. The update is checked in full
+	/// before any of it is applied, so on failure the monitor is left unchanged.
+	pub fn update_monitor(&mut self, update: &ChannelMonitorUpdate) -> Result<(), HandleError> {
+		if update.update_id != self.latest_update_id + 1 {
+			return Err(HandleError{err: "Got an out-of-order ChannelMonitorUpdate", msg: None});
+		}
+		// Only revocation secrets (each of which must also be consistent with any secrets provided
+		// earlier in the same update) and local commitment transactions (which we can only handle
+		// once their_to_self_delay is set) can be rejected.
+		let mut old_secrets = self.old_secrets;
+		for step in update.updates.iter() {
+			match step {
+				&ChannelMonitorUpdateStep::CommitmentSecret { idx, secret, .. } => {
+					ChannelMonitor::check_secret(&old_secrets, idx, secret)?;
+					old_secrets[ChannelMonitor::place_secret(idx) as usize] = (secret, idx);
+				},
+				&ChannelMonitorUpdateStep::LatestLocalCommitmentTXInfo { .. } => self.check_can_take_local_commitment_tx()?,
+				_ => {},
 			}
 		}
-		let other_min_secret = other.get_min_seen_secret();
-		let our_min_secret = self.get_min_seen_secret();
-		if our_min_secret > other_min_secret {
-			self.provide_secret(other_min_secret, other.get_secret(other_min_secret).unwrap(), None)?;
+		for step in update.updates.iter() {
+			self.apply_update_step(step)?;
 		}
-		if our_min_secret >= other_min_secret {
-			self.their_cur_revocation_points = other.their_cur_revocation_points;
-			for (txid, htlcs) in other.remote_claimable_outpoints.drain() {
-				self.remote_claimable_outpoints.insert(txid, htlcs);
-			}
-			if let Some(local_tx) = other.prev_local_signed_commitment_tx {
-				self.prev_local_signed_commitment_tx = Some(local_tx);
-			}
-			if let Some(local_tx) = other.current_local_signed_commitment_tx {
-				self.current_local_signed_commitment_tx = Some(local_tx);
-			}
-			self.payment_preimages = other.payment_preimages;
+		self.latest_update_id = update.update_id;
+		Ok(())
+	}
+
+	/// Applies a single update step without touching latest_update_id. Channel uses this to keep
+	/// its own copy of the monitor up-to-date as it goes, bundling the steps into a
+	/// ChannelMonitorUpdate afterwards (see set_latest_update_id).
+	pub(super) fn apply_update_step(&mut self, step: &ChannelMonitorUpdateStep) -> Result<(), HandleError> {
+		match step {
+			&ChannelMonitorUpdateStep::LatestLocalCommitmentTXInfo { ref signed_commitment_tx, ref local_keys, feerate_per_kw, ref htlc_outputs } => {
+				self.check_can_take_local_commitment_tx()?;
+				self.provide_latest_local_commitment_tx_info(signed_commitment_tx.clone(), local_keys.clone(), feerate_per_kw, htlc_outputs.clone());
+			},
+			&ChannelMonitorUpdateStep::LatestRemoteCommitmentTXInfo { ref unsigned_commitment_tx, ref htlc_outputs, commitment_number } => {
+				self.provide_latest_remote_commitment_tx_info(unsigned_commitment_tx, htlc_outputs.clone(), commitment_number);
+			},
+			&ChannelMonitorUpdateStep::PaymentPreimage { ref payment_hash, ref payment_preimage } => {
+				self.provide_payment_preimage(payment_hash, payment_preimage);
+			},
+			&ChannelMonitorUpdateStep::CommitmentSecret { idx, secret, their_next_revocation_point } => {
+				self.provide_secret(idx, secret, their_next_revocation_point)?;
+			},
+			&ChannelMonitorUpdateStep::OnionReplayLogEntry { onion_secret_hash, cltv_expiry } => {
+				self.provide_onion_replay_log_entry(onion_secret_hash, cltv_expiry);
+			},
+		}
+		Ok(())
+	}
+
+	/// Checks that provide_latest_local_commitment_tx_info won't panic, ie that
+	/// set_their_to_self_delay has been called.
+	fn check_can_take_local_commitment_tx(&self) -> Result<(), HandleError> {
+		if self.their_to_self_delay.is_none() {
+			return Err(HandleError{err: "Got a local commitment transaction before their_to_self_delay was set", msg: None});
 		}
 		Ok(())
 	}
 
+	pub(super) fn set_latest_update_id(&mut self, update_id: u64) {
+		self.latest_update_id = update_id;
+	}
+
+	/// Gets the update_id of the last ChannelMonitorUpdate applied to this monitor (or, for a
+	/// Channel's copy of its monitor, generated by the Channel).
+	pub fn get_latest_update_id(&self) -> u64 {
+		self.latest_update_id
+	}
+
 	/// Panics if commitment_transaction_number_obscure_factor doesn't fit in 48 bits
 	pub fn set_commitment_obscure_factor(&mut self, commitment_transaction_number_obscure_factor: u64) {
 		assert!(commitment_transaction_number_obscure_factor < (1 << 48));
@@ -445,6 +1015,27 @@ impl ChannelMonitor {
 		Err(HandleError{err: "idx too low", msg: None})
 	}
 
+	/// Gets the signed transactions which would claim all of the outputs of each revoked remote
+	/// commitment transaction this monitor still knows about (ie the most recently revoked one,
+	/// if any), along with its commitment number and txid. These are suitable for handing to a
+	/// watchtower (see ln::watchtower) as they claim every HTLC output in a single transaction,
+	/// rather than depending on the height at which the commitment transaction is confirmed.
+	/// Their fee is picked using fee_estimator now, as they cannot be re-signed later.
+	pub fn get_revoked_remote_justice_txn(&self, fee_estimator: &FeeEstimator) -> Vec<(u64, Sha256dHash, Vec<Transaction>)> {
+		let min_seen_secret = self.get_min_seen_secret();
+		let mut res = Vec::new();
+		for (commitment_number, tx) in self.remote_commitment_txn.iter() {
+			if *commitment_number >= min_seen_secret {
+				let commitment_txid = tx.txid();
+				let (justice_txn, _) = self.build_remote_claim_txn(tx, &commitment_txid, 0, fee_estimator);
+				if !justice_txn.is_empty() {
+					res.push((*commitment_number, commitment_txid, justice_txn));
+				}
+			}
+		}
+		res
+	}
+
 	pub fn get_min_seen_secret(&self) -> u64 {
 		//TODO This can be optimized?
 		let mut min = 1 << 48;
@@ -461,31 +1052,68 @@ impl ChannelMonitor {
 	/// height > height + CLTV_SHARED_CLAIM_BUFFER. In any case, will install monitoring for
 	/// HTLC-Success/HTLC-Timeout transactions, and claim them using the revocation key (if
 	/// applicable) as well.
-	fn check_spend_remote_transaction(&self, tx: &Transaction, height: u32) -> Vec<Transaction> {
+	fn check_spend_remote_transaction(&self, tx: &Transaction, commitment_txid: &Sha256dHash, height: u32, fee_estimator: &FeeEstimator) -> Vec<Transaction> {
+		let (txn_to_broadcast, revoked_commitment_number) = self.build_remote_claim_txn(tx, commitment_txid, height, fee_estimator);
+		if let Some(commitment_number) = revoked_commitment_number {
+			self.remote_htlc_outputs_on_chain.lock().unwrap().insert(commitment_txid.clone(), commitment_number);
+		}
+		txn_to_broadcast
+	}
+
+	/// Builds the transactions check_spend_remote_transaction would broadcast for tx, without
+	/// recording tx as being on chain. Also returns tx's commitment number if it is a revoked
+	/// remote commitment transaction we can claim outputs from.
+	/// commitment_txid must be tx's txid, which is passed in so that a caller checking a
+	/// transaction against many monitors only has to calculate it once.
+	/// Each transaction pays a fee at fee_estimator's HighPriority feerate, and any which would
+	/// have to spend all of their value on fees are omitted.
+	fn build_remote_claim_txn(&self, tx: &Transaction, commitment_txid: &Sha256dHash, height: u32, fee_estimator: &FeeEstimator) -> (Vec<Transaction>, Option<u64>) {
 		// Most secp and related errors trying to create keys means we have no hope of constructing
 		// a spend transaction...so we return no transactions to broadcast
 		let mut txn_to_broadcast = Vec::new();
+		let mut revoked_commitment_number = None;
 		macro_rules! ignore_error {
 			( $thing : expr ) => {
 				match $thing {
 					Ok(a) => a,
-					Err(_) => return txn_to_broadcast
+					Err(_) => return (txn_to_broadcast, revoked_commitment_number)
 				}
 			};
 		}
+		let feerate = fee_estimator.get_est_sat_per_vbyte(ConfirmationTarget::HighPriority);
+		// Signs $tx (whose single output pays its full input value) via $sign_all, which must fill
+		// in every input's witness, to find its weight, then deducts the fee for that weight from
+		// the output and re-signs. Evaluates to false (leaving $tx unsigned) if the fee would be at
+		// least the output's value.
+		macro_rules! subtract_fee_and_sign {
+			($tx: expr, $sign_all: expr) => {
+				{
+					$sign_all;
+					let fee = feerate * $tx.get_weight() / 4;
+					for input in $tx.input.iter_mut() {
+						input.witness.clear();
+					}
+					if fee < $tx.output[0].value {
+						$tx.output[0].value -= fee;
+						$sign_all;
+						true
+					} else { false }
+				}
+			}
+		}
 
-		let commitment_txid = tx.txid(); //TODO: This is gonna be a performance bottleneck for watchtowers!
-		let per_commitment_option = self.remote_claimable_outpoints.get(&commitment_txid);
+		let per_commitment_option = self.remote_claimable_outpoints.get(commitment_txid);
 
 		let commitment_number = (((tx.input[0].sequence as u64 & 0xffffff) << 3*8) | (tx.lock_time as u64 & 0xffffff)) ^ self.commitment_transaction_number_obscure_factor;
 		if commitment_number >= self.get_min_seen_secret() {
 			let secret = self.get_secret(commitment_number).unwrap();
 			let per_commitment_key = ignore_error!(SecretKey::from_slice(&self.secp_ctx, &secret));
 			let (revocation_pubkey, b_htlc_key) = match self.key_storage {
-				KeyStorage::PrivMode { ref revocation_base_key, ref htlc_base_key } => {
+				KeyStorage::SignerMode { ref signer } => {
 					let per_commitment_point = ignore_error!(PublicKey::from_secret_key(&self.secp_ctx, &per_commitment_key));
-					(ignore_error!(chan_utils::derive_public_revocation_key(&self.secp_ctx, &per_commitment_point, &ignore_error!(PublicKey::from_secret_key(&self.secp_ctx, &revocation_base_key)))),
-					ignore_error!(chan_utils::derive_public_key(&self.secp_ctx, &per_commitment_point, &ignore_error!(PublicKey::from_secret_key(&self.secp_ctx, &htlc_base_key)))))
+					let our_pubkeys = signer.pubkeys(&self.secp_ctx);
+					(ignore_error!(chan_utils::derive_public_revocation_key(&self.secp_ctx, &per_commitment_point, &our_pubkeys.revocation_basepoint)),
+					ignore_error!(chan_utils::derive_public_key(&self.secp_ctx, &per_commitment_point, &our_pubkeys.htlc_basepoint)))
 				},
 				KeyStorage::SigsMode { ref revocation_base_key, ref htlc_base_key, .. } => {
 					let per_commitment_point = ignore_error!(PublicKey::from_secret_key(&self.secp_ctx, &per_commitment_key));
@@ -495,7 +1123,7 @@ impl ChannelMonitor {
 			};
 			let delayed_key = ignore_error!(chan_utils::derive_public_key(&self.secp_ctx, &ignore_error!(PublicKey::from_secret_key(&self.secp_ctx, &per_commitment_key)), &self.delayed_payment_base_key));
 			let a_htlc_key = match self.their_htlc_base_key {
-				None => return txn_to_broadcast,
+				None => return (txn_to_broadcast, revoked_commitment_number),
 				Some(their_htlc_base_key) => ignore_error!(chan_utils::derive_public_key(&self.secp_ctx, &ignore_error!(PublicKey::from_secret_key(&self.secp_ctx, &per_commitment_key)), &their_htlc_base_key)),
 			};
 
@@ -510,7 +1138,7 @@ impl ChannelMonitor {
 			for (idx, outp) in tx.output.iter().enumerate() {
 				if outp.script_pubkey == revokeable_p2wsh {
 					inputs.push(TxIn {
-						prev_hash: commitment_txid,
+						prev_hash: commitment_txid.clone(),
 						prev_index: idx as u32,
 						script_sig: Script::new(),
 						sequence: 0xfffffffd,
@@ -524,30 +1152,29 @@ impl ChannelMonitor {
 			}
 
 			macro_rules! sign_input {
-				($sighash_parts: expr, $input: expr, $htlc_idx: expr, $amount: expr) => {
+				($tx: expr, $input_idx: expr, $htlc_idx: expr, $amount: expr) => {
 					{
 						let (sig, redeemscript) = match self.key_storage {
-							KeyStorage::PrivMode { ref revocation_base_key, .. } => {
+							KeyStorage::SignerMode { ref signer } => {
 								let redeemscript = if $htlc_idx.is_none() { revokeable_redeemscript.clone() } else {
 									let htlc = &per_commitment_option.unwrap()[$htlc_idx.unwrap()];
 									chan_utils::get_htlc_redeemscript_with_explicit_keys(htlc, &a_htlc_key, &b_htlc_key, &revocation_pubkey)
 								};
-								let sighash = ignore_error!(Message::from_slice(&$sighash_parts.sighash_all(&$input, &redeemscript, $amount)[..]));
-								let revocation_key = ignore_error!(chan_utils::derive_private_revocation_key(&self.secp_ctx, &per_commitment_key, &revocation_base_key));
-								(ignore_error!(self.secp_ctx.sign(&sighash, &revocation_key)), redeemscript)
+								(ignore_error!(signer.sign_justice_transaction(&self.secp_ctx, &$tx, $input_idx, &redeemscript, $amount, &per_commitment_key)), redeemscript)
 							},
 							KeyStorage::SigsMode { .. } => {
 								unimplemented!();
 							}
 						};
-						$input.witness.push(sig.serialize_der(&self.secp_ctx).to_vec());
-						$input.witness[0].push(SigHashType::All as u8);
+						let input = &mut $tx.input[$input_idx];
+						input.witness.push(sig.serialize_der(&self.secp_ctx).to_vec());
+						input.witness[0].push(SigHashType::All as u8);
 						if $htlc_idx.is_none() {
-							$input.witness.push(vec!(1));
+							input.witness.push(vec!(1));
 						} else {
-							$input.witness.push(revocation_pubkey.serialize().to_vec());
+							input.witness.push(revocation_pubkey.serialize().to_vec());
 						}
-						$input.witness.push(redeemscript.into_vec());
+						input.witness.push(redeemscript.into_vec());
 					}
 				}
 			}
@@ -560,10 +1187,10 @@ impl ChannelMonitor {
 					if htlc.transaction_output_index as usize >= tx.output.len() ||
 							tx.output[htlc.transaction_output_index as usize].value != htlc.amount_msat / 1000 ||
 							tx.output[htlc.transaction_output_index as usize].script_pubkey != expected_script.to_v0_p2wsh() {
-						return txn_to_broadcast; // Corrupted per_commitment_data, fuck this user
+						return (txn_to_broadcast, revoked_commitment_number); // Corrupted per_commitment_data, fuck this user
 					}
 					let input = TxIn {
-						prev_hash: commitment_txid,
+						prev_hash: commitment_txid.clone(),
 						prev_index: htlc.transaction_output_index,
 						script_sig: Script::new(),
 						sequence: 0xfffffffd,
@@ -581,12 +1208,12 @@ impl ChannelMonitor {
 							input: vec![input],
 							output: vec!(TxOut {
 								script_pubkey: self.destination_script.clone(),
-								value: htlc.amount_msat / 1000, //TODO: - fee
+								value: htlc.amount_msat / 1000,
 							}),
 						};
-						let sighash_parts = bip143::SighashComponents::new(&single_htlc_tx);
-						sign_input!(sighash_parts, single_htlc_tx.input[0], Some(idx), htlc.amount_msat / 1000);
-						txn_to_broadcast.push(single_htlc_tx); // TODO: This is not yet tested in ChannelManager!
+						if subtract_fee_and_sign!(single_htlc_tx, sign_input!(single_htlc_tx, 0, Some(idx), htlc.amount_msat / 1000)) {
+							txn_to_broadcast.push(single_htlc_tx); // TODO: This is not yet tested in ChannelManager!
+						}
 					}
 				}
 			}
@@ -594,13 +1221,13 @@ impl ChannelMonitor {
 			if !inputs.is_empty() || !txn_to_broadcast.is_empty() {
 				// We're definitely a remote commitment transaction!
 				// TODO: Register commitment_txid with the ChainWatchInterface!
-				self.remote_htlc_outputs_on_chain.lock().unwrap().insert(commitment_txid, commitment_number);
+				revoked_commitment_number = Some(commitment_number);
 			}
-			if inputs.is_empty() { return txn_to_broadcast; } // Nothing to be done...probably a false positive/local tx
+			if inputs.is_empty() { return (txn_to_broadcast, revoked_commitment_number); } // Nothing to be done...probably a false positive/local tx
 
 			let outputs = vec!(TxOut {
 				script_pubkey: self.destination_script.clone(),
-				value: total_value, //TODO: - fee
+				value: total_value,
 			});
 			let mut spend_tx = Transaction {
 				version: 2,
@@ -609,15 +1236,14 @@ impl ChannelMonitor {
 				output: outputs,
 			};
 
-			let mut values_drain = values.drain(..);
-			let sighash_parts = bip143::SighashComponents::new(&spend_tx);
-
-			for (input, htlc_idx) in spend_tx.input.iter_mut().zip(htlc_idxs.iter()) {
-				let value = values_drain.next().unwrap();
-				sign_input!(sighash_parts, input, htlc_idx, value);
+			let signed = subtract_fee_and_sign!(spend_tx, {
+				for (input_idx, (htlc_idx, value)) in htlc_idxs.iter().zip(values.iter()).enumerate() {
+					sign_input!(spend_tx, input_idx, htlc_idx, *value);
+				}
+			});
+			if signed {
+				txn_to_broadcast.push(spend_tx);
 			}
-
-			txn_to_broadcast.push(spend_tx);
 		} else if let Some(per_commitment_data) = per_commitment_option {
 			if let Some(revocation_points) = self.their_cur_revocation_points {
 				let revocation_point_option =
@@ -627,9 +1253,10 @@ impl ChannelMonitor {
 					} else { None };
 				if let Some(revocation_point) = revocation_point_option {
 					let (revocation_pubkey, b_htlc_key) = match self.key_storage {
-						KeyStorage::PrivMode { ref revocation_base_key, ref htlc_base_key } => {
-							(ignore_error!(chan_utils::derive_public_revocation_key(&self.secp_ctx, revocation_point, &ignore_error!(PublicKey::from_secret_key(&self.secp_ctx, &revocation_base_key)))),
-							ignore_error!(chan_utils::derive_public_key(&self.secp_ctx, revocation_point, &ignore_error!(PublicKey::from_secret_key(&self.secp_ctx, &htlc_base_key)))))
+						KeyStorage::SignerMode { ref signer } => {
+							let our_pubkeys = signer.pubkeys(&self.secp_ctx);
+							(ignore_error!(chan_utils::derive_public_revocation_key(&self.secp_ctx, revocation_point, &our_pubkeys.revocation_basepoint)),
+							ignore_error!(chan_utils::derive_public_key(&self.secp_ctx, revocation_point, &our_pubkeys.htlc_basepoint)))
 						},
 						KeyStorage::SigsMode { ref revocation_base_key, ref htlc_base_key, .. } => {
 							(ignore_error!(chan_utils::derive_public_revocation_key(&self.secp_ctx, revocation_point, &revocation_base_key)),
@@ -637,7 +1264,7 @@ impl ChannelMonitor {
 						},
 					};
 					let a_htlc_key = match self.their_htlc_base_key {
-						None => return txn_to_broadcast,
+						None => return (txn_to_broadcast, revoked_commitment_number),
 						Some(their_htlc_base_key) => ignore_error!(chan_utils::derive_public_key(&self.secp_ctx, revocation_point, &their_htlc_base_key)),
 					};
 
@@ -646,24 +1273,23 @@ impl ChannelMonitor {
 					let mut inputs = Vec::new();
 
 					macro_rules! sign_input {
-						($sighash_parts: expr, $input: expr, $amount: expr, $preimage: expr) => {
+						($tx: expr, $input_idx: expr, $amount: expr, $preimage: expr) => {
 							{
 								let (sig, redeemscript) = match self.key_storage {
-									KeyStorage::PrivMode { ref htlc_base_key, .. } => {
-										let htlc = &per_commitment_option.unwrap()[$input.sequence as usize];
+									KeyStorage::SignerMode { ref signer } => {
+										let htlc = &per_commitment_option.unwrap()[$tx.input[$input_idx].sequence as usize];
 										let redeemscript = chan_utils::get_htlc_redeemscript_with_explicit_keys(htlc, &a_htlc_key, &b_htlc_key, &revocation_pubkey);
-										let sighash = ignore_error!(Message::from_slice(&$sighash_parts.sighash_all(&$input, &redeemscript, $amount)[..]));
-										let htlc_key = ignore_error!(chan_utils::derive_private_key(&self.secp_ctx, revocation_point, &htlc_base_key));
-										(ignore_error!(self.secp_ctx.sign(&sighash, &htlc_key)), redeemscript)
+										(ignore_error!(signer.sign_htlc_transaction(&self.secp_ctx, &$tx, $input_idx, &redeemscript, $amount, revocation_point)), redeemscript)
 									},
 									KeyStorage::SigsMode { .. } => {
 										unimplemented!();
 									}
 								};
-								$input.witness.push(sig.serialize_der(&self.secp_ctx).to_vec());
-								$input.witness[0].push(SigHashType::All as u8);
-								$input.witness.push($preimage);
-								$input.witness.push(redeemscript.into_vec());
+								let input = &mut $tx.input[$input_idx];
+								input.witness.push(sig.serialize_der(&self.secp_ctx).to_vec());
+								input.witness[0].push(SigHashType::All as u8);
+								input.witness.push($preimage);
+								input.witness.push(redeemscript.into_vec());
 							}
 						}
 					}
@@ -671,7 +1297,7 @@ impl ChannelMonitor {
 					for (idx, htlc) in per_commitment_data.iter().enumerate() {
 						if let Some(payment_preimage) = self.payment_preimages.get(&htlc.payment_hash) {
 							let input = TxIn {
-								prev_hash: commitment_txid,
+								prev_hash: commitment_txid.clone(),
 								prev_index: htlc.transaction_output_index,
 								script_sig: Script::new(),
 								sequence: idx as u32, // reset to 0xfffffffd in sign_input
@@ -688,21 +1314,21 @@ impl ChannelMonitor {
 									input: vec![input],
 									output: vec!(TxOut {
 										script_pubkey: self.destination_script.clone(),
-										value: htlc.amount_msat / 1000, //TODO: - fee
+										value: htlc.amount_msat / 1000,
 									}),
 								};
-								let sighash_parts = bip143::SighashComponents::new(&single_htlc_tx);
-								sign_input!(sighash_parts, single_htlc_tx.input[0], htlc.amount_msat / 1000, payment_preimage.to_vec());
-								txn_to_broadcast.push(single_htlc_tx);
+								if subtract_fee_and_sign!(single_htlc_tx, sign_input!(single_htlc_tx, 0, htlc.amount_msat / 1000, payment_preimage.to_vec())) {
+									txn_to_broadcast.push(single_htlc_tx);
+								}
 							}
 						}
 					}
 
-					if inputs.is_empty() { return txn_to_broadcast; } // Nothing to be done...probably a false positive/local tx
+					if inputs.is_empty() { return (txn_to_broadcast, revoked_commitment_number); } // Nothing to be done...probably a false positive/local tx
 
 					let outputs = vec!(TxOut {
 						script_pubkey: self.destination_script.clone(),
-						value: total_value, //TODO: - fee
+						value: total_value,
 					});
 					let mut spend_tx = Transaction {
 						version: 2,
@@ -711,22 +1337,21 @@ impl ChannelMonitor {
 						output: outputs,
 					};
 
-					let mut values_drain = values.drain(..);
-					let sighash_parts = bip143::SighashComponents::new(&spend_tx);
-
-					for input in spend_tx.input.iter_mut() {
-						let value = values_drain.next().unwrap();
-						sign_input!(sighash_parts, input, value.0, value.1.to_vec());
+					let signed = subtract_fee_and_sign!(spend_tx, {
+						for (input_idx, value) in values.iter().enumerate() {
+							sign_input!(spend_tx, input_idx, value.0, value.1.to_vec());
+						}
+					});
+					if signed {
+						txn_to_broadcast.push(spend_tx);
 					}
-
-					txn_to_broadcast.push(spend_tx);
 				}
 			}
 		} else {
 			//TODO: For each input check if its in our remote_htlc_outputs_on_chain map!
 		}
 
-		txn_to_broadcast
+		(txn_to_broadcast, revoked_commitment_number)
 	}
 
 	fn broadcast_by_local_state(&self, local_tx: &LocalSignedTx) -> Vec<Transaction> {
@@ -772,32 +1397,37 @@ impl ChannelMonitor {
 	/// Attempts to claim any claimable HTLCs in a commitment transaction which was not (yet)
 	/// revoked using data in local_claimable_outpoints.
 	/// Should not be used if check_spend_revoked_transaction succeeds.
-	fn check_spend_local_transaction(&self, tx: &Transaction, _height: u32) -> Vec<Transaction> {
-		let commitment_txid = tx.txid();
+	fn check_spend_local_transaction(&self, commitment_txid: &Sha256dHash, _height: u32) -> Vec<Transaction> {
 		if let &Some(ref local_tx) = &self.current_local_signed_commitment_tx {
-			if local_tx.txid == commitment_txid {
+			if local_tx.txid == *commitment_txid {
 				return self.broadcast_by_local_state(local_tx);
 			}
 		}
 		if let &Some(ref local_tx) = &self.prev_local_signed_commitment_tx {
-			if local_tx.txid == commitment_txid {
+			if local_tx.txid == *commitment_txid {
 				return self.broadcast_by_local_state(local_tx);
 			}
 		}
 		Vec::new()
 	}
 
-	fn block_connected(&self, txn_matched: &[&Transaction], height: u32, broadcaster: &BroadcasterInterface) {
-		for tx in txn_matched {
-			for txin in tx.input.iter() {
-				if self.funding_txo.is_none() || (txin.prev_hash == self.funding_txo.unwrap().txid && txin.prev_index == self.funding_txo.unwrap().index as u32) {
-					let mut txn = self.check_spend_remote_transaction(tx, height);
-					if txn.is_empty() {
-						txn = self.check_spend_local_transaction(tx, height);
-					}
-					for tx in txn.iter() {
-						broadcaster.broadcast_transaction(tx);
-					}
+	/// Checks the given transactions (each paired with its txid) for spends of our funding output,
+	/// broadcasting any transactions we can claim from them, and broadcasts our local commitment
+	/// transaction if any of its HTLCs are about to expire.
+	fn block_connected(&self, txn_matched: &[(&Transaction, &Sha256dHash)], height: u32, broadcaster: &BroadcasterInterface, fee_estimator: &FeeEstimator) {
+		self.prune_onion_replay_log(height);
+		for &(tx, txid) in txn_matched {
+			let spends_funding = match self.funding_txo {
+				None => true,
+				Some(funding_txo) => tx.input.iter().any(|txin| txin.prev_hash == funding_txo.txid && txin.prev_index == funding_txo.index as u32),
+			};
+			if spends_funding {
+				let mut txn = self.check_spend_remote_transaction(tx, txid, height, fee_estimator);
+				if txn.is_empty() {
+					txn = self.check_spend_local_transaction(txid, height);
+				}
+				for tx in txn.iter() {
+					broadcaster.broadcast_transaction(tx);
 				}
 			}
 		}
@@ -837,22 +1467,31 @@ impl ChannelMonitor {
 #[cfg(test)]
 mod tests {
 	use bitcoin::util::misc::hex_bytes;
-	use bitcoin::blockdata::script::Script;
-	use bitcoin::blockdata::transaction::Transaction;
+	use bitcoin::blockdata::block::BlockHeader;
+	use bitcoin::blockdata::script::{Builder, Script};
+	use bitcoin::blockdata::transaction::{TxIn, TxOut, Transaction};
+	use bitcoin::network::constants::Network;
+	use bitcoin::util::hash::Sha256dHash;
+	use chain::chaininterface::{ChainListener, ChainWatchInterfaceUtil};
+	use chain::transaction::OutPoint;
 	use crypto::digest::Digest;
-	use ln::channelmonitor::ChannelMonitor;
+	use chain::keysinterface::ChannelSigner;
+	use ln::channel::ChannelKeys;
+	use ln::channelmonitor::{ChannelMonitor, ChannelMonitorUpdate, ChannelMonitorUpdateStep, SimpleManyChannelMonitor, COMMITMENT_TX_MAX_WATCH_DEPTH};
 	use ln::chan_utils::{HTLCOutputInCommitment, TxCreationKeys};
+	use util::rng::{EntropySource, SeededEntropySource};
 	use util::sha2::Sha256;
-	use secp256k1::key::{SecretKey,PublicKey};
+	use util::test_utils;
+	use secp256k1::key::{PublicKey, SecretKey};
 	use secp256k1::{Secp256k1, Signature};
-	use rand::{thread_rng,Rng};
+	use std::sync::{Arc, Mutex};
 
 	#[test]
 	fn test_per_commitment_storage() {
 		// Test vectors from BOLT 3:
 		let mut secrets: Vec<[u8; 32]> = Vec::new();
 		let mut monitor: ChannelMonitor;
-		let secp_ctx = Secp256k1::new();
+		let signer: Arc<ChannelSigner> = Arc::new(ChannelKeys::new_from_seed(&[42; 32]).unwrap());
 
 		macro_rules! test_secrets {
 			() => {
@@ -868,7 +1507,7 @@ mod tests {
 
 		{
 			// insert_secret correct sequence
-			monitor = ChannelMonitor::new(&SecretKey::from_slice(&secp_ctx, &[42; 32]).unwrap(), &PublicKey::new(), &SecretKey::from_slice(&secp_ctx, &[43; 32]).unwrap(), 0, Script::new());
+			monitor = ChannelMonitor::new(&signer, 0, Script::new());
 			secrets.clear();
 
 			secrets.push([0; 32]);
@@ -914,7 +1553,7 @@ mod tests {
 
 		{
 			// insert_secret #1 incorrect
-			monitor = ChannelMonitor::new(&SecretKey::from_slice(&secp_ctx, &[42; 32]).unwrap(), &PublicKey::new(), &SecretKey::from_slice(&secp_ctx, &[43; 32]).unwrap(), 0, Script::new());
+			monitor = ChannelMonitor::new(&signer, 0, Script::new());
 			secrets.clear();
 
 			secrets.push([0; 32]);
@@ -930,7 +1569,7 @@ mod tests {
 
 		{
 			// insert_secret #2 incorrect (#1 derived from incorrect)
-			monitor = ChannelMonitor::new(&SecretKey::from_slice(&secp_ctx, &[42; 32]).unwrap(), &PublicKey::new(), &SecretKey::from_slice(&secp_ctx, &[43; 32]).unwrap(), 0, Script::new());
+			monitor = ChannelMonitor::new(&signer, 0, Script::new());
 			secrets.clear();
 
 			secrets.push([0; 32]);
@@ -956,7 +1595,7 @@ mod tests {
 
 		{
 			// insert_secret #3 incorrect
-			monitor = ChannelMonitor::new(&SecretKey::from_slice(&secp_ctx, &[42; 32]).unwrap(), &PublicKey::new(), &SecretKey::from_slice(&secp_ctx, &[43; 32]).unwrap(), 0, Script::new());
+			monitor = ChannelMonitor::new(&signer, 0, Script::new());
 			secrets.clear();
 
 			secrets.push([0; 32]);
@@ -982,7 +1621,7 @@ mod tests {
 
 		{
 			// insert_secret #4 incorrect (1,2,3 derived from incorrect)
-			monitor = ChannelMonitor::new(&SecretKey::from_slice(&secp_ctx, &[42; 32]).unwrap(), &PublicKey::new(), &SecretKey::from_slice(&secp_ctx, &[43; 32]).unwrap(), 0, Script::new());
+			monitor = ChannelMonitor::new(&signer, 0, Script::new());
 			secrets.clear();
 
 			secrets.push([0; 32]);
@@ -1028,7 +1667,7 @@ mod tests {
 
 		{
 			// insert_secret #5 incorrect
-			monitor = ChannelMonitor::new(&SecretKey::from_slice(&secp_ctx, &[42; 32]).unwrap(), &PublicKey::new(), &SecretKey::from_slice(&secp_ctx, &[43; 32]).unwrap(), 0, Script::new());
+			monitor = ChannelMonitor::new(&signer, 0, Script::new());
 			secrets.clear();
 
 			secrets.push([0; 32]);
@@ -1064,7 +1703,7 @@ mod tests {
 
 		{
 			// insert_secret #6 incorrect (5 derived from incorrect)
-			monitor = ChannelMonitor::new(&SecretKey::from_slice(&secp_ctx, &[42; 32]).unwrap(), &PublicKey::new(), &SecretKey::from_slice(&secp_ctx, &[43; 32]).unwrap(), 0, Script::new());
+			monitor = ChannelMonitor::new(&signer, 0, Script::new());
 			secrets.clear();
 
 			secrets.push([0; 32]);
@@ -1110,7 +1749,7 @@ mod tests {
 
 		{
 			// insert_secret #7 incorrect
-			monitor = ChannelMonitor::new(&SecretKey::from_slice(&secp_ctx, &[42; 32]).unwrap(), &PublicKey::new(), &SecretKey::from_slice(&secp_ctx, &[43; 32]).unwrap(), 0, Script::new());
+			monitor = ChannelMonitor::new(&signer, 0, Script::new());
 			secrets.clear();
 
 			secrets.push([0; 32]);
@@ -1156,7 +1795,7 @@ mod tests {
 
 		{
 			// insert_secret #8 incorrect
-			monitor = ChannelMonitor::new(&SecretKey::from_slice(&secp_ctx, &[42; 32]).unwrap(), &PublicKey::new(), &SecretKey::from_slice(&secp_ctx, &[43; 32]).unwrap(), 0, Script::new());
+			monitor = ChannelMonitor::new(&signer, 0, Script::new());
 			secrets.clear();
 
 			secrets.push([0; 32]);
@@ -1204,6 +1843,7 @@ mod tests {
 	#[test]
 	fn test_prune_preimages() {
 		let secp_ctx = Secp256k1::new();
+		let signer: Arc<ChannelSigner> = Arc::new(ChannelKeys::new_from_seed(&[42; 32]).unwrap());
 		let dummy_sig = Signature::from_der(&secp_ctx, &hex_bytes("3045022100fa86fa9a36a8cd6a7bb8f06a541787d51371d067951a9461d5404de6b928782e02201c8b7c334c10aed8976a3a465be9a28abff4cb23acbf00022295b378ce1fa3cd").unwrap()[..]).unwrap();
 
 		macro_rules! dummy_keys {
@@ -1222,10 +1862,10 @@ mod tests {
 
 		let mut preimages = Vec::new();
 		{
-			let mut rng  = thread_rng();
+			let entropy_source = SeededEntropySource::new([42; 32]);
 			for _ in 0..20 {
 				let mut preimage = [0; 32];
-				rng.fill_bytes(&mut preimage);
+				entropy_source.fill_bytes(&mut preimage);
 				let mut sha = Sha256::new();
 				sha.input(&preimage);
 				let mut hash = [0; 32];
@@ -1271,7 +1911,7 @@ mod tests {
 
 		// Prune with one old state and a local commitment tx holding a few overlaps with the
 		// old state.
-		let mut monitor = ChannelMonitor::new(&SecretKey::from_slice(&secp_ctx, &[42; 32]).unwrap(), &PublicKey::new(), &SecretKey::from_slice(&secp_ctx, &[43; 32]).unwrap(), 0, Script::new());
+		let mut monitor = ChannelMonitor::new(&signer, 0, Script::new());
 		monitor.set_their_to_self_delay(10);
 
 		monitor.provide_latest_local_commitment_tx_info(dummy_tx.clone(), dummy_keys!(), 0, preimages_to_local_htlcs!(preimages[0..10]));
@@ -1315,5 +1955,342 @@ mod tests {
 		test_preimages_exist!(&preimages[0..5], monitor);
 	}
 
+	#[test]
+	fn test_update_serialization() {
+		let secp_ctx = Secp256k1::new();
+		let dummy_sig = Signature::from_der(&secp_ctx, &hex_bytes("3045022100fa86fa9a36a8cd6a7bb8f06a541787d51371d067951a9461d5404de6b928782e02201c8b7c334c10aed8976a3a465be9a28abff4cb23acbf00022295b378ce1fa3cd").unwrap()[..]).unwrap();
+		let pubkey = |byte| PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&secp_ctx, &[byte; 32]).unwrap()).unwrap();
+		let tx = Transaction {
+			version: 2,
+			lock_time: 42,
+			input: vec![TxIn { prev_hash: Sha256dHash::from_data(&[1; 32]), prev_index: 1, script_sig: Script::new(), sequence: 0x80000000, witness: vec![vec![1; 72], vec![2; 33]] }],
+			output: vec![TxOut { script_pubkey: Script::from(vec![0; 34]), value: 100000 }],
+		};
+		let htlc = |offered, idx| HTLCOutputInCommitment { offered, amount_msat: 1000000 + idx as u64, cltv_expiry: 500 + idx, payment_hash: [idx as u8; 32], transaction_output_index: idx };
+
+		let update = ChannelMonitorUpdate {
+			updates: vec![
+				ChannelMonitorUpdateStep::LatestLocalCommitmentTXInfo {
+					signed_commitment_tx: tx.clone(),
+					local_keys: TxCreationKeys {
+						per_commitment_point: pubkey(1),
+						revocation_key: pubkey(2),
+						a_htlc_key: pubkey(3),
+						b_htlc_key: pubkey(4),
+						a_delayed_payment_key: pubkey(5),
+						b_payment_key: pubkey(6),
+					},
+					feerate_per_kw: 253,
+					htlc_outputs: vec![(htlc(true, 1), dummy_sig.clone(), dummy_sig.clone()), (htlc(false, 2), dummy_sig.clone(), dummy_sig.clone())],
+				},
+				ChannelMonitorUpdateStep::LatestRemoteCommitmentTXInfo {
+					unsigned_commitment_tx: tx.clone(),
+					htlc_outputs: vec![htlc(false, 3)],
+					commitment_number: 281474976710654,
+				},
+				ChannelMonitorUpdateStep::PaymentPreimage { payment_hash: [7; 32], payment_preimage: [8; 32] },
+				ChannelMonitorUpdateStep::CommitmentSecret { idx: 281474976710655, secret: [9; 32], their_next_revocation_point: Some((281474976710653, pubkey(10))) },
+				ChannelMonitorUpdateStep::CommitmentSecret { idx: 281474976710654, secret: [11; 32], their_next_revocation_point: None },
+				ChannelMonitorUpdateStep::OnionReplayLogEntry { onion_secret_hash: [12; 32], cltv_expiry: 500 },
+			],
+			update_id: 42,
+		};
+
+		let serialized = update.serialize();
+		assert!(ChannelMonitorUpdate::from_serialized(&serialized).unwrap() == update);
+		// An empty update is fine too
+		let empty = ChannelMonitorUpdate { updates: Vec::new(), update_id: 1 };
+		assert!(ChannelMonitorUpdate::from_serialized(&empty.serialize()).unwrap() == empty);
+
+		// Truncated or extended data is rejected
+		for len in 0..serialized.len() {
+			assert!(ChannelMonitorUpdate::from_serialized(&serialized[..len]).is_err());
+		}
+		let mut extended = serialized.clone();
+		extended.push(0);
+		assert!(ChannelMonitorUpdate::from_serialized(&extended).is_err());
+		// ...as is an unknown version or step type
+		let mut bad_version = serialized.clone();
+		bad_version[0] = 2;
+		assert!(ChannelMonitorUpdate::from_serialized(&bad_version).is_err());
+		let mut bad_step = serialized.clone();
+		bad_step[1 + 8 + 8] = 5;
+		assert!(ChannelMonitorUpdate::from_serialized(&bad_step).is_err());
+	}
+
+	#[test]
+	fn test_update_applied_atomically() {
+		// A ChannelMonitorUpdate which fails partway through must not leave the monitor with only
+		// the steps before the failure applied.
+		let signer: Arc<ChannelSigner> = Arc::new(ChannelKeys::new_from_seed(&[42; 32]).unwrap());
+		let secp_ctx = Secp256k1::new();
+		let dummy_sig = Signature::from_der(&secp_ctx, &hex_bytes("3045022100fa86fa9a36a8cd6a7bb8f06a541787d51371d067951a9461d5404de6b928782e02201c8b7c334c10aed8976a3a465be9a28abff4cb23acbf00022295b378ce1fa3cd").unwrap()[..]).unwrap();
+		let dummy_keys = TxCreationKeys {
+			per_commitment_point: PublicKey::new(),
+			revocation_key: PublicKey::new(),
+			a_htlc_key: PublicKey::new(),
+			b_htlc_key: PublicKey::new(),
+			a_delayed_payment_key: PublicKey::new(),
+			b_payment_key: PublicKey::new(),
+		};
+		let dummy_tx = Transaction { version: 0, lock_time: 0, input: Vec::new(), output: Vec::new() };
+		let htlc = HTLCOutputInCommitment { offered: true, amount_msat: 0, cltv_expiry: 0, payment_hash: [1; 32], transaction_output_index: 0 };
+
+		// A local commitment transaction can't be provided until their_to_self_delay is set, which
+		// must be rejected rather than panicking.
+		let mut monitor = ChannelMonitor::new(&signer, 0, Script::new());
+		let update = ChannelMonitorUpdate {
+			updates: vec![
+				ChannelMonitorUpdateStep::PaymentPreimage { payment_hash: [1; 32], payment_preimage: [2; 32] },
+				ChannelMonitorUpdateStep::LatestLocalCommitmentTXInfo { signed_commitment_tx: dummy_tx.clone(), local_keys: dummy_keys.clone(), feerate_per_kw: 0, htlc_outputs: vec![(htlc.clone(), dummy_sig.clone(), dummy_sig.clone())] },
+			],
+			update_id: 1,
+		};
+		assert_eq!(monitor.update_monitor(&update).err().unwrap().err, "Got a local commitment transaction before their_to_self_delay was set");
+		assert_eq!(monitor.get_latest_update_id(), 0);
+		assert!(monitor.payment_preimages.is_empty());
+		assert!(monitor.current_local_signed_commitment_tx.is_none());
+
+		monitor.set_their_to_self_delay(10);
+		// Give the monitor a local commitment transaction holding the HTLC we'll provide the
+		// preimage for, so that it isn't pruned
+		monitor.provide_latest_local_commitment_tx_info(dummy_tx, dummy_keys, 0, vec![(htlc, dummy_sig.clone(), dummy_sig)]);
+		let mut first_secret = [0; 32];
+		first_secret[0..32].clone_from_slice(&hex_bytes("7cc854b54e3e0dcdb010d7a3fee464a9687be6e8db3be6854c475621e007a5dc").unwrap());
+		let mut second_secret = [0; 32];
+		second_secret[0..32].clone_from_slice(&hex_bytes("c7518c8ae4660ed02894df8976fa1a3659c1a8b4b5bec0c4b872abeba4cb8964").unwrap());
+
+		let mut update = ChannelMonitorUpdate {
+			updates: vec![
+				ChannelMonitorUpdateStep::PaymentPreimage { payment_hash: [1; 32], payment_preimage: [2; 32] },
+				ChannelMonitorUpdateStep::CommitmentSecret { idx: 281474976710655, secret: first_secret, their_next_revocation_point: None },
+				// Doesn't match the first secret
+				ChannelMonitorUpdateStep::CommitmentSecret { idx: 281474976710654, secret: [0xff; 32], their_next_revocation_point: None },
+			],
+			update_id: 1,
+		};
+		assert!(monitor.update_monitor(&update).is_err());
+		assert_eq!(monitor.get_latest_update_id(), 0);
+		assert!(monitor.get_secret(281474976710655).is_err());
+		assert!(monitor.payment_preimages.is_empty());
+
+		// Once the bad step is fixed the whole update applies
+		update.updates[2] = ChannelMonitorUpdateStep::CommitmentSecret { idx: 281474976710654, secret: second_secret, their_next_revocation_point: None };
+		monitor.update_monitor(&update).unwrap();
+		assert_eq!(monitor.get_latest_update_id(), 1);
+		assert_eq!(monitor.get_secret(281474976710655).unwrap(), first_secret);
+		assert_eq!(monitor.get_secret(281474976710654).unwrap(), second_secret);
+		assert_eq!(monitor.payment_preimages.get(&[1; 32]), Some(&[2; 32]));
+	}
+
 	// Further testing is done in the ChannelManager integration tests.
+
+	#[test]
+	fn test_spend_index() {
+		// Two monitors (under different keys) for the same funding outpoint must both see its
+		// spend, as well as any transactions spending the outputs of that spend, until the closed
+		// channel is resolved. A monitor for another channel must see neither.
+		let chain_monitor = Arc::new(ChainWatchInterfaceUtil::new(Network::Testnet));
+		let broadcaster = Arc::new(test_utils::TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()) });
+		let monitors: Arc<SimpleManyChannelMonitor<u64>> = SimpleManyChannelMonitor::new(chain_monitor.clone(), broadcaster, Arc::new(test_utils::TestFeeEstimator { sat_per_vbyte: 1 }));
+		let funding_txo = OutPoint::new(Sha256dHash::from_data(&[42; 32]), 1);
+		let other_funding_txo = OutPoint::new(Sha256dHash::from_data(&[43; 32]), 0);
+		for key in 0..3 {
+			let signer: Arc<ChannelSigner> = Arc::new(ChannelKeys::new_from_seed(&[42 + key as u8; 32]).unwrap());
+			let mut monitor = ChannelMonitor::new(&signer, 144, Script::new());
+			monitor.set_funding_info(if key < 2 { funding_txo } else { other_funding_txo });
+			monitors.add_monitor_by_key(key, monitor).unwrap();
+		}
+
+		// Two P2WSH outputs (like HTLC and to_local outputs) and a P2WPKH one (like a to_remote output)
+		let p2wsh_output = TxOut { script_pubkey: Script::new().to_v0_p2wsh(), value: 1000 };
+		let p2wpkh_output = TxOut { script_pubkey: Builder::new().push_int(0).push_slice(&[42; 20]).into_script(), value: 1000 };
+		macro_rules! spend_tx {
+			($prev_hash: expr, $prev_index: expr) => {
+				Transaction { version: 2, lock_time: 0, output: vec![p2wsh_output.clone(), p2wsh_output.clone(), p2wpkh_output.clone()], input: vec![TxIn {
+					prev_hash: $prev_hash, prev_index: $prev_index, script_sig: Script::new(), sequence: 0xffffffff, witness: Vec::new()
+				}] }
+			}
+		}
+		macro_rules! header {
+			($nonce: expr) => {
+				BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: $nonce }
+			}
+		}
+		macro_rules! connect_block {
+			($nonce: expr, $height: expr, $txn: expr) => { {
+				let txn: Vec<&Transaction> = $txn;
+				let indexes: Vec<u32> = (0..txn.len() as u32).collect();
+				monitors.block_connected(&header!($nonce), $height, &txn, &indexes);
+			} }
+		}
+		macro_rules! check_txn_seen {
+			($key: expr, $txn: expr) => { {
+				let mut txn_dispatched = monitors.txn_dispatched.lock().unwrap();
+				let txn_seen = txn_dispatched.entry($key).or_insert(Vec::new());
+				let txids: Vec<Sha256dHash> = $txn.iter().map(|tx: &&Transaction| tx.txid()).collect();
+				assert_eq!(*txn_seen, txids);
+				txn_seen.clear();
+			} }
+		}
+		macro_rules! check_channel_txn_seen {
+			($txn: expr) => { {
+				check_txn_seen!(0, $txn);
+				check_txn_seen!(1, $txn);
+				check_txn_seen!(2, Vec::<&Transaction>::new());
+			} }
+		}
+
+		let unrelated_tx = spend_tx!(funding_txo.txid, 0);
+		let commitment_tx = spend_tx!(funding_txo.txid, 1);
+		let htlc_tx = spend_tx!(commitment_tx.txid(), 0);
+		let htlc_claim_tx = spend_tx!(htlc_tx.txid(), 0);
+		let to_local_tx = spend_tx!(commitment_tx.txid(), 1);
+		let mut to_local_rbf_tx = to_local_tx.clone();
+		to_local_rbf_tx.lock_time = 1;
+		let to_remote_tx = spend_tx!(commitment_tx.txid(), 2);
+		let other_commitment_tx = spend_tx!(other_funding_txo.txid, 0);
+		let other_htlc_tx = spend_tx!(other_commitment_tx.txid(), 0);
+		let mut other_htlc_rbf_tx = other_htlc_tx.clone();
+		other_htlc_rbf_tx.lock_time = 1;
+
+		connect_block!(1, 1, vec![&unrelated_tx, &commitment_tx, &other_commitment_tx]);
+		check_txn_seen!(0, vec![&commitment_tx]);
+		check_txn_seen!(1, vec![&commitment_tx]);
+		check_txn_seen!(2, vec![&other_commitment_tx]);
+
+		// We ask to be told about spends of the commitment transaction's P2WSH outputs, but not of
+		// its to_remote output
+		assert!(chain_monitor.does_match_tx(&htlc_tx));
+		assert!(chain_monitor.does_match_tx(&to_local_tx));
+		assert!(!chain_monitor.does_match_tx(&to_remote_tx));
+
+		// Second-stage transactions are dispatched, but transactions spending them are not
+		connect_block!(2, 2, vec![&htlc_tx, &htlc_claim_tx]);
+		check_channel_txn_seen!(vec![&htlc_tx]);
+
+		// Once the commitment transaction is reorged out, spends of its outputs are no longer
+		// relevant...
+		monitors.block_disconnected(&header!(2));
+		monitors.block_disconnected(&header!(1));
+		connect_block!(11, 1, vec![&htlc_tx]);
+		check_channel_txn_seen!(Vec::<&Transaction>::new());
+
+		// ...until it confirms again, in which case they're dispatched even in the same block
+		connect_block!(12, 2, vec![&commitment_tx, &htlc_tx]);
+		check_channel_txn_seen!(vec![&commitment_tx, &htlc_tx]);
+
+		// With both its P2WSH outputs spent the commitment transaction would be resolved six blocks
+		// later (as its to_remote output needn't be spent), but not if the final spend is reorged
+		// out.
+		connect_block!(13, 3, vec![&to_local_tx]);
+		check_channel_txn_seen!(vec![&to_local_tx]);
+		monitors.block_disconnected(&header!(13));
+		for height in 3..10 {
+			connect_block!(20 + height, height, Vec::new());
+		}
+		connect_block!(30, 10, vec![&to_local_tx]);
+		check_channel_txn_seen!(vec![&to_local_tx]);
+
+		// Once resolved, nothing can spend the commitment transaction's outputs, so we stop looking
+		// for spends of it.
+		for height in 11..17 {
+			connect_block!(20 + height, height, Vec::new());
+		}
+		connect_block!(37, 17, vec![&to_local_rbf_tx]);
+		check_channel_txn_seen!(Vec::<&Transaction>::new());
+
+		// A commitment transaction with an output which is never spent is still only tracked for a
+		// bounded number of blocks
+		connect_block!(40, 18, vec![&other_commitment_tx]);
+		check_txn_seen!(2, vec![&other_commitment_tx]);
+		connect_block!(41, 17 + COMMITMENT_TX_MAX_WATCH_DEPTH, vec![&other_htlc_tx]);
+		check_txn_seen!(2, vec![&other_htlc_tx]);
+		connect_block!(42, 18 + COMMITMENT_TX_MAX_WATCH_DEPTH, Vec::new());
+		connect_block!(43, 19 + COMMITMENT_TX_MAX_WATCH_DEPTH, vec![&other_htlc_rbf_tx]);
+		check_txn_seen!(2, Vec::<&Transaction>::new());
+	}
+}
+
+#[cfg(all(test, feature = "unstable"))]
+mod benches {
+	use bitcoin::blockdata::block::BlockHeader;
+	use bitcoin::blockdata::script::Script;
+	use bitcoin::blockdata::transaction::{TxIn, TxOut, Transaction};
+	use bitcoin::network::constants::Network;
+	use bitcoin::util::hash::Sha256dHash;
+	use chain::chaininterface::{ChainListener, ChainWatchInterfaceUtil};
+	use chain::keysinterface::ChannelSigner;
+	use chain::transaction::OutPoint;
+	use ln::channel::ChannelKeys;
+	use ln::channelmonitor::{ChannelMonitor, SimpleManyChannelMonitor};
+	use util::byte_utils;
+	use util::test_utils;
+	use std::sync::{Arc, Mutex};
+	use test::Bencher;
+
+	const MONITOR_COUNT: u64 = 1000;
+	const TX_COUNT: u64 = 1000;
+
+	fn get_monitors_and_block() -> (Arc<SimpleManyChannelMonitor<OutPoint>>, Vec<Transaction>) {
+		let chain_monitor = Arc::new(ChainWatchInterfaceUtil::new(Network::Testnet));
+		let broadcaster = Arc::new(test_utils::TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()) });
+		let monitors = SimpleManyChannelMonitor::new(chain_monitor, broadcaster, Arc::new(test_utils::TestFeeEstimator { sat_per_vbyte: 1 }));
+		for i in 0..MONITOR_COUNT {
+			let mut seed = [0x42; 32];
+			seed[0..8].copy_from_slice(&byte_utils::be64_to_array(i));
+			let signer: Arc<ChannelSigner> = Arc::new(ChannelKeys::new_from_seed(&seed).unwrap());
+			let mut monitor = ChannelMonitor::new(&signer, 144, Script::new());
+			let funding_txo = OutPoint::new(Sha256dHash::from_data(&seed), 0);
+			monitor.set_funding_info(funding_txo);
+			monitors.add_monitor_by_key(funding_txo, monitor).unwrap();
+		}
+
+		// A block full of transactions which are irrelevant to all of our channels, as a tower
+		// watching all transactions would generally see.
+		let mut txn = Vec::new();
+		for i in 0..TX_COUNT {
+			txn.push(Transaction {
+				version: 2,
+				lock_time: 0,
+				input: vec![TxIn {
+					prev_hash: Sha256dHash::from_data(&byte_utils::be64_to_array(i)),
+					prev_index: 0,
+					script_sig: Script::new(),
+					sequence: 0xffffffff,
+					witness: Vec::new(),
+				}],
+				output: vec![TxOut {
+					script_pubkey: Script::new(),
+					value: 1000,
+				}],
+			});
+		}
+		(monitors, txn)
+	}
+
+	#[bench]
+	fn bench_block_connected(bench: &mut Bencher) {
+		let (monitors, txn) = get_monitors_and_block();
+		let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+		let txn_matched: Vec<&Transaction> = txn.iter().collect();
+		let indexes: Vec<u32> = (0..TX_COUNT as u32).collect();
+		bench.iter(|| {
+			monitors.block_connected(&header, 1, &txn_matched[..], &indexes[..]);
+		});
+	}
+
+	#[bench]
+	fn bench_block_connected_unindexed(bench: &mut Bencher) {
+		// Hands every transaction to every monitor, as SimpleManyChannelMonitor did before it
+		// indexed monitors by funding outpoint, to compare against bench_block_connected.
+		let (monitors, txn) = get_monitors_and_block();
+		let txids: Vec<Sha256dHash> = txn.iter().map(|tx| tx.txid()).collect();
+		let all_txn: Vec<(&Transaction, &Sha256dHash)> = txn.iter().zip(txids.iter()).collect();
+		bench.iter(|| {
+			for monitor in monitors.monitors.lock().unwrap().values() {
+				monitor.block_connected(&all_txn[..], 1, &*monitors.broadcaster, &*monitors.fee_estimator);
+			}
+		});
+	}
 }
@@ -2,8 +2,10 @@ pub mod channelmanager;
 pub mod channelmonitor;
 pub mod msgs;
 pub mod router;
+pub mod payment_tracker;
 pub mod peer_channel_encryptor;
 pub mod peer_handler;
+pub mod watchtower;
 
 #[cfg(feature = "fuzztarget")]
 pub mod channel;
@@ -36,6 +36,8 @@ pub enum DecodeError {
 	WrongLength,
 	/// node_announcement included more than one address of a given type!
 	ExtraAddressesPerType,
+	/// A field had a value we don't understand (eg a compressed short_channel_id encoding)
+	InvalidValue,
 }
 pub trait MsgDecodable: Sized {
 	fn decode(v: &[u8]) -> Result<Self, DecodeError>;
@@ -78,12 +80,30 @@ impl LocalFeatures {
 	pub fn requires_upfront_shutdown_script(&self) -> bool {
 		self.flags.len() > 0 && (self.flags[0] & (1 << 4)) != 0
 	}
+	pub fn set_supports_upfront_shutdown_script(&mut self) {
+		if self.flags.len() == 0 {
+			self.flags.resize(1, 1 << 5);
+		} else {
+			self.flags[0] |= 1 << 5;
+		}
+	}
+
+	pub fn supports_gossip_queries(&self) -> bool {
+		self.flags.len() > 0 && (self.flags[0] & (3 << 6)) != 0
+	}
+	pub fn set_supports_gossip_queries(&mut self) {
+		if self.flags.len() == 0 {
+			self.flags.resize(1, 1 << 7);
+		} else {
+			self.flags[0] |= 1 << 7;
+		}
+	}
 
 	pub fn requires_unknown_bits(&self) -> bool {
 		for (idx, &byte) in self.flags.iter().enumerate() {
 			if idx != 0 && (byte & 0x55) != 0 {
 				return true;
-			} else if idx == 0 && (byte & 0x14) != 0 {
+			} else if idx == 0 && (byte & 0x04) != 0 {
 				return true;
 			}
 		}
@@ -94,7 +114,7 @@ impl LocalFeatures {
 		for (idx, &byte) in self.flags.iter().enumerate() {
 			if idx != 0 && byte != 0 {
 				return true;
-			} else if idx == 0 && (byte & 0xc4) != 0 {
+			} else if idx == 0 && (byte & 0x04) != 0 {
 				return true;
 			}
 		}
@@ -139,6 +159,11 @@ pub struct Init {
 	pub local_features: LocalFeatures,
 }
 
+pub struct ErrorMessage {
+	pub channel_id: Uint256,
+	pub data: String,
+}
+
 pub struct Ping {
 	pub ponglen: u16,
 	pub byteslen: u16,
@@ -282,7 +307,7 @@ pub struct AnnouncementSignatures {
 	pub bitcoin_signature: Signature,
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub enum NetAddress {
 	IPv4 {
 		addr: [u8; 4],
@@ -304,7 +329,8 @@ pub enum NetAddress {
 	},
 }
 impl NetAddress {
-	fn get_id(&self) -> u8 {
+	/// Gets the address descriptor type, by which addresses must be ordered in a node_announcement
+	pub(super) fn get_id(&self) -> u8 {
 		match self {
 			&NetAddress::IPv4 {..} => { 1 },
 			&NetAddress::IPv6 {..} => { 2 },
@@ -314,6 +340,7 @@ impl NetAddress {
 	}
 }
 
+#[derive(PartialEq, Clone)]
 pub struct UnsignedNodeAnnouncement {
 	pub features: GlobalFeatures,
 	pub timestamp: u32,
@@ -324,6 +351,7 @@ pub struct UnsignedNodeAnnouncement {
 	/// address of each type, if you have more, they may be silently discarded or we may panic!
 	pub addresses: Vec<NetAddress>,
 }
+#[derive(PartialEq, Clone)]
 pub struct NodeAnnouncement {
 	pub signature: Signature,
 	pub contents: UnsignedNodeAnnouncement,
@@ -365,6 +393,51 @@ pub struct ChannelUpdate {
 	pub contents: UnsignedChannelUpdate,
 }
 
+/// Asks for the channel_announcements (and the latest channel_updates and node_announcements to
+/// go with them) for the given channels.
+#[derive(PartialEq, Clone)]
+pub struct QueryShortChannelIds {
+	pub chain_hash: Sha256dHash,
+	pub short_channel_ids: Vec<u64>,
+}
+
+/// Sent once we've responded to a query_short_channel_ids in full
+#[derive(PartialEq, Clone)]
+pub struct ReplyShortChannelIdsEnd {
+	pub chain_hash: Sha256dHash,
+	/// false if we don't maintain up-to-date information for the chain in question
+	pub full_information: bool,
+}
+
+/// Asks for the short_channel_ids of all the channels which were opened in the given range of
+/// blocks.
+#[derive(PartialEq, Clone)]
+pub struct QueryChannelRange {
+	pub chain_hash: Sha256dHash,
+	pub first_blocknum: u32,
+	pub number_of_blocks: u32,
+}
+
+/// One of (possibly) several responses to a query_channel_range
+#[derive(PartialEq, Clone)]
+pub struct ReplyChannelRange {
+	pub chain_hash: Sha256dHash,
+	pub first_blocknum: u32,
+	pub number_of_blocks: u32,
+	/// false if we don't maintain up-to-date information for the chain in question
+	pub full_information: bool,
+	pub short_channel_ids: Vec<u64>,
+}
+
+/// Limits the gossip a peer relays to us to messages with a timestamp in the range
+/// [first_timestamp, first_timestamp + timestamp_range).
+#[derive(PartialEq, Clone)]
+pub struct GossipTimestampFilter {
+	pub chain_hash: Sha256dHash,
+	pub first_timestamp: u32,
+	pub timestamp_range: u32,
+}
+
 /// Used to put an error message in a HandleError
 pub enum ErrorAction {
 	/// Indicates an inbound HTLC add resulted in a failure, and the UpdateFailHTLC provided in msg
@@ -376,6 +449,10 @@ pub enum ErrorAction {
 	DisconnectPeer,
 	/// The peer did something harmless that we weren't able to process, just log and ignore
 	IgnoreError,
+	/// We closed a channel with the peer, send them the given error message so they know
+	SendErrorMessage {
+		msg: ErrorMessage
+	},
 }
 
 pub struct HandleError { //TODO: rename me
@@ -421,7 +498,9 @@ pub trait ChannelMessageHandler : events::EventsProvider + Send + Sync {
 	fn handle_update_fulfill_htlc(&self, their_node_id: &PublicKey, msg: &UpdateFulfillHTLC) -> Result<(), HandleError>;
 	fn handle_update_fail_htlc(&self, their_node_id: &PublicKey, msg: &UpdateFailHTLC) -> Result<Option<HTLCFailChannelUpdate>, HandleError>;
 	fn handle_update_fail_malformed_htlc(&self, their_node_id: &PublicKey, msg: &UpdateFailMalformedHTLC) -> Result<(), HandleError>;
-	fn handle_commitment_signed(&self, their_node_id: &PublicKey, msg: &CommitmentSigned) -> Result<(RevokeAndACK, Option<CommitmentSigned>), HandleError>;
+	/// Returns no revoke_and_ack (or commitment_signed) if they must be held until a channel
+	/// monitor update completes, in which case they will be sent later via events.
+	fn handle_commitment_signed(&self, their_node_id: &PublicKey, msg: &CommitmentSigned) -> Result<(Option<RevokeAndACK>, Option<CommitmentSigned>), HandleError>;
 	fn handle_revoke_and_ack(&self, their_node_id: &PublicKey, msg: &RevokeAndACK) -> Result<Option<CommitmentUpdate>, HandleError>;
 
 	fn handle_update_fee(&self, their_node_id: &PublicKey, msg: &UpdateFee) -> Result<(), HandleError>;
@@ -430,6 +509,9 @@ pub trait ChannelMessageHandler : events::EventsProvider + Send + Sync {
 	fn handle_announcement_signatures(&self, their_node_id: &PublicKey, msg: &AnnouncementSignatures) -> Result<(), HandleError>;
 
 	// Informational:
+	/// Indicates a connection to the peer has been established and they sent us their init
+	/// message, with the given local features.
+	fn peer_connected(&self, their_node_id: &PublicKey, their_local_features: &LocalFeatures);
 	/// Indicates a connection to the peer failed/an existing connection was lost. If no connection
 	/// is believed to be possible in the future (eg they're sending us messages we don't
 	/// understand or indicate they require unknown feature bits), no_connection_possible is set
@@ -438,12 +520,38 @@ pub trait ChannelMessageHandler : events::EventsProvider + Send + Sync {
 }
 
 pub trait RoutingMessageHandler : Send + Sync {
-	fn handle_node_announcement(&self, msg: &NodeAnnouncement) -> Result<(), HandleError>;
+	/// Handle a node_announcement message, returning true if it should be forwarded on, false
+	/// or returning an Err otherwise.
+	fn handle_node_announcement(&self, msg: &NodeAnnouncement) -> Result<bool, HandleError>;
 	/// Handle a channel_announcement message, returning true if it should be forwarded on, false
 	/// or returning an Err otherwise.
 	fn handle_channel_announcement(&self, msg: &ChannelAnnouncement) -> Result<bool, HandleError>;
-	fn handle_channel_update(&self, msg: &ChannelUpdate) -> Result<(), HandleError>;
+	/// Handle a channel_update message, returning true if it should be forwarded on, false or
+	/// returning an Err otherwise.
+	fn handle_channel_update(&self, msg: &ChannelUpdate) -> Result<bool, HandleError>;
 	fn handle_htlc_fail_channel_update(&self, update: &HTLCFailChannelUpdate);
+	/// Gets up to batch_amount of the channel_announcements we know of (and the latest
+	/// channel_update in each direction for each), for channels with a short_channel_id of at least
+	/// starting_point, in short_channel_id order. Used to send our routing table to a peer a batch at
+	/// a time.
+	fn get_next_channel_announcements(&self, starting_point: u64, batch_amount: u8) -> Vec<(ChannelAnnouncement, Option<ChannelUpdate>, Option<ChannelUpdate>)>;
+	/// Gets up to batch_amount of the node_announcements we know of, for nodes after starting_point
+	/// (or from the first node if None), in node_id order.
+	fn get_next_node_announcements(&self, starting_point: Option<&PublicKey>, batch_amount: u8) -> Vec<NodeAnnouncement>;
+	/// Handle a query_short_channel_ids message, returning the channel_announcements (and latest
+	/// channel_updates) for the requested channels we know of, as well as the node_announcements
+	/// for the nodes on either end of them. These should be sent to the peer, followed by a
+	/// reply_short_channel_ids_end.
+	fn handle_query_short_channel_ids(&self, msg: &QueryShortChannelIds) -> Result<(Vec<(ChannelAnnouncement, Option<ChannelUpdate>, Option<ChannelUpdate>)>, Vec<NodeAnnouncement>), HandleError>;
+	/// Handle a query_channel_range message, returning the reply_channel_range messages to respond
+	/// with (of which there is always at least one).
+	fn handle_query_channel_range(&self, msg: &QueryChannelRange) -> Result<Vec<ReplyChannelRange>, HandleError>;
+	/// Handle a reply_channel_range message, returning the short_channel_ids in it which we don't
+	/// know of and should query the peer for.
+	fn handle_reply_channel_range(&self, msg: &ReplyChannelRange) -> Result<Vec<u64>, HandleError>;
+	/// Handle a reply_short_channel_ids_end message, indicating the peer has finished responding to
+	/// a query_short_channel_ids we sent.
+	fn handle_reply_short_channel_ids_end(&self, msg: &ReplyShortChannelIdsEnd) -> Result<(), HandleError>;
 }
 
 pub struct OnionRealm0HopData {
@@ -481,6 +589,150 @@ pub struct OnionErrorPacket {
 	pub data: Vec<u8>,
 }
 
+/// Failure code flag bit set when the erring node couldn't parse the onion it received
+pub const BADONION: u16 = 0x8000;
+/// Failure code flag bit set when retrying the same route will never succeed
+pub const PERM: u16 = 0x4000;
+/// Failure code flag bit set when the failure applies to the erring node as a whole, not just one
+/// of its channels
+pub const NODE: u16 = 0x2000;
+/// Failure code flag bit set when the failure data carries a channel_update for the failing
+/// channel
+pub const UPDATE: u16 = 0x1000;
+
+/// The failure_code of an onion error, as enumerated in BOLT 4.
+/// Unknown codes are kept as-is so that their flag bits can still be inspected.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HTLCFailureCode {
+	InvalidRealm,
+	TemporaryNodeFailure,
+	PermanentNodeFailure,
+	RequiredNodeFeatureMissing,
+	InvalidOnionVersion,
+	InvalidOnionHmac,
+	InvalidOnionKey,
+	/// Carries a channel_update
+	TemporaryChannelFailure,
+	PermanentChannelFailure,
+	RequiredChannelFeatureMissing,
+	UnknownNextPeer,
+	/// Carries htlc_msat and a channel_update
+	AmountBelowMinimum,
+	/// Carries htlc_msat and a channel_update
+	FeeInsufficient,
+	/// Carries cltv_expiry and a channel_update
+	IncorrectCLTVExpiry,
+	/// Carries a channel_update
+	ExpiryTooSoon,
+	UnknownPaymentHash,
+	IncorrectPaymentAmount,
+	FinalExpiryTooSoon,
+	/// Carries cltv_expiry
+	FinalIncorrectCLTVExpiry,
+	/// Carries incoming_htlc_amt
+	FinalIncorrectHTLCAmount,
+	/// Carries flags and a channel_update
+	ChannelDisabled,
+	ExpiryTooFar,
+	Unknown(u16),
+}
+
+impl HTLCFailureCode {
+	pub fn from_u16(code: u16) -> HTLCFailureCode {
+		match code {
+			x if x == PERM|1 => HTLCFailureCode::InvalidRealm,
+			x if x == NODE|2 => HTLCFailureCode::TemporaryNodeFailure,
+			x if x == PERM|NODE|2 => HTLCFailureCode::PermanentNodeFailure,
+			x if x == PERM|NODE|3 => HTLCFailureCode::RequiredNodeFeatureMissing,
+			x if x == BADONION|PERM|4 => HTLCFailureCode::InvalidOnionVersion,
+			x if x == BADONION|PERM|5 => HTLCFailureCode::InvalidOnionHmac,
+			x if x == BADONION|PERM|6 => HTLCFailureCode::InvalidOnionKey,
+			x if x == UPDATE|7 => HTLCFailureCode::TemporaryChannelFailure,
+			x if x == PERM|8 => HTLCFailureCode::PermanentChannelFailure,
+			x if x == PERM|9 => HTLCFailureCode::RequiredChannelFeatureMissing,
+			x if x == PERM|10 => HTLCFailureCode::UnknownNextPeer,
+			x if x == UPDATE|11 => HTLCFailureCode::AmountBelowMinimum,
+			x if x == UPDATE|12 => HTLCFailureCode::FeeInsufficient,
+			x if x == UPDATE|13 => HTLCFailureCode::IncorrectCLTVExpiry,
+			x if x == UPDATE|14 => HTLCFailureCode::ExpiryTooSoon,
+			x if x == PERM|15 => HTLCFailureCode::UnknownPaymentHash,
+			x if x == PERM|16 => HTLCFailureCode::IncorrectPaymentAmount,
+			17 => HTLCFailureCode::FinalExpiryTooSoon,
+			18 => HTLCFailureCode::FinalIncorrectCLTVExpiry,
+			19 => HTLCFailureCode::FinalIncorrectHTLCAmount,
+			x if x == UPDATE|20 => HTLCFailureCode::ChannelDisabled,
+			21 => HTLCFailureCode::ExpiryTooFar,
+			x => HTLCFailureCode::Unknown(x),
+		}
+	}
+
+	pub fn to_u16(&self) -> u16 {
+		match *self {
+			HTLCFailureCode::InvalidRealm => PERM|1,
+			HTLCFailureCode::TemporaryNodeFailure => NODE|2,
+			HTLCFailureCode::PermanentNodeFailure => PERM|NODE|2,
+			HTLCFailureCode::RequiredNodeFeatureMissing => PERM|NODE|3,
+			HTLCFailureCode::InvalidOnionVersion => BADONION|PERM|4,
+			HTLCFailureCode::InvalidOnionHmac => BADONION|PERM|5,
+			HTLCFailureCode::InvalidOnionKey => BADONION|PERM|6,
+			HTLCFailureCode::TemporaryChannelFailure => UPDATE|7,
+			HTLCFailureCode::PermanentChannelFailure => PERM|8,
+			HTLCFailureCode::RequiredChannelFeatureMissing => PERM|9,
+			HTLCFailureCode::UnknownNextPeer => PERM|10,
+			HTLCFailureCode::AmountBelowMinimum => UPDATE|11,
+			HTLCFailureCode::FeeInsufficient => UPDATE|12,
+			HTLCFailureCode::IncorrectCLTVExpiry => UPDATE|13,
+			HTLCFailureCode::ExpiryTooSoon => UPDATE|14,
+			HTLCFailureCode::UnknownPaymentHash => PERM|15,
+			HTLCFailureCode::IncorrectPaymentAmount => PERM|16,
+			HTLCFailureCode::FinalExpiryTooSoon => 17,
+			HTLCFailureCode::FinalIncorrectCLTVExpiry => 18,
+			HTLCFailureCode::FinalIncorrectHTLCAmount => 19,
+			HTLCFailureCode::ChannelDisabled => UPDATE|20,
+			HTLCFailureCode::ExpiryTooFar => 21,
+			HTLCFailureCode::Unknown(x) => x,
+		}
+	}
+
+	/// True if retrying the same route will never succeed
+	pub fn is_permanent(&self) -> bool { self.to_u16() & PERM == PERM }
+	/// True if the failure applies to the erring node as a whole, not just one of its channels
+	pub fn is_node_failure(&self) -> bool { self.to_u16() & NODE == NODE }
+	/// True if the failure data carries a channel_update for the failing channel
+	pub fn has_channel_update(&self) -> bool { self.to_u16() & UPDATE == UPDATE }
+	/// True if the erring node was unable to parse the onion it received
+	pub fn is_bad_onion(&self) -> bool { self.to_u16() & BADONION == BADONION }
+
+	/// Gets the offset into the failure data (ie failuremsg minus the 2-byte code) at which the
+	/// length-prefixed channel_update starts, if this code carries one.
+	pub fn channel_update_offset(&self) -> Option<usize> {
+		match *self {
+			HTLCFailureCode::TemporaryChannelFailure|HTLCFailureCode::ExpiryTooSoon => Some(0),
+			HTLCFailureCode::AmountBelowMinimum|HTLCFailureCode::FeeInsufficient => Some(8),
+			HTLCFailureCode::IncorrectCLTVExpiry => Some(4),
+			HTLCFailureCode::ChannelDisabled => Some(2),
+			_ => None,
+		}
+	}
+
+	/// Extracts the channel_update from the failure data (ie failuremsg minus the 2-byte code),
+	/// if this code carries one and it decodes.
+	pub fn decode_channel_update(&self, failure_data: &[u8]) -> Option<ChannelUpdate> {
+		let offset = match self.channel_update_offset() {
+			Some(offset) => offset,
+			None => return None,
+		};
+		if failure_data.len() < offset + 2 {
+			return None;
+		}
+		let update_len = byte_utils::slice_to_be16(&failure_data[offset..offset + 2]) as usize;
+		if failure_data.len() < offset + 2 + update_len {
+			return None;
+		}
+		ChannelUpdate::decode(&failure_data[offset + 2..offset + 2 + update_len]).ok()
+	}
+}
+
 impl Error for DecodeError {
 	fn description(&self) -> &str {
 		match *self {
@@ -489,6 +741,7 @@ impl Error for DecodeError {
 			DecodeError::BadSignature => "Invalid signature in packet",
 			DecodeError::WrongLength => "Data was wrong length for packet",
 			DecodeError::ExtraAddressesPerType => "More than one address of a single type",
+			DecodeError::InvalidValue => "Unknown or invalid value in packet",
 		}
 	}
 }
@@ -588,6 +841,31 @@ impl MsgEncodable for Init {
 	}
 }
 
+impl MsgDecodable for ErrorMessage {
+	fn decode(v: &[u8]) -> Result<Self, DecodeError> {
+		if v.len() < 32 + 2 {
+			return Err(DecodeError::WrongLength);
+		}
+		let len = byte_utils::slice_to_be16(&v[32..34]) as usize;
+		if v.len() < 32 + 2 + len {
+			return Err(DecodeError::WrongLength);
+		}
+		Ok(Self {
+			channel_id: deserialize(&v[0..32]).unwrap(),
+			data: String::from_utf8_lossy(&v[34..34 + len]).into_owned(),
+		})
+	}
+}
+impl MsgEncodable for ErrorMessage {
+	fn encode(&self) -> Vec<u8> {
+		let mut res = Vec::with_capacity(32 + 2 + self.data.len());
+		res.extend_from_slice(&serialize(&self.channel_id).unwrap());
+		res.extend_from_slice(&byte_utils::be16_to_array(self.data.len() as u16));
+		res.extend_from_slice(self.data.as_bytes());
+		res
+	}
+}
+
 impl MsgDecodable for Ping {
 	fn decode(v: &[u8]) -> Result<Self, DecodeError> {
 		if v.len() < 4 {
@@ -1397,6 +1675,150 @@ impl MsgEncodable for ChannelUpdate {
 	}
 }
 
+/// Decodes a length-prefixed encoded_short_ids field. Only the uncompressed encoding is supported.
+fn decode_short_channel_ids(v: &[u8]) -> Result<Vec<u64>, DecodeError> {
+	if v.len() < 2 {
+		return Err(DecodeError::WrongLength);
+	}
+	let len = byte_utils::slice_to_be16(&v[0..2]) as usize;
+	if len < 1 || v.len() < 2 + len || (len - 1) % 8 != 0 {
+		return Err(DecodeError::WrongLength);
+	}
+	if v[2] != 0 {
+		// 1 is zlib-compressed, which we don't support
+		return Err(DecodeError::InvalidValue);
+	}
+	let mut short_channel_ids = Vec::with_capacity((len - 1) / 8);
+	for chunk in v[3..2 + len].chunks(8) {
+		short_channel_ids.push(byte_utils::slice_to_be64(chunk));
+	}
+	Ok(short_channel_ids)
+}
+fn encode_short_channel_ids(short_channel_ids: &[u64], res: &mut Vec<u8>) {
+	res.extend_from_slice(&byte_utils::be16_to_array((1 + short_channel_ids.len() * 8) as u16));
+	res.push(0); // Uncompressed
+	for short_channel_id in short_channel_ids {
+		res.extend_from_slice(&byte_utils::be64_to_array(*short_channel_id));
+	}
+}
+fn decode_bool(v: u8) -> Result<bool, DecodeError> {
+	match v {
+		0 => Ok(false),
+		1 => Ok(true),
+		_ => Err(DecodeError::InvalidValue),
+	}
+}
+
+impl MsgDecodable for QueryShortChannelIds {
+	fn decode(v: &[u8]) -> Result<Self, DecodeError> {
+		if v.len() < 32 {
+			return Err(DecodeError::WrongLength);
+		}
+		Ok(Self {
+			chain_hash: deserialize(&v[0..32]).unwrap(),
+			short_channel_ids: decode_short_channel_ids(&v[32..])?,
+		})
+	}
+}
+impl MsgEncodable for QueryShortChannelIds {
+	fn encode(&self) -> Vec<u8> {
+		let mut res = Vec::with_capacity(32 + 3 + self.short_channel_ids.len() * 8);
+		res.extend_from_slice(&self.chain_hash[..]);
+		encode_short_channel_ids(&self.short_channel_ids, &mut res);
+		res
+	}
+}
+
+impl MsgDecodable for ReplyShortChannelIdsEnd {
+	fn decode(v: &[u8]) -> Result<Self, DecodeError> {
+		if v.len() < 33 {
+			return Err(DecodeError::WrongLength);
+		}
+		Ok(Self {
+			chain_hash: deserialize(&v[0..32]).unwrap(),
+			full_information: decode_bool(v[32])?,
+		})
+	}
+}
+impl MsgEncodable for ReplyShortChannelIdsEnd {
+	fn encode(&self) -> Vec<u8> {
+		let mut res = Vec::with_capacity(33);
+		res.extend_from_slice(&self.chain_hash[..]);
+		res.push(self.full_information as u8);
+		res
+	}
+}
+
+impl MsgDecodable for QueryChannelRange {
+	fn decode(v: &[u8]) -> Result<Self, DecodeError> {
+		if v.len() < 32+4+4 {
+			return Err(DecodeError::WrongLength);
+		}
+		Ok(Self {
+			chain_hash: deserialize(&v[0..32]).unwrap(),
+			first_blocknum: byte_utils::slice_to_be32(&v[32..36]),
+			number_of_blocks: byte_utils::slice_to_be32(&v[36..40]),
+		})
+	}
+}
+impl MsgEncodable for QueryChannelRange {
+	fn encode(&self) -> Vec<u8> {
+		let mut res = Vec::with_capacity(32+4+4);
+		res.extend_from_slice(&self.chain_hash[..]);
+		res.extend_from_slice(&byte_utils::be32_to_array(self.first_blocknum));
+		res.extend_from_slice(&byte_utils::be32_to_array(self.number_of_blocks));
+		res
+	}
+}
+
+impl MsgDecodable for ReplyChannelRange {
+	fn decode(v: &[u8]) -> Result<Self, DecodeError> {
+		if v.len() < 32+4+4+1 {
+			return Err(DecodeError::WrongLength);
+		}
+		Ok(Self {
+			chain_hash: deserialize(&v[0..32]).unwrap(),
+			first_blocknum: byte_utils::slice_to_be32(&v[32..36]),
+			number_of_blocks: byte_utils::slice_to_be32(&v[36..40]),
+			full_information: decode_bool(v[40])?,
+			short_channel_ids: decode_short_channel_ids(&v[41..])?,
+		})
+	}
+}
+impl MsgEncodable for ReplyChannelRange {
+	fn encode(&self) -> Vec<u8> {
+		let mut res = Vec::with_capacity(32+4+4+1 + 3 + self.short_channel_ids.len() * 8);
+		res.extend_from_slice(&self.chain_hash[..]);
+		res.extend_from_slice(&byte_utils::be32_to_array(self.first_blocknum));
+		res.extend_from_slice(&byte_utils::be32_to_array(self.number_of_blocks));
+		res.push(self.full_information as u8);
+		encode_short_channel_ids(&self.short_channel_ids, &mut res);
+		res
+	}
+}
+
+impl MsgDecodable for GossipTimestampFilter {
+	fn decode(v: &[u8]) -> Result<Self, DecodeError> {
+		if v.len() < 32+4+4 {
+			return Err(DecodeError::WrongLength);
+		}
+		Ok(Self {
+			chain_hash: deserialize(&v[0..32]).unwrap(),
+			first_timestamp: byte_utils::slice_to_be32(&v[32..36]),
+			timestamp_range: byte_utils::slice_to_be32(&v[36..40]),
+		})
+	}
+}
+impl MsgEncodable for GossipTimestampFilter {
+	fn encode(&self) -> Vec<u8> {
+		let mut res = Vec::with_capacity(32+4+4);
+		res.extend_from_slice(&self.chain_hash[..]);
+		res.extend_from_slice(&byte_utils::be32_to_array(self.first_timestamp));
+		res.extend_from_slice(&byte_utils::be32_to_array(self.timestamp_range));
+		res
+	}
+}
+
 impl MsgDecodable for OnionRealm0HopData {
 	fn decode(v: &[u8]) -> Result<Self, DecodeError> {
 		if v.len() < 32 {
@@ -1534,3 +1956,213 @@ impl MsgEncodable for OnionErrorPacket {
 		res
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use bitcoin::util::hash::Sha256dHash;
+	use bitcoin::util::uint::Uint256;
+
+	use ln::msgs::{MsgEncodable, MsgDecodable, DecodeError, ErrorMessage, QueryShortChannelIds, ReplyShortChannelIdsEnd, QueryChannelRange, ReplyChannelRange, GossipTimestampFilter};
+	use ln::msgs::{HTLCFailureCode, ChannelUpdate, UnsignedChannelUpdate, BADONION, PERM, NODE, UPDATE};
+	use util::byte_utils;
+
+	use secp256k1::{Secp256k1, Message};
+	use secp256k1::key::SecretKey;
+
+	#[test]
+	fn failure_code_decoding() {
+		let secp_ctx = Secp256k1::new();
+		let key = SecretKey::from_slice(&secp_ctx, &[42; 32]).unwrap();
+		let update = ChannelUpdate {
+			signature: secp_ctx.sign(&Message::from_slice(&[42; 32]).unwrap(), &key).unwrap(),
+			contents: UnsignedChannelUpdate {
+				chain_hash: Sha256dHash::from_data(&[42; 32]),
+				short_channel_id: 0xdeadbeef,
+				timestamp: 1540000000,
+				flags: 2,
+				cltv_expiry_delta: 144,
+				htlc_minimum_msat: 1000,
+				fee_base_msat: 1000,
+				fee_proportional_millionths: 100,
+			},
+		};
+		let encoded_update = update.encode();
+
+		// Each known code, the flags it should report and the length of the data preceding its
+		// channel_update, if it carries one
+		let codes = [
+			(HTLCFailureCode::InvalidRealm, PERM|1, None),
+			(HTLCFailureCode::TemporaryNodeFailure, NODE|2, None),
+			(HTLCFailureCode::PermanentNodeFailure, PERM|NODE|2, None),
+			(HTLCFailureCode::RequiredNodeFeatureMissing, PERM|NODE|3, None),
+			(HTLCFailureCode::InvalidOnionVersion, BADONION|PERM|4, None),
+			(HTLCFailureCode::InvalidOnionHmac, BADONION|PERM|5, None),
+			(HTLCFailureCode::InvalidOnionKey, BADONION|PERM|6, None),
+			(HTLCFailureCode::TemporaryChannelFailure, UPDATE|7, Some(0)),
+			(HTLCFailureCode::PermanentChannelFailure, PERM|8, None),
+			(HTLCFailureCode::RequiredChannelFeatureMissing, PERM|9, None),
+			(HTLCFailureCode::UnknownNextPeer, PERM|10, None),
+			(HTLCFailureCode::AmountBelowMinimum, UPDATE|11, Some(8)),
+			(HTLCFailureCode::FeeInsufficient, UPDATE|12, Some(8)),
+			(HTLCFailureCode::IncorrectCLTVExpiry, UPDATE|13, Some(4)),
+			(HTLCFailureCode::ExpiryTooSoon, UPDATE|14, Some(0)),
+			(HTLCFailureCode::UnknownPaymentHash, PERM|15, None),
+			(HTLCFailureCode::IncorrectPaymentAmount, PERM|16, None),
+			(HTLCFailureCode::FinalExpiryTooSoon, 17, None),
+			(HTLCFailureCode::FinalIncorrectCLTVExpiry, 18, None),
+			(HTLCFailureCode::FinalIncorrectHTLCAmount, 19, None),
+			(HTLCFailureCode::ChannelDisabled, UPDATE|20, Some(2)),
+			(HTLCFailureCode::ExpiryTooFar, 21, None),
+		];
+		for &(code, value, update_offset) in codes.iter() {
+			assert_eq!(code.to_u16(), value);
+			assert_eq!(HTLCFailureCode::from_u16(value), code);
+			assert_eq!(code.is_permanent(), value & PERM != 0);
+			assert_eq!(code.is_node_failure(), value & NODE != 0);
+			assert_eq!(code.is_bad_onion(), value & BADONION != 0);
+			assert_eq!(code.has_channel_update(), update_offset.is_some());
+			assert_eq!(code.channel_update_offset(), update_offset);
+
+			// The channel_update is found after the code's other fields, however many there are
+			let mut failure_data = vec![0xff; update_offset.unwrap_or(0)];
+			failure_data.extend_from_slice(&byte_utils::be16_to_array(encoded_update.len() as u16));
+			failure_data.extend_from_slice(&encoded_update);
+			match update_offset {
+				Some(_) => {
+					assert!(code.decode_channel_update(&failure_data).unwrap() == update);
+					assert!(code.decode_channel_update(&failure_data[..failure_data.len() - 1]).is_none());
+				},
+				None => assert!(code.decode_channel_update(&failure_data).is_none()),
+			}
+		}
+
+		// Unknown codes round-trip as-is, keeping their flags, but we don't know where to find
+		// any channel_update in their data
+		let mut failure_data = byte_utils::be16_to_array(encoded_update.len() as u16).to_vec();
+		failure_data.extend_from_slice(&encoded_update);
+		for &value in [0, 22, PERM|NODE|99, UPDATE|99, BADONION|PERM|99].iter() {
+			let code = HTLCFailureCode::from_u16(value);
+			assert_eq!(code, HTLCFailureCode::Unknown(value));
+			assert_eq!(code.to_u16(), value);
+			assert_eq!(code.is_permanent(), value & PERM != 0);
+			assert!(code.decode_channel_update(&failure_data).is_none());
+		}
+	}
+
+	#[test]
+	fn error_message_encoding() {
+		let msg = ErrorMessage {
+			channel_id: Uint256::from_u64(42).unwrap(),
+			data: "rust-lightning".to_string(),
+		};
+		let encoded = msg.encode();
+		assert_eq!(encoded.len(), 32 + 2 + 14);
+		let decoded = ErrorMessage::decode(&encoded[..]).unwrap();
+		assert_eq!(decoded.channel_id, msg.channel_id);
+		assert_eq!(decoded.data, msg.data);
+		match ErrorMessage::decode(&encoded[..encoded.len() - 1]) {
+			Err(DecodeError::WrongLength) => {},
+			_ => panic!(),
+		}
+	}
+
+	#[test]
+	fn query_short_channel_ids_encoding() {
+		let msg = QueryShortChannelIds {
+			chain_hash: Sha256dHash::from_data(&[42; 32]),
+			short_channel_ids: vec![0, 1, 0xdeadbeef, u64::max_value()],
+		};
+		let encoded = msg.encode();
+		assert_eq!(encoded.len(), 32 + 2 + 1 + 4 * 8);
+		assert_eq!(&encoded[32..35], &[0, 33, 0]);
+		assert!(QueryShortChannelIds::decode(&encoded[..]).unwrap() == msg);
+
+		let empty = QueryShortChannelIds {
+			chain_hash: Sha256dHash::from_data(&[42; 32]),
+			short_channel_ids: Vec::new(),
+		};
+		assert!(QueryShortChannelIds::decode(&empty.encode()[..]).unwrap() == empty);
+
+		// zlib-compressed short_channel_ids aren't supported
+		let mut compressed = encoded.clone();
+		compressed[34] = 1;
+		match QueryShortChannelIds::decode(&compressed[..]) {
+			Err(DecodeError::InvalidValue) => {},
+			_ => panic!(),
+		}
+		match QueryShortChannelIds::decode(&encoded[..encoded.len() - 1]) {
+			Err(DecodeError::WrongLength) => {},
+			_ => panic!(),
+		}
+	}
+
+	#[test]
+	fn reply_short_channel_ids_end_encoding() {
+		for &full_information in [true, false].iter() {
+			let msg = ReplyShortChannelIdsEnd {
+				chain_hash: Sha256dHash::from_data(&[42; 32]),
+				full_information,
+			};
+			let encoded = msg.encode();
+			assert_eq!(encoded.len(), 33);
+			assert!(ReplyShortChannelIdsEnd::decode(&encoded[..]).unwrap() == msg);
+		}
+
+		let mut encoded = ReplyShortChannelIdsEnd { chain_hash: Sha256dHash::from_data(&[42; 32]), full_information: true }.encode();
+		encoded[32] = 2;
+		match ReplyShortChannelIdsEnd::decode(&encoded[..]) {
+			Err(DecodeError::InvalidValue) => {},
+			_ => panic!(),
+		}
+	}
+
+	#[test]
+	fn query_channel_range_encoding() {
+		let msg = QueryChannelRange {
+			chain_hash: Sha256dHash::from_data(&[42; 32]),
+			first_blocknum: 500000,
+			number_of_blocks: 0xffffffff,
+		};
+		let encoded = msg.encode();
+		assert_eq!(encoded.len(), 32 + 4 + 4);
+		assert!(QueryChannelRange::decode(&encoded[..]).unwrap() == msg);
+		match QueryChannelRange::decode(&encoded[..39]) {
+			Err(DecodeError::WrongLength) => {},
+			_ => panic!(),
+		}
+	}
+
+	#[test]
+	fn reply_channel_range_encoding() {
+		let msg = ReplyChannelRange {
+			chain_hash: Sha256dHash::from_data(&[42; 32]),
+			first_blocknum: 500000,
+			number_of_blocks: 1000,
+			full_information: true,
+			short_channel_ids: vec![(500000 << 40) | (1 << 16) | 1, (500999 << 40) | (2 << 16)],
+		};
+		let encoded = msg.encode();
+		assert_eq!(encoded.len(), 32 + 4 + 4 + 1 + 2 + 1 + 2 * 8);
+		assert!(ReplyChannelRange::decode(&encoded[..]).unwrap() == msg);
+		match ReplyChannelRange::decode(&encoded[..encoded.len() - 8]) {
+			Err(DecodeError::WrongLength) => {},
+			_ => panic!(),
+		}
+	}
+
+	#[test]
+	fn gossip_timestamp_filter_encoding() {
+		let msg = GossipTimestampFilter {
+			chain_hash: Sha256dHash::from_data(&[42; 32]),
+			first_timestamp: 1540000000,
+			timestamp_range: 0xffffffff,
+		};
+		let encoded = msg.encode();
+		assert_eq!(encoded.len(), 32 + 4 + 4);
+		assert!(GossipTimestampFilter::decode(&encoded[..]).unwrap() == msg);
+		match GossipTimestampFilter::decode(&encoded[..39]) {
+			Err(DecodeError::WrongLength) => {},
+			_ => panic!(),
+		}
+	}
+}
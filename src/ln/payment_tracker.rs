@@ -0,0 +1,260 @@
+use secp256k1::key::PublicKey;
+
+use crypto::digest::Digest;
+
+use ln::channelmanager::ChannelManager;
+use ln::router::{Router,RouteHint,RouteParameters};
+use ln::msgs::{HandleError,HTLCFailureCode};
+use util::events::{Event,EventsProvider};
+use util::sha2::Sha256;
+
+use std::sync::{Arc,Mutex};
+use std::collections::HashMap;
+use std::time::{Duration,Instant};
+
+/// How long we remember the payment_hashes of completed payments for (and thus refuse to pay
+/// them again).
+const COMPLETED_PAYMENT_EXPIRY_SECS: u64 = 60*60*24;
+
+struct PendingPayment {
+	payment_id: u64,
+	target: PublicKey,
+	last_hops: Vec<RouteHint>,
+	final_value_msat: u64,
+	final_cltv: u32,
+	attempts: u32,
+	started: Instant,
+	/// Set once payment_timeout has passed, after which we won't retry the in-flight attempt if it
+	/// fails
+	timed_out: bool,
+	/// Excludes the channels (or nodes) which failed previous attempts
+	route_params: RouteParameters,
+}
+
+struct PaymentHolder {
+	next_payment_id: u64,
+	/// payment_hash -> the payment we have an attempt in flight for. There is always exactly one
+	/// attempt in flight for each entry here.
+	pending: HashMap<[u8; 32], PendingPayment>,
+	/// payment_hashes we've already been paid for (and when), so that we never pay twice. Entries
+	/// are removed COMPLETED_PAYMENT_EXPIRY_SECS after completion in timer_tick_occurred.
+	completed: HashMap<[u8; 32], Instant>,
+}
+
+/// Whether a failed attempt tells us that no other route will work either, ie the recipient
+/// rejected it or a node on the route failed permanently. Permanent channel-level failures only
+/// rule out the failing channel, which the next attempt avoids.
+fn is_final_failure(rejected_by_dest: bool, failure_code: Option<HTLCFailureCode>) -> bool {
+	rejected_by_dest || match failure_code {
+		Some(code) => code.is_permanent() && code.is_node_failure(),
+		None => false,
+	}
+}
+
+/// A layer over ChannelManager::send_payment and Router::get_route which tracks outbound
+/// payments, retrying them over routes which avoid the channels which failed previous attempts
+/// until they succeed, fail permanently, or we run out of attempts or time. Only the final outcome
+/// of each payment is handed to the user, as a TrackedPaymentSent or TrackedPaymentFailed event.
+/// Note that a new attempt is only made once the previous one has been failed back to us, so we
+/// never have two attempts for the same payment in flight at once.
+/// timer_tick_occurred should be called regularly (eg once a minute) to time out payments.
+pub struct PaymentTracker {
+	channel_manager: Arc<ChannelManager>,
+	router: Arc<Router>,
+	/// Where we get ChannelManager's events from (eg the PeerManager, or the ChannelManager itself
+	/// if nothing else is consuming its events).
+	event_source: Arc<EventsProvider>,
+	max_attempts: u32,
+	payment_timeout: Duration,
+	payments: Mutex<PaymentHolder>,
+}
+
+impl PaymentTracker {
+	/// Constructs a new PaymentTracker. Payments are retried at most max_attempts times in total,
+	/// and fail once payment_timeout has passed since the payment was started (see
+	/// timer_tick_occurred).
+	/// All events from event_source should be read through this PaymentTracker instead.
+	pub fn new(channel_manager: Arc<ChannelManager>, router: Arc<Router>, event_source: Arc<EventsProvider>, max_attempts: u32, payment_timeout: Duration) -> PaymentTracker {
+		PaymentTracker {
+			channel_manager,
+			router,
+			event_source,
+			max_attempts,
+			payment_timeout,
+			payments: Mutex::new(PaymentHolder {
+				next_payment_id: 0,
+				pending: HashMap::new(),
+				completed: HashMap::new(),
+			}),
+		}
+	}
+
+	/// Starts paying final_value_msat to target, returning the id the eventual TrackedPaymentSent
+	/// or TrackedPaymentFailed event will carry.
+	/// Fails if no route could be found for the first attempt, or if payment_hash is already being
+	/// paid or has already been paid via this PaymentTracker.
+	pub fn send_payment(&self, target: PublicKey, last_hops: Vec<RouteHint>, final_value_msat: u64, final_cltv: u32, payment_hash: [u8; 32]) -> Result<u64, HandleError> {
+		let mut payments = self.payments.lock().unwrap();
+		if payments.pending.contains_key(&payment_hash) {
+			return Err(HandleError{err: "Payment with the given hash is already in progress", msg: None});
+		}
+		if payments.completed.contains_key(&payment_hash) {
+			return Err(HandleError{err: "Payment with the given hash has already been completed", msg: None});
+		}
+
+		let mut payment = PendingPayment {
+			payment_id: payments.next_payment_id,
+			target,
+			last_hops,
+			final_value_msat,
+			final_cltv,
+			attempts: 0,
+			started: Instant::now(),
+			timed_out: false,
+			route_params: RouteParameters::new(),
+		};
+		self.send_attempt(&mut payment, payment_hash)?;
+
+		payments.next_payment_id += 1;
+		let payment_id = payment.payment_id;
+		payments.pending.insert(payment_hash, payment);
+		Ok(payment_id)
+	}
+
+	/// Stops retrying any payments which were started more than payment_timeout ago, and forgets
+	/// completed payments older than a day.
+	/// Note that we cannot cancel an attempt which is still in flight when its payment times out,
+	/// as it may still succeed, so the TrackedPaymentSent or TrackedPaymentFailed event for a
+	/// timed-out payment is only generated once its last attempt resolves.
+	/// Should be called regularly, eg once a minute.
+	pub fn timer_tick_occurred(&self) {
+		let mut payments = self.payments.lock().unwrap();
+		for payment in payments.pending.values_mut() {
+			if payment.started.elapsed() >= self.payment_timeout {
+				payment.timed_out = true;
+			}
+		}
+		payments.completed.retain(|_, completed| completed.elapsed() < Duration::from_secs(COMPLETED_PAYMENT_EXPIRY_SECS));
+	}
+
+	/// Gets the number of payments which currently have an attempt in flight.
+	pub fn pending_payment_count(&self) -> usize {
+		self.payments.lock().unwrap().pending.len()
+	}
+
+	fn send_attempt(&self, payment: &mut PendingPayment, payment_hash: [u8; 32]) -> Result<(), HandleError> {
+		let route = self.router.get_route_with_params(&payment.target, &payment.last_hops, payment.final_value_msat, payment.final_cltv, &payment.route_params)?;
+		payment.attempts += 1;
+		self.channel_manager.send_payment(route, payment_hash)
+	}
+}
+
+impl EventsProvider for PaymentTracker {
+	/// Gets the events from event_source, with the PaymentSent and PaymentFailed events for payments
+	/// we're tracking replaced by their final TrackedPaymentSent/TrackedPaymentFailed event (or
+	/// dropped if we're retrying them). Events generated by any retries are included.
+	fn get_and_clear_pending_events(&self) -> Vec<Event> {
+		let mut payments = self.payments.lock().unwrap();
+		let mut res = Vec::new();
+		loop {
+			let mut events = self.event_source.get_and_clear_pending_events();
+			if events.is_empty() { break; }
+			res.reserve(events.len());
+			for event in events.drain(..) {
+				match event {
+					Event::PaymentSent { payment_preimage } => {
+						let payment_hash = {
+							let mut sha = Sha256::new();
+							sha.input(&payment_preimage);
+							let mut ret = [0; 32];
+							sha.result(&mut ret);
+							ret
+						};
+						match payments.pending.remove(&payment_hash) {
+							Some(payment) => {
+								payments.completed.insert(payment_hash, Instant::now());
+								res.push(Event::TrackedPaymentSent {
+									payment_id: payment.payment_id,
+									payment_preimage,
+									attempts: payment.attempts,
+								});
+							},
+							None => res.push(event),
+						}
+					},
+					Event::PaymentFailed { payment_hash, rejected_by_dest, erring_node, short_channel_id, failure_code } => {
+						let mut payment = match payments.pending.remove(&payment_hash) {
+							Some(payment) => payment,
+							None => {
+								res.push(Event::PaymentFailed { payment_hash, rejected_by_dest, erring_node, short_channel_id, failure_code });
+								continue;
+							}
+						};
+						// Avoid the failing hop in the next attempt: the whole node if the failure
+						// was node-level (or we don't know which of its channels failed), otherwise
+						// just the failing channel.
+						let node_failure = match failure_code {
+							Some(code) => code.is_node_failure(),
+							None => short_channel_id.is_none(),
+						};
+						match (node_failure, erring_node, short_channel_id) {
+							(true, Some(node), _) if node != payment.target => {
+								payment.route_params.excluded_nodes.insert(node);
+							},
+							(_, _, Some(short_channel_id)) => {
+								payment.route_params.excluded_channels.insert(short_channel_id);
+							},
+							_ => {},
+						}
+						if payment.started.elapsed() >= self.payment_timeout {
+							payment.timed_out = true;
+						}
+						if !is_final_failure(rejected_by_dest, failure_code) && !payment.timed_out && payment.attempts < self.max_attempts {
+							if self.send_attempt(&mut payment, payment_hash).is_ok() {
+								payments.pending.insert(payment_hash, payment);
+								continue;
+							}
+						}
+						res.push(Event::TrackedPaymentFailed {
+							payment_id: payment.payment_id,
+							payment_hash,
+							rejected_by_dest,
+							attempts: payment.attempts,
+						});
+					},
+					Event::ProbeSuccessful { .. } | Event::ProbeFailed { .. } => {
+						self.router.handle_probe_result(&event);
+						res.push(event);
+					},
+					_ => res.push(event),
+				}
+			}
+		}
+		res
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ln::msgs::{HTLCFailureCode, PERM, NODE, UPDATE};
+	use ln::payment_tracker::is_final_failure;
+
+	#[test]
+	fn final_failure_test() {
+		assert!(is_final_failure(true, None));
+		assert!(is_final_failure(true, Some(HTLCFailureCode::UnknownPaymentHash)));
+		assert!(is_final_failure(true, Some(HTLCFailureCode::TemporaryChannelFailure)));
+		assert!(!is_final_failure(false, None));
+
+		// Permanent node-level failures rule out any route through that node...
+		assert!(is_final_failure(false, Some(HTLCFailureCode::PermanentNodeFailure)));
+		assert!(is_final_failure(false, Some(HTLCFailureCode::RequiredNodeFeatureMissing)));
+		assert!(is_final_failure(false, Some(HTLCFailureCode::from_u16(PERM|NODE|99))));
+		// ...but permanent channel-level failures only rule out that channel
+		assert!(!is_final_failure(false, Some(HTLCFailureCode::PermanentChannelFailure)));
+		assert!(!is_final_failure(false, Some(HTLCFailureCode::RequiredChannelFeatureMissing)));
+		assert!(!is_final_failure(false, Some(HTLCFailureCode::UnknownNextPeer)));
+		assert!(!is_final_failure(false, Some(HTLCFailureCode::TemporaryNodeFailure)));
+		assert!(!is_final_failure(false, Some(HTLCFailureCode::from_u16(UPDATE|7))));
+	}
+}
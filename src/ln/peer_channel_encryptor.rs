@@ -11,7 +11,8 @@ use crypto::hkdf::{hkdf_extract,hkdf_expand};
 use crypto::aead::{AeadEncryptor, AeadDecryptor};
 
 use util::chacha20poly1305rfc::ChaCha20Poly1305RFC;
-use util::{byte_utils,rng};
+use util::byte_utils;
+use util::rng::EntropySource;
 use util::sha2::Sha256;
 
 // Sha256("Noise_XK_secp256k1_ChaChaPoly_SHA256")
@@ -72,9 +73,9 @@ pub struct PeerChannelEncryptor {
 }
 
 impl PeerChannelEncryptor {
-	pub fn new_outbound(their_node_id: PublicKey) -> PeerChannelEncryptor {
+	pub fn new_outbound(their_node_id: PublicKey, entropy_source: &EntropySource) -> PeerChannelEncryptor {
 		let mut key = [0u8; 32];
-		rng::fill_bytes(&mut key);
+		entropy_source.fill_bytes(&mut key);
 
 		let secp_ctx = Secp256k1::new();
 		let sec_key = SecretKey::from_slice(&secp_ctx, &key).unwrap(); //TODO: nicer rng-is-bad error message
@@ -269,11 +270,11 @@ impl PeerChannelEncryptor {
 		}
 	}
 
-	pub fn process_act_one_with_key(&mut self, act_one: &[u8], our_node_secret: &SecretKey) -> Result<[u8; 50], HandleError> {
+	pub fn process_act_one_with_key(&mut self, act_one: &[u8], our_node_secret: &SecretKey, entropy_source: &EntropySource) -> Result<[u8; 50], HandleError> {
 		assert_eq!(act_one.len(), 50);
 
 		let mut key = [0u8; 32];
-		rng::fill_bytes(&mut key);
+		entropy_source.fill_bytes(&mut key);
 		let our_ephemeral_key = SecretKey::from_slice(&self.secp_ctx, &key).unwrap(); //TODO: nicer rng-is-bad error message
 		self.process_act_one_with_ephemeral_key(act_one, our_node_secret, our_ephemeral_key)
 	}
@@ -507,12 +508,13 @@ mod tests {
 	use bitcoin::util::misc::hex_bytes;
 
 	use ln::peer_channel_encryptor::{PeerChannelEncryptor,NoiseState,DirectionalNoiseState};
+	use util::rng::SeededEntropySource;
 
 	fn get_outbound_peer_for_initiator_test_vectors() -> PeerChannelEncryptor {
 		let secp_ctx = Secp256k1::new();
 		let their_node_id = PublicKey::from_slice(&secp_ctx, &hex_bytes("028d7500dd4c12685d1f568b4c2b5048e8534b873319f3a8daa612b469132ec7f7").unwrap()[..]).unwrap();
 
-		let mut outbound_peer = PeerChannelEncryptor::new_outbound(their_node_id);
+		let mut outbound_peer = PeerChannelEncryptor::new_outbound(their_node_id, &SeededEntropySource::new([42; 32]));
 		match outbound_peer.noise_state {
 			NoiseState::InProgress { state: _, ref mut directional_state, bidirectional_state: _ } => {
 				*directional_state = DirectionalNoiseState::Outbound { // overwrite ie...
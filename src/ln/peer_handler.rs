@@ -1,12 +1,16 @@
 use secp256k1::key::{SecretKey,PublicKey};
 
+use bitcoin::util::hash::Sha256dHash;
+
 use ln::msgs;
-use ln::msgs::{MsgEncodable,MsgDecodable};
+use ln::msgs::{MsgEncodable,MsgDecodable,HandleError};
+use ln::router::MAX_SHORT_CHANNEL_IDS_PER_MESSAGE;
 use ln::peer_channel_encryptor::{PeerChannelEncryptor,NextNoiseStep};
 use util::byte_utils;
+use util::rng::EntropySource;
 use util::events::{EventsProvider,Event};
 
-use std::collections::{HashMap,LinkedList};
+use std::collections::{HashMap,HashSet,LinkedList,VecDeque};
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::{cmp,error,mem,hash,fmt};
@@ -62,6 +66,23 @@ impl error::Error for PeerHandleError {
 	}
 }
 
+enum InitSyncTracker {
+	NoSyncRequested,
+	/// Sending channel_announcements (and their channel_updates), starting at the given
+	/// short_channel_id. Once we run out, this is set to u64::max_value() and we move on to nodes.
+	ChannelsSyncing(u64),
+	/// Sending node_announcements, starting after the given node
+	NodesSyncing(PublicKey),
+}
+
+/// What a gossip message we're relaying is about (and its timestamp, if it has one), so that we
+/// don't relay it to peers which will get it as a part of their initial routing sync anyway or
+/// which have filtered it out with a gossip_timestamp_filter.
+enum GossipSubject {
+	Channel(u64, Option<u32>),
+	Node(PublicKey, u32),
+}
+
 struct Peer {
 	channel_encryptor: PeerChannelEncryptor,
 	outbound: bool,
@@ -76,6 +97,108 @@ struct Peer {
 	pending_read_buffer: Vec<u8>,
 	pending_read_buffer_pos: usize,
 	pending_read_is_header: bool,
+
+	/// Encoded (but not yet encrypted) gossip messages from other peers waiting for the next
+	/// broadcast_pending_gossip call to be relayed to this peer.
+	pending_gossip: VecDeque<Vec<u8>>,
+
+	/// How far we've gotten in sending our routing table to this peer, if they asked for it via
+	/// initial_routing_sync.
+	sync_status: InitSyncTracker,
+	/// The last gossip_timestamp_filter the peer sent us. Peers which negotiated gossip_queries
+	/// get no gossip relayed to them until they've sent one.
+	gossip_timestamp_filter: Option<msgs::GossipTimestampFilter>,
+
+	/// The chain_hash and remaining short_channel_ids of a query_short_channel_ids the peer sent
+	/// us which we're still responding to, a few channels at a time.
+	pending_scid_query: Option<(Sha256dHash, VecDeque<u64>)>,
+	/// The node_announcements we've already sent in response to pending_scid_query, so that nodes
+	/// with several of the queried channels are only sent once.
+	scid_query_sent_nodes: HashSet<PublicKey>,
+	/// The last query_channel_range we sent the peer. Only reply_channel_range messages matching
+	/// it result in us querying for the channels we don't know of.
+	pending_channel_range_query: Option<msgs::QueryChannelRange>,
+	/// The chain_hash and short_channel_ids of channels we learned of from the peer's
+	/// reply_channel_range messages which we have yet to query it for. Only one
+	/// query_short_channel_ids may be outstanding at a time, so these are sent a batch at a time
+	/// as the peer's reply_short_channel_ids_end messages come in.
+	scids_to_query: Option<(Sha256dHash, VecDeque<u64>)>,
+	/// Whether we've sent the peer a query_short_channel_ids which it has yet to respond to with
+	/// a reply_short_channel_ids_end.
+	awaiting_scid_query_reply: bool,
+}
+
+impl Peer {
+	/// Returns true if gossip about the given channel or node should be relayed to this peer, ie
+	/// we aren't still sending our routing table to them or have already sent that part of it.
+	fn should_relay_gossip(&self, subject: &GossipSubject) -> bool {
+		if self.supports_gossip_queries() {
+			let timestamp = match subject {
+				&GossipSubject::Channel(_, timestamp) => timestamp,
+				&GossipSubject::Node(_, timestamp) => Some(timestamp),
+			};
+			match self.gossip_timestamp_filter {
+				None => return false,
+				Some(ref filter) => {
+					if let Some(timestamp) = timestamp {
+						if timestamp < filter.first_timestamp || timestamp as u64 >= filter.first_timestamp as u64 + filter.timestamp_range as u64 {
+							return false;
+						}
+					}
+				},
+			}
+		}
+		match (&self.sync_status, subject) {
+			(&InitSyncTracker::NoSyncRequested, _) => true,
+			(&InitSyncTracker::ChannelsSyncing(next_scid), &GossipSubject::Channel(short_channel_id, _)) => short_channel_id < next_scid,
+			(&InitSyncTracker::ChannelsSyncing(_), &GossipSubject::Node(_, _)) => false,
+			(&InitSyncTracker::NodesSyncing(_), &GossipSubject::Channel(_, _)) => true,
+			(&InitSyncTracker::NodesSyncing(ref last_node_id), &GossipSubject::Node(ref node_id, _)) => node_id <= last_node_id,
+		}
+	}
+
+	/// Returns true if the peer asked us for our full routing table via initial_routing_sync (and
+	/// didn't negotiate gossip_queries, in which case it asks for the gossip it wants instead).
+	fn wants_initial_sync(&self) -> bool {
+		match self.their_local_features {
+			Some(ref features) => features.initial_routing_sync() && !features.supports_gossip_queries(),
+			None => false,
+		}
+	}
+
+	/// Returns the next batch of scids_to_query to send the peer, if we aren't still waiting on a
+	/// reply to a previous query_short_channel_ids.
+	fn next_scid_query(&mut self) -> Option<msgs::QueryShortChannelIds> {
+		if self.awaiting_scid_query_reply {
+			return None;
+		}
+		let query = match self.scids_to_query {
+			Some((chain_hash, ref mut short_channel_ids)) => {
+				let batch_len = cmp::min(short_channel_ids.len(), MAX_SHORT_CHANNEL_IDS_PER_MESSAGE);
+				msgs::QueryShortChannelIds {
+					chain_hash,
+					short_channel_ids: short_channel_ids.drain(..batch_len).collect(),
+				}
+			},
+			None => return None,
+		};
+		if self.scids_to_query.as_ref().unwrap().1.is_empty() {
+			self.scids_to_query = None;
+		}
+		if query.short_channel_ids.is_empty() {
+			return None;
+		}
+		self.awaiting_scid_query_reply = true;
+		Some(query)
+	}
+
+	/// Returns true if we both support the gossip_queries extension (we always do).
+	fn supports_gossip_queries(&self) -> bool {
+		match self.their_local_features {
+			Some(ref features) => features.supports_gossip_queries(),
+			None => false,
+		}
+	}
 }
 
 struct PeerHolder<Descriptor: SocketDescriptor> {
@@ -89,6 +212,7 @@ pub struct PeerManager<Descriptor: SocketDescriptor> {
 	peers: Mutex<PeerHolder<Descriptor>>,
 	pending_events: Mutex<Vec<Event>>,
 	our_node_secret: SecretKey,
+	entropy_source: Arc<EntropySource>,
 	initial_syncs_sent: AtomicUsize,
 }
 
@@ -108,15 +232,28 @@ macro_rules! encode_msg {
 //TODO: Really should do something smarter for this
 const INITIAL_SYNCS_TO_SEND: usize = 5;
 
+/// The number of messages we allow to sit in a peer's outbound buffer before we stop filling it
+/// with (optional) routing table sync messages
+const OUTBOUND_BUFFER_SYNC_LIMIT: usize = 10;
+
+/// The maximum number of gossip messages we relay to each peer per broadcast_pending_gossip call
+const GOSSIP_MESSAGES_PER_BROADCAST: usize = 250;
+/// The maximum number of gossip messages we queue up for relay to each peer. Further messages are
+/// dropped until the queue drains.
+const MAX_PENDING_GOSSIP_PER_PEER: usize = 5000;
+
 /// Manages and reacts to connection events. You probably want to use file descriptors as PeerIds.
 /// PeerIds may repeat, but only after disconnect_event() has been called.
 impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
-	pub fn new(message_handler: MessageHandler, our_node_secret: SecretKey) -> PeerManager<Descriptor> {
+	/// Constructs a new PeerManager. entropy_source is used to generate the ephemeral keys for
+	/// each connection's noise handshake.
+	pub fn new(message_handler: MessageHandler, our_node_secret: SecretKey, entropy_source: Arc<EntropySource>) -> PeerManager<Descriptor> {
 		PeerManager {
 			message_handler: message_handler,
 			peers: Mutex::new(PeerHolder { peers: HashMap::new(), node_id_to_descriptor: HashMap::new() }),
 			pending_events: Mutex::new(Vec::new()),
 			our_node_secret: our_node_secret,
+			entropy_source: entropy_source,
 			initial_syncs_sent: AtomicUsize::new(0),
 		}
 	}
@@ -128,7 +265,7 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 	/// Panics if descriptor is duplicative with some other descriptor which has not yet has a
 	/// disconnect_event.
 	pub fn new_outbound_connection(&self, their_node_id: PublicKey, descriptor: Descriptor) -> Result<Vec<u8>, PeerHandleError> {
-		let mut peer_encryptor = PeerChannelEncryptor::new_outbound(their_node_id.clone());
+		let mut peer_encryptor = PeerChannelEncryptor::new_outbound(their_node_id.clone(), &*self.entropy_source);
 		let res = peer_encryptor.get_act_one().to_vec();
 		let pending_read_buffer = [0; 50].to_vec(); // Noise act two is 50 bytes
 
@@ -147,6 +284,16 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 			pending_read_buffer: pending_read_buffer,
 			pending_read_buffer_pos: 0,
 			pending_read_is_header: false,
+
+			pending_gossip: VecDeque::new(),
+			sync_status: InitSyncTracker::NoSyncRequested,
+			gossip_timestamp_filter: None,
+
+			pending_scid_query: None,
+			scid_query_sent_nodes: HashSet::new(),
+			pending_channel_range_query: None,
+			scids_to_query: None,
+			awaiting_scid_query_reply: false,
 		}).is_some() {
 			panic!("PeerManager driver duplicated descriptors!");
 		};
@@ -179,14 +326,116 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 			pending_read_buffer: pending_read_buffer,
 			pending_read_buffer_pos: 0,
 			pending_read_is_header: false,
+
+			pending_gossip: VecDeque::new(),
+			sync_status: InitSyncTracker::NoSyncRequested,
+			gossip_timestamp_filter: None,
+
+			pending_scid_query: None,
+			scid_query_sent_nodes: HashSet::new(),
+			pending_channel_range_query: None,
+			scids_to_query: None,
+			awaiting_scid_query_reply: false,
 		}).is_some() {
 			panic!("PeerManager driver duplicated descriptors!");
 		};
 		Ok(())
 	}
 
-	fn do_attempt_write_data(descriptor: &mut Descriptor, peer: &mut Peer) {
+	fn do_attempt_write_data(&self, descriptor: &mut Descriptor, peer: &mut Peer) {
+		macro_rules! encode_and_send_msg {
+			($msg: expr, $msg_code: expr) => {
+				peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!($msg, $msg_code)[..]));
+			}
+		}
 		while !peer.awaiting_write_event {
+			// Top up the outbound buffer with the next part of our routing table if the peer asked
+			// for it, only ever holding a few messages of it in memory at a time.
+			if peer.pending_outbound_buffer.len() < OUTBOUND_BUFFER_SYNC_LIMIT && peer.wants_initial_sync() {
+				match peer.sync_status {
+					InitSyncTracker::NoSyncRequested => {},
+					InitSyncTracker::ChannelsSyncing(next_scid) if next_scid < u64::max_value() => {
+						let steps = ((OUTBOUND_BUFFER_SYNC_LIMIT - peer.pending_outbound_buffer.len() + 2) / 3) as u8;
+						let all_messages = self.message_handler.route_handler.get_next_channel_announcements(next_scid, steps);
+						for &(ref announce, ref update_a, ref update_b) in all_messages.iter() {
+							encode_and_send_msg!(announce, 256);
+							if let &Some(ref update_a) = update_a {
+								encode_and_send_msg!(update_a, 258);
+							}
+							if let &Some(ref update_b) = update_b {
+								encode_and_send_msg!(update_b, 258);
+							}
+							peer.sync_status = InitSyncTracker::ChannelsSyncing(announce.contents.short_channel_id.saturating_add(1));
+						}
+						if all_messages.len() < steps as usize {
+							peer.sync_status = InitSyncTracker::ChannelsSyncing(u64::max_value());
+						}
+					},
+					InitSyncTracker::ChannelsSyncing(_) | InitSyncTracker::NodesSyncing(_) => {
+						let steps = (OUTBOUND_BUFFER_SYNC_LIMIT - peer.pending_outbound_buffer.len()) as u8;
+						let all_messages = match peer.sync_status {
+							InitSyncTracker::NodesSyncing(ref last_node_id) => self.message_handler.route_handler.get_next_node_announcements(Some(last_node_id), steps),
+							_ => self.message_handler.route_handler.get_next_node_announcements(None, steps),
+						};
+						for msg in all_messages.iter() {
+							encode_and_send_msg!(msg, 257);
+							peer.sync_status = InitSyncTracker::NodesSyncing(msg.contents.node_id);
+						}
+						if all_messages.len() < steps as usize {
+							peer.sync_status = InitSyncTracker::NoSyncRequested;
+						}
+					},
+				}
+			}
+
+			// Similarly, respond to query_short_channel_ids a few channels at a time, each of which
+			// takes up to five messages (the announcement, two updates and two node_announcements).
+			// We keep going until the buffer is full as we may not know of any of the channels in
+			// a given batch.
+			while peer.pending_outbound_buffer.len() + 5 <= OUTBOUND_BUFFER_SYNC_LIMIT {
+				let (chain_hash, mut short_channel_ids) = match peer.pending_scid_query.take() {
+					Some(query) => query,
+					None => break,
+				};
+				let steps = (OUTBOUND_BUFFER_SYNC_LIMIT - peer.pending_outbound_buffer.len()) / 5;
+				let batch = msgs::QueryShortChannelIds {
+					chain_hash: chain_hash,
+					short_channel_ids: short_channel_ids.drain(..cmp::min(steps, short_channel_ids.len())).collect(),
+				};
+				let full_information = match self.message_handler.route_handler.handle_query_short_channel_ids(&batch) {
+					Ok((channels, nodes)) => {
+						for &(ref announcement, ref update_a, ref update_b) in channels.iter() {
+							encode_and_send_msg!(announcement, 256);
+							if let &Some(ref update_a) = update_a {
+								encode_and_send_msg!(update_a, 258);
+							}
+							if let &Some(ref update_b) = update_b {
+								encode_and_send_msg!(update_b, 258);
+							}
+						}
+						for announcement in nodes.iter() {
+							if peer.scid_query_sent_nodes.insert(announcement.contents.node_id) {
+								encode_and_send_msg!(announcement, 257);
+							}
+						}
+						true
+					},
+					Err(_) => {
+						short_channel_ids.clear();
+						false
+					},
+				};
+				if short_channel_ids.is_empty() {
+					encode_and_send_msg!(msgs::ReplyShortChannelIdsEnd {
+						chain_hash: chain_hash,
+						full_information: full_information,
+					}, 262);
+					peer.scid_query_sent_nodes.clear();
+				} else {
+					peer.pending_scid_query = Some((chain_hash, short_channel_ids));
+				}
+			}
+
 			if {
 				let next_buff = match peer.pending_outbound_buffer.front() {
 					None => return,
@@ -219,7 +468,7 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 			None => panic!("Descriptor for write_event is not already known to PeerManager"),
 			Some(peer) => {
 				peer.awaiting_write_event = false;
-				Self::do_attempt_write_data(descriptor, peer);
+				self.do_attempt_write_data(descriptor, peer);
 			}
 		};
 		Ok(())
@@ -250,6 +499,9 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 	fn do_read_event(&self, peer_descriptor: &mut Descriptor, data: Vec<u8>) -> Result<bool, PeerHandleError> {
 		let pause_read = {
 			let mut peers = self.peers.lock().unwrap();
+			// Gossip messages to relay to our other peers, exactly as we received them so that any
+			// fields we don't understand (and the signatures over them) are preserved.
+			let mut gossip_to_relay = Vec::new();
 			let (should_insert_node_id, pause_read) = match peers.peers.get_mut(peer_descriptor) {
 				None => panic!("Descriptor for read_event is not already known to PeerManager"),
 				Some(peer) => {
@@ -293,6 +545,10 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 													msgs::ErrorAction::IgnoreError => {
 														continue;
 													},
+													msgs::ErrorAction::SendErrorMessage { msg } => {
+														encode_and_send_msg!(msg, 17);
+														continue;
+													},
 												}
 											} else {
 												return Err(PeerHandleError{ no_connection_possible: false });
@@ -330,7 +586,7 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 							let next_step = peer.channel_encryptor.get_noise_step();
 							match next_step {
 								NextNoiseStep::ActOne => {
-									let act_two = try_potential_handleerror!(peer.channel_encryptor.process_act_one_with_key(&peer.pending_read_buffer[..], &self.our_node_secret)).to_vec();
+									let act_two = try_potential_handleerror!(peer.channel_encryptor.process_act_one_with_key(&peer.pending_read_buffer[..], &self.our_node_secret, &*self.entropy_source)).to_vec();
 									peer.pending_outbound_buffer.push_back(act_two);
 									peer.pending_read_buffer = [0; 66].to_vec(); // act three is 66 bytes long
 								},
@@ -346,6 +602,8 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 										self.initial_syncs_sent.fetch_add(1, Ordering::AcqRel);
 										local_features.set_initial_routing_sync();
 									}
+									local_features.set_supports_gossip_queries();
+									local_features.set_supports_upfront_shutdown_script();
 									encode_and_send_msg!(msgs::Init {
 										global_features: msgs::GlobalFeatures::new(),
 										local_features,
@@ -390,8 +648,12 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 												if msg.local_features.requires_unknown_bits() {
 													return Err(PeerHandleError{ no_connection_possible: true });
 												}
+												self.message_handler.chan_handler.peer_connected(&peer.their_node_id.unwrap(), &msg.local_features);
 												peer.their_global_features = Some(msg.global_features);
 												peer.their_local_features = Some(msg.local_features);
+												if peer.wants_initial_sync() {
+													peer.sync_status = InitSyncTracker::ChannelsSyncing(0);
+												}
 
 												if !peer.outbound {
 													let mut local_features = msgs::LocalFeatures::new();
@@ -399,6 +661,8 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 														self.initial_syncs_sent.fetch_add(1, Ordering::AcqRel);
 														local_features.set_initial_routing_sync();
 													}
+													local_features.set_supports_gossip_queries();
+													local_features.set_supports_upfront_shutdown_script();
 													encode_and_send_msg!(msgs::Init {
 														global_features: msgs::GlobalFeatures::new(),
 														local_features,
@@ -490,7 +754,9 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 											132 => {
 												let msg = try_potential_decodeerror!(msgs::CommitmentSigned::decode(&msg_data[2..]));
 												let resps = try_potential_handleerror!(self.message_handler.chan_handler.handle_commitment_signed(&peer.their_node_id.unwrap(), &msg));
-												encode_and_send_msg!(resps.0, 133);
+												if let Some(resp) = resps.0 {
+													encode_and_send_msg!(resp, 133);
+												}
 												if let Some(resp) = resps.1 {
 													encode_and_send_msg!(resp, 132);
 												}
@@ -530,16 +796,83 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 												let should_forward = try_potential_handleerror!(self.message_handler.route_handler.handle_channel_announcement(&msg));
 
 												if should_forward {
-													// TODO: forward msg along to all our other peers!
+													gossip_to_relay.push((msg_data.clone(), GossipSubject::Channel(msg.contents.short_channel_id, None)));
 												}
 											},
 											257 => {
 												let msg = try_ignore_potential_decodeerror!(msgs::NodeAnnouncement::decode(&msg_data[2..]));
-												try_potential_handleerror!(self.message_handler.route_handler.handle_node_announcement(&msg));
+												let should_forward = try_potential_handleerror!(self.message_handler.route_handler.handle_node_announcement(&msg));
+
+												if should_forward {
+													gossip_to_relay.push((msg_data.clone(), GossipSubject::Node(msg.contents.node_id, msg.contents.timestamp)));
+												}
 											},
 											258 => {
 												let msg = try_potential_decodeerror!(msgs::ChannelUpdate::decode(&msg_data[2..]));
-												try_potential_handleerror!(self.message_handler.route_handler.handle_channel_update(&msg));
+												let should_forward = try_potential_handleerror!(self.message_handler.route_handler.handle_channel_update(&msg));
+
+												if should_forward {
+													gossip_to_relay.push((msg_data.clone(), GossipSubject::Channel(msg.contents.short_channel_id, Some(msg.contents.timestamp))));
+												}
+											},
+											261 => {
+												let msg = try_potential_decodeerror!(msgs::QueryShortChannelIds::decode(&msg_data[2..]));
+												// Peers must wait for our reply_short_channel_ids_end before sending
+												// another query
+												if peer.pending_scid_query.is_some() {
+													return Err(PeerHandleError{ no_connection_possible: false });
+												}
+												// The response is sent from do_attempt_write_data as the outbound
+												// buffer drains.
+												if msg.short_channel_ids.is_empty() {
+													encode_and_send_msg!(msgs::ReplyShortChannelIdsEnd {
+														chain_hash: msg.chain_hash,
+														full_information: true,
+													}, 262);
+												} else {
+													peer.pending_scid_query = Some((msg.chain_hash, msg.short_channel_ids.into_iter().collect()));
+												}
+											},
+											262 => {
+												let msg = try_potential_decodeerror!(msgs::ReplyShortChannelIdsEnd::decode(&msg_data[2..]));
+												peer.awaiting_scid_query_reply = false;
+												if let Some(query) = peer.next_scid_query() {
+													encode_and_send_msg!(query, 261);
+												}
+												try_potential_handleerror!(self.message_handler.route_handler.handle_reply_short_channel_ids_end(&msg));
+											},
+											263 => {
+												let msg = try_potential_decodeerror!(msgs::QueryChannelRange::decode(&msg_data[2..]));
+												let replies = try_potential_handleerror!(self.message_handler.route_handler.handle_query_channel_range(&msg));
+												for reply in replies.iter() {
+													encode_and_send_msg!(reply, 264);
+												}
+											},
+											264 => {
+												let msg = try_potential_decodeerror!(msgs::ReplyChannelRange::decode(&msg_data[2..]));
+												// Ignore replies to queries we didn't send
+												let solicited = match peer.pending_channel_range_query {
+													Some(ref query) => query.chain_hash == msg.chain_hash &&
+														msg.first_blocknum >= query.first_blocknum &&
+														msg.first_blocknum as u64 + msg.number_of_blocks as u64 <= query.first_blocknum as u64 + query.number_of_blocks as u64,
+													None => false,
+												};
+												if solicited {
+													let unknown_short_channel_ids = try_potential_handleerror!(self.message_handler.route_handler.handle_reply_channel_range(&msg));
+													// Queries are sent one at a time, with the rest queued up until
+													// the peer finishes responding to the previous one.
+													if peer.scids_to_query.as_ref().map(|&(ref chain_hash, _)| *chain_hash != msg.chain_hash).unwrap_or(true) {
+														peer.scids_to_query = Some((msg.chain_hash, VecDeque::new()));
+													}
+													peer.scids_to_query.as_mut().unwrap().1.extend(unknown_short_channel_ids);
+													if let Some(query) = peer.next_scid_query() {
+														encode_and_send_msg!(query, 261);
+													}
+												}
+											},
+											265 => {
+												let msg = try_potential_decodeerror!(msgs::GossipTimestampFilter::decode(&msg_data[2..]));
+												peer.gossip_timestamp_filter = Some(msg);
 											},
 											_ => {
 												if (msg_type & 1) == 0 {
@@ -553,7 +886,7 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 						}
 					}
 
-					Self::do_attempt_write_data(peer_descriptor, peer);
+					self.do_attempt_write_data(peer_descriptor, peer);
 
 					(insert_node_id /* should_insert_node_id */, peer.pending_outbound_buffer.len() > 10) // pause_read
 				}
@@ -564,6 +897,24 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 				None => {}
 			};
 
+			if !gossip_to_relay.is_empty() {
+				for (descriptor, peer) in peers.peers.iter_mut() {
+					// Skip the peer which sent us the messages, and peers which haven't yet sent us
+					// their init (and thus may not want our gossip at all).
+					if descriptor == peer_descriptor || peer.their_local_features.is_none() {
+						continue;
+					}
+					for &(ref msg, ref subject) in gossip_to_relay.iter() {
+						if peer.pending_gossip.len() >= MAX_PENDING_GOSSIP_PER_PEER {
+							break;
+						}
+						if peer.should_relay_gossip(subject) {
+							peer.pending_gossip.push_back(msg.clone());
+						}
+					}
+				}
+			}
+
 			pause_read
 		};
 
@@ -606,9 +957,14 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 				match event {
 					Event::FundingGenerationReady {..} => { /* Hand upstream */ },
 					Event::FundingBroadcastSafe {..} => { /* Hand upstream */ },
+					Event::ChannelClosed {..} => { /* Hand upstream */ },
 					Event::PaymentReceived {..} => { /* Hand upstream */ },
 					Event::PaymentSent {..} => { /* Hand upstream */ },
 					Event::PaymentFailed {..} => { /* Hand upstream */ },
+					Event::ProbeSuccessful {..} => { /* Hand upstream */ },
+					Event::ProbeFailed {..} => { /* Hand upstream */ },
+					Event::TrackedPaymentSent {..} => { /* Hand upstream */ },
+					Event::TrackedPaymentFailed {..} => { /* Hand upstream */ },
 
 					Event::PendingHTLCsForwardable {..} => {
 						//TODO: Handle upstream in some confused form so that upstream just knows
@@ -619,7 +975,7 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 								//TODO: Drop the pending channel? (or just let it timeout, but that sucks)
 							});
 						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg, 32)));
-						Self::do_attempt_write_data(&mut descriptor, peer);
+						self.do_attempt_write_data(&mut descriptor, peer);
 						continue;
 					},
 					Event::SendFundingCreated { ref node_id, ref msg } => {
@@ -628,7 +984,7 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 								//they should just throw away this funding transaction
 							});
 						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg, 34)));
-						Self::do_attempt_write_data(&mut descriptor, peer);
+						self.do_attempt_write_data(&mut descriptor, peer);
 						continue;
 					},
 					Event::SendFundingLocked { ref node_id, ref msg, ref announcement_sigs } => {
@@ -640,7 +996,7 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 							&Some(ref announce_msg) => peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(announce_msg, 259))),
 							&None => {},
 						}
-						Self::do_attempt_write_data(&mut descriptor, peer);
+						self.do_attempt_write_data(&mut descriptor, peer);
 						continue;
 					},
 					Event::SendHTLCs { ref node_id, ref msgs, ref commitment_msg } => {
@@ -651,7 +1007,7 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 							peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg, 128)));
 						}
 						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(commitment_msg, 132)));
-						Self::do_attempt_write_data(&mut descriptor, peer);
+						self.do_attempt_write_data(&mut descriptor, peer);
 						continue;
 					},
 					Event::SendFulfillHTLC { ref node_id, ref msg, ref commitment_msg } => {
@@ -660,7 +1016,7 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 							});
 						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg, 130)));
 						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(commitment_msg, 132)));
-						Self::do_attempt_write_data(&mut descriptor, peer);
+						self.do_attempt_write_data(&mut descriptor, peer);
 						continue;
 					},
 					Event::SendFailHTLC { ref node_id, ref msg, ref commitment_msg } => {
@@ -669,7 +1025,40 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 							});
 						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg, 131)));
 						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(commitment_msg, 132)));
-						Self::do_attempt_write_data(&mut descriptor, peer);
+						self.do_attempt_write_data(&mut descriptor, peer);
+						continue;
+					},
+					Event::SendRevokeAndACK { ref node_id, ref msg } => {
+						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
+								//TODO: Do whatever we're gonna do for handling dropped messages
+							});
+						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg, 133)));
+						self.do_attempt_write_data(&mut descriptor, peer);
+						continue;
+					},
+					Event::SendCommitmentUpdate { ref node_id, ref updates } => {
+						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
+								//TODO: Do whatever we're gonna do for handling dropped messages
+							});
+						for msg in updates.update_add_htlcs.iter() {
+							peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg, 128)));
+						}
+						for msg in updates.update_fulfill_htlcs.iter() {
+							peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg, 130)));
+						}
+						for msg in updates.update_fail_htlcs.iter() {
+							peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg, 131)));
+						}
+						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(updates.commitment_signed, 132)));
+						self.do_attempt_write_data(&mut descriptor, peer);
+						continue;
+					},
+					Event::SendErrorMessage { ref node_id, ref msg } => {
+						let (mut descriptor, peer) = get_peer_for_forwarding!(node_id, {
+								//TODO: Do whatever we're gonna do for handling dropped messages
+							});
+						peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg, 17)));
+						self.do_attempt_write_data(&mut descriptor, peer);
 						continue;
 					},
 					Event::BroadcastChannelAnnouncement { ref msg, ref update_msg } => {
@@ -691,7 +1080,7 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 								}
 								peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encoded_msg[..]));
 								peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encoded_update_msg[..]));
-								Self::do_attempt_write_data(&mut (*descriptor).clone(), peer);
+								self.do_attempt_write_data(&mut (*descriptor).clone(), peer);
 							}
 						}
 						continue;
@@ -705,7 +1094,21 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 									continue
 								}
 								peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encoded_msg[..]));
-								Self::do_attempt_write_data(&mut (*descriptor).clone(), peer);
+								self.do_attempt_write_data(&mut (*descriptor).clone(), peer);
+							}
+						}
+						continue;
+					},
+					Event::BroadcastNodeAnnouncement { ref msg } => {
+						if self.message_handler.route_handler.handle_node_announcement(msg).is_ok() {
+							let encoded_msg = encode_msg!(msg, 257);
+
+							for (ref descriptor, ref mut peer) in peers.peers.iter_mut() {
+								if !peer.channel_encryptor.is_ready_for_encryption() {
+									continue
+								}
+								peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encoded_msg[..]));
+								self.do_attempt_write_data(&mut (*descriptor).clone(), peer);
 							}
 						}
 						continue;
@@ -722,6 +1125,74 @@ impl<Descriptor: SocketDescriptor> PeerManager<Descriptor> {
 		}
 	}
 
+	fn send_gossip_query<M: MsgEncodable, F: FnOnce(&mut Peer) -> Result<(), HandleError>>(&self, their_node_id: &PublicKey, msg: &M, msg_code: u16, update_peer: F) -> Result<(), HandleError> {
+		let mut peers = self.peers.lock().unwrap();
+		let mut descriptor = match peers.node_id_to_descriptor.get(their_node_id) {
+			Some(descriptor) => descriptor.clone(),
+			None => return Err(HandleError{err: "No connected peer with the given node_id", msg: None}),
+		};
+		let peer = peers.peers.get_mut(&descriptor).unwrap();
+		if !peer.supports_gossip_queries() {
+			return Err(HandleError{err: "Peer does not support gossip_queries", msg: None});
+		}
+		update_peer(peer)?;
+		peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&encode_msg!(msg, msg_code)[..]));
+		self.do_attempt_write_data(&mut descriptor, peer);
+		Ok(())
+	}
+
+	/// Asks the given peer for the short_channel_ids of the channels opened in the given range of
+	/// blocks. Any we don't know of are then queried for automatically. Only the most recent query
+	/// sent to a given peer is tracked, replies to earlier ones are ignored.
+	/// Fails if we aren't connected to the peer or it doesn't support gossip_queries.
+	pub fn send_query_channel_range(&self, their_node_id: &PublicKey, msg: &msgs::QueryChannelRange) -> Result<(), HandleError> {
+		self.send_gossip_query(their_node_id, msg, 263, |peer| { peer.pending_channel_range_query = Some(msg.clone()); Ok(()) })
+	}
+
+	/// Asks the given peer for the announcements of the given channels (and their nodes).
+	/// Fails if we aren't connected to the peer, it doesn't support gossip_queries or it has yet
+	/// to finish responding to a previous query_short_channel_ids (including those we send
+	/// automatically in response to reply_channel_range messages).
+	pub fn send_query_short_channel_ids(&self, their_node_id: &PublicKey, msg: &msgs::QueryShortChannelIds) -> Result<(), HandleError> {
+		self.send_gossip_query(their_node_id, msg, 261, |peer| {
+			if peer.awaiting_scid_query_reply {
+				return Err(HandleError{err: "Peer has yet to respond to our previous query_short_channel_ids", msg: None});
+			}
+			peer.awaiting_scid_query_reply = true;
+			Ok(())
+		})
+	}
+
+	/// Tells the given peer which gossip it should relay to us. Peers which support gossip_queries
+	/// won't relay us any gossip until we've sent them one of these.
+	/// Fails if we aren't connected to the peer or it doesn't support gossip_queries.
+	pub fn send_gossip_timestamp_filter(&self, their_node_id: &PublicKey, msg: &msgs::GossipTimestampFilter) -> Result<(), HandleError> {
+		self.send_gossip_query(their_node_id, msg, 265, |_| { Ok(()) })
+	}
+
+	/// Relays gossip (channel_announcement, channel_update and node_announcement messages) we've
+	/// received and validated since the last call to our other peers. Gossip is queued up between
+	/// calls instead of being relayed immediately, so this should be called regularly (BOLT 7
+	/// suggests once a minute).
+	/// At most GOSSIP_MESSAGES_PER_BROADCAST messages are sent to each peer per call, and peers
+	/// whose send buffer is already backed up are skipped, leaving their gossip queued.
+	pub fn broadcast_pending_gossip(&self) {
+		let mut peers = self.peers.lock().unwrap();
+		for (descriptor, peer) in peers.peers.iter_mut() {
+			if !peer.channel_encryptor.is_ready_for_encryption() || peer.pending_outbound_buffer.len() >= OUTBOUND_BUFFER_SYNC_LIMIT {
+				continue;
+			}
+			let to_send = cmp::min(peer.pending_gossip.len(), GOSSIP_MESSAGES_PER_BROADCAST);
+			if to_send == 0 {
+				continue;
+			}
+			for msg in peer.pending_gossip.drain(..to_send) {
+				peer.pending_outbound_buffer.push_back(peer.channel_encryptor.encrypt_message(&msg[..]));
+			}
+			self.do_attempt_write_data(&mut descriptor.clone(), peer);
+		}
+	}
+
 	/// Indicates that the given socket descriptor's connection is now closed.
 	/// This must be called even if a PeerHandleError was given for a read_event or write_event,
 	/// but must NOT be called if a PeerHandleError was provided out of a new_*_connection event!
@@ -756,3 +1227,470 @@ impl<Descriptor: SocketDescriptor> EventsProvider for PeerManager<Descriptor> {
 		ret
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use bitcoin::network::constants::Network;
+	use bitcoin::util::hash::Sha256dHash;
+
+	use chain::chaininterface;
+	use ln::channelmanager::ChannelManager;
+	use ln::msgs;
+	use ln::msgs::{MsgEncodable,MsgDecodable};
+	use ln::peer_channel_encryptor::PeerChannelEncryptor;
+	use ln::router::MAX_SHORT_CHANNEL_IDS_PER_MESSAGE;
+	use ln::peer_handler::{PeerManager, MessageHandler, SocketDescriptor, InitSyncTracker, OUTBOUND_BUFFER_SYNC_LIMIT, GOSSIP_MESSAGES_PER_BROADCAST, MAX_PENDING_GOSSIP_PER_PEER};
+	use util::byte_utils;
+	use util::rng::SeededEntropySource;
+	use util::test_utils;
+
+	use secp256k1::{Secp256k1, Message};
+	use secp256k1::key::{PublicKey, SecretKey};
+
+	use std::sync::{Arc, Mutex};
+	use std::{hash, mem};
+
+	#[derive(Clone)]
+	struct FileDescriptor {
+		fd: u16,
+		outbound_data: Arc<Mutex<Vec<u8>>>,
+		/// If false, send_data accepts nothing, as if the socket's send buffer were full
+		accept_data: Arc<Mutex<bool>>,
+	}
+	impl PartialEq for FileDescriptor {
+		fn eq(&self, other: &Self) -> bool {
+			self.fd == other.fd
+		}
+	}
+	impl Eq for FileDescriptor {}
+	impl hash::Hash for FileDescriptor {
+		fn hash<H: hash::Hasher>(&self, hasher: &mut H) {
+			self.fd.hash(hasher)
+		}
+	}
+	impl SocketDescriptor for FileDescriptor {
+		fn send_data(&mut self, data: &Vec<u8>, write_offset: usize, _resume_read: bool) -> usize {
+			if !*self.accept_data.lock().unwrap() {
+				return 0;
+			}
+			self.outbound_data.lock().unwrap().extend_from_slice(&data[write_offset..]);
+			data.len() - write_offset
+		}
+	}
+
+	/// Creates a PeerManager whose route handler knows of channel_count channels
+	fn create_peer_manager(channel_count: u64) -> PeerManager<FileDescriptor> {
+		let secp_ctx = Secp256k1::new();
+		let entropy_source = Arc::new(SeededEntropySource::new([42; 32]));
+		let feeest = Arc::new(test_utils::TestFeeEstimator { sat_per_vbyte: 1 });
+		let chain_monitor = Arc::new(chaininterface::ChainWatchInterfaceUtil::new(Network::Testnet));
+		let tx_broadcaster = Arc::new(test_utils::TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()) });
+		let chan_monitor = Arc::new(test_utils::TestChannelMonitor::new(chain_monitor.clone(), tx_broadcaster.clone(), feeest.clone()));
+		let keys_manager = Arc::new(test_utils::TestKeysInterface::new(&[42; 32], Network::Testnet));
+		let chan_handler = ChannelManager::new(keys_manager, entropy_source.clone(), 0, true, Network::Testnet, feeest, chan_monitor, chain_monitor, tx_broadcaster).unwrap();
+		let route_handler = Arc::new(test_utils::TestRoutingMessageHandler::new(channel_count));
+
+		let our_node_secret = SecretKey::from_slice(&secp_ctx, &[42; 32]).unwrap();
+		PeerManager::new(MessageHandler { chan_handler, route_handler }, our_node_secret, entropy_source)
+	}
+
+	/// Completes the noise handshake with a new inbound connection (with the given fd) from a peer
+	/// we drive by hand, returning the connection's descriptor and the peer's side of the
+	/// encryption.
+	fn connect_peer(peer_manager: &PeerManager<FileDescriptor>, fd: u16) -> (FileDescriptor, PeerChannelEncryptor) {
+		let secp_ctx = Secp256k1::new();
+		let entropy_source = SeededEntropySource::new([fd as u8; 32]);
+		let our_node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&secp_ctx, &[42; 32]).unwrap()).unwrap();
+
+		let mut descriptor = FileDescriptor { fd, outbound_data: Arc::new(Mutex::new(Vec::new())), accept_data: Arc::new(Mutex::new(true)) };
+		let their_node_secret = SecretKey::from_slice(&secp_ctx, &[42 + fd as u8; 32]).unwrap();
+		let mut remote = PeerChannelEncryptor::new_outbound(our_node_id, &entropy_source);
+		peer_manager.new_inbound_connection(descriptor.clone()).unwrap();
+		assert!(!peer_manager.read_event(&mut descriptor, remote.get_act_one().to_vec()).unwrap());
+		let act_two = mem::replace(&mut *descriptor.outbound_data.lock().unwrap(), Vec::new());
+		let act_three = remote.process_act_two(&act_two[..], &their_node_secret).unwrap();
+		assert!(!peer_manager.read_event(&mut descriptor, act_three.to_vec()).unwrap());
+
+		(descriptor, remote)
+	}
+
+	/// Creates a PeerManager whose route handler knows of channel_count channels and connects a
+	/// single peer to it (see connect_peer).
+	fn create_peer_manager_and_connection(channel_count: u64) -> (PeerManager<FileDescriptor>, FileDescriptor, PeerChannelEncryptor) {
+		let peer_manager = create_peer_manager(channel_count);
+		let (descriptor, remote) = connect_peer(&peer_manager, 1);
+		(peer_manager, descriptor, remote)
+	}
+
+	/// Decrypts each message in data, returning their types and contents
+	fn decrypt_messages(remote: &mut PeerChannelEncryptor, data: &[u8]) -> Vec<(u16, Vec<u8>)> {
+		let mut res = Vec::new();
+		let mut pos = 0;
+		while pos < data.len() {
+			let msg_len = remote.decrypt_length_header(&data[pos..pos + 18]).unwrap() as usize;
+			let msg = remote.decrypt_message(&data[pos + 18..pos + 18 + msg_len + 16]).unwrap();
+			res.push((byte_utils::slice_to_be16(&msg[0..2]), msg[2..].to_vec()));
+			pos += 18 + msg_len + 16;
+		}
+		res
+	}
+
+	fn encrypt_msg<M: MsgEncodable>(remote: &mut PeerChannelEncryptor, msg: &M, msg_code: u16) -> Vec<u8> {
+		let mut encoded_msg = byte_utils::be16_to_array(msg_code).to_vec();
+		encoded_msg.extend_from_slice(&msg.encode()[..]);
+		remote.encrypt_message(&encoded_msg[..])
+	}
+
+	fn encode_init(remote: &mut PeerChannelEncryptor, initial_routing_sync: bool, gossip_queries: bool) -> Vec<u8> {
+		let mut local_features = msgs::LocalFeatures::new();
+		if initial_routing_sync {
+			local_features.set_initial_routing_sync();
+		}
+		if gossip_queries {
+			local_features.set_supports_gossip_queries();
+		}
+		encrypt_msg(remote, &msgs::Init { global_features: msgs::GlobalFeatures::new(), local_features }, 16)
+	}
+
+	#[test]
+	fn initial_sync_backpressure_test() {
+		// Tests that we only ever hold a few messages of our routing table in a peer's outbound
+		// buffer, pausing the initial sync while the peer isn't reading and resuming it (in order)
+		// as the buffer drains.
+		let (peer_manager, mut descriptor, mut remote) = create_peer_manager_and_connection(100);
+
+		*descriptor.accept_data.lock().unwrap() = false;
+		let init = encode_init(&mut remote, true, false);
+		peer_manager.read_event(&mut descriptor, init).unwrap();
+		{
+			let peers = peer_manager.peers.lock().unwrap();
+			let peer = peers.peers.get(&descriptor).unwrap();
+			assert!(peer.pending_outbound_buffer.len() >= OUTBOUND_BUFFER_SYNC_LIMIT - 2);
+			assert!(peer.pending_outbound_buffer.len() <= OUTBOUND_BUFFER_SYNC_LIMIT);
+			match peer.sync_status {
+				InitSyncTracker::ChannelsSyncing(next_scid) => assert!(next_scid > 0 && next_scid < 100),
+				_ => panic!("Expected to still be syncing channels"),
+			}
+		}
+		// Nothing gets added while the buffer is full
+		peer_manager.write_event(&mut descriptor).unwrap();
+		assert!(peer_manager.peers.lock().unwrap().peers.get(&descriptor).unwrap().pending_outbound_buffer.len() <= OUTBOUND_BUFFER_SYNC_LIMIT);
+
+		*descriptor.accept_data.lock().unwrap() = true;
+		peer_manager.write_event(&mut descriptor).unwrap();
+		match peer_manager.peers.lock().unwrap().peers.get(&descriptor).unwrap().sync_status {
+			InitSyncTracker::NoSyncRequested => {},
+			_ => panic!("Expected the sync to have completed"),
+		}
+
+		let msgs = decrypt_messages(&mut remote, &descriptor.outbound_data.lock().unwrap()[..]);
+		assert_eq!(msgs[0].0, 16);
+		let mut next_scid = 0;
+		for &(msg_type, ref msg) in msgs[1..].iter() {
+			match msg_type {
+				256 => {
+					assert_eq!(msgs::ChannelAnnouncement::decode(&msg[..]).unwrap().contents.short_channel_id, next_scid);
+					next_scid += 1;
+				},
+				258 => assert_eq!(msgs::ChannelUpdate::decode(&msg[..]).unwrap().contents.short_channel_id, next_scid - 1),
+				_ => panic!("Unexpected message type"),
+			}
+		}
+		assert_eq!(next_scid, 100);
+		assert_eq!(msgs.len(), 1 + 3 * 100);
+	}
+
+	#[test]
+	fn no_initial_sync_test() {
+		// Peers which don't set initial_routing_sync don't get our routing table
+		let (peer_manager, mut descriptor, mut remote) = create_peer_manager_and_connection(100);
+		let init = encode_init(&mut remote, false, false);
+		peer_manager.read_event(&mut descriptor, init).unwrap();
+		let msgs = decrypt_messages(&mut remote, &descriptor.outbound_data.lock().unwrap()[..]);
+		assert_eq!(msgs.len(), 1);
+		assert_eq!(msgs[0].0, 16);
+	}
+
+	#[test]
+	fn query_short_channel_ids_backpressure_test() {
+		// Tests that we respond to a query_short_channel_ids a few channels at a time as the
+		// peer's outbound buffer drains, instead of buffering the whole response.
+		let (peer_manager, mut descriptor, mut remote) = create_peer_manager_and_connection(100);
+		let chain_hash = Sha256dHash::from_data(&[42; 32]);
+
+		let init = encode_init(&mut remote, false, true);
+		peer_manager.read_event(&mut descriptor, init).unwrap();
+		decrypt_messages(&mut remote, &mem::replace(&mut *descriptor.outbound_data.lock().unwrap(), Vec::new())[..]);
+
+		*descriptor.accept_data.lock().unwrap() = false;
+		let query = encrypt_msg(&mut remote, &msgs::QueryShortChannelIds { chain_hash, short_channel_ids: (0..150).collect() }, 261);
+		peer_manager.read_event(&mut descriptor, query).unwrap();
+		{
+			let peers = peer_manager.peers.lock().unwrap();
+			let peer = peers.peers.get(&descriptor).unwrap();
+			assert!(peer.pending_outbound_buffer.len() > 0);
+			assert!(peer.pending_outbound_buffer.len() <= OUTBOUND_BUFFER_SYNC_LIMIT);
+			assert!(peer.pending_scid_query.as_ref().unwrap().1.len() > 0);
+		}
+
+		// A second query before we've finished responding to the first is a protocol violation (so
+		// check that on a separate connection)
+		let (peer_manager_2, mut descriptor_2, mut remote_2) = create_peer_manager_and_connection(100);
+		let init = encode_init(&mut remote_2, false, true);
+		peer_manager_2.read_event(&mut descriptor_2, init).unwrap();
+		*descriptor_2.accept_data.lock().unwrap() = false;
+		let first_query = encrypt_msg(&mut remote_2, &msgs::QueryShortChannelIds { chain_hash, short_channel_ids: (0..150).collect() }, 261);
+		peer_manager_2.read_event(&mut descriptor_2, first_query).unwrap();
+		let second_query = encrypt_msg(&mut remote_2, &msgs::QueryShortChannelIds { chain_hash, short_channel_ids: vec![0] }, 261);
+		assert!(peer_manager_2.read_event(&mut descriptor_2, second_query).is_err());
+
+		*descriptor.accept_data.lock().unwrap() = true;
+		peer_manager.write_event(&mut descriptor).unwrap();
+		assert!(peer_manager.peers.lock().unwrap().peers.get(&descriptor).unwrap().pending_scid_query.is_none());
+
+		let msgs = decrypt_messages(&mut remote, &descriptor.outbound_data.lock().unwrap()[..]);
+		let mut next_scid = 0;
+		for &(msg_type, ref msg) in msgs[..msgs.len() - 1].iter() {
+			match msg_type {
+				256 => {
+					assert_eq!(msgs::ChannelAnnouncement::decode(&msg[..]).unwrap().contents.short_channel_id, next_scid);
+					next_scid += 1;
+				},
+				258 => assert_eq!(msgs::ChannelUpdate::decode(&msg[..]).unwrap().contents.short_channel_id, next_scid - 1),
+				_ => panic!("Unexpected message type"),
+			}
+		}
+		assert_eq!(next_scid, 100);
+		assert_eq!(msgs.len(), 3 * 100 + 1);
+		assert_eq!(msgs[msgs.len() - 1].0, 262);
+		let end = msgs::ReplyShortChannelIdsEnd::decode(&msgs[msgs.len() - 1].1[..]).unwrap();
+		assert!(end.chain_hash == chain_hash);
+		assert!(end.full_information);
+	}
+
+	#[test]
+	fn unsolicited_reply_channel_range_test() {
+		// We only query for the unknown channels in a reply_channel_range if it responds to the
+		// query_channel_range we sent.
+		let (peer_manager, mut descriptor, mut remote) = create_peer_manager_and_connection(0);
+		let secp_ctx = Secp256k1::new();
+		let their_node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&secp_ctx, &[43; 32]).unwrap()).unwrap();
+		let chain_hash = Sha256dHash::from_data(&[42; 32]);
+
+		let init = encode_init(&mut remote, false, true);
+		peer_manager.read_event(&mut descriptor, init).unwrap();
+		decrypt_messages(&mut remote, &mem::replace(&mut *descriptor.outbound_data.lock().unwrap(), Vec::new())[..]);
+
+		let reply = msgs::ReplyChannelRange { chain_hash, first_blocknum: 100, number_of_blocks: 100, full_information: true, short_channel_ids: vec![1, 2, 3] };
+		let encrypted_reply = encrypt_msg(&mut remote, &reply, 264);
+		peer_manager.read_event(&mut descriptor, encrypted_reply).unwrap();
+		assert!(descriptor.outbound_data.lock().unwrap().is_empty());
+
+		peer_manager.send_query_channel_range(&their_node_id, &msgs::QueryChannelRange { chain_hash, first_blocknum: 150, number_of_blocks: 100 }).unwrap();
+		let msgs = decrypt_messages(&mut remote, &mem::replace(&mut *descriptor.outbound_data.lock().unwrap(), Vec::new())[..]);
+		assert_eq!(msgs.len(), 1);
+		assert_eq!(msgs[0].0, 263);
+
+		// Replies for a different chain or outside of the range we asked for are still ignored
+		let mut wrong_chain_reply = reply.clone();
+		wrong_chain_reply.chain_hash = Sha256dHash::from_data(&[43; 32]);
+		wrong_chain_reply.first_blocknum = 150;
+		let encrypted_reply = encrypt_msg(&mut remote, &wrong_chain_reply, 264);
+		peer_manager.read_event(&mut descriptor, encrypted_reply).unwrap();
+		let encrypted_reply = encrypt_msg(&mut remote, &reply, 264);
+		peer_manager.read_event(&mut descriptor, encrypted_reply).unwrap();
+		assert!(descriptor.outbound_data.lock().unwrap().is_empty());
+
+		let mut matching_reply = reply.clone();
+		matching_reply.first_blocknum = 150;
+		let encrypted_reply = encrypt_msg(&mut remote, &matching_reply, 264);
+		peer_manager.read_event(&mut descriptor, encrypted_reply).unwrap();
+		let msgs = decrypt_messages(&mut remote, &descriptor.outbound_data.lock().unwrap()[..]);
+		assert_eq!(msgs.len(), 1);
+		assert_eq!(msgs[0].0, 261);
+		let query = msgs::QueryShortChannelIds::decode(&msgs[0].1[..]).unwrap();
+		assert!(query.chain_hash == chain_hash);
+		assert_eq!(query.short_channel_ids, vec![1, 2, 3]);
+	}
+
+	fn dummy_channel_update(secp_ctx: &Secp256k1, short_channel_id: u64) -> msgs::ChannelUpdate {
+		let key = SecretKey::from_slice(&secp_ctx, &[42; 32]).unwrap();
+		msgs::ChannelUpdate {
+			signature: secp_ctx.sign(&Message::from_slice(&[42; 32]).unwrap(), &key).unwrap(),
+			contents: msgs::UnsignedChannelUpdate {
+				chain_hash: Sha256dHash::from_data(&[0; 32]),
+				short_channel_id,
+				timestamp: 0,
+				flags: 0,
+				cltv_expiry_delta: 0,
+				htlc_minimum_msat: 0,
+				fee_base_msat: 0,
+				fee_proportional_millionths: 0,
+			},
+		}
+	}
+
+	#[test]
+	fn gossip_relay_test() {
+		// Tests that gossip is only relayed (on broadcast_pending_gossip) to peers other than the
+		// one which sent it to us which have sent us their init, and that both the number of
+		// messages we relay per broadcast and the number we queue up per peer are limited.
+		let secp_ctx = Secp256k1::new();
+		let peer_manager = create_peer_manager(0);
+		let (mut origin, mut origin_remote) = connect_peer(&peer_manager, 1);
+		let (mut descriptor, mut remote) = connect_peer(&peer_manager, 2);
+		let (uninitialized, _) = connect_peer(&peer_manager, 3);
+		let init = encode_init(&mut origin_remote, false, false);
+		peer_manager.read_event(&mut origin, init).unwrap();
+		let init = encode_init(&mut remote, false, false);
+		peer_manager.read_event(&mut descriptor, init).unwrap();
+		decrypt_messages(&mut origin_remote, &mem::replace(&mut *origin.outbound_data.lock().unwrap(), Vec::new())[..]);
+		decrypt_messages(&mut remote, &mem::replace(&mut *descriptor.outbound_data.lock().unwrap(), Vec::new())[..]);
+
+		let mut updates = Vec::new();
+		for short_channel_id in 0..GOSSIP_MESSAGES_PER_BROADCAST as u64 + 10 {
+			updates.extend_from_slice(&encrypt_msg(&mut origin_remote, &dummy_channel_update(&secp_ctx, short_channel_id), 258)[..]);
+		}
+		peer_manager.read_event(&mut origin, updates).unwrap();
+		{
+			let peers = peer_manager.peers.lock().unwrap();
+			assert!(peers.peers.get(&origin).unwrap().pending_gossip.is_empty());
+			assert!(peers.peers.get(&uninitialized).unwrap().pending_gossip.is_empty());
+			assert_eq!(peers.peers.get(&descriptor).unwrap().pending_gossip.len(), GOSSIP_MESSAGES_PER_BROADCAST + 10);
+		}
+		// Nothing is relayed until broadcast_pending_gossip is called
+		assert!(descriptor.outbound_data.lock().unwrap().is_empty());
+
+		let check_relayed_updates = |remote: &mut PeerChannelEncryptor, descriptor: &FileDescriptor, first_scid: u64, count: usize| {
+			let msgs = decrypt_messages(remote, &mem::replace(&mut *descriptor.outbound_data.lock().unwrap(), Vec::new())[..]);
+			assert_eq!(msgs.len(), count);
+			for (idx, &(msg_type, ref msg)) in msgs.iter().enumerate() {
+				assert_eq!(msg_type, 258);
+				assert_eq!(msgs::ChannelUpdate::decode(&msg[..]).unwrap().contents.short_channel_id, first_scid + idx as u64);
+			}
+		};
+		peer_manager.broadcast_pending_gossip();
+		check_relayed_updates(&mut remote, &descriptor, 0, GOSSIP_MESSAGES_PER_BROADCAST);
+		assert!(origin.outbound_data.lock().unwrap().is_empty());
+		peer_manager.broadcast_pending_gossip();
+		check_relayed_updates(&mut remote, &descriptor, GOSSIP_MESSAGES_PER_BROADCAST as u64, 10);
+		peer_manager.broadcast_pending_gossip();
+		assert!(descriptor.outbound_data.lock().unwrap().is_empty());
+
+		// Once a peer has MAX_PENDING_GOSSIP_PER_PEER messages queued, further gossip is dropped
+		let mut updates = Vec::new();
+		for short_channel_id in 0..MAX_PENDING_GOSSIP_PER_PEER as u64 + 10 {
+			updates.extend_from_slice(&encrypt_msg(&mut origin_remote, &dummy_channel_update(&secp_ctx, short_channel_id), 258)[..]);
+		}
+		peer_manager.read_event(&mut origin, updates).unwrap();
+		{
+			let peers = peer_manager.peers.lock().unwrap();
+			let pending_gossip = &peers.peers.get(&descriptor).unwrap().pending_gossip;
+			assert_eq!(pending_gossip.len(), MAX_PENDING_GOSSIP_PER_PEER);
+			assert_eq!(msgs::ChannelUpdate::decode(&pending_gossip.back().unwrap()[2..]).unwrap().contents.short_channel_id, MAX_PENDING_GOSSIP_PER_PEER as u64 - 1);
+		}
+	}
+
+	#[test]
+	fn initial_sync_gossip_relay_test() {
+		// Tests that while we're sending a peer our routing table we only relay it gossip about the
+		// part of the table we've already sent (the rest it'll get as a part of the sync), and that
+		// peers which negotiated gossip_queries don't get a sync even if they set
+		// initial_routing_sync.
+		let secp_ctx = Secp256k1::new();
+		let peer_manager = create_peer_manager(100);
+		let (mut origin, mut origin_remote) = connect_peer(&peer_manager, 1);
+		let (mut descriptor, mut remote) = connect_peer(&peer_manager, 2);
+		let (mut queries_descriptor, mut queries_remote) = connect_peer(&peer_manager, 3);
+
+		let init = encode_init(&mut origin_remote, false, false);
+		peer_manager.read_event(&mut origin, init).unwrap();
+		let init = encode_init(&mut queries_remote, true, true);
+		peer_manager.read_event(&mut queries_descriptor, init).unwrap();
+		let msgs = decrypt_messages(&mut queries_remote, &mem::replace(&mut *queries_descriptor.outbound_data.lock().unwrap(), Vec::new())[..]);
+		assert_eq!(msgs.len(), 1);
+		assert_eq!(msgs[0].0, 16);
+
+		*descriptor.accept_data.lock().unwrap() = false;
+		let init = encode_init(&mut remote, true, false);
+		peer_manager.read_event(&mut descriptor, init).unwrap();
+		let next_scid = match peer_manager.peers.lock().unwrap().peers.get(&descriptor).unwrap().sync_status {
+			InitSyncTracker::ChannelsSyncing(next_scid) => next_scid,
+			_ => panic!("Expected to still be syncing channels"),
+		};
+		assert!(next_scid > 0 && next_scid < 99);
+
+		let mut updates = encrypt_msg(&mut origin_remote, &dummy_channel_update(&secp_ctx, 0), 258);
+		updates.extend_from_slice(&encrypt_msg(&mut origin_remote, &dummy_channel_update(&secp_ctx, 99), 258)[..]);
+		peer_manager.read_event(&mut origin, updates).unwrap();
+		{
+			let peers = peer_manager.peers.lock().unwrap();
+			let pending_gossip = &peers.peers.get(&descriptor).unwrap().pending_gossip;
+			assert_eq!(pending_gossip.len(), 1);
+			assert_eq!(msgs::ChannelUpdate::decode(&pending_gossip[0][2..]).unwrap().contents.short_channel_id, 0);
+			// The gossip_queries peer hasn't sent a gossip_timestamp_filter, so gets nothing
+			assert!(peers.peers.get(&queries_descriptor).unwrap().pending_gossip.is_empty());
+		}
+
+		*descriptor.accept_data.lock().unwrap() = true;
+		peer_manager.write_event(&mut descriptor).unwrap();
+		let msgs = decrypt_messages(&mut remote, &descriptor.outbound_data.lock().unwrap()[..]);
+		assert_eq!(msgs.len(), 1 + 3 * 100);
+		assert!(queries_descriptor.outbound_data.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn reply_channel_range_query_batching_test() {
+		// Tests that we only ever have one query_short_channel_ids outstanding, sending the next
+		// batch of unknown channels from reply_channel_range messages only once the peer has sent
+		// its reply_short_channel_ids_end.
+		let (peer_manager, mut descriptor, mut remote) = create_peer_manager_and_connection(0);
+		let secp_ctx = Secp256k1::new();
+		let their_node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&secp_ctx, &[43; 32]).unwrap()).unwrap();
+		let chain_hash = Sha256dHash::from_data(&[42; 32]);
+
+		let init = encode_init(&mut remote, false, true);
+		peer_manager.read_event(&mut descriptor, init).unwrap();
+		peer_manager.send_query_channel_range(&their_node_id, &msgs::QueryChannelRange { chain_hash, first_blocknum: 0, number_of_blocks: 1000 }).unwrap();
+		decrypt_messages(&mut remote, &mem::replace(&mut *descriptor.outbound_data.lock().unwrap(), Vec::new())[..]);
+
+		let outbound_data = descriptor.outbound_data.clone();
+		let read_queries = |remote: &mut PeerChannelEncryptor| -> Vec<msgs::QueryShortChannelIds> {
+			decrypt_messages(remote, &mem::replace(&mut *outbound_data.lock().unwrap(), Vec::new())[..]).iter().map(|&(msg_type, ref msg)| {
+				assert_eq!(msg_type, 261);
+				msgs::QueryShortChannelIds::decode(&msg[..]).unwrap()
+			}).collect()
+		};
+
+		let reply = msgs::ReplyChannelRange { chain_hash, first_blocknum: 0, number_of_blocks: 500, full_information: true, short_channel_ids: (0..MAX_SHORT_CHANNEL_IDS_PER_MESSAGE as u64 + 10).collect() };
+		let encrypted_reply = encrypt_msg(&mut remote, &reply, 264);
+		peer_manager.read_event(&mut descriptor, encrypted_reply).unwrap();
+		let queries = read_queries(&mut remote);
+		assert_eq!(queries.len(), 1);
+		assert_eq!(queries[0].short_channel_ids, (0..MAX_SHORT_CHANNEL_IDS_PER_MESSAGE as u64).collect::<Vec<_>>());
+
+		// Further unknown channels are queued up behind the outstanding query, as are our own
+		// queries
+		let reply = msgs::ReplyChannelRange { chain_hash, first_blocknum: 500, number_of_blocks: 500, full_information: true, short_channel_ids: vec![100_000, 100_001] };
+		let encrypted_reply = encrypt_msg(&mut remote, &reply, 264);
+		peer_manager.read_event(&mut descriptor, encrypted_reply).unwrap();
+		assert!(read_queries(&mut remote).is_empty());
+		assert!(peer_manager.send_query_short_channel_ids(&their_node_id, &msgs::QueryShortChannelIds { chain_hash, short_channel_ids: vec![1] }).is_err());
+
+		let end = encrypt_msg(&mut remote, &msgs::ReplyShortChannelIdsEnd { chain_hash, full_information: true }, 262);
+		peer_manager.read_event(&mut descriptor, end).unwrap();
+		let queries = read_queries(&mut remote);
+		assert_eq!(queries.len(), 1);
+		assert!(queries[0].chain_hash == chain_hash);
+		let mut expected_scids: Vec<u64> = (MAX_SHORT_CHANNEL_IDS_PER_MESSAGE as u64..MAX_SHORT_CHANNEL_IDS_PER_MESSAGE as u64 + 10).collect();
+		expected_scids.push(100_000);
+		expected_scids.push(100_001);
+		assert_eq!(queries[0].short_channel_ids, expected_scids);
+
+		let end = encrypt_msg(&mut remote, &msgs::ReplyShortChannelIdsEnd { chain_hash, full_information: true }, 262);
+		peer_manager.read_event(&mut descriptor, end).unwrap();
+		assert!(read_queries(&mut remote).is_empty());
+		peer_manager.send_query_short_channel_ids(&their_node_id, &msgs::QueryShortChannelIds { chain_hash, short_channel_ids: vec![1] }).unwrap();
+		assert_eq!(read_queries(&mut remote).len(), 1);
+	}
+}
@@ -2,14 +2,29 @@ use secp256k1::key::PublicKey;
 use secp256k1::{Secp256k1,Message};
 
 use bitcoin::util::hash::Sha256dHash;
-
-use ln::msgs::{ErrorAction,HandleError,RoutingMessageHandler,MsgEncodable,NetAddress,GlobalFeatures};
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::network::constants::Network;
+use bitcoin::network::serialize::BitcoinHash;
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::blockdata::opcodes;
+
+use chain::chaininterface::{ChainError, ChainListener, ChainWatchInterface};
+use chain::transaction::OutPoint;
+use ln::channelmonitor::ANTI_REORG_DELAY;
+use ln::msgs::{DecodeError,ErrorAction,HandleError,RoutingMessageHandler,MsgEncodable,MsgDecodable,NetAddress,GlobalFeatures};
 use ln::msgs;
+use util::byte_utils;
+use util::events::Event;
+use util::rng::EntropySource;
 
-use std::cmp;
-use std::sync::RwLock;
-use std::collections::{HashMap,BinaryHeap};
-use std::collections::hash_map::Entry;
+use std::{cmp, mem};
+use std::sync::{Arc,RwLock};
+use std::time::{Duration,Instant};
+use std::collections::{HashMap,HashSet,BinaryHeap,BTreeMap,BTreeSet};
+use std::collections::btree_map::Entry;
+use std::ops::Bound;
 
 /// A hop in a route
 #[derive(Clone)]
@@ -41,12 +56,24 @@ struct DirectionalChannelInfo {
 	htlc_minimum_msat: u64,
 	fee_base_msat: u32,
 	fee_proportional_millionths: u32,
+	/// The channel_update we got these values from, if we should relay it to our peers
+	last_update_message: Option<msgs::ChannelUpdate>,
 }
 
 struct ChannelInfo {
 	features: GlobalFeatures,
 	one_to_two: DirectionalChannelInfo,
 	two_to_one: DirectionalChannelInfo,
+	/// The channel_announcement this channel was added from, if we should relay it to our peers
+	announcement_message: Option<msgs::ChannelAnnouncement>,
+	/// The value of the funding output, if we were able to look it up on chain
+	capacity_sats: Option<u64>,
+	/// The funding output, if we were able to look it up on chain. We watch it so that we can
+	/// remove the channel once it is spent.
+	funding_txo: Option<OutPoint>,
+	/// The block time at which we first pruned stale channels after receiving the
+	/// channel_announcement, so that channels which never see a channel_update get pruned too.
+	announcement_received_time: u32,
 }
 
 struct NodeInfo {
@@ -63,16 +90,39 @@ struct NodeInfo {
 	rgb: [u8; 3],
 	alias: [u8; 32],
 	addresses: Vec<NetAddress>,
+	/// The node_announcement we got these values from, if we should relay it to our peers
+	announcement_message: Option<msgs::NodeAnnouncement>,
+}
+
+#[cfg(feature = "non_bitcoin_chain_hash_routing")]
+type ChannelKey = (u64, Sha256dHash);
+#[cfg(not(feature = "non_bitcoin_chain_hash_routing"))]
+type ChannelKey = u64;
+
+/// The channels (and the nodes left without channels) we removed from the network graph because
+/// their funding outputs were spent in a given block, so that they can be restored if the block
+/// is disconnected.
+struct SpentChannels {
+	block_hash: Sha256dHash,
+	height: u32,
+	channels: Vec<(ChannelKey, ChannelInfo)>,
+	nodes: Vec<(PublicKey, NodeInfo)>,
 }
 
 struct NetworkMap {
 	#[cfg(feature = "non_bitcoin_chain_hash_routing")]
-	channels: HashMap<(u64, Sha256dHash), ChannelInfo>,
+	channels: BTreeMap<(u64, Sha256dHash), ChannelInfo>,
 	#[cfg(not(feature = "non_bitcoin_chain_hash_routing"))]
-	channels: HashMap<u64, ChannelInfo>,
+	channels: BTreeMap<u64, ChannelInfo>,
 
 	our_node_id: PublicKey,
-	nodes: HashMap<PublicKey, NodeInfo>,
+	nodes: BTreeMap<PublicKey, NodeInfo>,
+	/// Channels closed in the last ANTI_REORG_DELAY blocks. Not serialized, so a reorg across a
+	/// restart relies on the channels' peers re-announcing them.
+	recently_spent_channels: Vec<SpentChannels>,
+	/// (short_channel_id, the node the channel leads to) -> the smallest amount (in msat) a probe
+	/// recently failed to send over the channel in that direction, and when. Not serialized.
+	probe_failures: HashMap<(u64, PublicKey), (u64, Instant)>,
 }
 
 impl NetworkMap {
@@ -87,9 +137,101 @@ impl NetworkMap {
 	fn get_key(short_channel_id: u64, _: Sha256dHash) -> u64 {
 		short_channel_id
 	}
+
+	#[cfg(feature = "non_bitcoin_chain_hash_routing")]
+	#[inline]
+	fn get_short_channel_id(key: &(u64, Sha256dHash)) -> u64 {
+		key.0
+	}
+
+	#[cfg(not(feature = "non_bitcoin_chain_hash_routing"))]
+	#[inline]
+	fn get_short_channel_id(key: &u64) -> u64 {
+		*key
+	}
+
+	#[cfg(feature = "non_bitcoin_chain_hash_routing")]
+	const KEY_LEN: usize = 8 + 32;
+	#[cfg(feature = "non_bitcoin_chain_hash_routing")]
+	fn write_key(key: &(u64, Sha256dHash), res: &mut Vec<u8>) {
+		res.extend_from_slice(&byte_utils::be64_to_array(key.0));
+		res.extend_from_slice(&key.1[..]);
+	}
+	#[cfg(feature = "non_bitcoin_chain_hash_routing")]
+	fn read_key(v: &[u8]) -> (u64, Sha256dHash) {
+		(byte_utils::slice_to_be64(&v[0..8]), Sha256dHash::from(&v[8..40]))
+	}
+
+	#[cfg(not(feature = "non_bitcoin_chain_hash_routing"))]
+	const KEY_LEN: usize = 8;
+	#[cfg(not(feature = "non_bitcoin_chain_hash_routing"))]
+	fn write_key(key: &u64, res: &mut Vec<u8>) {
+		res.extend_from_slice(&byte_utils::be64_to_array(*key));
+	}
+	#[cfg(not(feature = "non_bitcoin_chain_hash_routing"))]
+	fn read_key(v: &[u8]) -> u64 {
+		byte_utils::slice_to_be64(&v[0..8])
+	}
+}
+
+/// The version byte written at the start of a serialized network graph
+const SERIALIZATION_VERSION: u8 = 1;
+
+/// Channels (and directions of channels) which haven't had a channel_update for this long are
+/// considered stale and pruned from the network graph, as suggested by BOLT 7.
+const STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS: u32 = 60 * 60 * 24 * 14;
+
+/// The most short_channel_ids we put in a single reply_channel_range or query_short_channel_ids,
+/// keeping them well within the maximum message size.
+pub const MAX_SHORT_CHANNEL_IDS_PER_MESSAGE: usize = 8000;
+
+/// The most hops a route may have by protocol rules (limited by the size of the onion packet)
+pub const MAX_ROUTE_HOPS: usize = 20;
+
+/// The most hops we walk away from the target when picking a shadow route CLTV padding
+const MAX_SHADOW_ROUTE_HOPS: usize = 3;
+
+/// How long we avoid sending as much over a channel as a probe failed to send over it, as its
+/// balance may well have shifted since.
+const PROBE_FAILURE_EXPIRY_SECS: u64 = 60 * 60;
+
+/// Constraints on the routes returned by Router::get_route_with_params.
+#[derive(Clone)]
+pub struct RouteParameters {
+	/// The most total CLTV expiry delta the route may require, including the final_cltv.
+	pub max_total_cltv_expiry_delta: u32,
+	/// The most hops the route may have, including the final hop to the target. Values greater
+	/// than MAX_ROUTE_HOPS are treated as MAX_ROUTE_HOPS.
+	pub max_hops: usize,
+	/// The most total fees the route may require, in msat.
+	pub max_total_fee_msat: u64,
+	/// Nodes the route must not pass through. The target itself may not be excluded.
+	pub excluded_nodes: HashSet<PublicKey>,
+	/// Channels (by short_channel_id) the route must not use, including any in last_hops.
+	pub excluded_channels: HashSet<u64>,
+	/// If set, the final hop's CLTV expiry delta is padded with the deltas along a short random
+	/// walk away from the target in the network graph (a "shadow route"), so that nodes along the
+	/// route can't tell how close they are to the recipient from the CLTV expiry alone. The
+	/// padding is limited to keep the route within max_total_cltv_expiry_delta.
+	pub use_shadow_route: bool,
+}
+
+impl RouteParameters {
+	/// Creates a new RouteParameters with no constraints beyond the protocol's MAX_ROUTE_HOPS.
+	pub fn new() -> RouteParameters {
+		RouteParameters {
+			max_total_cltv_expiry_delta: u32::max_value(),
+			max_hops: MAX_ROUTE_HOPS,
+			max_total_fee_msat: u64::max_value(),
+			excluded_nodes: HashSet::new(),
+			excluded_channels: HashSet::new(),
+			use_shadow_route: false,
+		}
+	}
 }
 
 /// A channel descriptor which provides a last-hop route to get_route
+#[derive(Clone)]
 pub struct RouteHint {
 	pub src_node_id: PublicKey,
 	pub short_channel_id: u64,
@@ -99,11 +241,95 @@ pub struct RouteHint {
 	pub htlc_minimum_msat: u64,
 }
 
+/// The policy for forwarding over one direction of a channel in the network graph, as last
+/// announced in a channel_update.
+#[derive(Clone, PartialEq)]
+pub struct DirectionalChannelDetails {
+	/// The node which forwards HTLCs in this direction
+	pub src_node_id: PublicKey,
+	/// The timestamp of the last channel_update we got for this direction, or 0 if none
+	pub last_update: u32,
+	pub enabled: bool,
+	pub cltv_expiry_delta: u16,
+	pub htlc_minimum_msat: u64,
+	pub fee_base_msat: u32,
+	pub fee_proportional_millionths: u32,
+}
+
+/// A copy of what we know about a channel in the network graph, see Router::get_channel.
+#[derive(Clone, PartialEq)]
+pub struct NetworkChannelDetails {
+	pub short_channel_id: u64,
+	pub features: GlobalFeatures,
+	/// The direction from node_id_1 in the channel_announcement
+	pub one_to_two: DirectionalChannelDetails,
+	/// The direction from node_id_2 in the channel_announcement
+	pub two_to_one: DirectionalChannelDetails,
+	/// The value of the funding output, if we were able to look it up on chain
+	pub capacity_sats: Option<u64>,
+}
+
+/// A copy of what we know about a node in the network graph, see Router::get_nodes.
+#[derive(Clone, PartialEq)]
+pub struct NetworkNodeDetails {
+	pub node_id: PublicKey,
+	/// The short_channel_ids of the node's channels which we know of
+	pub short_channel_ids: Vec<u64>,
+	/// The timestamp of the node's last node_announcement, or 0 if we haven't received one, in
+	/// which case none of the announcement data below is set
+	pub last_update: u32,
+	pub features: GlobalFeatures,
+	pub rgb: [u8; 3],
+	pub alias: [u8; 32],
+	pub addresses: Vec<NetAddress>,
+}
+
+/// Summary statistics about the network graph, see Router::get_network_stats.
+#[derive(Clone, PartialEq)]
+pub struct NetworkStats {
+	pub node_count: usize,
+	pub channel_count: usize,
+	/// The sum of the capacities of all channels we were able to look up on chain
+	pub total_capacity_sats: u64,
+	/// The number of channels we weren't able to look up on chain, which aren't counted in
+	/// total_capacity_sats
+	pub channels_without_capacity: usize,
+}
+
 /// Tracks a view of the network, receiving updates from peers and generating Routes to
 /// payment destinations.
 pub struct Router {
 	secp_ctx: Secp256k1,
 	network_map: RwLock<NetworkMap>,
+	chain_monitor: Arc<ChainWatchInterface>,
+	entropy_source: Arc<EntropySource>,
+}
+
+/// Removes a channel from the network graph, along with any nodes (other than ourselves) which no
+/// longer have any channels. Evaluates to the removed channel and nodes, if the channel was known.
+macro_rules! remove_channel {
+	( $network: expr, $chan_key: expr ) => {
+		{
+			let network: &mut NetworkMap = &mut *$network;
+			let chan_key = $chan_key;
+			if let Some(chan) = network.channels.remove(&chan_key) {
+				let mut removed_nodes = Vec::new();
+				for node_id in [chan.one_to_two.src_node_id, chan.two_to_one.src_node_id].iter() {
+					let remove_node = match network.nodes.get_mut(node_id) {
+						Some(node) => {
+							node.channels.retain(|key| *key != chan_key);
+							node.channels.is_empty() && *node_id != network.our_node_id
+						},
+						None => false,
+					};
+					if remove_node {
+						removed_nodes.push((*node_id, network.nodes.remove(node_id).unwrap()));
+					}
+				}
+				Some((chan, removed_nodes))
+			} else { None }
+		}
+	};
 }
 
 macro_rules! secp_verify_sig {
@@ -116,7 +342,7 @@ macro_rules! secp_verify_sig {
 }
 
 impl RoutingMessageHandler for Router {
-	fn handle_node_announcement(&self, msg: &msgs::NodeAnnouncement) -> Result<(), HandleError> {
+	fn handle_node_announcement(&self, msg: &msgs::NodeAnnouncement) -> Result<bool, HandleError> {
 		let msg_hash = Message::from_slice(&Sha256dHash::from_data(&msg.contents.encode()[..])[..]).unwrap();
 		secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.signature, &msg.contents.node_id);
 
@@ -128,12 +354,14 @@ impl RoutingMessageHandler for Router {
 					return Err(HandleError{err: "Update older than last processed update", msg: Some(ErrorAction::IgnoreError)});
 				}
 
+				let should_relay = !msg.contents.features.supports_unknown_bits();
 				node.features = msg.contents.features.clone();
 				node.last_update = msg.contents.timestamp;
 				node.rgb = msg.contents.rgb;
 				node.alias = msg.contents.alias;
 				node.addresses = msg.contents.addresses.clone();
-				Ok(())
+				node.announcement_message = if should_relay { Some(msg.clone()) } else { None };
+				Ok(should_relay)
 			}
 		}
 	}
@@ -145,20 +373,60 @@ impl RoutingMessageHandler for Router {
 		secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.bitcoin_signature_1, &msg.contents.bitcoin_key_1);
 		secp_verify_sig!(self.secp_ctx, &msg_hash, &msg.bitcoin_signature_2, &msg.contents.bitcoin_key_2);
 
-		//TODO: Call blockchain thing to ask if the short_channel_id is valid
-		//TODO: Only allow bitcoin chain_hash
-
 		if msg.contents.features.requires_unknown_bits() {
 			return Err(HandleError{err: "Channel announcement required unknown feature flags", msg: None});
 		}
 
+		let funding_utxo = match self.chain_monitor.get_chain_utxo(msg.contents.chain_hash, msg.contents.short_channel_id) {
+			Ok((txid, script_pubkey, value)) => {
+				let key_1 = msg.contents.bitcoin_key_1.serialize();
+				let key_2 = msg.contents.bitcoin_key_2.serialize();
+				let builder = Builder::new().push_opcode(opcodes::All::OP_PUSHNUM_2);
+				let expected_script = if key_1[..] < key_2[..] {
+					builder.push_slice(&key_1).push_slice(&key_2)
+				} else {
+					builder.push_slice(&key_2).push_slice(&key_1)
+				}.push_opcode(opcodes::All::OP_PUSHNUM_2).push_opcode(opcodes::All::OP_CHECKMULTISIG).into_script().to_v0_p2wsh();
+				if script_pubkey != expected_script {
+					return Err(HandleError{err: "Channel announcement keys didn't match on-chain script", msg: Some(ErrorAction::IgnoreError)});
+				}
+				Some((OutPoint::new(txid, msg.contents.short_channel_id as u16), value))
+			},
+			Err(ChainError::NotSupported) => {
+				// Tentatively accept, potentially exposing us to DoS attacks
+				None
+			},
+			Err(ChainError::NotWatched) => {
+				return Err(HandleError{err: "Channel announced on an unknown chain", msg: Some(ErrorAction::IgnoreError)});
+			},
+			Err(ChainError::UnknownTx) => {
+				return Err(HandleError{err: "Channel announced without corresponding UTXO entry", msg: Some(ErrorAction::IgnoreError)});
+			},
+		};
+
+		let should_relay = !msg.contents.features.supports_unknown_bits();
+
 		let mut network = self.network_map.write().unwrap();
+		let chan_key = NetworkMap::get_key(msg.contents.short_channel_id, msg.contents.chain_hash);
 
-		match network.channels.entry(NetworkMap::get_key(msg.contents.short_channel_id, msg.contents.chain_hash)) {
+		let replace_unchecked = funding_utxo.is_some() && match network.channels.get(&chan_key) {
+			Some(chan) => chan.capacity_sats.is_none(),
+			None => false,
+		};
+		if replace_unchecked {
+			// We've checked the on-chain output, so this announcement replaces the one we accepted
+			// without being able to check it
+			if let Some(old_chan) = network.channels.remove(&chan_key) {
+				for node_id in [old_chan.one_to_two.src_node_id, old_chan.two_to_one.src_node_id].iter() {
+					if let Some(node) = network.nodes.get_mut(node_id) {
+						node.channels.retain(|key| *key != chan_key);
+					}
+				}
+			}
+		}
+
+		match network.channels.entry(chan_key) {
 			Entry::Occupied(_) => {
-				//TODO: because asking the blockchain if short_channel_id is valid is only optional
-				//in the blockchain API, we need to handle it smartly here, though its unclear
-				//exactly how...
 				return Err(HandleError{err: "Already have knowledge of channel", msg: Some(ErrorAction::IgnoreError)})
 			},
 			Entry::Vacant(entry) => {
@@ -172,6 +440,7 @@ impl RoutingMessageHandler for Router {
 						htlc_minimum_msat: u64::max_value(),
 						fee_base_msat: u32::max_value(),
 						fee_proportional_millionths: u32::max_value(),
+						last_update_message: None,
 					},
 					two_to_one: DirectionalChannelInfo {
 						src_node_id: msg.contents.node_id_2.clone(),
@@ -181,7 +450,12 @@ impl RoutingMessageHandler for Router {
 						htlc_minimum_msat: u64::max_value(),
 						fee_base_msat: u32::max_value(),
 						fee_proportional_millionths: u32::max_value(),
-					}
+						last_update_message: None,
+					},
+					announcement_message: if should_relay { Some(msg.clone()) } else { None },
+					capacity_sats: funding_utxo.map(|(_, value)| value),
+					funding_txo: funding_utxo.map(|(txo, _)| txo),
+					announcement_received_time: 0,
 				});
 			}
 		};
@@ -202,6 +476,7 @@ impl RoutingMessageHandler for Router {
 							rgb: [0; 3],
 							alias: [0; 32],
 							addresses: Vec::new(),
+							announcement_message: None,
 						});
 					}
 				}
@@ -210,8 +485,13 @@ impl RoutingMessageHandler for Router {
 
 		add_channel_to_node!(msg.contents.node_id_1);
 		add_channel_to_node!(msg.contents.node_id_2);
+		mem::drop(network);
+
+		if let Some((funding_txo, _)) = funding_utxo {
+			self.chain_monitor.install_watch_outpoint((funding_txo.txid, funding_txo.index as u32));
+		}
 
-		Ok(!msg.contents.features.supports_unknown_bits())
+		Ok(should_relay)
 	}
 
 	fn handle_htlc_fail_channel_update(&self, update: &msgs::HTLCFailChannelUpdate) {
@@ -221,12 +501,12 @@ impl RoutingMessageHandler for Router {
 			},
 			&msgs::HTLCFailChannelUpdate::ChannelClosed { ref short_channel_id } => {
 				let mut network = self.network_map.write().unwrap();
-				network.channels.remove(short_channel_id);
+				let _ = remove_channel!(network, *short_channel_id);
 			},
 		}
 	}
 
-	fn handle_channel_update(&self, msg: &msgs::ChannelUpdate) -> Result<(), HandleError> {
+	fn handle_channel_update(&self, msg: &msgs::ChannelUpdate) -> Result<bool, HandleError> {
 		let mut network = self.network_map.write().unwrap();
 		let dest_node_id;
 		let chan_enabled = msg.contents.flags & (1 << 1) != (1 << 1);
@@ -247,6 +527,7 @@ impl RoutingMessageHandler for Router {
 						$target.htlc_minimum_msat = msg.contents.htlc_minimum_msat;
 						$target.fee_base_msat = msg.contents.fee_base_msat;
 						$target.fee_proportional_millionths = msg.contents.fee_proportional_millionths;
+						$target.last_update_message = Some(msg.clone());
 					}
 				}
 
@@ -292,8 +573,196 @@ impl RoutingMessageHandler for Router {
 			mut_node.lowest_inbound_channel_fee_proportional_millionths = lowest_inbound_channel_fee_proportional_millionths;
 		}
 
+		Ok(true)
+	}
+
+	fn get_next_channel_announcements(&self, starting_point: u64, batch_amount: u8) -> Vec<(msgs::ChannelAnnouncement, Option<msgs::ChannelUpdate>, Option<msgs::ChannelUpdate>)> {
+		let network = self.network_map.read().unwrap();
+		let mut result = Vec::with_capacity(batch_amount as usize);
+		for (_, chan) in network.channels.range(NetworkMap::get_key(starting_point, Default::default())..) {
+			if result.len() >= batch_amount as usize {
+				break;
+			}
+			if let Some(ref announcement) = chan.announcement_message {
+				result.push((announcement.clone(), chan.one_to_two.last_update_message.clone(), chan.two_to_one.last_update_message.clone()));
+			}
+		}
+		result
+	}
+
+	fn handle_query_short_channel_ids(&self, msg: &msgs::QueryShortChannelIds) -> Result<(Vec<(msgs::ChannelAnnouncement, Option<msgs::ChannelUpdate>, Option<msgs::ChannelUpdate>)>, Vec<msgs::NodeAnnouncement>), HandleError> {
+		let network = self.network_map.read().unwrap();
+		let mut channels = Vec::new();
+		let mut node_ids = BTreeSet::new();
+		for short_channel_id in msg.short_channel_ids.iter() {
+			if let Some(chan) = network.channels.get(&NetworkMap::get_key(*short_channel_id, msg.chain_hash)) {
+				if let Some(ref announcement) = chan.announcement_message {
+					if announcement.contents.chain_hash != msg.chain_hash {
+						continue;
+					}
+					node_ids.insert(announcement.contents.node_id_1);
+					node_ids.insert(announcement.contents.node_id_2);
+					channels.push((announcement.clone(), chan.one_to_two.last_update_message.clone(), chan.two_to_one.last_update_message.clone()));
+				}
+			}
+		}
+		let mut nodes = Vec::new();
+		for node_id in node_ids.iter() {
+			if let Some(node) = network.nodes.get(node_id) {
+				if let Some(ref announcement) = node.announcement_message {
+					nodes.push(announcement.clone());
+				}
+			}
+		}
+		Ok((channels, nodes))
+	}
+
+	fn handle_query_channel_range(&self, msg: &msgs::QueryChannelRange) -> Result<Vec<msgs::ReplyChannelRange>, HandleError> {
+		// Block heights only take up the top 3 bytes of a short_channel_id
+		let first_block = cmp::min(msg.first_blocknum as u64, 0xffffff);
+		let end_block = msg.first_blocknum as u64 + msg.number_of_blocks as u64;
+		let start_key = Bound::Included(NetworkMap::get_key(first_block << 40, Default::default()));
+		let end_key = if end_block > 0xffffff { Bound::Unbounded } else { Bound::Excluded(NetworkMap::get_key(end_block << 40, Default::default())) };
+
+		let network = self.network_map.read().unwrap();
+		let mut short_channel_ids = Vec::new();
+		for (_, chan) in network.channels.range((start_key, end_key)) {
+			if let Some(ref announcement) = chan.announcement_message {
+				if announcement.contents.chain_hash == msg.chain_hash {
+					short_channel_ids.push(announcement.contents.short_channel_id);
+				}
+			}
+		}
+
+		let make_reply = |batch: &[u64]| msgs::ReplyChannelRange {
+			chain_hash: msg.chain_hash,
+			first_blocknum: msg.first_blocknum,
+			number_of_blocks: msg.number_of_blocks,
+			full_information: true,
+			short_channel_ids: batch.to_vec(),
+		};
+		let mut replies: Vec<msgs::ReplyChannelRange> = short_channel_ids.chunks(MAX_SHORT_CHANNEL_IDS_PER_MESSAGE).map(&make_reply).collect();
+		if replies.is_empty() {
+			replies.push(make_reply(&[]));
+		}
+		Ok(replies)
+	}
+
+	fn handle_reply_channel_range(&self, msg: &msgs::ReplyChannelRange) -> Result<Vec<u64>, HandleError> {
+		let network = self.network_map.read().unwrap();
+		Ok(msg.short_channel_ids.iter().filter(|short_channel_id| {
+			!network.channels.contains_key(&NetworkMap::get_key(**short_channel_id, msg.chain_hash))
+		}).cloned().collect())
+	}
+
+	fn handle_reply_short_channel_ids_end(&self, _msg: &msgs::ReplyShortChannelIdsEnd) -> Result<(), HandleError> {
 		Ok(())
 	}
+
+	fn get_next_node_announcements(&self, starting_point: Option<&PublicKey>, batch_amount: u8) -> Vec<msgs::NodeAnnouncement> {
+		let network = self.network_map.read().unwrap();
+		let mut result = Vec::with_capacity(batch_amount as usize);
+		let range = match starting_point {
+			Some(node_id) => network.nodes.range((Bound::Excluded(node_id), Bound::Unbounded)),
+			None => network.nodes.range::<PublicKey, _>(..),
+		};
+		for (_, node) in range {
+			if result.len() >= batch_amount as usize {
+				break;
+			}
+			if let Some(ref announcement) = node.announcement_message {
+				result.push(announcement.clone());
+			}
+		}
+		result
+	}
+}
+
+impl ChainListener for Router {
+	fn block_connected(&self, header: &BlockHeader, height: u32, txn_matched: &[&Transaction], _indexes_of_txn_matched: &[u32]) {
+		let mut network = self.network_map.write().unwrap();
+		network.recently_spent_channels.retain(|spent| spent.height + ANTI_REORG_DELAY > height);
+
+		let mut spent_txos = HashSet::new();
+		for tx in txn_matched {
+			for input in tx.input.iter() {
+				if input.prev_index <= u16::max_value() as u32 {
+					spent_txos.insert(OutPoint::new(input.prev_hash, input.prev_index as u16));
+				}
+			}
+		}
+
+		// Channels are closed once their funding output is spent, and we use the block time to
+		// prune channels we haven't heard from in a while. Our own channels are never pruned as
+		// stale, as we only learn of them closing from the chain (and our peers may simply have
+		// nothing to update).
+		let stale_cutoff = header.time.saturating_sub(STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS);
+		let our_node_id = network.our_node_id;
+		let mut spent_channels = Vec::new();
+		let mut channels_to_remove = Vec::new();
+		for (chan_key, chan) in network.channels.iter_mut() {
+			if let Some(ref funding_txo) = chan.funding_txo {
+				if spent_txos.contains(funding_txo) {
+					spent_channels.push(chan_key.clone());
+					continue;
+				}
+			}
+			if chan.one_to_two.src_node_id == our_node_id || chan.two_to_one.src_node_id == our_node_id {
+				continue;
+			}
+			if chan.announcement_received_time == 0 {
+				chan.announcement_received_time = header.time;
+			}
+			for directional_info in [&mut chan.one_to_two, &mut chan.two_to_one].iter_mut() {
+				if directional_info.last_update < stale_cutoff {
+					directional_info.enabled = false;
+					directional_info.last_update_message = None;
+				}
+			}
+			if cmp::max(chan.announcement_received_time, cmp::max(chan.one_to_two.last_update, chan.two_to_one.last_update)) < stale_cutoff {
+				channels_to_remove.push(chan_key.clone());
+			}
+		}
+		for chan_key in channels_to_remove {
+			let _ = remove_channel!(network, chan_key);
+		}
+		if !spent_channels.is_empty() {
+			let mut spent = SpentChannels { block_hash: header.bitcoin_hash(), height, channels: Vec::new(), nodes: Vec::new() };
+			for chan_key in spent_channels {
+				if let Some((chan, mut removed_nodes)) = remove_channel!(network, chan_key) {
+					spent.channels.push((chan_key, chan));
+					spent.nodes.append(&mut removed_nodes);
+				}
+			}
+			network.recently_spent_channels.push(spent);
+		}
+	}
+
+	fn block_disconnected(&self, header: &BlockHeader) {
+		// Restore the channels whose funding output was spent in the disconnected block. Channels
+		// we pruned as stale will be re-announced by their peers if they're still open.
+		let mut network = self.network_map.write().unwrap();
+		let block_hash = header.bitcoin_hash();
+		let spent = match network.recently_spent_channels.iter().position(|spent| spent.block_hash == block_hash) {
+			Some(idx) => network.recently_spent_channels.remove(idx),
+			None => return,
+		};
+		for (node_id, node) in spent.nodes {
+			network.nodes.entry(node_id).or_insert(node);
+		}
+		for (chan_key, chan) in spent.channels {
+			if network.channels.contains_key(&chan_key) {
+				// The channel has since been re-announced
+				continue;
+			}
+			for node_id in [chan.one_to_two.src_node_id, chan.two_to_one.src_node_id].iter() {
+				if let Some(node) = network.nodes.get_mut(node_id) {
+					node.channels.push(chan_key.clone());
+				}
+			}
+			network.channels.insert(chan_key, chan);
+		}
+	}
 }
 
 #[derive(Eq, PartialEq)]
@@ -316,8 +785,11 @@ impl cmp::PartialOrd for RouteGraphNode {
 }
 
 impl Router {
-	pub fn new(our_pubkey: PublicKey) -> Router {
-		let mut nodes = HashMap::new();
+	/// Creates a new Router, registering it with the given chain_monitor so that it can remove
+	/// channels from the network graph once their funding outputs are spent.
+	/// entropy_source is used to randomize shadow routes (see RouteParameters::use_shadow_route).
+	pub fn new(our_pubkey: PublicKey, chain_monitor: Arc<ChainWatchInterface>, entropy_source: Arc<EntropySource>) -> Arc<Router> {
+		let mut nodes = BTreeMap::new();
 		nodes.insert(our_pubkey.clone(), NodeInfo {
 			channels: Vec::new(),
 			lowest_inbound_channel_fee_base_msat: u32::max_value(),
@@ -327,14 +799,374 @@ impl Router {
 			rgb: [0; 3],
 			alias: [0; 32],
 			addresses: Vec::new(),
+			announcement_message: None,
 		});
-		Router {
+		let res = Arc::new(Router {
 			secp_ctx: Secp256k1::new(),
 			network_map: RwLock::new(NetworkMap {
-				channels: HashMap::new(),
+				channels: BTreeMap::new(),
 				our_node_id: our_pubkey,
 				nodes: nodes,
+				recently_spent_channels: Vec::new(),
+				probe_failures: HashMap::new(),
 			}),
+			chain_monitor: chain_monitor,
+			entropy_source: entropy_source,
+		});
+		let weak_res = Arc::downgrade(&res);
+		res.chain_monitor.register_listener(weak_res);
+		res
+	}
+
+	/// Serializes the network graph so that it can be reloaded with from_serialized on restart,
+	/// instead of waiting for gossip to slowly rebuild it.
+	/// network should be the network whose gossip we've been receiving.
+	pub fn serialize(&self, network: Network) -> Vec<u8> {
+		let network_map = self.network_map.read().unwrap();
+		let mut res = Vec::new();
+		res.push(SERIALIZATION_VERSION);
+		res.extend_from_slice(&genesis_block(network).header.bitcoin_hash()[..]);
+		res.extend_from_slice(&network_map.our_node_id.serialize());
+
+		macro_rules! write_msg_with_len {
+			( $msg: expr ) => {
+				let encoded = $msg.encode();
+				res.extend_from_slice(&byte_utils::be16_to_array(encoded.len() as u16));
+				res.extend_from_slice(&encoded[..]);
+			}
+		}
+		macro_rules! write_option {
+			( $option: expr, $value: ident, $write: block ) => {
+				match &$option {
+					&Some(ref $value) => {
+						res.push(1);
+						$write
+					},
+					&None => res.push(0),
+				}
+			}
+		}
+		macro_rules! write_directional_info {
+			( $directional_info: expr ) => {
+				res.extend_from_slice(&$directional_info.src_node_id.serialize());
+				res.extend_from_slice(&byte_utils::be32_to_array($directional_info.last_update));
+				res.push(if $directional_info.enabled { 1 } else { 0 });
+				res.extend_from_slice(&byte_utils::be16_to_array($directional_info.cltv_expiry_delta));
+				res.extend_from_slice(&byte_utils::be64_to_array($directional_info.htlc_minimum_msat));
+				res.extend_from_slice(&byte_utils::be32_to_array($directional_info.fee_base_msat));
+				res.extend_from_slice(&byte_utils::be32_to_array($directional_info.fee_proportional_millionths));
+				write_option!($directional_info.last_update_message, msg, { write_msg_with_len!(msg); });
+			}
+		}
+
+		res.extend_from_slice(&byte_utils::be64_to_array(network_map.channels.len() as u64));
+		for (chan_key, chan) in network_map.channels.iter() {
+			NetworkMap::write_key(chan_key, &mut res);
+			res.extend_from_slice(&chan.features.encode()[..]);
+			write_directional_info!(chan.one_to_two);
+			write_directional_info!(chan.two_to_one);
+			write_option!(chan.announcement_message, msg, { write_msg_with_len!(msg); });
+			write_option!(chan.capacity_sats, capacity_sats, { res.extend_from_slice(&byte_utils::be64_to_array(*capacity_sats)); });
+			write_option!(chan.funding_txo, funding_txo, {
+				res.extend_from_slice(&funding_txo.txid[..]);
+				res.extend_from_slice(&byte_utils::be16_to_array(funding_txo.index));
+			});
+			res.extend_from_slice(&byte_utils::be32_to_array(chan.announcement_received_time));
+		}
+
+		res.extend_from_slice(&byte_utils::be64_to_array(network_map.nodes.len() as u64));
+		for (node_id, node) in network_map.nodes.iter() {
+			res.extend_from_slice(&byte_utils::be64_to_array(node.channels.len() as u64));
+			for chan_key in node.channels.iter() {
+				NetworkMap::write_key(chan_key, &mut res);
+			}
+			res.extend_from_slice(&byte_utils::be32_to_array(node.lowest_inbound_channel_fee_base_msat));
+			res.extend_from_slice(&byte_utils::be32_to_array(node.lowest_inbound_channel_fee_proportional_millionths));
+			// The rest of the node's info is exactly what we'd put in a node_announcement
+			write_msg_with_len!(msgs::UnsignedNodeAnnouncement {
+				features: node.features.clone(),
+				timestamp: node.last_update,
+				node_id: *node_id,
+				rgb: node.rgb,
+				alias: node.alias,
+				addresses: node.addresses.clone(),
+			});
+			write_option!(node.announcement_message, msg, { write_msg_with_len!(msg); });
+		}
+
+		let checksum = Sha256dHash::from_data(&res[..]);
+		res.extend_from_slice(&checksum[..]);
+		res
+	}
+
+	/// Loads a network graph previously written by serialize, registering the new Router with
+	/// the given chain_monitor and using the given entropy_source as Router::new does.
+	/// Fails with DecodeError::InvalidValue if the data is corrupt or if it was written for (or
+	/// holds channel_announcements or channel_updates for) a different network.
+	pub fn from_serialized(data: &[u8], network: Network, chain_monitor: Arc<ChainWatchInterface>, entropy_source: Arc<EntropySource>) -> Result<Arc<Router>, DecodeError> {
+		if data.len() < 32 {
+			return Err(DecodeError::WrongLength);
+		}
+		let (data, checksum) = data.split_at(data.len() - 32);
+		if Sha256dHash::from_data(data)[..] != checksum[..] {
+			return Err(DecodeError::InvalidValue);
+		}
+
+		let secp_ctx = Secp256k1::without_caps();
+		let mut read_pos = 0;
+		macro_rules! read_bytes {
+			( $len: expr ) => {
+				{
+					let len = $len;
+					if data.len() < read_pos + len {
+						return Err(DecodeError::WrongLength);
+					}
+					read_pos += len;
+					&data[read_pos - len..read_pos]
+				}
+			}
+		}
+		macro_rules! read_pubkey {
+			() => {
+				match PublicKey::from_slice(&secp_ctx, read_bytes!(33)) {
+					Ok(key) => key,
+					Err(_) => return Err(DecodeError::BadPublicKey),
+				}
+			}
+		}
+		macro_rules! read_msg_with_len {
+			( $MsgType: path ) => {
+				{
+					let len = byte_utils::slice_to_be16(read_bytes!(2)) as usize;
+					<$MsgType>::decode(read_bytes!(len))?
+				}
+			}
+		}
+		macro_rules! read_option {
+			( $read: expr ) => {
+				match read_bytes!(1)[0] {
+					0 => None,
+					1 => Some($read),
+					_ => return Err(DecodeError::InvalidValue),
+				}
+			}
+		}
+		macro_rules! read_features {
+			() => {
+				{
+					let features = GlobalFeatures::decode(&data[read_pos..])?;
+					read_pos += features.encoded_len();
+					features
+				}
+			}
+		}
+		macro_rules! read_directional_info {
+			() => {
+				DirectionalChannelInfo {
+					src_node_id: read_pubkey!(),
+					last_update: byte_utils::slice_to_be32(read_bytes!(4)),
+					enabled: match read_bytes!(1)[0] {
+						0 => false,
+						1 => true,
+						_ => return Err(DecodeError::InvalidValue),
+					},
+					cltv_expiry_delta: byte_utils::slice_to_be16(read_bytes!(2)),
+					htlc_minimum_msat: byte_utils::slice_to_be64(read_bytes!(8)),
+					fee_base_msat: byte_utils::slice_to_be32(read_bytes!(4)),
+					fee_proportional_millionths: byte_utils::slice_to_be32(read_bytes!(4)),
+					last_update_message: read_option!(read_msg_with_len!(msgs::ChannelUpdate)),
+				}
+			}
+		}
+
+		if read_bytes!(1)[0] != SERIALIZATION_VERSION {
+			return Err(DecodeError::InvalidValue);
+		}
+		let chain_hash = genesis_block(network).header.bitcoin_hash();
+		if read_bytes!(32) != &chain_hash[..] {
+			return Err(DecodeError::InvalidValue);
+		}
+		let our_node_id = read_pubkey!();
+
+		let mut channels = BTreeMap::new();
+		let channel_count = byte_utils::slice_to_be64(read_bytes!(8));
+		for _ in 0..channel_count {
+			let chan_key = NetworkMap::read_key(read_bytes!(NetworkMap::KEY_LEN));
+			let chan = ChannelInfo {
+				features: read_features!(),
+				one_to_two: read_directional_info!(),
+				two_to_one: read_directional_info!(),
+				announcement_message: read_option!(read_msg_with_len!(msgs::ChannelAnnouncement)),
+				capacity_sats: read_option!(byte_utils::slice_to_be64(read_bytes!(8))),
+				funding_txo: read_option!(OutPoint::new(Sha256dHash::from(read_bytes!(32)), byte_utils::slice_to_be16(read_bytes!(2)))),
+				announcement_received_time: byte_utils::slice_to_be32(read_bytes!(4)),
+			};
+			// Don't trust the header alone, the messages we store must all be for the same chain
+			if let Some(ref msg) = chan.announcement_message {
+				if msg.contents.chain_hash != chain_hash {
+					return Err(DecodeError::InvalidValue);
+				}
+			}
+			for directional_info in [&chan.one_to_two, &chan.two_to_one].iter() {
+				if let Some(ref msg) = directional_info.last_update_message {
+					if msg.contents.chain_hash != chain_hash {
+						return Err(DecodeError::InvalidValue);
+					}
+				}
+			}
+			if channels.insert(chan_key, chan).is_some() {
+				return Err(DecodeError::InvalidValue);
+			}
+		}
+
+		let mut nodes = BTreeMap::new();
+		let node_count = byte_utils::slice_to_be64(read_bytes!(8));
+		for _ in 0..node_count {
+			let chan_key_count = byte_utils::slice_to_be64(read_bytes!(8));
+			let mut node_channels = Vec::new();
+			for _ in 0..chan_key_count {
+				node_channels.push(NetworkMap::read_key(read_bytes!(NetworkMap::KEY_LEN)));
+			}
+			let lowest_inbound_channel_fee_base_msat = byte_utils::slice_to_be32(read_bytes!(4));
+			let lowest_inbound_channel_fee_proportional_millionths = byte_utils::slice_to_be32(read_bytes!(4));
+			let info = read_msg_with_len!(msgs::UnsignedNodeAnnouncement);
+			let node = NodeInfo {
+				channels: node_channels,
+				lowest_inbound_channel_fee_base_msat,
+				lowest_inbound_channel_fee_proportional_millionths,
+				features: info.features,
+				last_update: info.timestamp,
+				rgb: info.rgb,
+				alias: info.alias,
+				addresses: info.addresses,
+				announcement_message: read_option!(read_msg_with_len!(msgs::NodeAnnouncement)),
+			};
+			if nodes.insert(info.node_id, node).is_some() {
+				return Err(DecodeError::InvalidValue);
+			}
+		}
+		if read_pos != data.len() {
+			return Err(DecodeError::WrongLength);
+		}
+
+		// Check that the channels and nodes all refer to each other, as get_route assumes
+		if !nodes.contains_key(&our_node_id) {
+			return Err(DecodeError::InvalidValue);
+		}
+		for (chan_key, chan) in channels.iter() {
+			for node_id in [chan.one_to_two.src_node_id, chan.two_to_one.src_node_id].iter() {
+				match nodes.get(node_id) {
+					Some(node) => if !node.channels.contains(chan_key) { return Err(DecodeError::InvalidValue); },
+					None => return Err(DecodeError::InvalidValue),
+				}
+			}
+		}
+		for (node_id, node) in nodes.iter() {
+			for chan_key in node.channels.iter() {
+				match channels.get(chan_key) {
+					Some(chan) => if chan.one_to_two.src_node_id != *node_id && chan.two_to_one.src_node_id != *node_id { return Err(DecodeError::InvalidValue); },
+					None => return Err(DecodeError::InvalidValue),
+				}
+			}
+		}
+
+		for chan in channels.values() {
+			if let Some(funding_txo) = chan.funding_txo {
+				chain_monitor.install_watch_outpoint((funding_txo.txid, funding_txo.index as u32));
+			}
+		}
+		let res = Router::new(our_node_id, chain_monitor, entropy_source);
+		*res.network_map.write().unwrap() = NetworkMap {
+			channels: channels,
+			our_node_id: our_node_id,
+			nodes: nodes,
+			recently_spent_channels: Vec::new(),
+			probe_failures: HashMap::new(),
+		};
+		Ok(res)
+	}
+
+	fn get_channel_details(short_channel_id: u64, chan: &ChannelInfo) -> NetworkChannelDetails {
+		macro_rules! directional_details {
+			( $directional_info: expr ) => {
+				DirectionalChannelDetails {
+					src_node_id: $directional_info.src_node_id,
+					last_update: $directional_info.last_update,
+					enabled: $directional_info.enabled,
+					cltv_expiry_delta: $directional_info.cltv_expiry_delta,
+					htlc_minimum_msat: $directional_info.htlc_minimum_msat,
+					fee_base_msat: $directional_info.fee_base_msat,
+					fee_proportional_millionths: $directional_info.fee_proportional_millionths,
+				}
+			}
+		}
+		NetworkChannelDetails {
+			short_channel_id: short_channel_id,
+			features: chan.features.clone(),
+			one_to_two: directional_details!(chan.one_to_two),
+			two_to_one: directional_details!(chan.two_to_one),
+			capacity_sats: chan.capacity_sats,
+		}
+	}
+
+	/// Gets what we know about the channel with the given short_channel_id, if it's in our
+	/// network graph.
+	pub fn get_channel(&self, short_channel_id: u64) -> Option<NetworkChannelDetails> {
+		let network = self.network_map.read().unwrap();
+		let chan_key = NetworkMap::get_key(short_channel_id, Default::default());
+		network.channels.get(&chan_key).map(|chan| Router::get_channel_details(short_channel_id, chan))
+	}
+
+	/// Gets what we know about each of the given node's channels. Empty if we don't know of the
+	/// node at all.
+	pub fn get_node_channels(&self, node_id: &PublicKey) -> Vec<NetworkChannelDetails> {
+		let network = self.network_map.read().unwrap();
+		match network.nodes.get(node_id) {
+			Some(node) => node.channels.iter().filter_map(|chan_key| {
+				network.channels.get(chan_key).map(|chan| Router::get_channel_details(NetworkMap::get_short_channel_id(chan_key), chan))
+			}).collect(),
+			None => Vec::new(),
+		}
+	}
+
+	/// Gets up to batch_amount nodes from our network graph, in node_id order, starting after the
+	/// given node_id (or from the first node if starting_point is None). Iterate over the whole
+	/// graph by passing the last node_id returned as the next starting_point until an empty Vec
+	/// is returned, which avoids holding the graph lock (and blocking gossip processing) for
+	/// long.
+	pub fn get_nodes(&self, starting_point: Option<&PublicKey>, batch_amount: usize) -> Vec<NetworkNodeDetails> {
+		let network = self.network_map.read().unwrap();
+		let range = match starting_point {
+			Some(node_id) => network.nodes.range((Bound::Excluded(node_id), Bound::Unbounded)),
+			None => network.nodes.range::<PublicKey, _>(..),
+		};
+		range.take(batch_amount).map(|(node_id, node)| NetworkNodeDetails {
+			node_id: *node_id,
+			short_channel_ids: node.channels.iter().map(|chan_key| NetworkMap::get_short_channel_id(chan_key)).collect(),
+			last_update: node.last_update,
+			features: node.features.clone(),
+			rgb: node.rgb,
+			alias: node.alias,
+			addresses: node.addresses.clone(),
+		}).collect()
+	}
+
+	/// Gets summary statistics about our network graph
+	pub fn get_network_stats(&self) -> NetworkStats {
+		let network = self.network_map.read().unwrap();
+		let mut total_capacity_sats = 0;
+		let mut channels_without_capacity = 0;
+		for chan in network.channels.values() {
+			match chan.capacity_sats {
+				Some(capacity_sats) => total_capacity_sats += capacity_sats,
+				None => channels_without_capacity += 1,
+			}
+		}
+		NetworkStats {
+			node_count: network.nodes.len(),
+			channel_count: network.channels.len(),
+			total_capacity_sats: total_capacity_sats,
+			channels_without_capacity: channels_without_capacity,
 		}
 	}
 
@@ -348,6 +1180,50 @@ impl Router {
 		unimplemented!();
 	}
 
+	/// Learns from the result of a probe sent via ChannelManager::send_probe, given its
+	/// ProbeSuccessful or ProbeFailed event (other events are ignored).
+	/// Once a probe fails at a channel (for a reason other than the node failing), routes won't
+	/// send as much over that channel in the same direction for PROBE_FAILURE_EXPIRY_SECS, or
+	/// until a later probe gets at least that much over it.
+	/// PaymentTracker calls this for the events it reads, otherwise whatever handles
+	/// ChannelManager's events should.
+	pub fn handle_probe_result(&self, event: &Event) {
+		// The amount sent over each hop of the route: the fees of the hops after it plus the value
+		let hop_amounts_msat = |hops: &Vec<RouteHop>| -> Vec<u64> {
+			let mut amount_msat = 0;
+			let mut res: Vec<u64> = hops.iter().rev().map(|hop| { amount_msat += hop.fee_msat; amount_msat }).collect();
+			res.reverse();
+			res
+		};
+
+		let mut network = self.network_map.write().unwrap();
+		network.probe_failures.retain(|_, &mut (_, failed_at)| failed_at.elapsed() < Duration::from_secs(PROBE_FAILURE_EXPIRY_SECS));
+		match event {
+			&Event::ProbeSuccessful { ref route, .. } => {
+				for (hop, amount_msat) in route.hops.iter().zip(hop_amounts_msat(&route.hops)) {
+					let carried = match network.probe_failures.get(&(hop.short_channel_id, hop.pubkey)) {
+						Some(&(failed_msat, _)) => amount_msat >= failed_msat,
+						None => false,
+					};
+					if carried {
+						network.probe_failures.remove(&(hop.short_channel_id, hop.pubkey));
+					}
+				}
+			},
+			&Event::ProbeFailed { ref route, short_channel_id: Some(short_channel_id), ref failure_code, .. } => {
+				if failure_code.as_ref().map(|code| code.is_node_failure()).unwrap_or(false) {
+					return;
+				}
+				if let Some(idx) = route.hops.iter().position(|hop| hop.short_channel_id == short_channel_id) {
+					let amount_msat = hop_amounts_msat(&route.hops)[idx];
+					let failure = network.probe_failures.entry((short_channel_id, route.hops[idx].pubkey)).or_insert((amount_msat, Instant::now()));
+					*failure = (cmp::min(failure.0, amount_msat), Instant::now());
+				}
+			},
+			_ => {},
+		}
+	}
+
 	/// Gets a route from us to the given target node.
 	/// Extra routing hops between known nodes and the target will be used if they are included in
 	/// last_hops.
@@ -355,6 +1231,56 @@ impl Router {
 	/// equal), however the enabled/disabled bit on such channels as well as the htlc_minimum_msat
 	/// *is* checked as they may change based on the receiving node.
 	pub fn get_route(&self, target: &PublicKey, last_hops: &Vec<RouteHint>, final_value_msat: u64, final_cltv: u32) -> Result<Route, HandleError> {
+		self.get_route_with_params(target, last_hops, final_value_msat, final_cltv, &RouteParameters::new())
+	}
+
+	/// Takes a random walk of up to MAX_SHADOW_ROUTE_HOPS from the target over enabled channels,
+	/// returning the total CLTV expiry delta the walk would have added to a route.
+	fn get_shadow_route_cltv_padding(&self, network: &NetworkMap, target: &PublicKey) -> u32 {
+		let mut padding: u32 = 0;
+		let mut cur_node_id = *target;
+		let mut prev_chan_key = None;
+		for walked_hops in 0..MAX_SHADOW_ROUTE_HOPS {
+			// Stop walking at each hop after the first with probability 1/2, so that walks of any
+			// length are plausible
+			if walked_hops > 0 && self.entropy_source.rand_u32() % 2 == 0 {
+				break;
+			}
+			let node = match network.nodes.get(&cur_node_id) {
+				Some(node) => node,
+				None => break,
+			};
+			let mut next_hops = Vec::with_capacity(node.channels.len());
+			for chan_key in node.channels.iter() {
+				if Some(chan_key) == prev_chan_key { continue; }
+				if let Some(chan) = network.channels.get(chan_key) {
+					// The delta cur_node_id would charge to forward over the channel
+					let (directional_info, next_node_id) = if chan.one_to_two.src_node_id == cur_node_id {
+						(&chan.one_to_two, chan.two_to_one.src_node_id)
+					} else {
+						(&chan.two_to_one, chan.one_to_two.src_node_id)
+					};
+					if directional_info.enabled {
+						next_hops.push((chan_key, directional_info.cltv_expiry_delta, next_node_id));
+					}
+				}
+			}
+			if next_hops.is_empty() {
+				break;
+			}
+			let (chan_key, cltv_expiry_delta, next_node_id) = next_hops[self.entropy_source.rand_u32() as usize % next_hops.len()];
+			padding = padding.saturating_add(cltv_expiry_delta as u32);
+			cur_node_id = next_node_id;
+			prev_chan_key = Some(chan_key);
+		}
+		padding
+	}
+
+	/// Gets a route from us to the given target node, as get_route, but only returning routes
+	/// which satisfy the constraints in params.
+	/// Note that routes are still selected by lowest fee, so we may fail to find a route within
+	/// the hop and CLTV constraints even where one exists through more expensive channels.
+	pub fn get_route_with_params(&self, target: &PublicKey, last_hops: &Vec<RouteHint>, final_value_msat: u64, final_cltv: u32, params: &RouteParameters) -> Result<Route, HandleError> {
 		// TODO: Obviously *only* using total fee cost sucks. We should consider weighting by
 		// uptime/success in using a node in the past.
 		let network = self.network_map.read().unwrap();
@@ -362,6 +1288,32 @@ impl Router {
 		if *target == network.our_node_id {
 			return Err(HandleError{err: "Cannot generate a route to ourselves", msg: None});
 		}
+		if params.excluded_nodes.contains(target) {
+			return Err(HandleError{err: "Cannot generate a route to an excluded node", msg: None});
+		}
+
+		if let Some(route) = self.find_route(&network, target, last_hops, final_value_msat, final_cltv, params) {
+			return Ok(route);
+		}
+		// Only blame max_hops, max_total_cltv_expiry_delta or max_total_fee_msat if there's a path
+		// we'd have found without them, giving a more useful error.
+		let mut unconstrained_params = params.clone();
+		unconstrained_params.max_hops = MAX_ROUTE_HOPS;
+		unconstrained_params.max_total_cltv_expiry_delta = u32::max_value();
+		unconstrained_params.max_total_fee_msat = u64::max_value();
+		unconstrained_params.use_shadow_route = false;
+		if self.find_route(&network, target, last_hops, final_value_msat, final_cltv, &unconstrained_params).is_some() {
+			return Err(HandleError{err: "Failed to find a path to the given destination within the route constraints", msg: None});
+		}
+		Err(HandleError{err: "Failed to find a path to the given destination", msg: None})
+	}
+
+	/// Finds the cheapest route to target within the given constraints, if there is one
+	fn find_route(&self, network: &NetworkMap, target: &PublicKey, last_hops: &Vec<RouteHint>, final_value_msat: u64, final_cltv: u32, params: &RouteParameters) -> Option<Route> {
+		if final_cltv > params.max_total_cltv_expiry_delta {
+			return None;
+		}
+		let max_hops = cmp::min(params.max_hops, MAX_ROUTE_HOPS);
 
 		// We do a dest-to-source Dijkstra's sorting by each node's distance from the destination
 		// plus the minimum per-HTLC fee to get from it to another node (aka "shitty A*").
@@ -370,6 +1322,9 @@ impl Router {
 		// one.
 
 		let mut targets = BinaryHeap::new(); //TODO: Do we care about switching to eg Fibbonaci heap?
+		// For each node: the lowest (estimated) fee to the target through it, the lowest fees to
+		// reach it, the next hop towards the target, and the number of hops, CLTV expiry delta and
+		// actual fees from it to the target.
 		let mut dist = HashMap::with_capacity(network.nodes.len());
 		for (key, node) in network.nodes.iter() {
 			dist.insert(key.clone(), (u64::max_value(),
@@ -380,7 +1335,8 @@ impl Router {
 					short_channel_id: 0,
 					fee_msat: 0,
 					cltv_expiry_delta: 0,
-			}));
+				},
+				0usize, 0u32, 0u64));
 		}
 
 		macro_rules! add_entry {
@@ -389,28 +1345,49 @@ impl Router {
 			// $directional_info.
 			( $chan_id: expr, $dest_node_id: expr, $directional_info: expr, $starting_fee_msat: expr ) => {
 				//TODO: Explore simply adding fee to hit htlc_minimum_msat
-				if $starting_fee_msat as u64 + final_value_msat > $directional_info.htlc_minimum_msat {
+				if $starting_fee_msat as u64 + final_value_msat > $directional_info.htlc_minimum_msat &&
+						($directional_info.src_node_id == network.our_node_id || !params.excluded_nodes.contains(&$directional_info.src_node_id)) {
 					let new_fee = $directional_info.fee_base_msat as u64 + ($starting_fee_msat + final_value_msat) * ($directional_info.fee_proportional_millionths as u64) / 1000000;
+					let dest_node_id: PublicKey = $dest_node_id.clone();
+					let (dest_hops, dest_cltv, dest_fee) = match dist.get(&dest_node_id) {
+						Some(entry) if dest_node_id != *target => (entry.4, entry.5, entry.6),
+						_ => (0, final_cltv, 0),
+					};
+					// Skip channels a probe recently failed to send as much over
+					let probe_failed = match network.probe_failures.get(&($chan_id.clone(), dest_node_id)) {
+						Some(&(failed_msat, failed_at)) => $starting_fee_msat as u64 + final_value_msat >= failed_msat &&
+							failed_at.elapsed() < Duration::from_secs(PROBE_FAILURE_EXPIRY_SECS),
+						None => false,
+					};
 					let mut total_fee = $starting_fee_msat as u64;
+					let mut path_cltv = dest_cltv;
+					let mut path_fee = dest_fee;
 					let old_entry = dist.get_mut(&$directional_info.src_node_id).unwrap();
 					if $directional_info.src_node_id != network.our_node_id {
 						// Ignore new_fee for channel-from-us as we assume all channels-from-us
 						// will have the same effective-fee
 						total_fee += new_fee;
 						total_fee += old_entry.2 * (final_value_msat + total_fee) / 1000000 + old_entry.1;
+						path_cltv = path_cltv.saturating_add($directional_info.cltv_expiry_delta as u32);
+						path_fee = path_fee.saturating_add(new_fee);
 					}
-					let new_graph_node = RouteGraphNode {
-						pubkey: $directional_info.src_node_id,
-						lowest_fee_to_peer_through_node: total_fee,
-					};
-					if old_entry.0 > total_fee {
-						targets.push(new_graph_node);
-						old_entry.0 = total_fee;
-						old_entry.3 = RouteHop {
-							pubkey: $dest_node_id.clone(),
-							short_channel_id: $chan_id.clone(),
-							fee_msat: new_fee, // This field is ignored on the last-hop anyway
-							cltv_expiry_delta: $directional_info.cltv_expiry_delta as u32,
+					if !probe_failed && dest_hops + 1 <= max_hops && path_cltv <= params.max_total_cltv_expiry_delta && path_fee <= params.max_total_fee_msat {
+						let new_graph_node = RouteGraphNode {
+							pubkey: $directional_info.src_node_id,
+							lowest_fee_to_peer_through_node: total_fee,
+						};
+						if old_entry.0 > total_fee {
+							targets.push(new_graph_node);
+							old_entry.0 = total_fee;
+							old_entry.3 = RouteHop {
+								pubkey: dest_node_id,
+								short_channel_id: $chan_id.clone(),
+								fee_msat: new_fee, // This field is ignored on the last-hop anyway
+								cltv_expiry_delta: $directional_info.cltv_expiry_delta as u32,
+							};
+							old_entry.4 = dest_hops + 1;
+							old_entry.5 = path_cltv;
+							old_entry.6 = path_fee;
 						}
 					}
 				}
@@ -420,7 +1397,12 @@ impl Router {
 		macro_rules! add_entries_to_cheapest_to_target_node {
 			( $node: expr, $node_id: expr, $fee_to_target_msat: expr ) => {
 				for chan_id in $node.channels.iter() {
+					if params.excluded_channels.contains(&NetworkMap::get_short_channel_id(chan_id)) { continue; }
 					let chan = network.channels.get(chan_id).unwrap();
+					if let Some(capacity_sats) = chan.capacity_sats {
+						// Skip channels which are too small to carry the payment at all
+						if capacity_sats * 1000 < $fee_to_target_msat + final_value_msat { continue; }
+					}
 					if chan.one_to_two.src_node_id == *$node_id {
 						// ie $node is one, ie next hop in A* is two, via the two_to_one channel
 						if chan.two_to_one.enabled {
@@ -443,7 +1425,7 @@ impl Router {
 		}
 
 		for hop in last_hops.iter() {
-			if network.nodes.get(&hop.src_node_id).is_some() {
+			if network.nodes.get(&hop.src_node_id).is_some() && !params.excluded_channels.contains(&hop.short_channel_id) {
 				add_entry!(hop.short_channel_id, target, hop, 0);
 			}
 		}
@@ -459,7 +1441,20 @@ impl Router {
 				}
 				res.last_mut().unwrap().fee_msat = final_value_msat;
 				res.last_mut().unwrap().cltv_expiry_delta = final_cltv;
-				return Ok(Route {
+
+				// Double-check the route we actually built against the constraints, as the
+				// per-node values we checked above may have come from different paths
+				let total_fee: u64 = res[..res.len() - 1].iter().map(|hop| hop.fee_msat).sum();
+				let total_cltv: u64 = res.iter().map(|hop| hop.cltv_expiry_delta as u64).sum();
+				if res.len() > max_hops || total_cltv > params.max_total_cltv_expiry_delta as u64 || total_fee > params.max_total_fee_msat {
+					return None;
+				}
+				if params.use_shadow_route {
+					let max_padding = params.max_total_cltv_expiry_delta as u64 - total_cltv;
+					let padding = cmp::min(self.get_shadow_route_cltv_padding(network, target) as u64, max_padding);
+					res.last_mut().unwrap().cltv_expiry_delta += padding as u32;
+				}
+				return Some(Route {
 					hops: res
 				});
 			}
@@ -474,26 +1469,118 @@ impl Router {
 			}
 		}
 
-		Err(HandleError{err: "Failed to find a path to the given destination", msg: None})
+		None
 	}
 }
 
 #[cfg(test)]
 mod tests {
-	use ln::router::{Router,NodeInfo,NetworkMap,ChannelInfo,DirectionalChannelInfo,RouteHint};
-	use ln::msgs::GlobalFeatures;
-
+	use ln::router::{Router,NodeInfo,NetworkMap,ChannelInfo,DirectionalChannelInfo,RouteHint,RouteParameters,DirectionalChannelDetails,NetworkStats};
+	use chain::chaininterface::{ChainError,ChainListener};
+	use ln::channelmonitor::ANTI_REORG_DELAY;
+	use ln::msgs;
+	use ln::msgs::{DecodeError,GlobalFeatures,MsgEncodable,NetAddress,RoutingMessageHandler};
+	use util::rng::SeededEntropySource;
+	use util::test_utils;
+
+	use bitcoin::blockdata::block::BlockHeader;
+	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::blockdata::transaction::{Transaction,TxIn};
+	use bitcoin::blockdata::script::{Builder,Script};
+	use bitcoin::blockdata::opcodes;
+	use bitcoin::network::constants::Network;
+	use bitcoin::network::serialize::BitcoinHash;
 	use bitcoin::util::misc::hex_bytes;
 	use bitcoin::util::hash::Sha256dHash;
 
 	use secp256k1::key::{PublicKey,SecretKey};
-	use secp256k1::Secp256k1;
+	use secp256k1::{Secp256k1,Message,Signature};
+
+	use std::sync::Arc;
+
+	/// Signs gossip for a few nodes with known keys, for tests which build up a network graph
+	/// through the RoutingMessageHandler interface. Messages are for the testnet chain.
+	struct GossipSigner {
+		secp_ctx: Secp256k1,
+		node_keys: Vec<SecretKey>,
+		node_ids: Vec<PublicKey>,
+		chain_hash: Sha256dHash,
+	}
+	impl GossipSigner {
+		fn new(node_count: u8) -> GossipSigner {
+			let secp_ctx = Secp256k1::new();
+			let node_keys: Vec<SecretKey> = (1..node_count + 1).map(|i| SecretKey::from_slice(&secp_ctx, &[i; 32]).unwrap()).collect();
+			let node_ids = node_keys.iter().map(|key| PublicKey::from_secret_key(&secp_ctx, key).unwrap()).collect();
+			GossipSigner { secp_ctx, node_keys, node_ids, chain_hash: genesis_block(Network::Testnet).header.bitcoin_hash() }
+		}
+
+		fn sign<M: MsgEncodable>(&self, contents: &M, node: usize) -> Signature {
+			self.secp_ctx.sign(&Message::from_slice(&Sha256dHash::from_data(&contents.encode()[..])[..]).unwrap(), &self.node_keys[node]).unwrap()
+		}
+
+		/// Announces a channel between the two given nodes, using their node_ids as the funding
+		/// keys too.
+		fn channel_announcement(&self, short_channel_id: u64, node_1: usize, node_2: usize) -> msgs::ChannelAnnouncement {
+			let contents = msgs::UnsignedChannelAnnouncement {
+				features: GlobalFeatures::new(),
+				chain_hash: self.chain_hash,
+				short_channel_id,
+				node_id_1: self.node_ids[node_1],
+				node_id_2: self.node_ids[node_2],
+				bitcoin_key_1: self.node_ids[node_1],
+				bitcoin_key_2: self.node_ids[node_2],
+			};
+			let sig_1 = self.sign(&contents, node_1);
+			let sig_2 = self.sign(&contents, node_2);
+			msgs::ChannelAnnouncement {
+				node_signature_1: sig_1.clone(),
+				node_signature_2: sig_2.clone(),
+				bitcoin_signature_1: sig_1,
+				bitcoin_signature_2: sig_2,
+				contents,
+			}
+		}
+
+		/// The funding output script of a channel announced by channel_announcement
+		fn funding_script(&self, node_1: usize, node_2: usize) -> Script {
+			let (key_a, key_b) = if self.node_ids[node_1].serialize()[..] < self.node_ids[node_2].serialize()[..] { (self.node_ids[node_1], self.node_ids[node_2]) } else { (self.node_ids[node_2], self.node_ids[node_1]) };
+			Builder::new().push_opcode(opcodes::All::OP_PUSHNUM_2)
+				.push_slice(&key_a.serialize()).push_slice(&key_b.serialize())
+				.push_opcode(opcodes::All::OP_PUSHNUM_2).push_opcode(opcodes::All::OP_CHECKMULTISIG).into_script().to_v0_p2wsh()
+		}
+
+		fn channel_update(&self, short_channel_id: u64, node: usize, flags: u16, timestamp: u32) -> msgs::ChannelUpdate {
+			let contents = msgs::UnsignedChannelUpdate {
+				chain_hash: self.chain_hash,
+				short_channel_id,
+				timestamp,
+				flags,
+				cltv_expiry_delta: 144,
+				htlc_minimum_msat: 1000,
+				fee_base_msat: 1000,
+				fee_proportional_millionths: 1,
+			};
+			msgs::ChannelUpdate { signature: self.sign(&contents, node), contents }
+		}
+
+		fn node_announcement(&self, node: usize, addresses: Vec<NetAddress>) -> msgs::NodeAnnouncement {
+			let contents = msgs::UnsignedNodeAnnouncement {
+				features: GlobalFeatures::new(),
+				timestamp: 1,
+				node_id: self.node_ids[node],
+				rgb: [node as u8; 3],
+				alias: [node as u8; 32],
+				addresses,
+			};
+			msgs::NodeAnnouncement { signature: self.sign(&contents, node), contents }
+		}
+	}
 
 	#[test]
 	fn route_test() {
 		let secp_ctx = Secp256k1::new();
 		let our_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&secp_ctx, &hex_bytes("0101010101010101010101010101010101010101010101010101010101010101").unwrap()[..]).unwrap()).unwrap();
-		let router = Router::new(our_id);
+		let router = Router::new(our_id, Arc::new(test_utils::TestChainWatcher::new()), Arc::new(SeededEntropySource::new([42; 32])));
 
 		// Build network from our_id to node8:
 		//
@@ -569,6 +1656,7 @@ mod tests {
 				rgb: [0; 3],
 				alias: [0; 32],
 				addresses: Vec::new(),
+				announcement_message: None,
 			});
 			network.channels.insert(NetworkMap::get_key(1, zero_hash.clone()), ChannelInfo {
 				features: GlobalFeatures::new(),
@@ -580,6 +1668,7 @@ mod tests {
 					htlc_minimum_msat: 0,
 					fee_base_msat: u32::max_value(), // This value should be ignored
 					fee_proportional_millionths: u32::max_value(), // This value should be ignored
+					last_update_message: None,
 				}, two_to_one: DirectionalChannelInfo {
 					src_node_id: node1.clone(),
 					last_update: 0,
@@ -588,7 +1677,12 @@ mod tests {
 					htlc_minimum_msat: 0,
 					fee_base_msat: 0,
 					fee_proportional_millionths: 0,
+					last_update_message: None,
 				},
+				announcement_message: None,
+				capacity_sats: None,
+				funding_txo: None,
+				announcement_received_time: 0,
 			});
 			network.nodes.insert(node2.clone(), NodeInfo {
 				channels: vec!(NetworkMap::get_key(2, zero_hash.clone()), NetworkMap::get_key(4, zero_hash.clone())),
@@ -599,6 +1693,7 @@ mod tests {
 				rgb: [0; 3],
 				alias: [0; 32],
 				addresses: Vec::new(),
+				announcement_message: None,
 			});
 			network.channels.insert(NetworkMap::get_key(2, zero_hash.clone()), ChannelInfo {
 				features: GlobalFeatures::new(),
@@ -610,6 +1705,7 @@ mod tests {
 					htlc_minimum_msat: 0,
 					fee_base_msat: u32::max_value(), // This value should be ignored
 					fee_proportional_millionths: u32::max_value(), // This value should be ignored
+					last_update_message: None,
 				}, two_to_one: DirectionalChannelInfo {
 					src_node_id: node2.clone(),
 					last_update: 0,
@@ -618,7 +1714,12 @@ mod tests {
 					htlc_minimum_msat: 0,
 					fee_base_msat: 0,
 					fee_proportional_millionths: 0,
+					last_update_message: None,
 				},
+				announcement_message: None,
+				capacity_sats: None,
+				funding_txo: None,
+				announcement_received_time: 0,
 			});
 			network.nodes.insert(node3.clone(), NodeInfo {
 				channels: vec!(
@@ -634,6 +1735,7 @@ mod tests {
 				rgb: [0; 3],
 				alias: [0; 32],
 				addresses: Vec::new(),
+				announcement_message: None,
 			});
 			network.channels.insert(NetworkMap::get_key(3, zero_hash.clone()), ChannelInfo {
 				features: GlobalFeatures::new(),
@@ -645,6 +1747,7 @@ mod tests {
 					htlc_minimum_msat: 0,
 					fee_base_msat: 0,
 					fee_proportional_millionths: 0,
+					last_update_message: None,
 				}, two_to_one: DirectionalChannelInfo {
 					src_node_id: node3.clone(),
 					last_update: 0,
@@ -653,7 +1756,12 @@ mod tests {
 					htlc_minimum_msat: 0,
 					fee_base_msat: 100,
 					fee_proportional_millionths: 0,
+					last_update_message: None,
 				},
+				announcement_message: None,
+				capacity_sats: None,
+				funding_txo: None,
+				announcement_received_time: 0,
 			});
 			network.channels.insert(NetworkMap::get_key(4, zero_hash.clone()), ChannelInfo {
 				features: GlobalFeatures::new(),
@@ -665,6 +1773,7 @@ mod tests {
 					htlc_minimum_msat: 0,
 					fee_base_msat: 0,
 					fee_proportional_millionths: 1000000,
+					last_update_message: None,
 				}, two_to_one: DirectionalChannelInfo {
 					src_node_id: node3.clone(),
 					last_update: 0,
@@ -673,7 +1782,12 @@ mod tests {
 					htlc_minimum_msat: 0,
 					fee_base_msat: 0,
 					fee_proportional_millionths: 0,
+					last_update_message: None,
 				},
+				announcement_message: None,
+				capacity_sats: None,
+				funding_txo: None,
+				announcement_received_time: 0,
 			});
 			network.nodes.insert(node4.clone(), NodeInfo {
 				channels: vec!(NetworkMap::get_key(5, zero_hash.clone()), NetworkMap::get_key(11, zero_hash.clone())),
@@ -684,6 +1798,7 @@ mod tests {
 				rgb: [0; 3],
 				alias: [0; 32],
 				addresses: Vec::new(),
+				announcement_message: None,
 			});
 			network.channels.insert(NetworkMap::get_key(5, zero_hash.clone()), ChannelInfo {
 				features: GlobalFeatures::new(),
@@ -695,6 +1810,7 @@ mod tests {
 					htlc_minimum_msat: 0,
 					fee_base_msat: 100,
 					fee_proportional_millionths: 0,
+					last_update_message: None,
 				}, two_to_one: DirectionalChannelInfo {
 					src_node_id: node4.clone(),
 					last_update: 0,
@@ -703,7 +1819,12 @@ mod tests {
 					htlc_minimum_msat: 0,
 					fee_base_msat: 0,
 					fee_proportional_millionths: 0,
+					last_update_message: None,
 				},
+				announcement_message: None,
+				capacity_sats: None,
+				funding_txo: None,
+				announcement_received_time: 0,
 			});
 			network.nodes.insert(node5.clone(), NodeInfo {
 				channels: vec!(NetworkMap::get_key(6, zero_hash.clone()), NetworkMap::get_key(11, zero_hash.clone())),
@@ -714,6 +1835,7 @@ mod tests {
 				rgb: [0; 3],
 				alias: [0; 32],
 				addresses: Vec::new(),
+				announcement_message: None,
 			});
 			network.channels.insert(NetworkMap::get_key(6, zero_hash.clone()), ChannelInfo {
 				features: GlobalFeatures::new(),
@@ -725,6 +1847,7 @@ mod tests {
 					htlc_minimum_msat: 0,
 					fee_base_msat: 0,
 					fee_proportional_millionths: 0,
+					last_update_message: None,
 				}, two_to_one: DirectionalChannelInfo {
 					src_node_id: node5.clone(),
 					last_update: 0,
@@ -733,7 +1856,12 @@ mod tests {
 					htlc_minimum_msat: 0,
 					fee_base_msat: 0,
 					fee_proportional_millionths: 0,
+					last_update_message: None,
 				},
+				announcement_message: None,
+				capacity_sats: None,
+				funding_txo: None,
+				announcement_received_time: 0,
 			});
 			network.channels.insert(NetworkMap::get_key(11, zero_hash.clone()), ChannelInfo {
 				features: GlobalFeatures::new(),
@@ -745,6 +1873,7 @@ mod tests {
 					htlc_minimum_msat: 0,
 					fee_base_msat: 0,
 					fee_proportional_millionths: 0,
+					last_update_message: None,
 				}, two_to_one: DirectionalChannelInfo {
 					src_node_id: node4.clone(),
 					last_update: 0,
@@ -753,7 +1882,12 @@ mod tests {
 					htlc_minimum_msat: 0,
 					fee_base_msat: 0,
 					fee_proportional_millionths: 0,
+					last_update_message: None,
 				},
+				announcement_message: None,
+				capacity_sats: None,
+				funding_txo: None,
+				announcement_received_time: 0,
 			});
 			network.nodes.insert(node6.clone(), NodeInfo {
 				channels: vec!(NetworkMap::get_key(7, zero_hash.clone())),
@@ -764,6 +1898,7 @@ mod tests {
 				rgb: [0; 3],
 				alias: [0; 32],
 				addresses: Vec::new(),
+				announcement_message: None,
 			});
 			network.channels.insert(NetworkMap::get_key(7, zero_hash.clone()), ChannelInfo {
 				features: GlobalFeatures::new(),
@@ -775,6 +1910,7 @@ mod tests {
 					htlc_minimum_msat: 0,
 					fee_base_msat: 0,
 					fee_proportional_millionths: 1000000,
+					last_update_message: None,
 				}, two_to_one: DirectionalChannelInfo {
 					src_node_id: node6.clone(),
 					last_update: 0,
@@ -783,7 +1919,12 @@ mod tests {
 					htlc_minimum_msat: 0,
 					fee_base_msat: 0,
 					fee_proportional_millionths: 0,
+					last_update_message: None,
 				},
+				announcement_message: None,
+				capacity_sats: None,
+				funding_txo: None,
+				announcement_received_time: 0,
 			});
 		}
 
@@ -931,5 +2072,414 @@ mod tests {
 			assert_eq!(route.hops[4].fee_msat, 2000);
 			assert_eq!(route.hops[4].cltv_expiry_delta, 42);
 		}
+
+		{ // Route constraints
+			let mut params = RouteParameters::new();
+			params.excluded_channels.insert(10);
+			let route = router.get_route_with_params(&node7, &last_hops, 100, 42, &params).unwrap();
+			assert_eq!(route.hops.len(), 5);
+			assert_eq!(route.hops[4].short_channel_id, 8);
+
+			// The route to 1 has 3 hops, a total CLTV expiry delta of 42 + (4 << 8) + 1 + (3 << 8)
+			// + 2 and costs 300 msat in fees
+			let mut params = RouteParameters::new();
+			params.max_hops = 3;
+			params.max_total_cltv_expiry_delta = 42 + (4 << 8) + 1 + (3 << 8) + 2;
+			params.max_total_fee_msat = 300;
+			assert_eq!(router.get_route_with_params(&node1, &Vec::new(), 100, 42, &params).unwrap().hops.len(), 3);
+
+			for i in 0..3 {
+				let mut tight_params = params.clone();
+				match i {
+					0 => tight_params.max_hops -= 1,
+					1 => tight_params.max_total_cltv_expiry_delta -= 1,
+					_ => tight_params.max_total_fee_msat -= 1,
+				}
+				assert_eq!(router.get_route_with_params(&node1, &Vec::new(), 100, 42, &tight_params).err().unwrap().err,
+					"Failed to find a path to the given destination within the route constraints");
+			}
+
+			// Everything to 1 and 3 goes through 2
+			let mut params = RouteParameters::new();
+			params.excluded_nodes.insert(node2);
+			assert_eq!(router.get_route_with_params(&node1, &Vec::new(), 100, 42, &params).err().unwrap().err,
+				"Failed to find a path to the given destination");
+			assert_eq!(router.get_route_with_params(&node2, &Vec::new(), 100, 42, &params).err().unwrap().err,
+				"Cannot generate a route to an excluded node");
+
+			// ...so we don't blame the constraints when there's no path at all, even if they'd
+			// have ruled out paths too
+			for i in 0..3 {
+				let mut tight_params = params.clone();
+				match i {
+					0 => tight_params.max_hops = 1,
+					1 => tight_params.max_total_cltv_expiry_delta = 41,
+					_ => tight_params.max_total_fee_msat = 0,
+				}
+				assert_eq!(router.get_route_with_params(&node1, &Vec::new(), 100, 42, &tight_params).err().unwrap().err,
+					"Failed to find a path to the given destination");
+			}
+		}
+
+		{ // Shadow route CLTV padding
+			let mut params = RouteParameters::new();
+			params.use_shadow_route = true;
+			for _ in 0..16 {
+				// 3 always has a channel to walk over, and the largest delta in the graph is (11 << 8) | 2
+				let route = router.get_route_with_params(&node3, &Vec::new(), 100, 42, &params).unwrap();
+				assert_eq!(route.hops.len(), 2);
+				assert_eq!(route.hops[0].cltv_expiry_delta, (4 << 8) | 1);
+				assert!(route.hops[1].cltv_expiry_delta > 42);
+				assert!(route.hops[1].cltv_expiry_delta <= 42 + 3 * ((11 << 8) | 2));
+			}
+
+			// ...but the padding never takes the route over max_total_cltv_expiry_delta
+			params.max_total_cltv_expiry_delta = 42 + (4 << 8) + 1 + 10;
+			let route = router.get_route_with_params(&node3, &Vec::new(), 100, 42, &params).unwrap();
+			assert_eq!(route.hops[1].cltv_expiry_delta, 42 + 10);
+		}
+	}
+
+	#[test]
+	fn routing_table_iteration_test() {
+		// Tests that we hand out the announcements we've been given, in order and a batch at a time,
+		// for syncing our routing table to peers.
+		let signer = GossipSigner::new(4);
+		let router = Router::new(signer.node_ids[0], Arc::new(test_utils::TestChainWatcher::new()), Arc::new(SeededEntropySource::new([42; 32])));
+
+		// Channels 1 <-> 2, 2 <-> 3 and 3 <-> 4, announced out of order
+		for &(short_channel_id, node_1, node_2) in [(30, 2, 3), (10, 0, 1), (20, 1, 2)].iter() {
+			assert!(router.handle_channel_announcement(&signer.channel_announcement(short_channel_id, node_1, node_2)).unwrap());
+		}
+		let update = signer.channel_update(20, 2, 1, 1);
+		assert!(router.handle_channel_update(&update).unwrap());
+		for node in 0..4 {
+			assert!(router.handle_node_announcement(&signer.node_announcement(node, Vec::new())).unwrap());
+		}
+
+		let first_batch = router.get_next_channel_announcements(0, 2);
+		assert_eq!(first_batch.len(), 2);
+		assert_eq!(first_batch[0].0.contents.short_channel_id, 10);
+		assert!(first_batch[0].1.is_none() && first_batch[0].2.is_none());
+		assert_eq!(first_batch[1].0.contents.short_channel_id, 20);
+		assert!(first_batch[1].1.is_none());
+		assert!(first_batch[1].2 == Some(update));
+		let second_batch = router.get_next_channel_announcements(21, 2);
+		assert_eq!(second_batch.len(), 1);
+		assert_eq!(second_batch[0].0.contents.short_channel_id, 30);
+		assert!(router.get_next_channel_announcements(31, 2).is_empty());
+
+		let mut sorted_node_ids = signer.node_ids.clone();
+		sorted_node_ids.sort();
+		let first_batch = router.get_next_node_announcements(None, 3);
+		assert_eq!(first_batch.len(), 3);
+		let second_batch = router.get_next_node_announcements(Some(&first_batch[2].contents.node_id), 3);
+		assert_eq!(second_batch.len(), 1);
+		let synced_node_ids: Vec<PublicKey> = first_batch.iter().chain(second_batch.iter()).map(|msg| msg.contents.node_id).collect();
+		assert_eq!(synced_node_ids, sorted_node_ids);
+	}
+
+	#[test]
+	fn gossip_queries_test() {
+		// Tests that we answer channel range and short_channel_id queries from our routing table,
+		// and pick out the short_channel_ids we don't know from a peer's channel range reply.
+		let signer = GossipSigner::new(3);
+		let router = Router::new(signer.node_ids[0], Arc::new(test_utils::TestChainWatcher::new()), Arc::new(SeededEntropySource::new([42; 32])));
+		let chain_hash = signer.chain_hash;
+
+		// Channels 1 <-> 2 in block 100 and 2 <-> 3 in block 200
+		let scid_1 = (100 << 40) | (1 << 16);
+		let scid_2 = (200 << 40) | (2 << 16);
+		for &(short_channel_id, node_1, node_2) in [(scid_1, 0, 1), (scid_2, 1, 2)].iter() {
+			assert!(router.handle_channel_announcement(&signer.channel_announcement(short_channel_id, node_1, node_2)).unwrap());
+		}
+		let node_announcement = signer.node_announcement(2, Vec::new());
+		assert!(router.handle_node_announcement(&node_announcement).unwrap());
+
+		let replies = router.handle_query_channel_range(&msgs::QueryChannelRange { chain_hash, first_blocknum: 0, number_of_blocks: 150 }).unwrap();
+		assert_eq!(replies.len(), 1);
+		assert_eq!(replies[0].short_channel_ids, vec![scid_1]);
+		let replies = router.handle_query_channel_range(&msgs::QueryChannelRange { chain_hash, first_blocknum: 0, number_of_blocks: 0xffffffff }).unwrap();
+		assert_eq!(replies.len(), 1);
+		assert_eq!(replies[0].short_channel_ids, vec![scid_1, scid_2]);
+		let replies = router.handle_query_channel_range(&msgs::QueryChannelRange { chain_hash, first_blocknum: 300, number_of_blocks: 100 }).unwrap();
+		assert_eq!(replies.len(), 1);
+		assert!(replies[0].short_channel_ids.is_empty());
+
+		let (channels, nodes) = router.handle_query_short_channel_ids(&msgs::QueryShortChannelIds { chain_hash, short_channel_ids: vec![scid_2, 42] }).unwrap();
+		assert_eq!(channels.len(), 1);
+		assert_eq!(channels[0].0.contents.short_channel_id, scid_2);
+		assert!(channels[0].1.is_none() && channels[0].2.is_none());
+		// Only the node which has announced itself gets returned
+		assert!(nodes == vec![node_announcement]);
+
+		let unknown = router.handle_reply_channel_range(&msgs::ReplyChannelRange {
+			chain_hash,
+			first_blocknum: 0,
+			number_of_blocks: 0xffffffff,
+			full_information: true,
+			short_channel_ids: vec![scid_1, 42, scid_2, 43],
+		}).unwrap();
+		assert_eq!(unknown, vec![42, 43]);
+	}
+
+	#[test]
+	fn channel_announcement_utxo_test() {
+		// Tests that we check channel announcements against the funding output the chain gives us
+		let signer = GossipSigner::new(2);
+		let node_ids = &signer.node_ids;
+		let chain_monitor = Arc::new(test_utils::TestChainWatcher::new());
+		let router = Router::new(node_ids[0], chain_monitor.clone(), Arc::new(SeededEntropySource::new([42; 32])));
+		let announcement = signer.channel_announcement(42, 0, 1);
+
+		// Without UTXO lookup support we tentatively accept the announcement
+		assert!(router.handle_channel_announcement(&announcement).unwrap());
+		assert!(router.network_map.read().unwrap().channels.get(&42).unwrap().capacity_sats.is_none());
+		assert_eq!(router.handle_channel_announcement(&announcement).err().unwrap().err, "Already have knowledge of channel");
+
+		*chain_monitor.utxo_ret.lock().unwrap() = Err(ChainError::NotWatched);
+		assert_eq!(router.handle_channel_announcement(&announcement).err().unwrap().err, "Channel announced on an unknown chain");
+		*chain_monitor.utxo_ret.lock().unwrap() = Err(ChainError::UnknownTx);
+		assert_eq!(router.handle_channel_announcement(&announcement).err().unwrap().err, "Channel announced without corresponding UTXO entry");
+
+		let good_script = signer.funding_script(0, 1);
+		// The keys in the wrong order
+		let (key_a, key_b) = if node_ids[0].serialize()[..] < node_ids[1].serialize()[..] { (node_ids[0], node_ids[1]) } else { (node_ids[1], node_ids[0]) };
+		let bad_script = Builder::new().push_opcode(opcodes::All::OP_PUSHNUM_2)
+			.push_slice(&key_b.serialize()).push_slice(&key_a.serialize())
+			.push_opcode(opcodes::All::OP_PUSHNUM_2).push_opcode(opcodes::All::OP_CHECKMULTISIG).into_script().to_v0_p2wsh();
+
+		*chain_monitor.utxo_ret.lock().unwrap() = Ok((Sha256dHash::from_data(&[42; 32]), bad_script, 100000));
+		assert_eq!(router.handle_channel_announcement(&announcement).err().unwrap().err, "Channel announcement keys didn't match on-chain script");
+
+		// A checked announcement replaces the one we accepted without checking
+		*chain_monitor.utxo_ret.lock().unwrap() = Ok((Sha256dHash::from_data(&[42; 32]), good_script, 100000));
+		assert!(router.handle_channel_announcement(&announcement).unwrap());
+		let network = router.network_map.read().unwrap();
+		assert_eq!(network.channels.get(&42).unwrap().capacity_sats, Some(100000));
+		assert_eq!(network.nodes.get(&node_ids[0]).unwrap().channels, vec![42]);
+		assert_eq!(network.nodes.get(&node_ids[1]).unwrap().channels, vec![42]);
+		drop(network);
+		assert_eq!(router.handle_channel_announcement(&announcement).err().unwrap().err, "Already have knowledge of channel");
+	}
+
+	#[test]
+	fn channel_pruning_test() {
+		// Tests that we remove channels whose funding output is spent or which haven't been updated
+		// for two weeks (unless they're our own), as well as any nodes left without channels, and
+		// that channels closed in a block which is disconnected are restored.
+		let signer = GossipSigner::new(5);
+		let node_ids = &signer.node_ids;
+		let chain_monitor = Arc::new(test_utils::TestChainWatcher::new());
+		let router = Router::new(node_ids[0], chain_monitor.clone(), Arc::new(SeededEntropySource::new([42; 32])));
+		let funding_txid = Sha256dHash::from_data(&[42; 32]);
+		let start_time = 1500000000;
+
+		macro_rules! announce_channel {
+			($short_channel_id: expr, $node_1: expr, $node_2: expr) => {
+				assert!(router.handle_channel_announcement(&signer.channel_announcement($short_channel_id, $node_1, $node_2)).unwrap());
+			}
+		}
+		macro_rules! update_channel {
+			($short_channel_id: expr, $node: expr, $direction: expr, $timestamp: expr) => {
+				assert!(router.handle_channel_update(&signer.channel_update($short_channel_id, $node, $direction, $timestamp)).unwrap());
+			}
+		}
+		let mut height = 0;
+		macro_rules! connect_block {
+			($time: expr, $txn: expr) => {
+				{
+					let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: $time, bits: 42, nonce: 42 };
+					let txn: Vec<Transaction> = $txn;
+					height += 1;
+					router.block_connected(&header, height, &txn.iter().collect::<Vec<&Transaction>>()[..], &[0; 0]);
+					header
+				}
+			}
+		}
+
+		// Channel 1 (nodes 1 <-> 2) is checked on chain, channels 2 (nodes 2 <-> 3), 3 (nodes
+		// 3 <-> 4) and 4 (us <-> node 3) aren't
+		*chain_monitor.utxo_ret.lock().unwrap() = Ok((funding_txid, signer.funding_script(1, 2), 100000));
+		announce_channel!(1, 1, 2);
+		*chain_monitor.utxo_ret.lock().unwrap() = Err(ChainError::NotSupported);
+		announce_channel!(2, 2, 3);
+		announce_channel!(3, 3, 4);
+		announce_channel!(4, 0, 3);
+		update_channel!(2, 2, 0, start_time);
+		connect_block!(start_time, Vec::new());
+		assert_eq!(router.network_map.read().unwrap().channels.len(), 4);
+
+		// Spending some other output of the funding transaction doesn't close channel 1
+		let spend_tx = |index| Transaction {
+			version: 2,
+			lock_time: 0,
+			input: vec![TxIn {
+				prev_hash: funding_txid,
+				prev_index: index,
+				script_sig: Script::new(),
+				sequence: 0xffffffff,
+				witness: Vec::new(),
+			}],
+			output: Vec::new(),
+		};
+		connect_block!(start_time + 600, vec![spend_tx(0)]);
+		assert_eq!(router.network_map.read().unwrap().channels.len(), 4);
+		let check_channel_1_removed = || {
+			let network = router.network_map.read().unwrap();
+			assert!(!network.channels.contains_key(&1));
+			assert!(!network.nodes.contains_key(&node_ids[1]));
+			assert_eq!(network.nodes.get(&node_ids[2]).unwrap().channels, vec![2]);
+		};
+		let spend_header = connect_block!(start_time + 1200, vec![spend_tx(1)]);
+		check_channel_1_removed();
+
+		// If the block spending channel 1's funding output is disconnected, the channel (and the
+		// node which only had that channel) are restored.
+		router.block_disconnected(&spend_header);
+		height -= 1;
+		{
+			let network = router.network_map.read().unwrap();
+			assert_eq!(network.channels.get(&1).unwrap().capacity_sats, Some(100000));
+			assert_eq!(network.nodes.get(&node_ids[1]).unwrap().channels, vec![1]);
+			let mut node_2_channels = network.nodes.get(&node_ids[2]).unwrap().channels.clone();
+			node_2_channels.sort();
+			assert_eq!(node_2_channels, vec![1, 2]);
+		}
+		connect_block!(start_time + 1200, vec![spend_tx(1)]);
+		check_channel_1_removed();
+		// Once the spend is buried we forget it
+		for i in 0..ANTI_REORG_DELAY {
+			connect_block!(start_time + 1800 + i, Vec::new());
+		}
+		assert!(router.network_map.read().unwrap().recently_spent_channels.is_empty());
+
+		// Channel 2 gets a fresh update in one direction, channel 3 never gets one
+		update_channel!(2, 3, 1, start_time + 60 * 60 * 24 * 13);
+		connect_block!(start_time + 60 * 60 * 24 * 14 + 1, Vec::new());
+		{
+			let network = router.network_map.read().unwrap();
+			assert!(!network.channels.contains_key(&3));
+			assert!(!network.nodes.contains_key(&node_ids[4]));
+			let chan = network.channels.get(&2).unwrap();
+			// The direction which hasn't been updated since start_time is now stale
+			let (stale, fresh) = if chan.one_to_two.src_node_id == node_ids[2] { (&chan.one_to_two, &chan.two_to_one) } else { (&chan.two_to_one, &chan.one_to_two) };
+			assert!(!stale.enabled && stale.last_update_message.is_none());
+			assert!(fresh.enabled && fresh.last_update_message.is_some());
+		}
+
+		// Once channel 2 goes stale too it is removed, but we never remove ourselves or our own
+		// channels
+		connect_block!(start_time + 60 * 60 * 24 * 28, Vec::new());
+		let network = router.network_map.read().unwrap();
+		assert_eq!(network.channels.keys().cloned().collect::<Vec<_>>(), vec![4]);
+		assert_eq!(network.nodes.len(), 2);
+		assert_eq!(network.nodes.get(&node_ids[0]).unwrap().channels, vec![4]);
+		assert_eq!(network.nodes.get(&node_ids[3]).unwrap().channels, vec![4]);
+	}
+
+	/// Creates a Router for the first of the given three nodes and announces channel 1 (us <->
+	/// node 2), which is checked on chain, and channel 2 (node 2 <-> node 3), which isn't.
+	fn create_router_with_two_channels(signer: &GossipSigner) -> (Arc<Router>, Arc<test_utils::TestChainWatcher>) {
+		let chain_monitor = Arc::new(test_utils::TestChainWatcher::new());
+		let router = Router::new(signer.node_ids[0], chain_monitor.clone(), Arc::new(SeededEntropySource::new([42; 32])));
+		*chain_monitor.utxo_ret.lock().unwrap() = Ok((Sha256dHash::from_data(&[42; 32]), signer.funding_script(0, 1), 100000));
+		assert!(router.handle_channel_announcement(&signer.channel_announcement(1, 0, 1)).unwrap());
+		*chain_monitor.utxo_ret.lock().unwrap() = Err(ChainError::NotSupported);
+		assert!(router.handle_channel_announcement(&signer.channel_announcement(2, 1, 2)).unwrap());
+		(router, chain_monitor)
+	}
+
+	#[test]
+	fn network_graph_serialization_test() {
+		let signer = GossipSigner::new(3);
+		let node_ids = &signer.node_ids;
+		let (router, chain_monitor) = create_router_with_two_channels(&signer);
+		for &(short_channel_id, node) in [(1, 0), (2, 1)].iter() {
+			assert!(router.handle_channel_update(&signer.channel_update(short_channel_id, node, 0, 1)).unwrap());
+		}
+		let addresses = vec![NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 }, NetAddress::OnionV2 { addr: [2; 10], port: 9735 }];
+		assert!(router.handle_node_announcement(&signer.node_announcement(2, addresses)).unwrap());
+
+		let serialized = router.serialize(Network::Testnet);
+		let reloaded = Router::from_serialized(&serialized, Network::Testnet, chain_monitor.clone(), Arc::new(SeededEntropySource::new([42; 32]))).unwrap();
+		assert!(reloaded.serialize(Network::Testnet) == serialized);
+		{
+			let network = reloaded.network_map.read().unwrap();
+			assert_eq!(network.our_node_id, node_ids[0]);
+			assert_eq!(network.channels.get(&1).unwrap().capacity_sats, Some(100000));
+			assert!(network.channels.get(&2).unwrap().funding_txo.is_none());
+			assert!(network.nodes.get(&node_ids[2]).unwrap().addresses == vec![NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 }, NetAddress::OnionV2 { addr: [2; 10], port: 9735 }]);
+		}
+		assert_eq!(reloaded.get_route(&node_ids[2], &Vec::new(), 1000000, 42).unwrap().hops.len(), 2);
+
+		match Router::from_serialized(&serialized, Network::Bitcoin, chain_monitor.clone(), Arc::new(SeededEntropySource::new([42; 32]))) {
+			Err(DecodeError::InvalidValue) => {},
+			_ => panic!("Loaded a graph for the wrong chain"),
+		}
+		let mut corrupted = serialized.clone();
+		corrupted[50] ^= 1;
+		match Router::from_serialized(&corrupted, Network::Testnet, chain_monitor.clone(), Arc::new(SeededEntropySource::new([42; 32]))) {
+			Err(DecodeError::InvalidValue) => {},
+			_ => panic!("Loaded a corrupted graph"),
+		}
+		assert!(Router::from_serialized(&serialized[..serialized.len() - 1], Network::Testnet, chain_monitor.clone(), Arc::new(SeededEntropySource::new([42; 32]))).is_err());
+
+		// A graph written for one chain but holding announcements for another isn't loaded either
+		let serialized = router.serialize(Network::Bitcoin);
+		match Router::from_serialized(&serialized, Network::Bitcoin, chain_monitor.clone(), Arc::new(SeededEntropySource::new([42; 32]))) {
+			Err(DecodeError::InvalidValue) => {},
+			_ => panic!("Loaded announcements for the wrong chain"),
+		}
+	}
+
+	#[test]
+	fn network_graph_query_test() {
+		let signer = GossipSigner::new(3);
+		let node_ids = &signer.node_ids;
+		let (router, _) = create_router_with_two_channels(&signer);
+		assert!(router.handle_channel_update(&signer.channel_update(2, 2, 1, 1)).unwrap());
+		assert!(router.handle_node_announcement(&signer.node_announcement(2, Vec::new())).unwrap());
+
+		assert!(router.get_channel(3).is_none());
+		let chan = router.get_channel(2).unwrap();
+		assert_eq!(chan.short_channel_id, 2);
+		assert!(chan.capacity_sats.is_none());
+		assert!(!chan.one_to_two.enabled && chan.one_to_two.last_update == 0);
+		assert!(chan.two_to_one == DirectionalChannelDetails {
+			src_node_id: node_ids[2],
+			last_update: 1,
+			enabled: true,
+			cltv_expiry_delta: 144,
+			htlc_minimum_msat: 1000,
+			fee_base_msat: 1000,
+			fee_proportional_millionths: 1,
+		});
+		assert_eq!(router.get_channel(1).unwrap().capacity_sats, Some(100000));
+
+		let node_channels = router.get_node_channels(&node_ids[1]);
+		assert_eq!(node_channels.iter().map(|chan| chan.short_channel_id).collect::<Vec<u64>>(), vec![1, 2]);
+		assert!(router.get_node_channels(&PublicKey::from_secret_key(&signer.secp_ctx, &SecretKey::from_slice(&signer.secp_ctx, &[42; 32]).unwrap()).unwrap()).is_empty());
+
+		let mut sorted_node_ids = node_ids.clone();
+		sorted_node_ids.sort();
+		let first_batch = router.get_nodes(None, 2);
+		assert_eq!(first_batch.len(), 2);
+		let second_batch = router.get_nodes(Some(&first_batch[1].node_id), 2);
+		assert_eq!(second_batch.len(), 1);
+		assert!(router.get_nodes(Some(&second_batch[0].node_id), 2).is_empty());
+		let nodes: Vec<_> = first_batch.iter().chain(second_batch.iter()).collect();
+		assert_eq!(nodes.iter().map(|node| node.node_id).collect::<Vec<PublicKey>>(), sorted_node_ids);
+		let announced_node = nodes.iter().find(|node| node.node_id == node_ids[2]).unwrap();
+		assert_eq!(announced_node.last_update, 1);
+		assert_eq!(announced_node.rgb, [2; 3]);
+		assert_eq!(announced_node.short_channel_ids, vec![2]);
+
+		assert!(router.get_network_stats() == NetworkStats {
+			node_count: 3,
+			channel_count: 2,
+			total_capacity_sats: 100000,
+			channels_without_capacity: 1,
+		});
 	}
 }
@@ -0,0 +1,297 @@
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::network::serialize::{deserialize, serialize, BitcoinHash};
+use bitcoin::util::hash::Sha256dHash;
+
+use crypto::aead::{AeadEncryptor, AeadDecryptor};
+use crypto::digest::Digest;
+
+use chain::chaininterface::{ChainListener, ChainWatchInterface, BroadcasterInterface, FeeEstimator};
+use chain::transaction::OutPoint;
+use ln::channelmonitor::{ChannelMonitor, ChannelMonitorUpdate, ChannelMonitorUpdateErr, ManyChannelMonitor, ANTI_REORG_DELAY};
+use util::chacha20poly1305rfc::ChaCha20Poly1305RFC;
+use util::sha2::Sha256;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// The number of bytes of a revoked commitment transaction's txid which are handed to a
+/// watchtower (as a "hint") to identify the transaction a justice blob is for.
+pub const JUSTICE_HINT_LEN: usize = 16;
+
+/// An interface to a (likely remote) watchtower which can store justice blobs.
+/// A justice blob is a set of signed transactions claiming the outputs of a revoked commitment
+/// transaction, encrypted with the SHA256 of that commitment transaction's txid. The hint is the
+/// first JUSTICE_HINT_LEN bytes of the txid, so the tower can match the blob against confirmed
+/// transactions but cannot decrypt it (or learn anything about the channel) until the revoked
+/// commitment transaction is actually confirmed.
+pub trait WatchtowerInterface: Send + Sync {
+	/// Stores a justice blob on the tower. If the tower cannot currently be reached but may be
+	/// later, this should return InProgress, which will be handed back to the ChannelManager,
+	/// freezing the channel. The implementation is then responsible for retrying and calling
+	/// ChannelManager::update_completed once the blob has been stored.
+	fn store_justice_blob(&self, hint: [u8; JUSTICE_HINT_LEN], blob: Vec<u8>) -> Result<(), ChannelMonitorUpdateErr>;
+}
+
+#[inline]
+fn justice_key(commitment_txid: &Sha256dHash) -> [u8; 32] {
+	let mut key = [0; 32];
+	let mut sha = Sha256::new();
+	sha.input(&commitment_txid[..]);
+	sha.result(&mut key);
+	key
+}
+
+#[inline]
+fn justice_hint(commitment_txid: &Sha256dHash) -> [u8; JUSTICE_HINT_LEN] {
+	let mut hint = [0; JUSTICE_HINT_LEN];
+	hint.copy_from_slice(&commitment_txid[0..JUSTICE_HINT_LEN]);
+	hint
+}
+
+// Each key is only ever used to encrypt the justice transactions for a single commitment
+// transaction (which are deterministic), so we can safely use a fixed nonce.
+fn encrypt_justice_blob(commitment_txid: &Sha256dHash, justice_txn: &Vec<Transaction>) -> Vec<u8> {
+	let plaintext = serialize(justice_txn).unwrap();
+	let mut res = vec![0; plaintext.len() + 16];
+	let mut chacha = ChaCha20Poly1305RFC::new(&justice_key(commitment_txid), &[0; 12], &[]);
+	let mut tag = [0; 16];
+	chacha.encrypt(&plaintext, &mut res[0..plaintext.len()], &mut tag);
+	res[plaintext.len()..].copy_from_slice(&tag);
+	res
+}
+
+fn decrypt_justice_blob(commitment_txid: &Sha256dHash, blob: &[u8]) -> Option<Vec<Transaction>> {
+	if blob.len() < 16 {
+		return None;
+	}
+	let mut plaintext = vec![0; blob.len() - 16];
+	let mut chacha = ChaCha20Poly1305RFC::new(&justice_key(commitment_txid), &[0; 12], &[]);
+	if !chacha.decrypt(&blob[0..blob.len() - 16], &mut plaintext, &blob[blob.len() - 16..]) {
+		return None;
+	}
+	deserialize(&plaintext).ok()
+}
+
+/// A ManyChannelMonitor which passes all updates through to another (generally local)
+/// ManyChannelMonitor, while also handing a watchtower a justice blob for each remote commitment
+/// transaction which is revoked.
+/// Note that justice transactions are built from the client's own copy of each ChannelMonitor,
+/// which is kept current by applying each ChannelMonitorUpdate, so all updates must go through
+/// the same client for the tower to be able to act on every revoked state.
+pub struct WatchtowerClient {
+	monitor: Arc<ManyChannelMonitor>,
+	tower: Arc<WatchtowerInterface>,
+	fee_estimator: Arc<FeeEstimator>,
+	monitors: Mutex<HashMap<OutPoint, ChannelMonitor>>,
+	/// The lowest commitment number we've handed the tower a justice blob for, per channel
+	backed_up_commitment_numbers: Mutex<HashMap<OutPoint, u64>>,
+}
+
+impl WatchtowerClient {
+	/// fee_estimator is used to pick the fee the justice transactions handed to the tower pay.
+	/// As they are signed when the commitment transaction is revoked, which may be long before
+	/// they're broadcast, you may wish to provide a relatively high estimate.
+	pub fn new(monitor: Arc<ManyChannelMonitor>, tower: Arc<WatchtowerInterface>, fee_estimator: Arc<FeeEstimator>) -> WatchtowerClient {
+		WatchtowerClient {
+			monitor: monitor,
+			tower: tower,
+			fee_estimator: fee_estimator,
+			monitors: Mutex::new(HashMap::new()),
+			backed_up_commitment_numbers: Mutex::new(HashMap::new()),
+		}
+	}
+}
+
+impl WatchtowerClient {
+	fn back_up_justice_txn(&self, funding_txo: OutPoint, justice_txn: Vec<(u64, Sha256dHash, Vec<Transaction>)>) -> Result<(), ChannelMonitorUpdateErr> {
+		let mut backed_up_commitment_numbers = self.backed_up_commitment_numbers.lock().unwrap();
+		for (commitment_number, commitment_txid, txn) in justice_txn {
+			let already_backed_up = match backed_up_commitment_numbers.get(&funding_txo) {
+				Some(backed_up_number) => commitment_number >= *backed_up_number,
+				None => false,
+			};
+			if !already_backed_up {
+				self.tower.store_justice_blob(justice_hint(&commitment_txid), encrypt_justice_blob(&commitment_txid, &txn))?;
+				backed_up_commitment_numbers.insert(funding_txo, commitment_number);
+			}
+		}
+		Ok(())
+	}
+}
+
+impl ManyChannelMonitor for WatchtowerClient {
+	fn add_monitor(&self, funding_txo: OutPoint, monitor: ChannelMonitor) -> Result<(), ChannelMonitorUpdateErr> {
+		let justice_txn = monitor.get_revoked_remote_justice_txn(&*self.fee_estimator);
+		self.monitors.lock().unwrap().insert(funding_txo, monitor.clone());
+		self.monitor.add_monitor(funding_txo, monitor)?;
+		self.back_up_justice_txn(funding_txo, justice_txn)
+	}
+
+	fn update_monitor(&self, funding_txo: OutPoint, update: ChannelMonitorUpdate) -> Result<(), ChannelMonitorUpdateErr> {
+		let justice_txn = {
+			let mut monitors = self.monitors.lock().unwrap();
+			let monitor = match monitors.get_mut(&funding_txo) {
+				Some(monitor) => monitor,
+				None => return Err(ChannelMonitorUpdateErr::PermanentFailure),
+			};
+			if let Err(_) = monitor.update_monitor(&update) {
+				return Err(ChannelMonitorUpdateErr::PermanentFailure);
+			}
+			monitor.get_revoked_remote_justice_txn(&*self.fee_estimator)
+		};
+		self.monitor.update_monitor(funding_txo, update)?;
+		self.back_up_justice_txn(funding_txo, justice_txn)
+	}
+}
+
+/// A justice blob whose revoked commitment transaction has been confirmed.
+struct TriggeredBlob {
+	hint: [u8; JUSTICE_HINT_LEN],
+	blob: Vec<u8>,
+	/// The hash of the block which included the revoked commitment transaction
+	block_hash: Sha256dHash,
+	justice_txn: Vec<Transaction>,
+	/// For each justice transaction, the hash and height of the block which included a spend of
+	/// (any of) its inputs, ie generally the justice transaction itself, if any
+	justice_spends: Vec<Option<(Sha256dHash, u32)>>,
+}
+
+impl TriggeredBlob {
+	/// Whether the inputs of all of the justice transactions have been spent, with each spend at
+	/// least ANTI_REORG_DELAY blocks deep at the given height.
+	fn is_resolved(&self, height: u32) -> bool {
+		self.justice_spends.iter().all(|spend| match spend {
+			&Some((_, spend_height)) => height >= spend_height + ANTI_REORG_DELAY,
+			&None => false,
+		})
+	}
+}
+
+/// A simple watchtower which stores justice blobs in memory and watches all transactions in
+/// connected blocks, broadcasting the contents of any blob which matches (and decrypts with) a
+/// confirmed transaction.
+/// Justice transactions are rebroadcast each block until they (or a conflicting spend of their
+/// inputs) are ANTI_REORG_DELAY blocks deep, and a blob is restored if the block which included
+/// its revoked commitment transaction is disconnected, so that it may be matched again.
+/// Note that any client may store any number of blobs, so if you're running a tower for
+/// untrusted clients you should authenticate them (and/or charge for storage) before passing
+/// blobs on to store_justice_blob.
+pub struct WatchtowerServer {
+	blobs: Mutex<HashMap<[u8; JUSTICE_HINT_LEN], Vec<Vec<u8>>>>,
+	/// Blobs which matched a confirmed transaction, by that transaction's txid
+	triggered_blobs: Mutex<HashMap<Sha256dHash, TriggeredBlob>>,
+	chain_monitor: Arc<ChainWatchInterface>,
+	broadcaster: Arc<BroadcasterInterface>,
+}
+
+impl WatchtowerServer {
+	/// Creates a new WatchtowerServer, registering it with the given chain_monitor and asking
+	/// it to match all transactions (as we cannot know which outpoints revoked transactions will
+	/// spend).
+	pub fn new(chain_monitor: Arc<ChainWatchInterface>, broadcaster: Arc<BroadcasterInterface>) -> Arc<WatchtowerServer> {
+		let res = Arc::new(WatchtowerServer {
+			blobs: Mutex::new(HashMap::new()),
+			triggered_blobs: Mutex::new(HashMap::new()),
+			chain_monitor: chain_monitor,
+			broadcaster: broadcaster,
+		});
+		let weak_res = Arc::downgrade(&res);
+		res.chain_monitor.register_listener(weak_res);
+		res.chain_monitor.watch_all_txn();
+		res
+	}
+}
+
+impl WatchtowerInterface for WatchtowerServer {
+	fn store_justice_blob(&self, hint: [u8; JUSTICE_HINT_LEN], blob: Vec<u8>) -> Result<(), ChannelMonitorUpdateErr> {
+		let mut blobs = self.blobs.lock().unwrap();
+		let hint_blobs = blobs.entry(hint).or_insert(Vec::new());
+		if !hint_blobs.contains(&blob) {
+			hint_blobs.push(blob);
+		}
+		Ok(())
+	}
+}
+
+impl ChainListener for WatchtowerServer {
+	fn block_connected(&self, header: &BlockHeader, height: u32, txn_matched: &[&Transaction], _indexes_of_txn_matched: &[u32]) {
+		let block_hash = header.bitcoin_hash();
+		let mut blobs = self.blobs.lock().unwrap();
+		let mut triggered_blobs = self.triggered_blobs.lock().unwrap();
+		for tx in txn_matched {
+			let txid = tx.txid();
+			let hint = justice_hint(&txid);
+			let mut remove_hint = false;
+			if let Some(hint_blobs) = blobs.get_mut(&hint) {
+				// Blobs which don't decrypt are for a different transaction with the same hint
+				hint_blobs.retain(|blob| {
+					match decrypt_justice_blob(&txid, blob) {
+						Some(justice_txn) => {
+							for justice_tx in justice_txn.iter() {
+								self.broadcaster.broadcast_transaction(justice_tx);
+							}
+							triggered_blobs.insert(txid.clone(), TriggeredBlob {
+								hint,
+								blob: blob.clone(),
+								block_hash,
+								justice_spends: vec![None; justice_txn.len()],
+								justice_txn,
+							});
+							false
+						},
+						None => true,
+					}
+				});
+				remove_hint = hint_blobs.is_empty();
+			}
+			if remove_hint {
+				blobs.remove(&hint);
+			}
+
+			for triggered in triggered_blobs.values_mut() {
+				for (justice_tx, spend) in triggered.justice_txn.iter().zip(triggered.justice_spends.iter_mut()) {
+					if spend.is_none() && tx.input.iter().any(|txin| justice_tx.input.iter().any(|justice_txin| txin.prev_hash == justice_txin.prev_hash && txin.prev_index == justice_txin.prev_index)) {
+						*spend = Some((block_hash, height));
+					}
+				}
+			}
+		}
+
+		triggered_blobs.retain(|_, triggered| !triggered.is_resolved(height));
+		for triggered in triggered_blobs.values() {
+			// Blobs triggered in this block were just broadcast above
+			if triggered.block_hash != block_hash {
+				for (justice_tx, spend) in triggered.justice_txn.iter().zip(triggered.justice_spends.iter()) {
+					if spend.is_none() {
+						self.broadcaster.broadcast_transaction(justice_tx);
+					}
+				}
+			}
+		}
+	}
+
+	fn block_disconnected(&self, header: &BlockHeader) {
+		let block_hash = header.bitcoin_hash();
+		let mut blobs = self.blobs.lock().unwrap();
+		let mut triggered_blobs = self.triggered_blobs.lock().unwrap();
+		triggered_blobs.retain(|_, triggered| {
+			if triggered.block_hash == block_hash {
+				// The revoked commitment transaction may never be confirmed again (or be confirmed in
+				// a different block), so go back to waiting for it.
+				let hint_blobs = blobs.entry(triggered.hint).or_insert(Vec::new());
+				if !hint_blobs.contains(&triggered.blob) {
+					hint_blobs.push(triggered.blob.clone());
+				}
+				false
+			} else {
+				for spend in triggered.justice_spends.iter_mut() {
+					if spend.map(|(spend_block_hash, _)| spend_block_hash == block_hash).unwrap_or(false) {
+						*spend = None;
+					}
+				}
+				true
+			}
+		});
+	}
+}
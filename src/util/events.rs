@@ -1,4 +1,5 @@
 use ln::msgs;
+use ln::router::Route;
 use chain::transaction::OutPoint;
 
 use bitcoin::blockdata::script::Script;
@@ -28,6 +29,19 @@ pub enum Event {
 		/// The value passed in to ChannelManager::create_channel
 		user_channel_id: u64,
 	},
+	/// Indicates a channel we or our counterparty began closing cooperatively has closed.
+	/// Generated once we have a signed closing transaction, once either side sends shutdown if
+	/// the channel was never funded, or, if we failed to agree on a closing fee and
+	/// ClosingFeeFailureAction::ForceClose was set, once we've broadcast our latest commitment
+	/// transaction instead. Also generated by ChannelManager::force_close_channel.
+	ChannelClosed {
+		channel_id: Uint256,
+		/// The value passed in to ChannelManager::create_channel, or 0 for inbound channels
+		user_channel_id: u64,
+		/// The fee paid by the closing transaction, or None if we force-closed the channel or it
+		/// was never funded
+		closing_fee_satoshis: Option<u64>,
+	},
 	/// Indicates we've received money! Just gotta dig out that payment preimage and feed it to
 	/// ChannelManager::claim_funds to get it....
 	/// Note that if the preimage is not known, you must call ChannelManager::fail_htlc_backwards
@@ -44,8 +58,64 @@ pub enum Event {
 	},
 	/// Indicates an outbound payment we made failed. Probably some intermediary node dropped
 	/// something. You may wish to retry with a different route.
+	/// Any channel_update included in the failure has already been handed to the Router.
 	PaymentFailed {
 		payment_hash: [u8; 32],
+		/// Indicates the payment was rejected for some reason by the recipient. This implies that
+		/// the payment has failed, not just the route in question. If this is not set, you may
+		/// retry the payment via a different route.
+		rejected_by_dest: bool,
+		/// The node which generated the failure, if we could authenticate the error. Set to our
+		/// own node id if the failure occurred before the HTLC left us.
+		erring_node: Option<PublicKey>,
+		/// The channel the erring node was unable to forward over (or, if the destination
+		/// rejected the payment, the channel into it), if known.
+		short_channel_id: Option<u64>,
+		/// The BOLT 4 failure code, if the error could be decoded.
+		failure_code: Option<msgs::HTLCFailureCode>,
+	},
+	/// Indicates a probe sent via ChannelManager::send_probe reached its destination (which then
+	/// rejected it), ie every channel along the route could carry the route's value. Replaces
+	/// the PaymentFailed event for the probe.
+	ProbeSuccessful {
+		/// The payment_hash returned by ChannelManager::send_probe
+		payment_hash: [u8; 32],
+		route: Route,
+	},
+	/// Indicates a probe sent via ChannelManager::send_probe failed before reaching its
+	/// destination. Replaces the PaymentFailed event for the probe.
+	/// Should be passed to Router::handle_probe_result so that routes avoid the failing channel.
+	ProbeFailed {
+		/// The payment_hash returned by ChannelManager::send_probe
+		payment_hash: [u8; 32],
+		route: Route,
+		/// See PaymentFailed::erring_node
+		erring_node: Option<PublicKey>,
+		/// See PaymentFailed::short_channel_id
+		short_channel_id: Option<u64>,
+		/// See PaymentFailed::failure_code
+		failure_code: Option<msgs::HTLCFailureCode>,
+	},
+	/// Indicates a payment made via PaymentTracker::send_payment succeeded. This replaces the
+	/// PaymentSent event for the attempt which made it.
+	TrackedPaymentSent {
+		/// The id returned by PaymentTracker::send_payment
+		payment_id: u64,
+		payment_preimage: [u8; 32],
+		/// The number of routes we tried, including the successful one
+		attempts: u32,
+	},
+	/// Indicates a payment made via PaymentTracker::send_payment failed and will not be retried,
+	/// either because the recipient rejected it, a node on the route failed permanently, or we ran
+	/// out of attempts, time or routes. This replaces the PaymentFailed event for each failed attempt.
+	TrackedPaymentFailed {
+		/// The id returned by PaymentTracker::send_payment
+		payment_id: u64,
+		payment_hash: [u8; 32],
+		/// See PaymentFailed::rejected_by_dest for the last attempt
+		rejected_by_dest: bool,
+		/// The number of routes we tried
+		attempts: u32,
 	},
 
 	// Events indicating the network loop should send a message to a peer:
@@ -90,6 +160,26 @@ pub enum Event {
 		msg: msgs::UpdateFailHTLC,
 		commitment_msg: msgs::CommitmentSigned,
 	},
+	/// Used to indicate that a revoke_and_ack message, which we held back while a channel monitor
+	/// update was in progress, should be sent to the peer with the given node_id.
+	SendRevokeAndACK {
+		node_id: PublicKey,
+		msg: msgs::RevokeAndACK,
+	},
+	/// Used to indicate that a series of HTLC update messages, as well as a commitment_signed
+	/// message, which we held back while a channel monitor update was in progress, should be sent
+	/// to the peer with the given node_id.
+	SendCommitmentUpdate {
+		node_id: PublicKey,
+		updates: msgs::CommitmentUpdate,
+	},
+	/// Used to indicate that an error message should be sent to the peer with the given node_id,
+	/// eg because we force-closed a channel with them other than in response to one of their
+	/// messages.
+	SendErrorMessage {
+		node_id: PublicKey,
+		msg: msgs::ErrorMessage,
+	},
 	/// Used to indicate that a channel_announcement and channel_update should be broadcast to all
 	/// peers (except the peer with node_id either msg.contents.node_id_1 or msg.contents.node_id_2).
 	BroadcastChannelAnnouncement {
@@ -100,6 +190,11 @@ pub enum Event {
 	BroadcastChannelUpdate {
 		msg: msgs::ChannelUpdate,
 	},
+	/// Used to indicate that a node_announcement for our own node should be broadcast to all
+	/// peers.
+	BroadcastNodeAnnouncement {
+		msg: msgs::NodeAnnouncement,
+	},
 }
 
 pub trait EventsProvider {
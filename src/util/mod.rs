@@ -1,14 +1,11 @@
 pub mod transaction_utils;
 pub mod events;
+pub mod rng;
 
 pub(crate) mod byte_utils;
 pub(crate) mod chacha20poly1305rfc;
 pub(crate) mod internal_traits;
-pub(crate) mod rng;
 pub(crate) mod sha2;
 
-#[cfg(feature = "fuzztarget")]
-pub use self::rng::reset_rng_state;
-
 #[cfg(test)]
 pub(crate) mod test_utils;
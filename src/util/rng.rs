@@ -1,56 +1,121 @@
-#[cfg(not(feature = "fuzztarget"))]
-mod real_rng {
-	use rand::{thread_rng,Rng};
-	use bitcoin::util::uint::Uint256;
+//! Sources of randomness, used for ephemeral keys, temporary channel ids, onion session keys and
+//! route randomization.
 
-	pub fn fill_bytes(data: &mut [u8]) {
-		let mut rng = thread_rng();
-		rng.fill_bytes(data);
+use bitcoin::util::uint::Uint256;
+
+use crypto::chacha20::ChaCha20;
+use crypto::symmetriccipher::SynchronousStreamCipher;
+
+use rand::{thread_rng,Rng};
+
+use util::byte_utils;
+
+use std::sync::Mutex;
+
+/// A trait to describe an object which can provide random bytes.
+/// Note that all of the functions implemented here *must* be reentrant-safe as they may be
+/// called from multiple threads at once.
+pub trait EntropySource: Send + Sync {
+	/// Fills data with random bytes. For anything but test implementations these should come
+	/// from a cryptographically-secure source, as they are used to generate private keys.
+	fn fill_bytes(&self, data: &mut [u8]);
+
+	/// Gets a random u32
+	fn rand_u32(&self) -> u32 {
+		let mut bytes = [0; 4];
+		self.fill_bytes(&mut bytes);
+		byte_utils::slice_to_be32(&bytes)
 	}
 
-	pub fn rand_uint256() -> Uint256 {
-		let mut rng = thread_rng();
-		Uint256([rng.gen(), rng.gen(), rng.gen(), rng.gen()])
+	/// Gets a random Uint256
+	fn rand_uint256(&self) -> Uint256 {
+		let mut bytes = [0; 32];
+		self.fill_bytes(&mut bytes);
+		Uint256([byte_utils::slice_to_be64(&bytes[0..8]), byte_utils::slice_to_be64(&bytes[8..16]),
+		         byte_utils::slice_to_be64(&bytes[16..24]), byte_utils::slice_to_be64(&bytes[24..32])])
 	}
 
-	pub fn rand_f32() -> f32 {
-		let mut rng = thread_rng();
-		rng.next_f32()
+	/// Gets a random f32 in the range [0, 1)
+	fn rand_f32(&self) -> f32 {
+		(self.rand_u32() >> 8) as f32 / (1u32 << 24) as f32
 	}
 }
-#[cfg(not(feature = "fuzztarget"))]
-pub use self::real_rng::*;
-
-#[cfg(feature = "fuzztarget")]
-mod fuzzy_rng {
-	use bitcoin::util::uint::Uint256;
-	use util::byte_utils;
 
-	static mut RNG_ITER: u64 = 0;
+/// An EntropySource which uses rand's thread_rng (which is seeded from the operating system).
+pub struct ThreadRngEntropySource {}
+impl ThreadRngEntropySource {
+	pub fn new() -> ThreadRngEntropySource {
+		ThreadRngEntropySource {}
+	}
+}
+impl EntropySource for ThreadRngEntropySource {
+	fn fill_bytes(&self, data: &mut [u8]) {
+		let mut rng = thread_rng();
+		rng.fill_bytes(data);
+	}
+}
 
-	pub fn fill_bytes(data: &mut [u8]) {
-		let rng = unsafe { RNG_ITER += 1; RNG_ITER -1 };
-		for i in 0..data.len() / 8 {
-			data[i*8..(i+1)*8].copy_from_slice(&byte_utils::be64_to_array(rng));
+/// A deterministic EntropySource which expands a 32-byte seed using ChaCha20, with each call
+/// to fill_bytes using the next nonce. Given the same seed and the same sequence of calls it will
+/// always return the same bytes, making it useful for reproducible tests. Because of this, it
+/// must only ever be used with a seed which is itself securely random outside of tests.
+pub struct SeededEntropySource {
+	seed: [u8; 32],
+	/// A u64 (rather than an AtomicUsize) so that it never wraps around and re-uses a nonce, even
+	/// on 32-bit platforms.
+	next_nonce: Mutex<u64>,
+}
+impl SeededEntropySource {
+	pub fn new(seed: [u8; 32]) -> SeededEntropySource {
+		SeededEntropySource {
+			seed: seed,
+			next_nonce: Mutex::new(0),
 		}
-		let rem = data.len() % 8;
-		let off = data.len() - rem;
-		data[off..].copy_from_slice(&byte_utils::be64_to_array(rng)[0..rem]);
 	}
-
-	pub fn rand_uint256() -> Uint256 {
-		let rng = unsafe { RNG_ITER += 1; RNG_ITER - 1 };
-		Uint256([rng, rng, rng, rng])
+}
+impl EntropySource for SeededEntropySource {
+	fn fill_bytes(&self, data: &mut [u8]) {
+		let nonce = {
+			let mut next_nonce = self.next_nonce.lock().unwrap();
+			let nonce = *next_nonce;
+			*next_nonce += 1;
+			nonce
+		};
+		let mut chacha = ChaCha20::new(&self.seed, &byte_utils::be64_to_array(nonce));
+		let zeros = vec![0; data.len()];
+		chacha.process(&zeros, data);
 	}
+}
 
-	pub fn rand_f32() -> f32 {
-		let rng = unsafe { RNG_ITER += 1; RNG_ITER - 1 };
-		f64::from_bits(rng) as f32
-	}
+#[cfg(test)]
+mod tests {
+	use util::rng::{EntropySource, SeededEntropySource};
+
+	#[test]
+	fn seeded_entropy_source_test() {
+		let source_a = SeededEntropySource::new([42; 32]);
+		let source_b = SeededEntropySource::new([42; 32]);
+		let mut bytes_a = [0; 100];
+		let mut bytes_b = [0; 100];
+		source_a.fill_bytes(&mut bytes_a);
+		source_b.fill_bytes(&mut bytes_b);
+		assert_eq!(&bytes_a[..], &bytes_b[..]);
+		assert!(bytes_a.iter().any(|b| *b != 0));
+
+		// Subsequent calls return new bytes, still in lockstep
+		let mut next_a = [0; 100];
+		let mut next_b = [0; 100];
+		source_a.fill_bytes(&mut next_a);
+		source_b.fill_bytes(&mut next_b);
+		assert_eq!(&next_a[..], &next_b[..]);
+		assert!(&next_a[..] != &bytes_a[..]);
+		assert_eq!(source_a.rand_u32(), source_b.rand_u32());
+		assert_eq!(source_a.rand_uint256(), source_b.rand_uint256());
 
-	pub fn reset_rng_state() {
-		unsafe { RNG_ITER = 0; }
+		// ...but a different seed gives different bytes
+		let source_c = SeededEntropySource::new([43; 32]);
+		let mut bytes_c = [0; 100];
+		source_c.fill_bytes(&mut bytes_c);
+		assert!(&bytes_c[..] != &bytes_a[..]);
 	}
 }
-#[cfg(feature = "fuzztarget")]
-pub use self::fuzzy_rng::*;
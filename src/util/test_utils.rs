@@ -1,11 +1,22 @@
 use chain::chaininterface;
 use chain::chaininterface::ConfirmationTarget;
+use chain::keysinterface::{KeysInterface, KeysManager, ChannelSigner, ChannelPublicKeys};
 use chain::transaction::OutPoint;
+use ln::channel::ChannelKeys;
 use ln::channelmonitor;
+use ln::msgs;
+use util::rng::{EntropySource, SeededEntropySource};
 
 use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::blockdata::script::Script;
+use bitcoin::network::constants::Network;
+use bitcoin::util::hash::Sha256dHash;
 
-use std::sync::{Arc,Mutex};
+use secp256k1::key::{PublicKey, SecretKey};
+use secp256k1::{Secp256k1, Signature, Message};
+
+use std::cmp;
+use std::sync::{Arc,Mutex,Weak};
 
 pub struct TestFeeEstimator {
 	pub sat_per_vbyte: u64,
@@ -17,21 +28,48 @@ impl chaininterface::FeeEstimator for TestFeeEstimator {
 }
 
 pub struct TestChannelMonitor {
-	pub added_monitors: Mutex<Vec<(OutPoint, channelmonitor::ChannelMonitor)>>,
+	/// The funding outpoint and update_id of each monitor added or updated since this was last
+	/// cleared (a newly-added monitor has an update_id of 0).
+	pub added_monitors: Mutex<Vec<(OutPoint, u64)>>,
+	/// Every ChannelMonitorUpdate we've been given, in order. Unlike added_monitors this is never
+	/// cleared by the test helpers, so it can be replayed into another ManyChannelMonitor.
+	pub monitor_updates: Mutex<Vec<(OutPoint, channelmonitor::ChannelMonitorUpdate)>>,
+	/// Returned from add_monitor/update_monitor (after applying the monitor/update to
+	/// simple_monitor), eg to simulate asynchronous persistence.
+	pub update_ret: Mutex<Result<(), channelmonitor::ChannelMonitorUpdateErr>>,
+	/// Called from within add_monitor/update_monitor, if set.
+	pub call_hook: Mutex<Option<Box<Fn() + Send>>>,
 	pub simple_monitor: Arc<channelmonitor::SimpleManyChannelMonitor<OutPoint>>,
 }
 impl TestChannelMonitor {
-	pub fn new(chain_monitor: Arc<chaininterface::ChainWatchInterface>, broadcaster: Arc<chaininterface::BroadcasterInterface>) -> Self {
+	pub fn new(chain_monitor: Arc<chaininterface::ChainWatchInterface>, broadcaster: Arc<chaininterface::BroadcasterInterface>, fee_estimator: Arc<chaininterface::FeeEstimator>) -> Self {
 		Self {
 			added_monitors: Mutex::new(Vec::new()),
-			simple_monitor: channelmonitor::SimpleManyChannelMonitor::new(chain_monitor, broadcaster),
+			monitor_updates: Mutex::new(Vec::new()),
+			update_ret: Mutex::new(Ok(())),
+			call_hook: Mutex::new(None),
+			simple_monitor: channelmonitor::SimpleManyChannelMonitor::new(chain_monitor, broadcaster, fee_estimator),
 		}
 	}
 }
 impl channelmonitor::ManyChannelMonitor for TestChannelMonitor {
-	fn add_update_monitor(&self, funding_txo: OutPoint, monitor: channelmonitor::ChannelMonitor) -> Result<(), channelmonitor::ChannelMonitorUpdateErr> {
-		self.added_monitors.lock().unwrap().push((funding_txo, monitor.clone()));
-		self.simple_monitor.add_update_monitor(funding_txo, monitor)
+	fn add_monitor(&self, funding_txo: OutPoint, monitor: channelmonitor::ChannelMonitor) -> Result<(), channelmonitor::ChannelMonitorUpdateErr> {
+		self.added_monitors.lock().unwrap().push((funding_txo, monitor.get_latest_update_id()));
+		self.simple_monitor.add_monitor(funding_txo, monitor)?;
+		if let Some(ref hook) = *self.call_hook.lock().unwrap() {
+			hook();
+		}
+		self.update_ret.lock().unwrap().clone()
+	}
+
+	fn update_monitor(&self, funding_txo: OutPoint, update: channelmonitor::ChannelMonitorUpdate) -> Result<(), channelmonitor::ChannelMonitorUpdateErr> {
+		self.added_monitors.lock().unwrap().push((funding_txo, update.update_id));
+		self.monitor_updates.lock().unwrap().push((funding_txo, update.clone()));
+		self.simple_monitor.update_monitor(funding_txo, update)?;
+		if let Some(ref hook) = *self.call_hook.lock().unwrap() {
+			hook();
+		}
+		self.update_ret.lock().unwrap().clone()
 	}
 }
 
@@ -43,3 +81,254 @@ impl chaininterface::BroadcasterInterface for TestBroadcaster {
 		self.txn_broadcasted.lock().unwrap().push(tx.clone());
 	}
 }
+
+pub struct TestChainWatcher {
+	pub utxo_ret: Mutex<Result<(Sha256dHash, Script, u64), chaininterface::ChainError>>,
+}
+impl TestChainWatcher {
+	pub fn new() -> Self {
+		Self {
+			utxo_ret: Mutex::new(Err(chaininterface::ChainError::NotSupported)),
+		}
+	}
+}
+impl chaininterface::ChainWatchInterface for TestChainWatcher {
+	fn install_watch_script(&self, _script_pub_key: Script) { }
+	fn install_watch_outpoint(&self, _outpoint: (Sha256dHash, u32)) { }
+	fn watch_all_txn(&self) { }
+	fn register_listener(&self, _listener: Weak<chaininterface::ChainListener>) { }
+
+	fn get_chain_utxo(&self, _genesis_hash: Sha256dHash, _unspent_tx_output_identifier: u64) -> Result<(Sha256dHash, Script, u64), chaininterface::ChainError> {
+		self.utxo_ret.lock().unwrap().clone()
+	}
+}
+
+/// A ChannelSigner which wraps an in-memory ChannelKeys but panics if asked to sign a local
+/// commitment transaction which we've already revoked, or a remote commitment transaction older
+/// than the latest one we've signed.
+pub struct EnforcingChannelSigner {
+	inner: ChannelKeys,
+	/// The lowest commitment number for which we've released the per-commitment secret
+	revoked_local_commitment: Mutex<u64>,
+	/// The lowest remote commitment number we've signed
+	last_remote_commitment: Mutex<u64>,
+}
+impl EnforcingChannelSigner {
+	pub fn new(inner: ChannelKeys) -> Self {
+		Self {
+			inner,
+			revoked_local_commitment: Mutex::new(1 << 48),
+			last_remote_commitment: Mutex::new(1 << 48),
+		}
+	}
+}
+impl ChannelSigner for EnforcingChannelSigner {
+	fn pubkeys(&self, secp_ctx: &Secp256k1) -> ChannelPublicKeys {
+		self.inner.pubkeys(secp_ctx)
+	}
+
+	fn get_per_commitment_point(&self, secp_ctx: &Secp256k1, commitment_number: u64) -> PublicKey {
+		self.inner.get_per_commitment_point(secp_ctx, commitment_number)
+	}
+
+	fn release_commitment_secret(&self, commitment_number: u64) -> [u8; 32] {
+		let mut revoked = self.revoked_local_commitment.lock().unwrap();
+		*revoked = cmp::min(*revoked, commitment_number);
+		self.inner.release_commitment_secret(commitment_number)
+	}
+
+	fn sign_remote_commitment(&self, secp_ctx: &Secp256k1, commitment_tx: &Transaction, commitment_number: u64, funding_redeemscript: &Script, channel_value_satoshis: u64) -> Result<Signature, ()> {
+		let mut last_remote = self.last_remote_commitment.lock().unwrap();
+		assert!(commitment_number <= *last_remote, "Tried to sign a remote commitment transaction older than one we already signed");
+		*last_remote = commitment_number;
+		self.inner.sign_remote_commitment(secp_ctx, commitment_tx, commitment_number, funding_redeemscript, channel_value_satoshis)
+	}
+
+	fn sign_local_commitment(&self, secp_ctx: &Secp256k1, commitment_tx: &Transaction, commitment_number: u64, funding_redeemscript: &Script, channel_value_satoshis: u64) -> Result<Signature, ()> {
+		assert!(commitment_number < *self.revoked_local_commitment.lock().unwrap(), "Tried to sign a revoked local commitment transaction");
+		self.inner.sign_local_commitment(secp_ctx, commitment_tx, commitment_number, funding_redeemscript, channel_value_satoshis)
+	}
+
+	fn sign_htlc_transaction(&self, secp_ctx: &Secp256k1, htlc_tx: &Transaction, input: usize, htlc_redeemscript: &Script, amount_satoshis: u64, per_commitment_point: &PublicKey) -> Result<Signature, ()> {
+		self.inner.sign_htlc_transaction(secp_ctx, htlc_tx, input, htlc_redeemscript, amount_satoshis, per_commitment_point)
+	}
+
+	fn sign_justice_transaction(&self, secp_ctx: &Secp256k1, justice_tx: &Transaction, input: usize, redeemscript: &Script, amount_satoshis: u64, per_commitment_key: &SecretKey) -> Result<Signature, ()> {
+		self.inner.sign_justice_transaction(secp_ctx, justice_tx, input, redeemscript, amount_satoshis, per_commitment_key)
+	}
+
+	fn sign_closing_transaction(&self, secp_ctx: &Secp256k1, closing_tx: &Transaction, funding_redeemscript: &Script, channel_value_satoshis: u64) -> Result<Signature, ()> {
+		self.inner.sign_closing_transaction(secp_ctx, closing_tx, funding_redeemscript, channel_value_satoshis)
+	}
+
+	fn sign_channel_announcement(&self, secp_ctx: &Secp256k1, msg: &msgs::UnsignedChannelAnnouncement) -> Result<Signature, ()> {
+		self.inner.sign_channel_announcement(secp_ctx, msg)
+	}
+}
+
+/// A KeysInterface which hands out EnforcingChannelSigners, deferring to a KeysManager for
+/// everything else. Channel keys are deterministically derived from the seed as well.
+pub struct TestKeysInterface {
+	pub backing: KeysManager,
+	channel_seeds: SeededEntropySource,
+}
+impl TestKeysInterface {
+	pub fn new(seed: &[u8; 32], network: Network) -> Self {
+		Self {
+			backing: KeysManager::new(seed, network, 0, 0, 0),
+			channel_seeds: SeededEntropySource::new(*seed),
+		}
+	}
+}
+impl KeysInterface for TestKeysInterface {
+	fn get_node_secret(&self) -> SecretKey { self.backing.get_node_secret() }
+	fn get_destination_script(&self) -> Script { self.backing.get_destination_script() }
+	fn get_shutdown_pubkey(&self) -> PublicKey { self.backing.get_shutdown_pubkey() }
+
+	fn get_channel_signer(&self) -> Arc<ChannelSigner> {
+		let mut seed = [0; 32];
+		self.channel_seeds.fill_bytes(&mut seed);
+		Arc::new(EnforcingChannelSigner::new(ChannelKeys::new_from_seed(&seed).unwrap()))
+	}
+}
+
+/// A RoutingMessageHandler which knows of channel_count (fake) channels, with short_channel_ids
+/// 0..channel_count, each with a channel_update in both directions, and no nodes.
+pub struct TestRoutingMessageHandler {
+	pub channel_count: u64,
+	secp_ctx: Secp256k1,
+}
+impl TestRoutingMessageHandler {
+	pub fn new(channel_count: u64) -> Self {
+		Self {
+			channel_count,
+			secp_ctx: Secp256k1::new(),
+		}
+	}
+
+	fn get_channel(&self, short_channel_id: u64) -> (msgs::ChannelAnnouncement, Option<msgs::ChannelUpdate>, Option<msgs::ChannelUpdate>) {
+		let key = SecretKey::from_slice(&self.secp_ctx, &[42; 32]).unwrap();
+		let pubkey = PublicKey::from_secret_key(&self.secp_ctx, &key).unwrap();
+		let sig = self.secp_ctx.sign(&Message::from_slice(&[42; 32]).unwrap(), &key).unwrap();
+		let announcement = msgs::ChannelAnnouncement {
+			node_signature_1: sig.clone(),
+			node_signature_2: sig.clone(),
+			bitcoin_signature_1: sig.clone(),
+			bitcoin_signature_2: sig.clone(),
+			contents: msgs::UnsignedChannelAnnouncement {
+				features: msgs::GlobalFeatures::new(),
+				chain_hash: Sha256dHash::from_data(&[0; 32]),
+				short_channel_id,
+				node_id_1: pubkey.clone(),
+				node_id_2: pubkey.clone(),
+				bitcoin_key_1: pubkey.clone(),
+				bitcoin_key_2: pubkey.clone(),
+			},
+		};
+		let update = |flags| msgs::ChannelUpdate {
+			signature: sig.clone(),
+			contents: msgs::UnsignedChannelUpdate {
+				chain_hash: Sha256dHash::from_data(&[0; 32]),
+				short_channel_id,
+				timestamp: 0,
+				flags,
+				cltv_expiry_delta: 0,
+				htlc_minimum_msat: 0,
+				fee_base_msat: 0,
+				fee_proportional_millionths: 0,
+			},
+		};
+		(announcement, Some(update(0)), Some(update(1)))
+	}
+}
+impl msgs::RoutingMessageHandler for TestRoutingMessageHandler {
+	fn handle_node_announcement(&self, _msg: &msgs::NodeAnnouncement) -> Result<bool, msgs::HandleError> { Ok(false) }
+	fn handle_channel_announcement(&self, _msg: &msgs::ChannelAnnouncement) -> Result<bool, msgs::HandleError> { Ok(false) }
+	fn handle_channel_update(&self, _msg: &msgs::ChannelUpdate) -> Result<bool, msgs::HandleError> { Ok(true) }
+	fn handle_htlc_fail_channel_update(&self, _update: &msgs::HTLCFailChannelUpdate) {}
+	fn get_next_channel_announcements(&self, starting_point: u64, batch_amount: u8) -> Vec<(msgs::ChannelAnnouncement, Option<msgs::ChannelUpdate>, Option<msgs::ChannelUpdate>)> {
+		let end = cmp::min(starting_point.saturating_add(batch_amount as u64), self.channel_count);
+		(cmp::min(starting_point, end)..end).map(|short_channel_id| self.get_channel(short_channel_id)).collect()
+	}
+	fn get_next_node_announcements(&self, _starting_point: Option<&PublicKey>, _batch_amount: u8) -> Vec<msgs::NodeAnnouncement> { Vec::new() }
+	fn handle_query_short_channel_ids(&self, msg: &msgs::QueryShortChannelIds) -> Result<(Vec<(msgs::ChannelAnnouncement, Option<msgs::ChannelUpdate>, Option<msgs::ChannelUpdate>)>, Vec<msgs::NodeAnnouncement>), msgs::HandleError> {
+		Ok((msg.short_channel_ids.iter().filter(|scid| **scid < self.channel_count).map(|scid| self.get_channel(*scid)).collect(), Vec::new()))
+	}
+	fn handle_query_channel_range(&self, msg: &msgs::QueryChannelRange) -> Result<Vec<msgs::ReplyChannelRange>, msgs::HandleError> {
+		Ok(vec![msgs::ReplyChannelRange {
+			chain_hash: msg.chain_hash,
+			first_blocknum: msg.first_blocknum,
+			number_of_blocks: msg.number_of_blocks,
+			full_information: true,
+			short_channel_ids: Vec::new(),
+		}])
+	}
+	fn handle_reply_channel_range(&self, msg: &msgs::ReplyChannelRange) -> Result<Vec<u64>, msgs::HandleError> {
+		Ok(msg.short_channel_ids.clone())
+	}
+	fn handle_reply_short_channel_ids_end(&self, _msg: &msgs::ReplyShortChannelIdsEnd) -> Result<(), msgs::HandleError> { Ok(()) }
+}
+
+#[cfg(test)]
+mod tests {
+	use chain::keysinterface::ChannelSigner;
+	use ln::channel::ChannelKeys;
+	use util::test_utils::EnforcingChannelSigner;
+
+	use bitcoin::blockdata::transaction::{Transaction, TxIn};
+	use bitcoin::blockdata::script::Script;
+	use bitcoin::util::hash::Sha256dHash;
+
+	use secp256k1::Secp256k1;
+
+	const INITIAL_COMMITMENT_NUMBER: u64 = (1 << 48) - 1;
+
+	fn dummy_commitment_tx() -> Transaction {
+		Transaction {
+			version: 2,
+			lock_time: 0,
+			input: vec![TxIn {
+				prev_hash: Sha256dHash::from_data(&[42; 32]),
+				prev_index: 0,
+				script_sig: Script::new(),
+				sequence: 0,
+				witness: Vec::new(),
+			}],
+			output: Vec::new(),
+		}
+	}
+
+	#[test]
+	fn enforcing_signer_signs_current_state() {
+		let secp_ctx = Secp256k1::new();
+		let signer = EnforcingChannelSigner::new(ChannelKeys::new_from_seed(&[42; 32]).unwrap());
+		let tx = dummy_commitment_tx();
+		assert!(signer.sign_local_commitment(&secp_ctx, &tx, INITIAL_COMMITMENT_NUMBER, &Script::new(), 100000).is_ok());
+		signer.release_commitment_secret(INITIAL_COMMITMENT_NUMBER);
+		assert!(signer.sign_local_commitment(&secp_ctx, &tx, INITIAL_COMMITMENT_NUMBER - 1, &Script::new(), 100000).is_ok());
+
+		assert!(signer.sign_remote_commitment(&secp_ctx, &tx, INITIAL_COMMITMENT_NUMBER, &Script::new(), 100000).is_ok());
+		// Re-signing the latest remote commitment is fine, eg on reconnect
+		assert!(signer.sign_remote_commitment(&secp_ctx, &tx, INITIAL_COMMITMENT_NUMBER, &Script::new(), 100000).is_ok());
+		assert!(signer.sign_remote_commitment(&secp_ctx, &tx, INITIAL_COMMITMENT_NUMBER - 1, &Script::new(), 100000).is_ok());
+	}
+
+	#[test]
+	#[should_panic(expected = "Tried to sign a revoked local commitment transaction")]
+	fn enforcing_signer_refuses_revoked_local_commitment() {
+		let secp_ctx = Secp256k1::new();
+		let signer = EnforcingChannelSigner::new(ChannelKeys::new_from_seed(&[42; 32]).unwrap());
+		signer.release_commitment_secret(INITIAL_COMMITMENT_NUMBER);
+		let _ = signer.sign_local_commitment(&secp_ctx, &dummy_commitment_tx(), INITIAL_COMMITMENT_NUMBER, &Script::new(), 100000);
+	}
+
+	#[test]
+	#[should_panic(expected = "Tried to sign a remote commitment transaction older than one we already signed")]
+	fn enforcing_signer_refuses_old_remote_commitment() {
+		let secp_ctx = Secp256k1::new();
+		let signer = EnforcingChannelSigner::new(ChannelKeys::new_from_seed(&[42; 32]).unwrap());
+		let tx = dummy_commitment_tx();
+		assert!(signer.sign_remote_commitment(&secp_ctx, &tx, INITIAL_COMMITMENT_NUMBER - 1, &Script::new(), 100000).is_ok());
+		let _ = signer.sign_remote_commitment(&secp_ctx, &tx, INITIAL_COMMITMENT_NUMBER, &Script::new(), 100000);
+	}
+}